@@ -0,0 +1,103 @@
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+use crate::config::{DriveConfig, DriveInterface, SnapshotBootConfig};
+use crate::drive;
+use crate::qmp::QmpClient;
+
+/// The saved state of a booted VM, which later runs restore instead of booting again
+#[derive(Debug, Clone)]
+pub struct BootSnapshot {
+    pub config: SnapshotBootConfig,
+    /// The qcow2 disk the state is saved to
+    pub disk: PathBuf,
+    /// Whether the snapshot is restored, otherwise it is saved by this run
+    pub restore: bool,
+    key: String,
+}
+
+impl BootSnapshot {
+    /// Opens the snapshot in `dir`, which is only restored if it was saved by a run with the same
+    /// `key`, otherwise it is discarded and saved again
+    pub fn open(dir: &Path, config: &SnapshotBootConfig, key: String) -> Result<Self, String> {
+        let disk = dir.join("boot-snapshot.qcow2");
+        let key_path = disk.with_extension("key");
+        let restore =
+            disk.exists() && std::fs::read_to_string(&key_path).is_ok_and(|saved| saved == key);
+        if !restore {
+            let _ = std::fs::remove_file(&key_path);
+            // QEMU saves the state of the VM past the end of the disk, so it can be small
+            let drive = DriveConfig {
+                path: String::new(),
+                format: "qcow2".to_string(),
+                interface: DriveInterface::Virtio,
+                size: Some("1M".to_string()),
+                fresh: true,
+            };
+            drive::prepare_drive(&disk, &drive)?;
+        }
+        Ok(Self {
+            config: config.clone(),
+            disk,
+            restore,
+            key,
+        })
+    }
+
+    /// The QEMU arguments that attach the disk, and restore the snapshot if it was saved
+    pub fn qemu_args(&self) -> Vec<String> {
+        // The disk isn't attached to the guest, it only holds the state
+        let mut args = vec![
+            "-drive".to_string(),
+            format!(
+                "if=none,id=boot-snapshot,format=qcow2,file={}",
+                self.disk.to_string_lossy().replace(',', ",,")
+            ),
+        ];
+        if self.restore {
+            args.push("-loadvm".to_string());
+            args.push(self.config.name.clone());
+        }
+        args
+    }
+
+    /// Saves the snapshot through the QMP server on `port`, the guest is paused meanwhile
+    pub fn save(&self, port: u16) -> Result<(), String> {
+        let mut client = QmpClient::connect(("127.0.0.1", port))?;
+        client.execute("stop", None)?;
+        // savevm is only available as a monitor command
+        let output = client.execute(
+            "human-monitor-command",
+            Some(json!({ "command-line": format!("savevm {}", self.config.name) })),
+        );
+        client.execute("cont", None)?;
+        match output?.as_str() {
+            Some("") | None => {}
+            Some(error) => return Err(error.trim().to_string()),
+        }
+        std::fs::write(self.disk.with_extension("key"), &self.key).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_boot_snapshot_args() {
+    let snapshot = BootSnapshot {
+        config: SnapshotBootConfig {
+            marker: "READY".to_string(),
+            name: "booted".to_string(),
+        },
+        disk: PathBuf::from("/tmp/boot-snapshot.qcow2"),
+        restore: true,
+        key: String::new(),
+    };
+    assert_eq!(
+        snapshot.qemu_args(),
+        [
+            "-drive",
+            "if=none,id=boot-snapshot,format=qcow2,file=/tmp/boot-snapshot.qcow2",
+            "-loadvm",
+            "booted"
+        ]
+    );
+}
@@ -2,21 +2,429 @@
 use git2::{FetchOptions, RemoteCallbacks};
 #[cfg(feature = "pretty-output")]
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::{
+    Arch, BootType, BootloaderKind, ExternalBootloaderConfig, GitFetchConfig, GitRefType,
+    ImageRunnerConfig, LIMINE_GIT, LimineSource, SystemdBootConfig, UBootConfig, UpdatePolicy,
+    substitute_vars,
+};
+use crate::context::Context;
+use crate::requirements::Requirement;
+use crate::sbom::Component;
+
+/// Attributes of a file in the image, honored by the image formats that support them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// The unix permissions of the file in a directory image, ISO 9660 images have no
+    /// permissions without Rock Ridge, which isn't written
+    pub mode: Option<u32>,
+}
+
+/// A file to copy into the image
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub source: PathBuf,
+    /// The destination, relative to the root of the image
+    pub dest: PathBuf,
+    pub attributes: FileAttributes,
+}
+
+impl FileEntry {
+    pub fn new(source: impl Into<PathBuf>, dest: impl Into<PathBuf>) -> Self {
+        Self {
+            source: source.into(),
+            dest: dest.into(),
+            attributes: FileAttributes::default(),
+        }
+    }
+
+    pub fn with_attributes(mut self, attributes: FileAttributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
+}
+
+/// The files a bootloader installs into the image
+#[derive(Debug, Clone, Default)]
+pub struct BootloaderFiles {
+    pub files: Vec<FileEntry>,
+    /// The El Torito BIOS boot image, relative to the root of the image
+    pub bios_boot_image: Option<PathBuf>,
+    /// The El Torito UEFI boot image, relative to the root of the image
+    pub uefi_boot_image: Option<PathBuf>,
+}
+
+/// A bootloader that is installed into the image
+pub trait Bootloader {
+    /// Fetches the bootloader files into the cache directory
+    fn prepare(&self, ctx: &Context);
+
+    /// The files to install into the image
+    fn files(&self, ctx: &Context) -> BootloaderFiles;
+
+    /// Where the executable is placed, relative to the root of the image
+    fn executable_dest(&self, ctx: &Context) -> PathBuf {
+        PathBuf::from(ctx.executable_name())
+    }
+
+    /// Whether the bootloader reads the (processed) config file
+    fn uses_config_file(&self) -> bool {
+        true
+    }
+
+    /// Called after the image has been built, e.g. to install boot code into the finished image
+    fn post_image(&self, _ctx: &Context, _image_path: &Path) {}
+
+    /// Identifies the version of the bootloader files, the image is staged from scratch when it
+    /// changes so files of the previous version don't linger
+    fn version(&self) -> Option<String> {
+        None
+    }
+
+    /// What the bootloader needs from the host, such as external tools or network access
+    fn requirements(&self) -> Vec<Requirement> {
+        Vec::new()
+    }
+
+    /// The firmware QEMU is started with instead of its default one or OVMF
+    fn firmware(&self, _ctx: &Context) -> Option<PathBuf> {
+        None
+    }
+
+    /// The arguments that make QEMU load the kernel itself, in which case no image is built
+    fn direct_boot_args(&self, _ctx: &Context) -> Option<Vec<String>> {
+        None
+    }
+
+    /// The third-party components the bootloader ships in the image or runs it with, listed in
+    /// the SBOM
+    fn components(&self, _ctx: &Context) -> Vec<Component> {
+        Vec::new()
+    }
+}
+
+/// Creates the bootloader selected in the config
+pub fn from_config(config: &ImageRunnerConfig) -> Box<dyn Bootloader> {
+    if config.direct_kernel {
+        return Box::new(DirectKernelBootloader {
+            initrd: config.initrd.clone(),
+            multiboot: config.bootloader == BootloaderKind::Multiboot,
+            limine: None,
+        });
+    }
+    match config.bootloader {
+        BootloaderKind::Limine => Box::new(LimineBootloader {
+            branch: config.limine_branch.clone(),
+            fetch: config.limine_fetch.clone(),
+            source: config.limine_source.clone(),
+        }),
+        BootloaderKind::None => Box::new(NoneBootloader),
+        BootloaderKind::External => Box::new(ExternalBootloader {
+            config: config.external_bootloader.clone(),
+        }),
+        BootloaderKind::SystemdBoot => Box::new(SystemdBootBootloader {
+            config: config.systemd_boot.clone(),
+        }),
+        BootloaderKind::UBoot => Box::new(UBootBootloader {
+            config: config.u_boot.clone(),
+        }),
+        BootloaderKind::Multiboot => Box::new(DirectKernelBootloader {
+            initrd: config.initrd.clone(),
+            multiboot: true,
+            limine: Some(LimineBootloader {
+                branch: config.limine_branch.clone(),
+                fetch: config.limine_fetch.clone(),
+                source: config.limine_source.clone(),
+            }),
+        }),
+    }
+}
+
+/// The limine bootloader, fetched from the limine repository, and built if a source reference is
+/// used
+pub struct LimineBootloader {
+    /// The branch, tag or commit to fetch, depending on `fetch.ref_type`
+    pub branch: String,
+    pub fetch: GitFetchConfig,
+    pub source: LimineSource,
+}
+
+impl LimineBootloader {
+    /// The directory containing the limine repository
+    fn repo_dir(&self, ctx: &Context) -> PathBuf {
+        match &self.source {
+            LimineSource::Git { .. } => ctx.cache_dir().join("limine"),
+            LimineSource::Path { path } => ctx.root_dir().join(path),
+        }
+    }
+
+    /// The directory containing the limine binaries
+    fn limine_dir(&self, ctx: &Context) -> PathBuf {
+        let repo_dir = self.repo_dir(ctx);
+        // Limine built from source leaves its binaries in bin
+        let bin_dir = repo_dir.join("bin");
+        if bin_dir.is_dir() { bin_dir } else { repo_dir }
+    }
+
+    /// Whether the configured reference is (probably) a source reference, binary branches and
+    /// tags end in `-binary`
+    fn is_source_ref(&self) -> bool {
+        self.fetch.ref_type != GitRefType::Commit && !self.branch.ends_with("-binary")
+    }
+}
+
+/// The major version of a limine branch or tag, like `v8.x-binary` or `v8.6.0`, `None` for commits
+/// and other references, which are assumed to be recent
+fn limine_major_version(reference: &str) -> Option<u32> {
+    let version = reference.strip_prefix('v')?;
+    let end = version
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(version.len());
+    version[..end].parse().ok()
+}
+
+#[cfg(test)]
+#[test]
+fn test_limine_major_version() {
+    assert_eq!(limine_major_version("v4.x-branch-binary"), Some(4));
+    assert_eq!(limine_major_version("v8.6.0"), Some(8));
+    assert_eq!(limine_major_version("v10.x"), Some(10));
+    assert_eq!(
+        limine_major_version("3f1c9a2b5e7d8c0a1b2c3d4e5f60718293a4b5c6"),
+        None
+    );
+    assert_eq!(limine_major_version("trunk"), None);
+}
+
+/// The host tools needed to build limine from source, with `make` and the toolchain, `mformat`
+/// is part of mtools
+const LIMINE_BUILD_TOOLS: &[&str] = &["autoconf", "automake", "nasm", "mformat"];
+
+fn missing_build_tool(tool: &str) -> ! {
+    panic!(
+        "building limine from source requires {}, which was not found, use a -binary branch instead",
+        tool
+    );
+}
+
+/// Builds limine from a source checkout in `repo_dir`, the binaries are put in `repo_dir/bin`
+fn build_limine(repo_dir: &Path, arch: Arch) {
+    let found = |program: &str| Requirement::Program(program.to_string()).check("").is_ok();
+    let make = ["gmake", "make"]
+        .into_iter()
+        .find(|make| found(make))
+        .unwrap_or_else(|| missing_build_tool("make"));
+    if let Some(missing) = LIMINE_BUILD_TOOLS.iter().find(|tool| !found(tool)) {
+        missing_build_tool(missing);
+    }
+    // Limine uses LLVM by default, but can also be built with a GNU cross toolchain
+    let cross_prefix = match arch {
+        Arch::X86_64 => "x86_64-elf",
+        Arch::Aarch64 => "aarch64-elf",
+        Arch::Riscv64 => "riscv64-elf",
+        Arch::Ia32 => "i686-elf",
+    };
+    let toolchain = if found("clang") && found("ld.lld") {
+        "llvm".to_string()
+    } else if found(&format!("{}-gcc", cross_prefix)) {
+        cross_prefix.to_string()
+    } else {
+        missing_build_tool(&format!("clang and ld.lld, or {}-gcc", cross_prefix));
+    };
+
+    let configure_flags: &[&str] = match arch {
+        Arch::X86_64 => &[
+            "--enable-bios",
+            "--enable-bios-cd",
+            "--enable-uefi-x86-64",
+            "--enable-uefi-cd",
+        ],
+        Arch::Aarch64 => &["--enable-uefi-aarch64", "--enable-uefi-cd"],
+        Arch::Riscv64 => &["--enable-uefi-riscv64", "--enable-uefi-cd"],
+        Arch::Ia32 => &["--enable-uefi-ia32", "--enable-uefi-cd"],
+    };
+
+    eprintln!("Building limine from source...");
+    let run = |command: &mut Command| {
+        let status = command
+            .current_dir(repo_dir)
+            .status()
+            .unwrap_or_else(|err| panic!("failed to run {:?}: {}", command, err));
+        if !status.success() {
+            panic!("{:?} failed while building limine", command);
+        }
+    };
+    run(&mut Command::new("./bootstrap"));
+    run(Command::new("./configure")
+        .args(configure_flags)
+        .arg(format!("TOOLCHAIN_FOR_TARGET={}", toolchain)));
+    run(&mut Command::new(make));
+}
+
+impl Bootloader for LimineBootloader {
+    fn prepare(&self, ctx: &Context) {
+        let url = match &self.source {
+            LimineSource::Git { git } => git,
+            LimineSource::Path { .. } => {
+                let limine_dir = self.limine_dir(ctx);
+                if !limine_dir.is_dir() {
+                    panic!("limine directory {} does not exist", limine_dir.display());
+                }
+                return;
+            }
+        };
+        fetch_limine(url, &self.branch, &self.fetch, ctx.cache_dir());
+
+        // Source references have no prebuilt binaries
+        let repo_dir = self.repo_dir(ctx);
+        let arch = ctx.config().arch;
+        let built = repo_dir.join("bin").join(arch.uefi_boot_file()).exists();
+        if !repo_dir.join(arch.uefi_boot_file()).exists()
+            && !built
+            && repo_dir.join("bootstrap").exists()
+        {
+            build_limine(&repo_dir, arch);
+        }
+    }
+
+    fn version(&self) -> Option<String> {
+        Some(match &self.source {
+            LimineSource::Git { git } => {
+                format!("{} {:?} {}", git, self.fetch.ref_type, self.branch)
+            }
+            LimineSource::Path { path } => format!("path {}", path),
+        })
+    }
+
+    fn components(&self, ctx: &Context) -> Vec<Component> {
+        let mut limine = Component::new("limine", "BSD-2-Clause");
+        if let LimineSource::Git { .. } = &self.source {
+            limine.version = Some(self.branch.clone());
+        }
+        limine.files = self
+            .files(ctx)
+            .files
+            .into_iter()
+            .map(|file| file.source)
+            .collect();
+        vec![limine]
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        match &self.source {
+            LimineSource::Git { git } => {
+                let mut requirements = git_requirements(
+                    format!("fetching limine {} on the first run", self.branch),
+                    git,
+                );
+                if self.is_source_ref() {
+                    requirements.push(Requirement::Program("make".to_string()));
+                    requirements.extend(
+                        LIMINE_BUILD_TOOLS
+                            .iter()
+                            .map(|tool| Requirement::Program(tool.to_string())),
+                    );
+                }
+                requirements
+            }
+            LimineSource::Path { .. } => Vec::new(),
+        }
+    }
+
+    fn files(&self, ctx: &Context) -> BootloaderFiles {
+        let limine_dir = self.limine_dir(ctx);
+        // Limine v4 used different names for its files
+        let (sys_file, bios_cd_file, uefi_cd_file) =
+            if limine_major_version(&self.branch) == Some(4) {
+                ("limine.sys", "limine-cd.bin", "limine-cd-efi.bin")
+            } else {
+                (
+                    "limine-bios.sys",
+                    "limine-bios-cd.bin",
+                    "limine-uefi-cd.bin",
+                )
+            };
+
+        // BIOS booting is only possible on x86
+        let bios = ctx.config().arch == Arch::X86_64;
+        let support_files: &[&str] = if bios {
+            &[sys_file, bios_cd_file, uefi_cd_file]
+        } else {
+            &[uefi_cd_file]
+        };
+        let mut files: Vec<FileEntry> = support_files
+            .iter()
+            .map(|file| FileEntry::new(limine_dir.join(file), file))
+            .collect();
+        let uefi_boot_file = ctx.config().arch.uefi_boot_file();
+        files.push(FileEntry::new(
+            limine_dir.join(uefi_boot_file),
+            Path::new("EFI/BOOT").join(uefi_boot_file),
+        ));
+
+        BootloaderFiles {
+            files,
+            bios_boot_image: bios.then(|| bios_cd_file.into()),
+            uefi_boot_image: cfg!(feature = "uefi").then(|| uefi_cd_file.into()),
+        }
+    }
+}
 
 /// Prepares the limine bootloader
 pub fn prepare_bootloader(limine_branch: &str, file_dir: &Path) {
-    let limine_dir = file_dir.join("limine");
-    // Stores the old version, so that the crate re-clones if the branch has changed
-    let meta_path = limine_dir.join("meta.old");
-    let old_branch = std::fs::read_to_string(&meta_path).unwrap_or_default();
-    if old_branch == limine_branch {
-        // Nothing to do
-        return;
+    fetch_limine(
+        LIMINE_GIT,
+        limine_branch,
+        &GitFetchConfig::default(),
+        file_dir,
+    );
+}
+
+/// Fetches `reference` of the limine repository at `url` into `file_dir/limine`, following `fetch`
+pub fn fetch_limine(url: &str, reference: &str, fetch: &GitFetchConfig, file_dir: &Path) {
+    fetch_git("limine", url, reference, fetch, &file_dir.join("limine"));
+}
+
+/// Fetches `reference` of the repository of `name` at `url` into `repo_dir`, following `fetch`
+pub fn fetch_git(name: &str, url: &str, reference: &str, fetch: &GitFetchConfig, repo_dir: &Path) {
+    // Stores the old version, so that the crate re-fetches if the reference has changed
+    let meta_path = repo_dir.join("meta.old");
+    let mut meta = match fetch.ref_type {
+        GitRefType::Branch => reference.to_string(),
+        GitRefType::Tag => format!("tag:{}", reference),
+        GitRefType::Commit => format!("commit:{}", reference),
+    };
+    // Mirrors may lag behind, so switching between them fetches again
+    if url != LIMINE_GIT {
+        meta = format!("{}@{}", meta, url);
+    }
+    let old_meta = std::fs::read_to_string(&meta_path).ok();
+    match fetch.update {
+        UpdatePolicy::Never => match old_meta {
+            Some(old_meta) => {
+                if old_meta != meta {
+                    eprintln!("Using cached {} {}, updates are disabled", name, old_meta);
+                }
+                return;
+            }
+            None => panic!(
+                "{} is not cached in {} and updates are disabled",
+                name,
+                repo_dir.display()
+            ),
+        },
+        UpdatePolicy::IfMissing if old_meta.as_deref() == Some(meta.as_str()) => {
+            // Nothing to do
+            return;
+        }
+        _ => {}
     }
 
-    // We first remove the old version, so that we can re-clone
-    std::fs::remove_dir_all(&limine_dir).ok();
+    // We first remove the old version, so that we can fetch again
+    std::fs::remove_dir_all(repo_dir).ok();
     #[cfg(feature = "bundle-git")]
     {
         #[cfg(feature = "pretty-output")]
@@ -28,18 +436,18 @@ pub fn prepare_bootloader(limine_branch: &str, file_dir: &Path) {
                 .unwrap()
                 .progress_chars("#>-"));
 
-            pb.set_message("Cloning limine...");
+            pb.set_message(format!("Fetching {}...", name));
             (multi, pb)
         };
 
         let start_time = std::time::Instant::now();
 
         #[cfg(feature = "pretty-output")]
-        let mut callbacks = {
+        let callbacks = {
             let mut callbacks = RemoteCallbacks::new();
             callbacks.transfer_progress(|stats| {
                 // Rough calculations, we just do integer division
-                let progress = stats.received_objects() * 100 / stats.total_objects();
+                let progress = stats.received_objects() * 100 / stats.total_objects().max(1);
                 pb.set_position(progress as u64);
                 pb.set_message(format!(
                     "Objects: {}/{}, Deltas: {}/{}",
@@ -56,23 +464,31 @@ pub fn prepare_bootloader(limine_branch: &str, file_dir: &Path) {
         let mut fetch_options = FetchOptions::new();
         #[cfg(feature = "pretty-output")]
         fetch_options.remote_callbacks(callbacks);
-        fetch_options.depth(1);
+        // A depth of 0 fetches the full history
+        if fetch.depth > 0 {
+            fetch_options.depth(fetch.depth as i32);
+        }
         fetch_options.download_tags(git2::AutotagOption::None);
-        fetch_options.update_fetchhead(false);
 
-        let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(fetch_options);
-        builder.branch(limine_branch);
+        // Only the requested reference is fetched, branches, tags and commits alike
+        let refspec = match fetch.ref_type {
+            GitRefType::Branch => format!("refs/heads/{0}:refs/remotes/origin/{0}", reference),
+            GitRefType::Tag => format!("refs/tags/{0}:refs/tags/{0}", reference),
+            GitRefType::Commit => reference.to_string(),
+        };
 
-        const LIMINE_GIT: &str = "https://github.com/limine-bootloader/limine";
-        let repo = builder.clone(LIMINE_GIT, &limine_dir).unwrap();
+        let repo = git2::Repository::init(repo_dir).unwrap();
+        repo.remote_anonymous(url)
+            .unwrap()
+            .fetch(&[refspec.as_str()], Some(&mut fetch_options), None)
+            .unwrap_or_else(|err| panic!("failed to fetch {} {}: {}", name, reference, err));
 
         let duration = std::time::Instant::now()
             .duration_since(start_time)
             .as_secs_f32();
 
         #[cfg(feature = "pretty-output")]
-        pb.finish_with_message(format!("Clone completed in {:.2}s", duration));
+        pb.finish_with_message(format!("Fetch completed in {:.2}s", duration));
 
         #[cfg(feature = "pretty-output")]
         let checkout_pb = {
@@ -82,27 +498,797 @@ pub fn prepare_bootloader(limine_branch: &str, file_dir: &Path) {
                     .template("{spinner:.blue} {msg}")
                     .unwrap(),
             );
-            checkout_pb.set_message(format!("Checking out branch {}", limine_branch));
+            checkout_pb.set_message(format!("Checking out {}", reference));
             checkout_pb
         };
 
-        let obj = repo
-            .revparse_single(&format!("origin/{}", limine_branch))
-            .unwrap();
-        repo.checkout_tree(&obj, None).unwrap();
-        repo.set_head(&format!("refs/heads/{}", limine_branch))
+        let commit = repo
+            .find_reference("FETCH_HEAD")
+            .and_then(|head| head.peel_to_commit())
             .unwrap();
+        repo.checkout_tree(
+            commit.as_object(),
+            Some(git2::build::CheckoutBuilder::new().force()),
+        )
+        .unwrap();
+        repo.set_head_detached(commit.id()).unwrap();
 
         let duration = std::time::Instant::now()
             .duration_since(start_time)
             .as_secs_f32();
-        println!();
+        eprintln!();
         #[cfg(feature = "pretty-output")]
-        checkout_pb.finish_with_message(format!(
-            "Branch {} checked out in {:.2}s",
-            limine_branch, duration
-        ));
+        checkout_pb.finish_with_message(format!("{} checked out in {:.2}s", reference, duration));
+    }
+    // Without the bundled git, the git in the PATH is used
+    #[cfg(not(feature = "bundle-git"))]
+    {
+        eprintln!("Fetching {}...", name);
+        let refspec = match fetch.ref_type {
+            GitRefType::Branch => format!("refs/heads/{}", reference),
+            GitRefType::Tag => format!("refs/tags/{}", reference),
+            GitRefType::Commit => reference.to_string(),
+        };
+        std::fs::create_dir_all(repo_dir).unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(repo_dir)
+                .args(args)
+                .status()
+                .unwrap_or_else(|err| panic!("failed to run git: {}", err));
+            if !status.success() {
+                panic!(
+                    "failed to fetch {} {}: git {}",
+                    name,
+                    reference,
+                    args.join(" ")
+                );
+            }
+        };
+        git(&["init", "--quiet"]);
+        let depth = format!("--depth={}", fetch.depth);
+        let mut fetch_args = vec!["fetch", "--quiet", "--no-tags"];
+        // A depth of 0 fetches the full history
+        if fetch.depth > 0 {
+            fetch_args.push(&depth);
+        }
+        fetch_args.extend([url, refspec.as_str()]);
+        git(&fetch_args);
+        git(&["checkout", "--quiet", "--force", "--detach", "FETCH_HEAD"]);
     }
 
-    std::fs::write(&meta_path, limine_branch).expect("failed to write to target/limine/meta");
+    std::fs::write(&meta_path, meta).expect("failed to write the fetched version");
+}
+
+/// What fetching the repository at `url` with [`fetch_git`] needs from the host, network access
+/// for `purpose`, and git itself unless it is bundled
+fn git_requirements(purpose: String, url: &str) -> Vec<Requirement> {
+    let mut requirements = vec![Requirement::Network {
+        purpose,
+        url: url.to_string(),
+    }];
+    if !cfg!(feature = "bundle-git") {
+        requirements.push(Requirement::Program("git".to_string()));
+    }
+    requirements
+}
+
+/// No bootloader, the executable is a UEFI application that is booted directly by the firmware
+pub struct NoneBootloader;
+
+impl Bootloader for NoneBootloader {
+    fn prepare(&self, ctx: &Context) {
+        if ctx.config().boot_type != BootType::Uefi {
+            ctx.diagnostics().warn(
+                "uefi-app-boot-type",
+                "running without a bootloader requires boot-type = \"uefi\"",
+            );
+        }
+        let mut magic = [0; 2];
+        let is_pe = std::fs::File::open(ctx.executable_path())
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+            && &magic == b"MZ";
+        if !is_pe {
+            ctx.diagnostics().warn(
+                "uefi-app-not-pe",
+                format!(
+                    "{} is not a PE executable, running without a bootloader requires a UEFI application (e.g. x86_64-unknown-uefi or aarch64-unknown-uefi)",
+                    ctx.executable_path().display()
+                ),
+            );
+        }
+    }
+
+    fn files(&self, ctx: &Context) -> BootloaderFiles {
+        let Some(startup_nsh) = &ctx.config().startup_nsh else {
+            return BootloaderFiles::default();
+        };
+
+        let mut args = startup_nsh.args.clone();
+        for arg in args.iter_mut() {
+            *arg = substitute_vars(arg, &ctx.config().vars);
+        }
+        let executable = self.executable_dest(ctx);
+        // The UEFI shell stores the status of the last command in %lasterror%
+        let script = format!(
+            "@echo -off\r\nfs0:\r\n\\{} {}\r\necho \"exit status: %lasterror%\"\r\nreset -s\r\n",
+            executable.display(),
+            args.join(" ")
+        );
+        let script_path = ctx.scratch_dir().join("startup.nsh");
+        std::fs::create_dir_all(ctx.scratch_dir()).unwrap();
+        std::fs::write(&script_path, script).expect("failed to write startup.nsh");
+
+        BootloaderFiles {
+            files: vec![FileEntry::new(script_path, "startup.nsh")],
+            ..Default::default()
+        }
+    }
+
+    fn executable_dest(&self, ctx: &Context) -> PathBuf {
+        if ctx.config().startup_nsh.is_some() {
+            // The firmware should not boot the executable directly, so it falls back to the
+            // UEFI shell, which runs startup.nsh
+            let mut name = PathBuf::from(ctx.executable_name());
+            name.set_extension("efi");
+            return name;
+        }
+        Path::new("EFI/BOOT").join(ctx.config().arch.uefi_boot_file())
+    }
+
+    fn uses_config_file(&self) -> bool {
+        false
+    }
+}
+
+/// A bootloader the runner doesn't support natively, prepared by commands from the config
+pub struct ExternalBootloader {
+    pub config: ExternalBootloaderConfig,
+}
+
+impl ExternalBootloader {
+    /// Expands the templates and variables in `str`
+    fn expand(&self, ctx: &Context, str: &str) -> String {
+        let arch = serde_plain::to_string(&ctx.config().arch).unwrap();
+        let str = str
+            .replace("{{ROOT_DIR}}", &ctx.root_dir().to_string_lossy())
+            .replace("{{CACHE_DIR}}", &ctx.cache_dir().to_string_lossy())
+            .replace("{{OUTPUT_DIR}}", &ctx.output_dir().to_string_lossy())
+            .replace("{{EXECUTABLE}}", &ctx.executable_path().to_string_lossy())
+            .replace("{{ARCH}}", &arch);
+        substitute_vars(&str, &ctx.config().vars)
+    }
+}
+
+impl Bootloader for ExternalBootloader {
+    fn prepare(&self, ctx: &Context) {
+        let Some((program, args)) = self.config.prepare_cmd.split_first() else {
+            return;
+        };
+        let program = self.expand(ctx, program);
+        let status = Command::new(&program)
+            .args(args.iter().map(|arg| self.expand(ctx, arg)))
+            .current_dir(ctx.root_dir())
+            .status()
+            .unwrap_or_else(|err| panic!("failed to run {}: {}", program, err));
+        if !status.success() {
+            panic!(
+                "preparing the bootloader failed: {} exited with {}",
+                program, status
+            );
+        }
+    }
+
+    fn files(&self, ctx: &Context) -> BootloaderFiles {
+        BootloaderFiles {
+            files: self
+                .config
+                .files
+                .iter()
+                .map(|file| {
+                    FileEntry::new(
+                        ctx.root_dir().join(self.expand(ctx, &file.source)),
+                        self.expand(ctx, &file.dest),
+                    )
+                })
+                .collect(),
+            bios_boot_image: self
+                .config
+                .bios_boot_image
+                .as_ref()
+                .map(|path| self.expand(ctx, path).into()),
+            uefi_boot_image: self
+                .config
+                .uefi_boot_image
+                .as_ref()
+                .map(|path| self.expand(ctx, path).into()),
+        }
+    }
+
+    fn executable_dest(&self, ctx: &Context) -> PathBuf {
+        match &self.config.executable_dest {
+            Some(dest) => self.expand(ctx, dest).into(),
+            None => PathBuf::from(ctx.executable_name()),
+        }
+    }
+
+    fn uses_config_file(&self) -> bool {
+        self.config.uses_config_file
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        self.config
+            .requires
+            .iter()
+            .map(|requirement| {
+                Requirement::parse(requirement).unwrap_or_else(|err| panic!("{}", err))
+            })
+            .collect()
+    }
+}
+
+/// systemd-boot, which boots the kernel from an entry in `loader/entries`
+pub struct SystemdBootBootloader {
+    pub config: SystemdBootConfig,
+}
+
+impl SystemdBootBootloader {
+    /// The path of the systemd-boot EFI binary
+    fn efi_path(&self, ctx: &Context) -> PathBuf {
+        if let Some(efi) = &self.config.efi {
+            return ctx.root_dir().join(efi);
+        }
+        let suffix = match ctx.config().arch {
+            Arch::X86_64 => "x64",
+            Arch::Aarch64 => "aa64",
+            Arch::Riscv64 => "riscv64",
+            Arch::Ia32 => "ia32",
+        };
+        PathBuf::from(format!(
+            "/usr/lib/systemd/boot/efi/systemd-boot{}.efi",
+            suffix
+        ))
+    }
+
+    /// The name of the boot entry
+    fn entry_name(&self, ctx: &Context) -> String {
+        ctx.executable_name().to_string_lossy().into_owned()
+    }
+}
+
+impl Bootloader for SystemdBootBootloader {
+    fn prepare(&self, ctx: &Context) {
+        if ctx.config().boot_type != BootType::Uefi {
+            ctx.diagnostics().warn(
+                "systemd-boot-boot-type",
+                "systemd-boot requires boot-type = \"uefi\"",
+            );
+        }
+        let efi_path = self.efi_path(ctx);
+        if !efi_path.is_file() {
+            panic!(
+                "systemd-boot was not found at {}, install it or set systemd-boot.efi",
+                efi_path.display()
+            );
+        }
+    }
+
+    fn files(&self, ctx: &Context) -> BootloaderFiles {
+        let name = self.entry_name(ctx);
+        let kernel = self.executable_dest(ctx);
+        let substitute = |str: &str| substitute_vars(str, &ctx.config().vars);
+
+        let mut loader_conf = format!("default {}.conf\n", name);
+        if let Some(timeout) = self.config.timeout {
+            loader_conf.push_str(&format!("timeout {}\n", timeout));
+        }
+
+        let mut entry = format!("title {}\n", name);
+        let key = if self.config.linux { "linux" } else { "efi" };
+        entry.push_str(&format!("{} /{}\n", key, kernel.display()));
+        for initrd in self.config.initrd.iter() {
+            entry.push_str(&format!(
+                "initrd /{}\n",
+                substitute(initrd).trim_start_matches('/')
+            ));
+        }
+        if !ctx.config().cmdline.is_empty() {
+            entry.push_str(&format!("options {}\n", substitute(&ctx.config().cmdline)));
+        }
+
+        let dir = ctx.scratch_dir().join("systemd-boot");
+        std::fs::create_dir_all(&dir).unwrap();
+        let loader_conf_path = dir.join("loader.conf");
+        let entry_path = dir.join(format!("{}.conf", name));
+        std::fs::write(&loader_conf_path, loader_conf).expect("failed to write loader.conf");
+        std::fs::write(&entry_path, entry).expect("failed to write the boot entry");
+
+        let efi_path = self.efi_path(ctx);
+        let boot_file = Path::new("EFI/BOOT").join(ctx.config().arch.uefi_boot_file());
+        BootloaderFiles {
+            files: vec![
+                FileEntry::new(&efi_path, boot_file),
+                FileEntry::new(loader_conf_path, "loader/loader.conf"),
+                FileEntry::new(entry_path, format!("loader/entries/{}.conf", name)),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn executable_dest(&self, ctx: &Context) -> PathBuf {
+        let mut name = PathBuf::from(ctx.executable_name());
+        if !self.config.linux {
+            name.set_extension("efi");
+        }
+        name
+    }
+
+    fn uses_config_file(&self) -> bool {
+        false
+    }
+
+    fn components(&self, ctx: &Context) -> Vec<Component> {
+        let mut systemd_boot = Component::new("systemd-boot", "LGPL-2.1-or-later");
+        systemd_boot.files = vec![self.efi_path(ctx)];
+        vec![systemd_boot]
+    }
+}
+
+/// U-Boot, which QEMU runs as its firmware, booting the kernel from `extlinux/extlinux.conf`
+pub struct UBootBootloader {
+    pub config: UBootConfig,
+}
+
+impl UBootBootloader {
+    fn repo_dir(&self, ctx: &Context) -> PathBuf {
+        ctx.cache_dir().join("u-boot")
+    }
+
+    fn board(&self, ctx: &Context) -> String {
+        if let Some(board) = &self.config.board {
+            return board.clone();
+        }
+        match ctx.config().arch {
+            Arch::X86_64 => "qemu-x86_64",
+            Arch::Aarch64 => "qemu_arm64",
+            Arch::Riscv64 => "qemu-riscv64",
+            Arch::Ia32 => "qemu-x86",
+        }
+        .to_string()
+    }
+
+    fn cross_compile(&self, ctx: &Context) -> String {
+        if let Some(cross_compile) = &self.config.cross_compile {
+            return cross_compile.clone();
+        }
+        match ctx.config().arch {
+            Arch::X86_64 | Arch::Ia32 => "",
+            Arch::Aarch64 => "aarch64-linux-gnu-",
+            Arch::Riscv64 => "riscv64-linux-gnu-",
+        }
+        .to_string()
+    }
+
+    /// The path of the built (or prebuilt) U-Boot binary
+    fn binary_path(&self, ctx: &Context) -> PathBuf {
+        if let Some(binary) = &self.config.binary {
+            return ctx.root_dir().join(binary);
+        }
+        // The x86 build produces a ROM image, which includes the reset vector
+        let name = match ctx.config().arch {
+            Arch::X86_64 | Arch::Ia32 => "u-boot.rom",
+            Arch::Aarch64 | Arch::Riscv64 => "u-boot.bin",
+        };
+        self.repo_dir(ctx).join(name)
+    }
+
+    fn build(&self, ctx: &Context) {
+        let repo_dir = self.repo_dir(ctx);
+        let board = self.board(ctx);
+        let cross_compile = self.cross_compile(ctx);
+        // The board is part of the stamp, so changing it rebuilds U-Boot
+        let stamp_path = repo_dir.join("built.old");
+        let stamp = format!("{} {}", board, cross_compile);
+        if self.binary_path(ctx).is_file()
+            && std::fs::read_to_string(&stamp_path).is_ok_and(|old| old == stamp)
+        {
+            return;
+        }
+
+        eprintln!("Building U-Boot for {}...", board);
+        let run = |command: &mut Command| {
+            let status = command
+                .current_dir(&repo_dir)
+                .status()
+                .unwrap_or_else(|err| panic!("failed to run {:?}: {}", command, err));
+            if !status.success() {
+                panic!("{:?} failed while building U-Boot", command);
+            }
+        };
+        run(Command::new("make").arg(format!("{}_defconfig", board)));
+        run(Command::new("make")
+            .arg(format!(
+                "-j{}",
+                std::thread::available_parallelism().map_or(1, |n| n.get())
+            ))
+            .arg(format!("CROSS_COMPILE={}", cross_compile)));
+        std::fs::write(&stamp_path, stamp).expect("failed to write the U-Boot build stamp");
+    }
+
+    /// Generates `extlinux.conf` from the template, or a single entry booting the executable
+    fn extlinux_conf(&self, ctx: &Context) -> String {
+        let kernel = format!("/{}", self.executable_dest(ctx).display());
+        let substitute = |str: &str| substitute_vars(str, &ctx.config().vars);
+        let fdt = self
+            .config
+            .fdt
+            .as_deref()
+            .map(|fdt| format!("/{}", substitute(fdt).trim_start_matches('/')));
+        let initrd = self
+            .config
+            .initrd
+            .as_deref()
+            .map(|initrd| format!("/{}", substitute(initrd).trim_start_matches('/')));
+        let cmdline = substitute(&ctx.config().cmdline);
+
+        if let Some(template) = &self.config.extlinux {
+            let template_path = ctx.root_dir().join(template);
+            let template = std::fs::read_to_string(&template_path).unwrap_or_else(|err| {
+                panic!("failed to read {}: {}", template_path.display(), err)
+            });
+            return substitute(
+                &template
+                    .replace("{{BINARY_NAME}}", &kernel)
+                    .replace("{{CMDLINE}}", &cmdline)
+                    .replace("{{FDT}}", fdt.as_deref().unwrap_or_default())
+                    .replace("{{INITRD}}", initrd.as_deref().unwrap_or_default()),
+            );
+        }
+
+        let name = ctx.executable_name().to_string_lossy().into_owned();
+        let mut conf = format!("default {}\nlabel {}\n    kernel {}\n", name, name, kernel);
+        if let Some(fdt) = fdt {
+            conf.push_str(&format!("    fdt {}\n", fdt));
+        }
+        if let Some(initrd) = initrd {
+            conf.push_str(&format!("    initrd {}\n", initrd));
+        }
+        if !cmdline.is_empty() {
+            conf.push_str(&format!("    append {}\n", cmdline));
+        }
+        conf
+    }
+}
+
+impl Bootloader for UBootBootloader {
+    fn prepare(&self, ctx: &Context) {
+        if let Some(binary) = &self.config.binary {
+            let binary = ctx.root_dir().join(binary);
+            if !binary.is_file() {
+                panic!("U-Boot binary {} does not exist", binary.display());
+            }
+            return;
+        }
+        fetch_git(
+            "U-Boot",
+            &self.config.git,
+            &self.config.reference,
+            &self.config.fetch,
+            &self.repo_dir(ctx),
+        );
+        self.build(ctx);
+    }
+
+    fn files(&self, ctx: &Context) -> BootloaderFiles {
+        let dir = ctx.scratch_dir().join("u-boot");
+        std::fs::create_dir_all(&dir).unwrap();
+        let conf_path = dir.join("extlinux.conf");
+        std::fs::write(&conf_path, self.extlinux_conf(ctx)).expect("failed to write extlinux.conf");
+        BootloaderFiles {
+            files: vec![FileEntry::new(conf_path, "extlinux/extlinux.conf")],
+            ..Default::default()
+        }
+    }
+
+    fn uses_config_file(&self) -> bool {
+        false
+    }
+
+    fn version(&self) -> Option<String> {
+        match &self.config.binary {
+            Some(binary) => Some(format!("binary {}", binary)),
+            None => Some(format!(
+                "{} {:?} {}",
+                self.config.git, self.config.fetch.ref_type, self.config.reference
+            )),
+        }
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        if self.config.binary.is_some() {
+            return Vec::new();
+        }
+        let mut requirements = git_requirements("fetching U-Boot".to_string(), &self.config.git);
+        for tool in ["make", "bison", "flex"] {
+            requirements.push(Requirement::Program(tool.to_string()));
+        }
+        // The cross compiler isn't known without a context, so only a configured one is checked
+        if let Some(cross_compile) = &self.config.cross_compile {
+            requirements.push(Requirement::Program(format!("{}gcc", cross_compile)));
+        }
+        requirements
+    }
+
+    fn firmware(&self, ctx: &Context) -> Option<PathBuf> {
+        Some(self.binary_path(ctx))
+    }
+
+    fn components(&self, ctx: &Context) -> Vec<Component> {
+        let mut u_boot = Component::new("u-boot", "GPL-2.0-or-later");
+        if self.config.binary.is_none() {
+            u_boot.version = Some(self.config.reference.clone());
+        }
+        u_boot.files = vec![self.binary_path(ctx)];
+        // U-Boot is the firmware QEMU is started with, it isn't in the image
+        u_boot.shipped = false;
+        vec![u_boot]
+    }
+}
+
+const MULTIBOOT_MAGIC: u32 = 0x1BAD_B002;
+const MULTIBOOT2_MAGIC: u32 = 0xE852_50D6;
+
+/// The version of the Multiboot header in `kernel`, preferring Multiboot 1 if it has both
+///
+/// Multiboot 1 headers are 4 byte aligned in the first 8 KiB, Multiboot 2 headers are 8 byte
+/// aligned in the first 32 KiB, and both are only valid if their checksum matches.
+pub fn multiboot_version(kernel: &[u8]) -> Option<u8> {
+    let word = |offset: usize| {
+        kernel
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let has_header = |magic: u32, limit: usize, align: usize| {
+        (0..limit.min(kernel.len())).step_by(align).any(|offset| {
+            word(offset) == Some(magic)
+                && match (word(offset + 4), word(offset + 8), word(offset + 12)) {
+                    // Multiboot 1: magic, flags, checksum
+                    (Some(flags), Some(checksum), _) if magic == MULTIBOOT_MAGIC => {
+                        magic.wrapping_add(flags).wrapping_add(checksum) == 0
+                    }
+                    // Multiboot 2: magic, architecture, header length, checksum
+                    (Some(arch), Some(length), Some(checksum)) => {
+                        magic
+                            .wrapping_add(arch)
+                            .wrapping_add(length)
+                            .wrapping_add(checksum)
+                            == 0
+                    }
+                    _ => false,
+                }
+        })
+    };
+    if has_header(MULTIBOOT_MAGIC, 8192, 4) {
+        Some(1)
+    } else if has_header(MULTIBOOT2_MAGIC, 32768, 8) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// The Multiboot version of the kernel at `path`, see [`multiboot_version`]
+pub fn kernel_multiboot_version(path: &Path) -> Option<u8> {
+    let mut kernel = Vec::new();
+    std::fs::File::open(path)
+        .ok()?
+        .take(32768)
+        .read_to_end(&mut kernel)
+        .ok()?;
+    multiboot_version(&kernel)
+}
+
+/// Loads the kernel with QEMU's `-kernel`, without a bootloader or an image
+pub struct DirectKernelBootloader {
+    /// Passed with `-initrd`, relative to the workspace root
+    pub initrd: Vec<String>,
+    /// Checks that the kernel has a Multiboot header QEMU can load
+    pub multiboot: bool,
+    /// Boots Multiboot kernels that only have a Multiboot2 header, which QEMU can't load itself,
+    /// from an image instead
+    pub limine: Option<LimineBootloader>,
+}
+
+impl DirectKernelBootloader {
+    /// The bootloader that boots the kernel instead of QEMU, if it only has a Multiboot2 header
+    fn multiboot2_loader(&self, ctx: &Context) -> Option<&LimineBootloader> {
+        let limine = self.limine.as_ref().filter(|_| self.multiboot)?;
+        (kernel_multiboot_version(ctx.executable_path()) == Some(2)).then_some(limine)
+    }
+
+    /// The limine config that boots the kernel with the Multiboot2 protocol, with its name
+    fn limine_config(&self, ctx: &Context, limine: &LimineBootloader) -> (&'static str, String) {
+        let substitute = |str: &str| substitute_vars(str, &ctx.config().vars);
+        let kernel = self.executable_dest(ctx);
+        let cmdline = substitute(&ctx.config().cmdline);
+        let modules: Vec<String> = self
+            .initrd
+            .iter()
+            .map(|initrd| substitute(initrd).trim_start_matches('/').to_string())
+            .collect();
+        // Limine 8 replaced limine.cfg with limine.conf, which has a different syntax
+        if limine_major_version(&limine.branch).is_some_and(|version| version < 8) {
+            let mut conf = format!(
+                "TIMEOUT=0\n\n:Multiboot2\n    PROTOCOL=multiboot2\n    KERNEL_PATH=boot:///{}\n",
+                kernel.display()
+            );
+            if !cmdline.is_empty() {
+                conf.push_str(&format!("    CMDLINE={}\n", cmdline));
+            }
+            for module in modules {
+                conf.push_str(&format!("    MODULE_PATH=boot:///{}\n", module));
+            }
+            ("limine.cfg", conf)
+        } else {
+            let mut conf = format!(
+                "timeout: 0\n\n/Multiboot2\n    protocol: multiboot2\n    path: boot():/{}\n",
+                kernel.display()
+            );
+            if !cmdline.is_empty() {
+                conf.push_str(&format!("    cmdline: {}\n", cmdline));
+            }
+            for module in modules {
+                conf.push_str(&format!("    module_path: boot():/{}\n", module));
+            }
+            ("limine.conf", conf)
+        }
+    }
+}
+
+impl Bootloader for DirectKernelBootloader {
+    fn prepare(&self, ctx: &Context) {
+        if !self.multiboot {
+            return;
+        }
+        let kernel = std::fs::read(ctx.executable_path()).unwrap_or_else(|err| {
+            panic!(
+                "failed to read {}: {}",
+                ctx.executable_path().display(),
+                err
+            )
+        });
+        match (multiboot_version(&kernel), &self.limine) {
+            (Some(1), _) => {}
+            // QEMU's loader only implements the first version of the specification
+            (Some(_), Some(limine)) => limine.prepare(ctx),
+            (Some(_), None) => panic!(
+                "{} only has a Multiboot2 header, which QEMU can't load with -kernel, remove \
+                 direct-kernel to boot it with limine",
+                ctx.executable_path().display()
+            ),
+            (None, _) => panic!(
+                "{} has no Multiboot header, so QEMU can't load it with -kernel",
+                ctx.executable_path().display()
+            ),
+        }
+    }
+
+    fn files(&self, ctx: &Context) -> BootloaderFiles {
+        let Some(limine) = self.multiboot2_loader(ctx) else {
+            return BootloaderFiles::default();
+        };
+        let mut files = limine.files(ctx);
+        let (name, conf) = self.limine_config(ctx, limine);
+        let conf_path = ctx.scratch_dir().join(name);
+        std::fs::create_dir_all(ctx.scratch_dir()).unwrap();
+        std::fs::write(&conf_path, conf)
+            .unwrap_or_else(|err| panic!("failed to write {}: {}", name, err));
+        files.files.push(FileEntry::new(conf_path, name));
+        // The modules are loaded from the image, at the same path as in the workspace
+        for initrd in &self.initrd {
+            let initrd = substitute_vars(initrd, &ctx.config().vars);
+            let dest = initrd.trim_start_matches('/');
+            files
+                .files
+                .push(FileEntry::new(ctx.root_dir().join(dest), dest));
+        }
+        files
+    }
+
+    fn version(&self) -> Option<String> {
+        self.limine.as_ref().and_then(LimineBootloader::version)
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        self.limine
+            .as_ref()
+            .map(LimineBootloader::requirements)
+            .unwrap_or_default()
+    }
+
+    fn components(&self, ctx: &Context) -> Vec<Component> {
+        self.multiboot2_loader(ctx)
+            .map(|limine| limine.components(ctx))
+            .unwrap_or_default()
+    }
+
+    fn uses_config_file(&self) -> bool {
+        false
+    }
+
+    fn direct_boot_args(&self, ctx: &Context) -> Option<Vec<String>> {
+        if self.multiboot2_loader(ctx).is_some() {
+            return None;
+        }
+        let substitute = |str: &str| substitute_vars(str, &ctx.config().vars);
+        let mut args = vec![
+            "-kernel".to_string(),
+            ctx.executable_path().to_string_lossy().into_owned(),
+        ];
+        if !ctx.config().cmdline.is_empty() {
+            args.push("-append".to_string());
+            args.push(substitute(&ctx.config().cmdline));
+        }
+        if !self.initrd.is_empty() {
+            // Multiboot modules are separated by commas, so commas in the paths are doubled
+            let modules: Vec<String> = self
+                .initrd
+                .iter()
+                .map(|initrd| {
+                    ctx.root_dir()
+                        .join(substitute(initrd))
+                        .to_string_lossy()
+                        .replace(',', ",,")
+                })
+                .collect();
+            args.push("-initrd".to_string());
+            args.push(modules.join(","));
+        }
+        Some(args)
+    }
+}
+
+/// Boots an image that was built elsewhere, such as a third-party test suite, as it is
+pub struct PrebuiltImage;
+
+impl Bootloader for PrebuiltImage {
+    fn prepare(&self, ctx: &Context) {
+        if !ctx.executable_path().is_file() {
+            panic!("image {} does not exist", ctx.executable_path().display());
+        }
+    }
+
+    fn files(&self, _ctx: &Context) -> BootloaderFiles {
+        BootloaderFiles::default()
+    }
+
+    fn uses_config_file(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_multiboot_version() {
+    let header = |magic: u32, words: &[u32]| {
+        let checksum = words
+            .iter()
+            .fold(magic, |sum, word| sum.wrapping_add(*word))
+            .wrapping_neg();
+        let mut bytes = vec![0u8; 16];
+        for word in [magic].iter().chain(words).chain([&checksum]) {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    };
+    assert_eq!(multiboot_version(&header(MULTIBOOT_MAGIC, &[3])), Some(1));
+    assert_eq!(
+        multiboot_version(&header(MULTIBOOT2_MAGIC, &[0, 24])),
+        Some(2)
+    );
+    let mut invalid = header(MULTIBOOT_MAGIC, &[3]);
+    invalid[20] ^= 1;
+    assert_eq!(multiboot_version(&invalid), None);
 }
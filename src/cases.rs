@@ -0,0 +1,167 @@
+use regex::bytes::Regex;
+use std::time::Duration;
+
+use crate::config::TestCasesConfig;
+
+/// What a line of the serial output tells about the test cases
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaseEvent {
+    Started(String),
+    Passed(String),
+    Failed(String),
+    /// The summary was printed after the last case
+    Summary,
+}
+
+/// Matches the lines of the test cases, see [`TestCasesConfig`]
+#[derive(Debug, Clone)]
+pub struct CaseMatcher {
+    pass: Regex,
+    fail: Regex,
+    summary: Option<Regex>,
+    start: Option<Regex>,
+    timeout: Option<Duration>,
+}
+
+impl CaseMatcher {
+    pub fn new(config: &TestCasesConfig) -> Result<Self, String> {
+        let compile = |pattern: &str| {
+            Regex::new(pattern).map_err(|err| format!("invalid case pattern {}: {}", pattern, err))
+        };
+        if config.timeout.is_some() && config.start.is_none() {
+            return Err("the timeout of test cases needs a start pattern".to_string());
+        }
+        Ok(Self {
+            pass: compile(&config.pass)?,
+            fail: compile(&config.fail)?,
+            summary: config.summary.as_deref().map(compile).transpose()?,
+            start: config.start.as_deref().map(compile).transpose()?,
+            timeout: config.timeout.map(Duration::from_millis),
+        })
+    }
+
+    /// How long a case may run after it started, if it is limited
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Matches a line of the output, the line ending and escape sequences should already be
+    /// removed
+    pub fn match_line(&self, line: &[u8]) -> Option<CaseEvent> {
+        // A case is named by the `name` group, or by the whole line
+        let name = |regex: &Regex| {
+            let captures = regex.captures(line)?;
+            let name = captures.name("name").map_or(line, |name| name.as_bytes());
+            Some(String::from_utf8_lossy(name).into_owned())
+        };
+        if let Some(name) = name(&self.fail) {
+            return Some(CaseEvent::Failed(name));
+        }
+        if let Some(name) = name(&self.pass) {
+            return Some(CaseEvent::Passed(name));
+        }
+        if let Some(name) = self.start.as_ref().and_then(name) {
+            return Some(CaseEvent::Started(name));
+        }
+        self.summary
+            .as_ref()
+            .is_some_and(|summary| summary.is_match(line))
+            .then_some(CaseEvent::Summary)
+    }
+}
+
+/// The test cases that finished in a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaseResults {
+    pub passed: Vec<String>,
+    pub failed: Vec<String>,
+    /// Whether the summary was printed, so all cases finished
+    pub finished: bool,
+}
+
+impl CaseResults {
+    /// Records the case of `event`, a case that started is only recorded once it finishes
+    pub fn record(&mut self, event: CaseEvent) {
+        match event {
+            CaseEvent::Started(_) => {}
+            CaseEvent::Passed(name) => self.passed.push(name),
+            CaseEvent::Failed(name) => self.failed.push(name),
+            CaseEvent::Summary => self.finished = true,
+        }
+    }
+
+    /// Describes how many cases passed, and which failed
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "{} test cases passed, {} failed",
+            self.passed.len(),
+            self.failed.len()
+        );
+        if !self.failed.is_empty() {
+            description.push_str(&format!(": {}", self.failed.join(", ")));
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_case_matcher() {
+    let config = TestCasesConfig {
+        pass: r"^test (?<name>\S+) \.\.\. ok".to_string(),
+        fail: r"^test (?<name>\S+) \.\.\. FAILED".to_string(),
+        summary: Some("^test result: ".to_string()),
+        fail_fast: false,
+        start: None,
+        timeout: None,
+    };
+    let matcher = CaseMatcher::new(&config).unwrap();
+    let mut results = CaseResults::default();
+    for line in [
+        &b"running 3 tests"[..],
+        b"test alloc::vec ... ok",
+        b"test paging::map ... FAILED",
+        b"test interrupts ... ok",
+        b"test result: FAILED. 2 passed; 1 failed",
+    ] {
+        if let Some(event) = matcher.match_line(line) {
+            results.record(event);
+        }
+    }
+    assert_eq!(results.passed, ["alloc::vec", "interrupts"]);
+    assert_eq!(results.failed, ["paging::map"]);
+    assert!(results.finished);
+    assert_eq!(
+        results.describe(),
+        "2 test cases passed, 1 failed: paging::map"
+    );
+
+    // Without a name group, the line names the case
+    let config = TestCasesConfig {
+        pass: "PASS".to_string(),
+        ..config
+    };
+    assert_eq!(
+        CaseMatcher::new(&config)
+            .unwrap()
+            .match_line(b"[PASS] heap"),
+        Some(CaseEvent::Passed("[PASS] heap".to_string()))
+    );
+
+    // A timeout needs to know when a case starts
+    let config = TestCasesConfig {
+        timeout: Some(1000),
+        ..config
+    };
+    assert!(CaseMatcher::new(&config).is_err());
+    let config = TestCasesConfig {
+        start: Some(r"^\[RUN\] (?<name>.+)".to_string()),
+        ..config
+    };
+    let matcher = CaseMatcher::new(&config).unwrap();
+    assert_eq!(matcher.timeout(), Some(Duration::from_millis(1000)));
+    assert_eq!(
+        matcher.match_line(b"[RUN] heap"),
+        Some(CaseEvent::Started("heap".to_string()))
+    );
+}
@@ -0,0 +1,384 @@
+use crate::bootloader;
+use crate::config::{BootType, DeviceConfig, ImageRunnerConfig};
+use crate::env::{EnvOverride, env_overrides};
+use crate::qemu;
+use crate::requirements::{Requirement, firmware_requirements};
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The severity of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The result of a single check
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckItem {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+/// The results of checking the environment the runner is used in
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckReport {
+    pub items: Vec<CheckItem>,
+}
+
+impl CheckReport {
+    pub fn push(&mut self, name: &'static str, status: CheckStatus, message: impl Into<String>) {
+        self.items.push(CheckItem {
+            name,
+            status,
+            message: message.into(),
+        });
+    }
+
+    /// Whether any of the checks failed
+    pub fn has_errors(&self) -> bool {
+        self.items
+            .iter()
+            .any(|item| item.status == CheckStatus::Error)
+    }
+
+    /// Prints the report to stdout
+    pub fn print(&self) {
+        for item in self.items.iter() {
+            println!("[{}] {}: {}", item.status, item.name, item.message);
+        }
+    }
+
+    /// The report as JSON, e.g. `{"items":[{"name":"qemu","status":"ok","message":"..."}]}`
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Runs all the checks of the environment for `config`: the cargo runner wiring of `dir`, QEMU,
+/// KVM, the OVMF cache in `target_dir`, what the bootloader and devices need, and the overrides
+/// from the environment of the workspace at `root_dir`
+pub fn check_environment(
+    config: &ImageRunnerConfig,
+    dir: &Path,
+    root_dir: &Path,
+    target_dir: &Path,
+) -> CheckReport {
+    let mut report = CheckReport::default();
+    check_runner_wiring(dir, &mut report);
+
+    let default_run_command = config.arch.default_run_command();
+    let qemu_binary = config
+        .run_command
+        .first()
+        .unwrap_or(&default_run_command[0]);
+    check_qemu(qemu_binary, &mut report);
+    check_kvm(qemu_binary, &mut report);
+
+    let mut requirements = bootloader::from_config(config).requirements();
+    // The firmware is only fetched for the files that aren't configured
+    let ovmf = &config.ovmf;
+    if config.boot_type == BootType::Uefi && (ovmf.code.is_none() || ovmf.vars.is_none()) {
+        check_ovmf(&target_dir.join("ovmf"), &mut report);
+    }
+    requirements.extend(firmware_requirements(config, root_dir));
+    check_requirements(&requirements, qemu_binary, &mut report);
+    check_devices(&config.devices, qemu_binary, &mut report);
+    check_env_overrides(root_dir, &mut report);
+    report
+}
+
+/// Reports the errors of config tables that could not be parsed
+pub fn check_config(errors: &[String], report: &mut CheckReport) {
+    if errors.is_empty() {
+        report.push("config", CheckStatus::Ok, "the config is valid");
+    }
+    for error in errors {
+        report.push("config", CheckStatus::Error, error.clone());
+    }
+}
+
+/// Checks whether KVM can be used, the guest is only slower without it
+pub fn check_kvm(qemu_binary: &str, report: &mut CheckReport) {
+    match Requirement::Kvm.check(qemu_binary) {
+        Ok(()) => report.push("kvm", CheckStatus::Ok, "kvm is available"),
+        Err(reason) => report.push(
+            "kvm",
+            CheckStatus::Warning,
+            format!("{}, the guest runs without acceleration", reason),
+        ),
+    }
+}
+
+/// Checks whether the OVMF firmware is cached in `dir`, it is fetched on the first run otherwise
+pub fn check_ovmf(dir: &Path, report: &mut CheckReport) {
+    if dir.is_dir() {
+        report.push(
+            "ovmf",
+            CheckStatus::Ok,
+            format!("the firmware is cached in {}", dir.display()),
+        );
+    } else {
+        report.push(
+            "ovmf",
+            CheckStatus::Warning,
+            format!(
+                "the firmware is not cached in {}, it is fetched on the first run",
+                dir.display()
+            ),
+        );
+    }
+}
+
+/// Reports the config values and variables that are overridden by the environment, as they can
+/// be surprising when they are set by a forgotten env file
+pub fn check_env_overrides(root_dir: &Path, report: &mut CheckReport) {
+    for env_override in env_overrides(root_dir) {
+        let message = match env_override {
+            EnvOverride::Config(key, value) => format!("{} is overridden with {}", key, value),
+            EnvOverride::Var(var, value) => format!("variable {} is set to {}", var, value),
+        };
+        report.push("env-override", CheckStatus::Ok, message);
+    }
+}
+
+/// The stanza to add to `.cargo/config.toml` to use the runner
+const RUNNER_STANZA: &str = "[target.'cfg(target_os = \"none\")']\nrunner = \"cargo image-runner\"";
+
+/// Finds the cargo config files that apply to `dir`, the closest one first
+fn find_cargo_configs(dir: &Path) -> Vec<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| {
+            ["config.toml", "config"]
+                .into_iter()
+                .map(move |file| dir.join(".cargo").join(file))
+        })
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Splits a runner value, which is either a string or an array of strings, into its words
+fn runner_words(runner: &toml::Value) -> Vec<String> {
+    match runner {
+        toml::Value::String(runner) => runner.split_whitespace().map(str::to_string).collect(),
+        toml::Value::Array(runner) => runner
+            .iter()
+            .filter_map(|word| word.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Checks the `[target.<triple>] runner` entries and the `build-std` and target settings of the
+/// cargo configs that apply to `dir`
+pub fn check_runner_wiring(dir: &Path, report: &mut CheckReport) {
+    let mut runner_found = false;
+    let mut build_target = None;
+    let mut build_std = false;
+
+    for path in find_cargo_configs(dir) {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                report.push(
+                    "cargo-config",
+                    CheckStatus::Error,
+                    format!("failed to read {}: {}", path.display(), err),
+                );
+                continue;
+            }
+        };
+        let config: toml::Table = match contents.parse() {
+            Ok(config) => config,
+            Err(err) => {
+                report.push(
+                    "cargo-config",
+                    CheckStatus::Error,
+                    format!("failed to parse {}: {}", path.display(), err),
+                );
+                continue;
+            }
+        };
+
+        let targets = config.get("target").and_then(|targets| targets.as_table());
+        for (target, table) in targets.into_iter().flatten() {
+            let Some(runner) = table.get("runner") else {
+                continue;
+            };
+            let words = runner_words(runner);
+            if !words.iter().any(|word| word.contains("image-runner")) {
+                continue;
+            }
+            runner_found = true;
+            // cargo appends the executable to the runner, which we expect right after the subcommand
+            if words == ["cargo", "image-runner"] {
+                report.push(
+                    "runner",
+                    CheckStatus::Ok,
+                    format!("target {} uses the image runner", target),
+                );
+            } else {
+                report.push(
+                    "runner",
+                    CheckStatus::Error,
+                    format!(
+                        "the runner of target {} in {} should be exactly \"cargo image-runner\", options are passed after `--` instead",
+                        target,
+                        path.display()
+                    ),
+                );
+            }
+        }
+
+        // The closest config takes precedence
+        if build_target.is_none() {
+            build_target = config
+                .get("build")
+                .and_then(|build| build.get("target"))
+                .and_then(|target| target.as_str())
+                .map(|target| (target.to_string(), path.clone()));
+        }
+        build_std |= config
+            .get("unstable")
+            .and_then(|unstable| unstable.get("build-std"))
+            .is_some();
+    }
+
+    if !runner_found {
+        report.push(
+            "runner",
+            CheckStatus::Error,
+            format!(
+                "no target uses the image runner, add this to .cargo/config.toml:\n{}",
+                RUNNER_STANZA
+            ),
+        );
+    }
+
+    if let Some((target, config_path)) = build_target
+        && target.ends_with(".json")
+    {
+        // Relative paths are relative to the directory containing `.cargo`
+        let base = config_path.parent().and_then(Path::parent).unwrap_or(dir);
+        if !base.join(&target).is_file() {
+            report.push(
+                "build-target",
+                CheckStatus::Error,
+                format!("the target specification {} does not exist", target),
+            );
+        } else if !build_std {
+            report.push(
+                "build-target",
+                CheckStatus::Error,
+                format!(
+                    "custom target {} requires `build-std` in the [unstable] section",
+                    target
+                ),
+            );
+        } else {
+            report.push(
+                "build-target",
+                CheckStatus::Ok,
+                format!("building for {} with build-std", target),
+            );
+        }
+    }
+}
+
+/// Checks that the QEMU binary can be run, and that it has the devices the runner relies on
+pub fn check_qemu(binary: &str, report: &mut CheckReport) {
+    let Some(info) = qemu::probe(binary) else {
+        report.push(
+            "qemu",
+            CheckStatus::Error,
+            format!("{} could not be run, is QEMU installed?", binary),
+        );
+        return;
+    };
+    let (major, minor, patch) = info.version;
+    report.push(
+        "qemu",
+        CheckStatus::Ok,
+        format!(
+            "{} {}.{}.{} with accelerators {}",
+            binary,
+            major,
+            minor,
+            patch,
+            info.accelerators.join(", ")
+        ),
+    );
+    // Tests report their result through the isa-debug-exit device
+    if !info.has_device("isa-debug-exit") {
+        report.push(
+            "qemu",
+            CheckStatus::Warning,
+            format!(
+                "{} has no isa-debug-exit device, tests can't report their exit code",
+                binary
+            ),
+        );
+    }
+}
+
+/// Checks the requirements of the components of the runner, such as the bootloader, network
+/// access is only a warning, as it isn't needed once everything is cached
+pub fn check_requirements(
+    requirements: &[Requirement],
+    qemu_binary: &str,
+    report: &mut CheckReport,
+) {
+    for requirement in requirements {
+        match requirement.check(qemu_binary) {
+            Ok(()) => report.push("requirement", CheckStatus::Ok, requirement.to_string()),
+            Err(reason) => {
+                let status = match requirement {
+                    Requirement::Network { .. } => CheckStatus::Warning,
+                    _ => CheckStatus::Error,
+                };
+                report.push("requirement", status, reason);
+            }
+        }
+    }
+}
+
+/// Checks the requirements of the configured devices, unmet ones are only warnings for optional
+/// devices, as those are skipped
+pub fn check_devices(devices: &[DeviceConfig], qemu_binary: &str, report: &mut CheckReport) {
+    for device in devices {
+        for requirement in device.requires.iter() {
+            let result = Requirement::parse(requirement)
+                .and_then(|requirement| requirement.check(qemu_binary));
+            match result {
+                Ok(()) => report.push(
+                    "device",
+                    CheckStatus::Ok,
+                    format!("{}: {}", device.name, requirement),
+                ),
+                Err(reason) => report.push(
+                    "device",
+                    if device.optional {
+                        CheckStatus::Warning
+                    } else {
+                        CheckStatus::Error
+                    },
+                    format!("{}: {}", device.name, reason),
+                ),
+            }
+        }
+    }
+}
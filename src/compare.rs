@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::config::SnapshotFilterConfig;
+use crate::outcome::{FailureKind, RunOutcome};
+use crate::scrub::ScrubChain;
+use crate::serial::strip_ansi;
+use crate::snapshot;
+
+/// A run of the comparison, with its serial output normalized
+#[derive(Debug, Clone)]
+pub struct ComparedRun {
+    pub outcome: RunOutcome,
+    pub serial: String,
+}
+
+impl ComparedRun {
+    /// Normalizes the serial output of `outcome` like for snapshots, with the `scrubbers` and
+    /// the `filters` applied and without escape sequences, so colors and addresses don't count as
+    /// divergences
+    pub fn new(
+        outcome: RunOutcome,
+        scrubbers: &ScrubChain,
+        filters: &[SnapshotFilterConfig],
+    ) -> Result<Self, String> {
+        let serial = String::from_utf8_lossy(&strip_ansi(outcome.output.serial())).into_owned();
+        let serial = snapshot::normalize(scrubbers.scrub(&serial).as_bytes(), filters)?;
+        Ok(Self { outcome, serial })
+    }
+}
+
+/// How two runs of the same image diverged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// QEMU exited with different codes, `None` if it was killed by a signal
+    ExitCode(Option<i32>, Option<i32>),
+    /// One run failed and the other didn't, or they failed differently
+    Failure(Option<FailureKind>, Option<FailureKind>),
+    /// The serial output differs, from the first differing line, which is `None` past the end of
+    /// the output
+    Output {
+        line: usize,
+        left: Option<String>,
+        right: Option<String>,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exit_code = |code: &Option<i32>| match code {
+            Some(code) => code.to_string(),
+            None => "killed by a signal".to_string(),
+        };
+        let failure = |failure: &Option<FailureKind>| match failure {
+            Some(kind) => kind.to_string(),
+            None => "success".to_string(),
+        };
+        match self {
+            Self::ExitCode(left, right) => write!(
+                f,
+                "the exit codes differ: {} and {}",
+                exit_code(left),
+                exit_code(right)
+            ),
+            Self::Failure(left, right) => write!(
+                f,
+                "the runs ended differently: {} and {}",
+                failure(left),
+                failure(right)
+            ),
+            Self::Output { line, left, right } => write!(
+                f,
+                "the serial output differs at line {}:\n< {}\n> {}",
+                line,
+                left.as_deref().unwrap_or("<end of output>"),
+                right.as_deref().unwrap_or("<end of output>")
+            ),
+        }
+    }
+}
+
+/// Compares two runs of the same image, e.g. with KVM and TCG, by their exit codes, how they
+/// ended and their serial output
+pub fn compare_runs(left: &ComparedRun, right: &ComparedRun) -> Vec<Divergence> {
+    let mut divergences = Vec::new();
+    if left.outcome.exit_code != right.outcome.exit_code {
+        divergences.push(Divergence::ExitCode(
+            left.outcome.exit_code,
+            right.outcome.exit_code,
+        ));
+    }
+    // Different exit codes are already reported, and so are different lines in the output
+    let same_failure = match (&left.outcome.failure, &right.outcome.failure) {
+        (Some(FailureKind::ExitCode(_)), Some(FailureKind::ExitCode(_))) => true,
+        (Some(FailureKind::AbortPattern(_)), Some(FailureKind::AbortPattern(_))) => true,
+        (left, right) => left == right,
+    };
+    if !same_failure {
+        divergences.push(Divergence::Failure(
+            left.outcome.failure.clone(),
+            right.outcome.failure.clone(),
+        ));
+    }
+    let mut left_lines = left.serial.lines();
+    let mut right_lines = right.serial.lines();
+    let mut line = 1;
+    loop {
+        match (left_lines.next(), right_lines.next()) {
+            (None, None) => break,
+            (Some(left), Some(right)) if left == right => line += 1,
+            (left, right) => {
+                divergences.push(Divergence::Output {
+                    line,
+                    left: left.map(str::to_string),
+                    right: right.map(str::to_string),
+                });
+                break;
+            }
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+#[test]
+fn test_compare_runs() {
+    use crate::outcome::CapturedOutput;
+    use std::time::Duration;
+
+    let run = |exit_code, failure, serial: &[u8]| {
+        let mut output = CapturedOutput::default();
+        output.insert(CapturedOutput::SERIAL, serial.to_vec());
+        let outcome = RunOutcome {
+            exit_code: Some(exit_code),
+            failure,
+            shutdown: None,
+            duration: Duration::ZERO,
+            output,
+        };
+        let filters = [SnapshotFilterConfig {
+            pattern: "0x[0-9a-f]+".to_string(),
+            replacement: "[addr]".to_string(),
+        }];
+        ComparedRun::new(outcome, &ScrubChain::default(), &filters).unwrap()
+    };
+    let kvm = run(0, None, b"\x1b[32mboot\x1b[0m\r\nheap at 0xffff8000\r\n");
+    let tcg = run(0, None, b"boot\nheap at 0x1000\n");
+    assert_eq!(compare_runs(&kvm, &tcg), []);
+
+    let tcg = run(
+        3,
+        Some(FailureKind::GuestPanic),
+        b"boot\npanicked at src/main.rs:10:5\n",
+    );
+    let divergences = compare_runs(&kvm, &tcg);
+    assert_eq!(
+        divergences,
+        [
+            Divergence::ExitCode(Some(0), Some(3)),
+            Divergence::Failure(None, Some(FailureKind::GuestPanic)),
+            Divergence::Output {
+                line: 2,
+                left: Some("heap at [addr]".to_string()),
+                right: Some("panicked at src/main.rs:10:5".to_string()),
+            },
+        ]
+    );
+    assert_eq!(
+        divergences[1].to_string(),
+        "the runs ended differently: success and guest panic"
+    );
+    assert_eq!(
+        FailureKind::AbortPattern("KERNEL PANIC".to_string()).to_string(),
+        "the guest printed \"KERNEL PANIC\""
+    );
+}
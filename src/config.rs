@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
@@ -12,20 +13,1246 @@ pub enum BootType {
     Uefi,
 }
 
+/// The architecture of the kernel
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum Arch {
+    #[default]
+    #[serde(rename = "x86_64")]
+    X86_64,
+    #[serde(rename = "aarch64")]
+    Aarch64,
+    #[serde(rename = "riscv64")]
+    Riscv64,
+    /// 32-bit x86, for the 32-bit UEFI firmware of some Atom-era devices
+    #[serde(rename = "ia32")]
+    Ia32,
+}
+
+impl Arch {
+    /// The architecture that the UEFI target `triple` has to be booted on, if it isn't the
+    /// default one, e.g. `ia32` for `i686-unknown-uefi`
+    pub fn from_uefi_target(triple: &str) -> Option<Self> {
+        let (arch, os) = triple.split_once('-')?;
+        let ia32 = matches!(arch, "i386" | "i486" | "i586" | "i686");
+        (ia32 && os.ends_with("-uefi")).then_some(Self::Ia32)
+    }
+
+    /// The name of the removable media boot file in `EFI/BOOT`
+    pub fn uefi_boot_file(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "BOOTX64.EFI",
+            Self::Aarch64 => "BOOTAA64.EFI",
+            Self::Riscv64 => "BOOTRISCV64.EFI",
+            Self::Ia32 => "BOOTIA32.EFI",
+        }
+    }
+
+    /// The default command used to run a kernel loaded with `-kernel`, without an image
+    pub fn default_direct_run_command(&self) -> Vec<String> {
+        let command: &[&str] = match self {
+            Self::X86_64 => &["qemu-system-x86_64"],
+            Self::Aarch64 => &["qemu-system-aarch64", "-machine", "virt", "-cpu", "cortex-a72"],
+            Self::Riscv64 => &["qemu-system-riscv64", "-machine", "virt"],
+            Self::Ia32 => &["qemu-system-i386"],
+        };
+        command.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// The default command used to run a directory image, which QEMU attaches as a FAT drive
+    pub fn default_directory_run_command(&self) -> Vec<String> {
+        let mut command = self.default_direct_run_command();
+        // The virt machine has no IDE controller, so the drive is attached with virtio
+        let drive = match self {
+            Self::X86_64 | Self::Ia32 => "format=raw,file=fat:rw:{}",
+            Self::Aarch64 | Self::Riscv64 => "if=virtio,format=raw,file=fat:rw:{}",
+        };
+        command.extend(["-drive".to_string(), drive.to_string()]);
+        command
+    }
+
+    /// The default command used to run an ISO image when the execution is recorded or replayed,
+    /// which attaches it through `blkreplay` so its reads are recorded as well
+    pub fn replay_run_command(&self) -> Vec<String> {
+        let mut command = self.default_direct_run_command();
+        let device = match self {
+            Self::X86_64 | Self::Ia32 => ["-device", "ide-cd,drive=cd0"].as_slice(),
+            Self::Aarch64 | Self::Riscv64 => {
+                &["-device", "virtio-scsi-pci", "-device", "scsi-cd,drive=cd0"]
+            }
+        };
+        command.extend(device.iter().map(|arg| arg.to_string()));
+        command.extend([
+            "-drive".to_string(),
+            "if=none,id=cd0-direct,format=raw,readonly=on,file={}".to_string(),
+            "-drive".to_string(),
+            "driver=blkreplay,if=none,image=cd0-direct,id=cd0".to_string(),
+        ]);
+        command
+    }
+
+    /// The default command used to run an ISO image
+    pub fn default_run_command(&self) -> Vec<String> {
+        let command: &[&str] = match self {
+            Self::X86_64 => &["qemu-system-x86_64", "-cdrom", "{}"],
+            Self::Ia32 => &["qemu-system-i386", "-cdrom", "{}"],
+            // The virt machine has no IDE controller, so the image is attached as a SCSI CD-ROM
+            Self::Aarch64 => &[
+                "qemu-system-aarch64",
+                "-machine",
+                "virt",
+                "-cpu",
+                "cortex-a72",
+                "-device",
+                "virtio-scsi-pci",
+                "-device",
+                "scsi-cd,drive=cd0",
+                "-drive",
+                "if=none,id=cd0,format=raw,media=cdrom,file={}",
+            ],
+            Self::Riscv64 => &[
+                "qemu-system-riscv64",
+                "-machine",
+                "virt",
+                "-device",
+                "virtio-scsi-pci",
+                "-device",
+                "scsi-cd,drive=cd0",
+                "-drive",
+                "if=none,id=cd0,format=raw,media=cdrom,file={}",
+            ],
+        };
+        command.iter().map(|arg| arg.to_string()).collect()
+    }
+}
+
+/// The El Torito emulation mode of a boot entry
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum Emulation {
+    #[default]
+    #[serde(rename = "no-emulation")]
+    None,
+    #[serde(rename = "floppy-1.2m")]
+    Floppy12M,
+    #[serde(rename = "floppy-1.44m")]
+    Floppy144M,
+    #[serde(rename = "floppy-2.88m")]
+    Floppy288M,
+    #[serde(rename = "hard-disk")]
+    HardDisk,
+}
+
+/// Options for a single El Torito boot entry
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct BootEntryConfig {
+    #[serde(default)]
+    pub emulation: Emulation,
+    /// The number of virtual (512 byte) sectors to load, defaults to the bootloader's requirement
+    #[serde(rename = "load-size")]
+    #[serde(default)]
+    pub load_size: Option<u16>,
+}
+
+/// The El Torito platform id of a boot entry
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum Platform {
+    #[serde(rename = "x86")]
+    X86,
+    #[serde(rename = "powerpc")]
+    PowerPC,
+    #[serde(rename = "mac")]
+    Mac,
+    #[serde(rename = "uefi")]
+    Uefi,
+}
+
+/// An additional boot entry, added as its own section in the boot catalogue
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtraBootEntry {
+    pub platform: Platform,
+    /// The path of the boot image, relative to the ISO root
+    pub path: String,
+    #[serde(flatten)]
+    pub options: BootEntryConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct IsoConfig {
+    /// The default (BIOS) boot entry
+    #[serde(default)]
+    pub bios: BootEntryConfig,
+    /// The UEFI boot entry
+    #[serde(default)]
+    pub uefi: BootEntryConfig,
+    #[serde(rename = "boot-entries")]
+    #[serde(default)]
+    pub boot_entries: Vec<ExtraBootEntry>,
+}
+
+/// The bootloader to install into the image
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum BootloaderKind {
+    #[default]
+    #[serde(rename = "limine")]
+    Limine,
+    /// No bootloader, the executable is a UEFI application booted directly by the firmware
+    #[serde(rename = "none")]
+    None,
+    /// A bootloader prepared by commands from the config, see [`ExternalBootloaderConfig`]
+    #[serde(rename = "external")]
+    External,
+    /// systemd-boot, from the host or a configured path, see [`SystemdBootConfig`]
+    #[serde(rename = "systemd-boot")]
+    SystemdBoot,
+    /// U-Boot, built for a board and loaded by QEMU as its firmware, see [`UBootConfig`]
+    #[serde(rename = "u-boot")]
+    UBoot,
+    /// No bootloader, QEMU loads the kernel directly after checking its Multiboot header
+    #[serde(rename = "multiboot")]
+    Multiboot,
+}
+
+pub const U_BOOT_GIT: &str = "https://source.denx.de/u-boot/u-boot.git";
+
+fn def_u_boot_git() -> String {
+    U_BOOT_GIT.to_string()
+}
+
+fn def_u_boot_reference() -> String {
+    "master".to_string()
+}
+
+/// Options for U-Boot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UBootConfig {
+    /// A prebuilt U-Boot binary, relative to the workspace root, instead of building it
+    #[serde(default)]
+    pub binary: Option<String>,
+    /// The board to build U-Boot for, e.g. `qemu_arm64`, defaults to the QEMU board of the arch
+    #[serde(default)]
+    pub board: Option<String>,
+    /// The repository U-Boot is fetched from
+    #[serde(default = "def_u_boot_git")]
+    pub git: String,
+    /// The branch, tag or commit to fetch, depending on `fetch.ref-type`
+    #[serde(default = "def_u_boot_reference")]
+    pub reference: String,
+    #[serde(default)]
+    pub fetch: GitFetchConfig,
+    /// The prefix of the cross compiler, e.g. `aarch64-linux-gnu-`, defaults to the one of the arch
+    #[serde(rename = "cross-compile")]
+    #[serde(default)]
+    pub cross_compile: Option<String>,
+    /// The path of the device tree blob in the image, variables are supported
+    #[serde(default)]
+    pub fdt: Option<String>,
+    /// The path of the initrd in the image, variables are supported
+    #[serde(default)]
+    pub initrd: Option<String>,
+    /// A template for `extlinux/extlinux.conf`, relative to the workspace root, which replaces the
+    /// generated one
+    #[serde(default)]
+    pub extlinux: Option<String>,
+}
+
+impl Default for UBootConfig {
+    fn default() -> Self {
+        Self {
+            binary: None,
+            board: None,
+            git: def_u_boot_git(),
+            reference: def_u_boot_reference(),
+            fetch: GitFetchConfig::default(),
+            cross_compile: None,
+            fdt: None,
+            initrd: None,
+            extlinux: None,
+        }
+    }
+}
+
+/// Options for systemd-boot
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SystemdBootConfig {
+    /// The path of the systemd-boot EFI binary, defaults to the one installed on the host
+    #[serde(default)]
+    pub efi: Option<String>,
+    /// Whether the kernel is booted as a Linux kernel with an EFI stub, instead of as an EFI
+    /// application
+    #[serde(default)]
+    pub linux: bool,
+    /// The paths of the initrds in the image, variables are supported
+    #[serde(default)]
+    pub initrd: Vec<String>,
+    /// The menu timeout in seconds
+    #[serde(default)]
+    pub timeout: Option<u32>,
+}
+
+/// A file produced by an external bootloader
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalFile {
+    /// The path of the file, relative to the workspace root
+    pub source: String,
+    /// The destination in the image
+    pub dest: String,
+}
+
+/// A bootloader the runner doesn't support natively, prepared by running commands
+///
+/// All strings support the `{{ROOT_DIR}}`, `{{CACHE_DIR}}`, `{{OUTPUT_DIR}}`, `{{EXECUTABLE}}` and
+/// `{{ARCH}}` templates, and variables with `$name`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExternalBootloaderConfig {
+    /// The command that builds or fetches the bootloader, run in the workspace root
+    #[serde(rename = "prepare-cmd")]
+    #[serde(default)]
+    pub prepare_cmd: Vec<String>,
+    /// The files the command produces, and where they go in the image
+    #[serde(default)]
+    pub files: Vec<ExternalFile>,
+    /// The El Torito BIOS boot image, relative to the root of the image
+    #[serde(rename = "bios-boot-image")]
+    #[serde(default)]
+    pub bios_boot_image: Option<String>,
+    /// The El Torito UEFI boot image, relative to the root of the image
+    #[serde(rename = "uefi-boot-image")]
+    #[serde(default)]
+    pub uefi_boot_image: Option<String>,
+    /// Where the executable is placed in the image, defaults to its name
+    #[serde(rename = "executable-dest")]
+    #[serde(default)]
+    pub executable_dest: Option<String>,
+    /// Whether `config-file` is copied into the image
+    #[serde(rename = "uses-config-file")]
+    #[serde(default)]
+    pub uses_config_file: bool,
+    /// Host requirements, such as the programs the command needs
+    #[serde(default)]
+    pub requires: Vec<String>,
+}
+
+/// The format of the built image
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum ImageFormat {
+    #[serde(rename = "iso")]
+    Iso,
+    /// The staged directory itself, which QEMU can use as a FAT drive
+    #[serde(rename = "directory")]
+    Directory,
+}
+
+/// Options for the generated `startup.nsh` script, which the UEFI shell runs on startup
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct StartupNshConfig {
+    /// The arguments passed to the executable, variables are supported
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// Options for the OVMF firmware, used with `boot-type = "uefi"`
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct OvmfConfig {
+    /// The code image to use instead of the fetched one, e.g. of a debug build
+    #[serde(default)]
+    pub code: Option<String>,
+    /// The variable store to use instead of the fetched one, it is copied before every run
+    #[serde(default)]
+    pub vars: Option<String>,
+    /// Writes the debug output of the firmware to `ovmf.log` next to the image, only on x86_64
+    #[serde(rename = "debug-log")]
+    #[serde(default)]
+    pub debug_log: bool,
+}
+
+fn def_manifest_dest() -> String {
+    "manifest.json".to_string()
+}
+
+/// A device added to QEMU, which can depend on capabilities of the host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// Identifies the device in messages
+    pub name: String,
+    /// A common device, whose QEMU arguments are generated before `args`
+    #[serde(default)]
+    pub device: Option<DeviceKind>,
+    /// The QEMU arguments that add the device, variables are supported
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Host requirements, in the same format as `test.require`
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Skips the device with a warning if its requirements aren't met, instead of failing the run
+    #[serde(default)]
+    pub optional: bool,
+}
+
+fn def_debug_exit_iobase() -> u16 {
+    0xf4
+}
+
+fn def_debug_exit_iosize() -> u16 {
+    0x04
+}
+
+fn def_drive_format() -> String {
+    "raw".to_string()
+}
+
+fn def_forward_protocol() -> String {
+    "tcp".to_string()
+}
+
+/// A common device, see [`DeviceConfig::device`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DeviceKind {
+    /// Lets the guest exit QEMU with an exit code, which tests report their result with
+    #[serde(rename = "isa-debug-exit")]
+    IsaDebugExit {
+        #[serde(default = "def_debug_exit_iobase")]
+        iobase: u16,
+        #[serde(default = "def_debug_exit_iosize")]
+        iosize: u16,
+    },
+    /// A network card with user networking, which forwards ports of the host to the guest
+    #[serde(rename = "virtio-net")]
+    VirtioNet {
+        #[serde(default)]
+        forwards: Vec<PortForward>,
+    },
+    /// A source of entropy for the guest
+    #[serde(rename = "virtio-rng")]
+    VirtioRng,
+    /// The display adapter, e.g. `vga = "std"`, and how QEMU shows it, e.g. `display = "none"`
+    #[serde(rename = "display")]
+    Display {
+        #[serde(default)]
+        vga: Option<String>,
+        #[serde(default)]
+        display: Option<String>,
+    },
+}
+
+impl DeviceKind {
+    /// The QEMU arguments that add the device, `id` identifies its backend, such as the network
+    pub fn qemu_args(&self, id: &str) -> Vec<String> {
+        match self {
+            Self::IsaDebugExit { iobase, iosize } => vec![
+                "-device".to_string(),
+                format!("isa-debug-exit,iobase={:#x},iosize={:#x}", iobase, iosize),
+            ],
+            Self::VirtioNet { forwards } => vec![
+                "-netdev".to_string(),
+                user_netdev(id, forwards),
+                "-device".to_string(),
+                format!("virtio-net-pci,netdev={}", id),
+            ],
+            Self::VirtioRng => vec!["-device".to_string(), "virtio-rng-pci".to_string()],
+            Self::Display { vga, display } => {
+                let mut args = Vec::new();
+                if let Some(vga) = vga {
+                    args.extend(["-vga".to_string(), vga.clone()]);
+                }
+                if let Some(display) = display {
+                    args.extend(["-display".to_string(), display.clone()]);
+                }
+                args
+            }
+        }
+    }
+}
+
+/// How a drive is attached to the guest
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum DriveInterface {
+    #[default]
+    #[serde(rename = "virtio")]
+    Virtio,
+    #[serde(rename = "ide")]
+    Ide,
+    #[serde(rename = "nvme")]
+    Nvme,
+}
+
+/// An extra disk attached to the guest, e.g. a scratch disk for filesystem tests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriveConfig {
+    /// The disk image, relative to the workspace root, variables are supported
+    pub path: String,
+    /// The format of the image, e.g. `raw` or `qcow2`
+    #[serde(default = "def_drive_format")]
+    pub format: String,
+    #[serde(default)]
+    pub interface: DriveInterface,
+    /// Creates a blank image of this size if it doesn't exist, e.g. `64M` or `1G`
+    #[serde(default)]
+    pub size: Option<String>,
+    /// Creates the blank image again before every run, so each run starts from an empty disk
+    #[serde(default)]
+    pub fresh: bool,
+}
+
+/// The `-netdev` option of user networking, with a `hostfwd` for each of the `forwards`
+fn user_netdev(id: &str, forwards: &[PortForward]) -> String {
+    let mut netdev = format!("user,id={}", id);
+    for forward in forwards {
+        netdev.push_str(&format!(
+            ",hostfwd={}::{}-:{}",
+            forward.protocol, forward.host, forward.guest
+        ));
+    }
+    netdev
+}
+
+fn def_network_model() -> String {
+    "virtio-net-pci".to_string()
+}
+
+fn def_tap_ifname() -> String {
+    "tap0".to_string()
+}
+
+/// How the guest is connected to the network
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum NetworkMode {
+    /// No network card at all, not even the default one of QEMU
+    #[serde(rename = "none")]
+    None,
+    /// User networking, which needs no privileges and can forward ports of the host to the guest
+    #[default]
+    #[serde(rename = "user")]
+    User,
+    /// A tap interface of the host, which is set up beforehand
+    #[serde(rename = "tap")]
+    Tap,
+}
+
+/// The network of the guest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub mode: NetworkMode,
+    /// Ports of the host forwarded to the guest, only with user networking
+    #[serde(default)]
+    pub forwards: Vec<PortForward>,
+    /// The tap interface, with the tap mode
+    #[serde(default = "def_tap_ifname")]
+    pub ifname: String,
+    /// The network card, e.g. `e1000`
+    #[serde(default = "def_network_model")]
+    pub model: String,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            mode: NetworkMode::default(),
+            forwards: Vec::new(),
+            ifname: def_tap_ifname(),
+            model: def_network_model(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// The QEMU arguments that connect the guest
+    pub fn qemu_args(&self) -> Result<Vec<String>, String> {
+        if !self.forwards.is_empty() && self.mode != NetworkMode::User {
+            return Err("ports can only be forwarded with network mode \"user\"".to_string());
+        }
+        let netdev = match self.mode {
+            NetworkMode::None => return Ok(vec!["-nic".to_string(), "none".to_string()]),
+            NetworkMode::User => user_netdev("net0", &self.forwards),
+            NetworkMode::Tap => {
+                format!("tap,id=net0,ifname={},script=no,downscript=no", self.ifname)
+            }
+        };
+        Ok(vec![
+            "-netdev".to_string(),
+            netdev,
+            "-device".to_string(),
+            format!("{},netdev=net0", self.model),
+        ])
+    }
+}
+
+fn def_share_memory() -> String {
+    "512M".to_string()
+}
+
+/// How a shared folder is passed to the guest
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum ShareDriver {
+    /// virtio-9p, which QEMU serves itself
+    #[default]
+    #[serde(rename = "9p")]
+    NineP,
+    /// virtio-fs, which is served by `virtiofsd` and is faster, but shares the guest memory
+    #[serde(rename = "virtiofs")]
+    Virtiofs,
+}
+
+/// A directory of the host shared with the guest, e.g. for test fixtures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareConfig {
+    /// The directory, relative to the workspace root, variables are supported
+    pub path: String,
+    /// The tag the guest mounts it by, defaults to `share<n>` for the n-th share
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub driver: ShareDriver,
+    #[serde(default)]
+    pub readonly: bool,
+    /// The memory of the guest with virtiofs, which replaces `-m` as it is shared with
+    /// `virtiofsd`
+    #[serde(default = "def_share_memory")]
+    pub memory: String,
+}
+
+impl ShareConfig {
+    /// The mount tag of the share at `index`
+    pub fn tag(&self, index: usize) -> String {
+        self.tag
+            .clone()
+            .unwrap_or_else(|| format!("share{}", index))
+    }
+}
+
+/// A port of the host forwarded to the guest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortForward {
+    /// Either `tcp` or `udp`
+    #[serde(default = "def_forward_protocol")]
+    pub protocol: String,
+    pub host: u16,
+    pub guest: u16,
+}
+
+/// A milestone of the boot, recognized by a line the guest or the firmware prints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootStageConfig {
+    /// Identifies the stage in messages, e.g. `kernel entry`
+    pub name: String,
+    /// The text printed when the stage is reached, in the serial output or the firmware log
+    pub pattern: String,
+    /// Only looks for the pattern in this channel, e.g. `debugcon` for the firmware log, see
+    /// [`crate::outcome::CapturedOutput`]
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+/// Whether the image is staged in memory, and only the finished image is copied to the output
+/// directory
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum FastStaging {
+    #[default]
+    #[serde(rename = "never")]
+    Never,
+    /// Only when the output directory is on a network file system
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "always")]
+    Always,
+}
+
+/// How the runner reports what it does
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum MessageFormat {
+    #[default]
+    #[serde(rename = "human")]
+    Human,
+    /// Machine-readable messages on stdout, see [`crate::message::Message`]
+    #[serde(rename = "json")]
+    Json,
+}
+
+/// Options for the manifest of the image, which lists the shipped files with their SHA-256
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestConfig {
+    /// Where the manifest is placed in the image
+    #[serde(default = "def_manifest_dest")]
+    pub dest: String,
+}
+
+/// A secondary artifact built by another package in the workspace, e.g. a userspace initrd
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PayloadConfig {
+    /// The package that builds the artifact
+    pub package: String,
+    /// The name of the artifact, defaults to the package name
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The kind of the artifact, e.g. `bin` or `staticlib`
+    #[serde(default = "def_payload_kind")]
+    pub kind: String,
+    /// The target to build the artifact for, defaults to the host
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub release: bool,
+    /// The destination of the artifact in the image
+    pub dest: String,
+}
+
+/// What to do with a test when its requirements aren't met
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RequirementPolicy {
+    /// Reports the test as skipped, with the reason, and passes it
+    #[default]
+    #[serde(rename = "skip")]
+    Skip,
+    #[serde(rename = "fail")]
+    Fail,
+}
+
+/// Options for runs that aren't tests
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RunConfig {
+    /// A regex the serial output has to match for the run to succeed, whatever the exit code of
+    /// QEMU, which is stopped once it matches, e.g. for smoke tests in CI
+    #[serde(rename = "success-pattern")]
+    #[serde(default)]
+    pub success_pattern: Option<String>,
+    /// A regex that fails the run when the serial output matches it, QEMU is stopped once it
+    /// does
+    #[serde(rename = "failure-pattern")]
+    #[serde(default)]
+    pub failure_pattern: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TestConfig {
+    /// What the host needs to run the tests, e.g. `kvm`, `qemu>=8.0` or a program such as `swtpm`
+    #[serde(default)]
+    pub require: Vec<String>,
+    #[serde(default)]
+    pub policy: RequirementPolicy,
+    /// Fails the test when the guest outputs nothing for this long, in milliseconds, e.g. when a
+    /// test case hangs
+    #[serde(rename = "idle-timeout")]
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    /// Runs a failing test again up to this many times, for flaky tests
+    #[serde(default)]
+    pub retries: u32,
+    /// A file the serial output of the test is compared against, relative to the workspace root
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// Replacements applied to the serial output before it is compared against the snapshot
+    #[serde(rename = "snapshot-filters")]
+    #[serde(default)]
+    pub snapshot_filters: Vec<SnapshotFilterConfig>,
+    /// Writes the serial output to the snapshot instead of comparing it
+    #[serde(rename = "update-snapshots")]
+    #[serde(default)]
+    pub update_snapshots: bool,
+    /// Appended to `cmdline` when cargo passes a filter to the test, with `$TEST_FILTER`
+    /// replaced by it, e.g. `test-filter=$TEST_FILTER`
+    #[serde(rename = "filter-arg-template")]
+    #[serde(default)]
+    pub filter_arg_template: Option<String>,
+    /// How the guest is stopped when the runner gives up on it, e.g. after the idle timeout
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    /// Keys of the image-runner table that only apply to tests, merged over the rest of the
+    /// config, e.g. to emulate with `accel = "tcg"` for determinism
+    #[serde(default)]
+    pub overrides: Option<serde_json::Value>,
+    /// Saves the state of the VM once the guest prints a marker, and restores it in later runs
+    /// instead of booting again
+    #[serde(rename = "snapshot-boot")]
+    #[serde(default)]
+    pub snapshot_boot: Option<SnapshotBootConfig>,
+    /// Recognizes the test cases in the serial output as they finish
+    #[serde(default)]
+    pub cases: Option<TestCasesConfig>,
+    /// The test is expected to panic, it only passes once the guest prints this pattern, like
+    /// `#[should_panic]`
+    #[serde(rename = "expect-panic-pattern")]
+    #[serde(default)]
+    pub expect_panic_pattern: Option<String>,
+}
+
+/// The lines printed by the test cases of a test, matched as they are printed, see
+/// [`TestConfig::cases`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestCasesConfig {
+    /// Matches the line of a case that passed, named by the `name` group, like libtest by default
+    #[serde(default = "def_case_pass")]
+    pub pass: String,
+    /// Matches the line of a case that failed
+    #[serde(default = "def_case_fail")]
+    pub fail: String,
+    /// Matches the line printed after the last case, the test is stopped once it is printed
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Stops the test once a case fails
+    #[serde(rename = "fail-fast")]
+    #[serde(default)]
+    pub fail_fast: bool,
+    /// Matches the line printed when a case starts, named by the `name` group, for `timeout`
+    #[serde(default)]
+    pub start: Option<String>,
+    /// Fails a case that started, but printed neither the pass nor the fail line within this
+    /// many milliseconds, and stops the test
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+fn def_case_pass() -> String {
+    r"^test (?<name>\S+) \.\.\. ok".to_string()
+}
+
+fn def_case_fail() -> String {
+    r"^test (?<name>\S+) \.\.\. FAILED".to_string()
+}
+
+/// Where the state of a booted test VM is saved, see [`TestConfig::snapshot_boot`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotBootConfig {
+    /// Printed by the guest once it is booted, the state is saved right after it
+    pub marker: String,
+    /// The name of the snapshot, as listed by `qemu-img snapshot -l`
+    #[serde(default = "def_snapshot_boot_name")]
+    pub name: String,
+}
+
+fn def_snapshot_boot_name() -> String {
+    "booted".to_string()
+}
+
+/// How the guest is first asked to shut down, before QEMU is terminated and then killed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ShutdownMethod {
+    /// Kills QEMU right away
+    #[default]
+    #[serde(rename = "kill")]
+    Kill,
+    /// Sends `system_powerdown` over QMP, which needs `qmp-port`
+    #[serde(rename = "qmp")]
+    Qmp,
+    /// Sends `command` to the serial input of the guest
+    #[serde(rename = "serial")]
+    Serial,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownConfig {
+    #[serde(default)]
+    pub method: ShutdownMethod,
+    /// How long each step waits for QEMU to exit before the next one, in seconds
+    #[serde(default = "def_shutdown_grace")]
+    pub grace: u64,
+    /// What is sent to the guest with the `serial` method
+    #[serde(default = "def_shutdown_command")]
+    pub command: String,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            method: ShutdownMethod::default(),
+            grace: def_shutdown_grace(),
+            command: def_shutdown_command(),
+        }
+    }
+}
+
+const fn def_shutdown_grace() -> u64 {
+    5
+}
+
+fn def_shutdown_command() -> String {
+    "poweroff\n".to_string()
+}
+
+/// Replaces the matches of a regex in the output, e.g. to mask addresses before it is compared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubConfig {
+    pub pattern: String,
+    /// The replacement, which can refer to capture groups like `$1`
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// Replaces the matches of a regex in the serial output, before it is compared against the
+/// snapshot
+pub type SnapshotFilterConfig = ScrubConfig;
+
+/// Input fed to the serial port of the guest
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SerialInputConfig {
+    /// A file whose contents are fed to the guest, relative to the workspace root
+    #[serde(rename = "stdin-file")]
+    #[serde(default)]
+    pub file: Option<String>,
+    /// The delay between lines, in milliseconds
+    #[serde(rename = "stdin-line-delay")]
+    #[serde(default)]
+    pub line_delay: Option<u64>,
+    /// Waits for the guest to output this before sending each line
+    #[serde(rename = "stdin-prompt")]
+    #[serde(default)]
+    pub prompt: Option<String>,
+    /// Limits the input to this many bytes per second
+    #[serde(rename = "stdin-rate")]
+    #[serde(default)]
+    pub rate: Option<u32>,
+    /// Waits for each line to be echoed back by the guest before sending the next one
+    #[serde(rename = "stdin-wait-for-echo")]
+    #[serde(default)]
+    pub wait_for_echo: bool,
+    /// Stops sending input if the guest outputs nothing for this long while waiting for a prompt
+    /// or an echo, in milliseconds
+    #[serde(rename = "stdin-timeout")]
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Answers the guest when its output matches a regex
+    #[serde(rename = "stdin-responders")]
+    #[serde(default)]
+    pub responders: Vec<ResponderConfig>,
+    /// An expect script, whose steps wait for the guest to print something and answer it
+    #[serde(default)]
+    pub expect: Vec<ExpectStepConfig>,
+}
+
+/// A step of an expect script, see [`crate::serial::ExpectStep`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpectStepConfig {
+    /// Waits for the guest to output this
+    #[serde(default)]
+    pub wait: Option<String>,
+    /// Then sends this to the guest
+    #[serde(default)]
+    pub send: Option<String>,
+    /// Fails the run if `wait` isn't output within this time, in milliseconds
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+/// A response sent to the guest when its output matches `pattern`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponderConfig {
+    /// A regex, which should end with a delimiter such as `\n` to not match a partial line
+    pub pattern: String,
+    /// The response, where `$1` or `${name}` are replaced by the capture groups of the match
+    pub response: String,
+    /// Only answers the first match, instead of every one
+    #[serde(default)]
+    pub once: bool,
+}
+
+/// The official limine repository
+pub const LIMINE_GIT: &str = "https://github.com/limine-bootloader/limine";
+
+/// Where limine comes from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LimineSource {
+    /// A git repository, e.g. an internal mirror
+    Git { git: String },
+    /// A directory with the limine binaries, relative to the workspace root, e.g. vendored in
+    /// the repository
+    Path { path: String },
+}
+
+impl Default for LimineSource {
+    fn default() -> Self {
+        Self::Git {
+            git: LIMINE_GIT.to_string(),
+        }
+    }
+}
+
+/// The accelerator QEMU runs the guest with, passed with `-accel`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum Accel {
+    /// KVM, on Linux hosts
+    #[serde(rename = "kvm")]
+    Kvm,
+    /// The Hypervisor framework, on macOS hosts
+    #[serde(rename = "hvf")]
+    Hvf,
+    /// The Windows Hypervisor Platform, on Windows hosts
+    #[serde(rename = "whpx")]
+    Whpx,
+    /// Emulates the guest, which works everywhere but is slow
+    #[serde(rename = "tcg")]
+    Tcg,
+    /// The fastest accelerator that QEMU and the host support
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+impl Accel {
+    /// The name of the accelerator, as QEMU knows it
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Kvm => "kvm",
+            Self::Hvf => "hvf",
+            Self::Whpx => "whpx",
+            Self::Tcg => "tcg",
+            Self::Auto => "auto",
+        }
+    }
+}
+
+/// What kind of git reference `limine-branch` is
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum GitRefType {
+    #[default]
+    #[serde(rename = "branch")]
+    Branch,
+    #[serde(rename = "tag")]
+    Tag,
+    #[serde(rename = "commit")]
+    Commit,
+}
+
+/// When to fetch the bootloader again
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone, Copy)]
+pub enum UpdatePolicy {
+    /// Never fetches, the cached version is used even if the reference changed
+    #[serde(rename = "never")]
+    Never,
+    /// Fetches if nothing, or a different reference, is cached
+    #[default]
+    #[serde(rename = "if-missing")]
+    IfMissing,
+    /// Fetches on every run, to pick up updates of movable references like `v8.x-binary`
+    #[serde(rename = "always")]
+    Always,
+}
+
+/// How the bootloader is fetched with git
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitFetchConfig {
+    /// The number of commits to fetch, 0 fetches the full history
+    #[serde(default = "def_git_depth")]
+    pub depth: u32,
+    #[serde(rename = "ref-type")]
+    #[serde(default)]
+    pub ref_type: GitRefType,
+    #[serde(default)]
+    pub update: UpdatePolicy,
+}
+
+impl Default for GitFetchConfig {
+    fn default() -> Self {
+        Self {
+            depth: def_git_depth(),
+            ref_type: GitRefType::default(),
+            update: UpdatePolicy::default(),
+        }
+    }
+}
+
+const fn def_git_depth() -> u32 {
+    1
+}
+
+/// Options for debugging the kernel with GDB
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DebugConfig {
+    /// Starts QEMU with a GDB server
+    #[serde(default)]
+    pub enabled: bool,
+    /// The port of the GDB server
+    #[serde(default = "def_gdb_port")]
+    pub port: u16,
+    /// Waits for GDB to connect before starting the guest
+    #[serde(default = "def_true")]
+    pub wait: bool,
+    /// The debugger to launch, e.g. `gdb` or `rust-gdb`, nothing is launched if unset
+    #[serde(default)]
+    pub gdb: Option<String>,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: def_gdb_port(),
+            wait: true,
+            gdb: None,
+        }
+    }
+}
+
+/// Writes the serial output and what QEMU printed on stderr to a log file while the guest runs,
+/// for post-mortem debugging of long runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+    /// Where the logs are written, relative to the workspace root, defaults to
+    /// `image-runner/logs` in the target directory
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Prefixes each line with when it was received, in seconds since QEMU was started
+    #[serde(default)]
+    pub timestamps: bool,
+    /// Removes ANSI escape sequences, such as colors
+    #[serde(rename = "strip-ansi")]
+    #[serde(default)]
+    pub strip_ansi: bool,
+    /// How many logs of each executable are kept, the oldest ones are removed
+    #[serde(default = "def_log_keep")]
+    pub keep: usize,
+    /// Writes an index next to logs of at least this many bytes, so tools can seek to a time or
+    /// a marker without reading the whole log
+    #[serde(rename = "index-threshold")]
+    #[serde(default)]
+    pub index_threshold: Option<usize>,
+    /// Patterns of the lines that are indexed, e.g. the start of each test case
+    #[serde(rename = "index-markers")]
+    #[serde(default)]
+    pub index_markers: Vec<String>,
+}
+
+const fn def_log_keep() -> usize {
+    10
+}
+
+/// Collects the coverage profiles the guest prints on the serial port, framed by markers and
+/// encoded in base64
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoverageConfig {
+    /// Printed before each profile
+    #[serde(default = "def_coverage_begin")]
+    #[serde(deserialize_with = "non_empty_marker")]
+    pub begin: String,
+    /// Printed after each profile
+    #[serde(default = "def_coverage_end")]
+    #[serde(deserialize_with = "non_empty_marker")]
+    pub end: String,
+    /// Where the `.profraw` files are written, relative to the workspace root
+    #[serde(default = "def_coverage_dir")]
+    pub dir: String,
+    /// Merges the profiles in `dir` into `coverage.profdata` after each run
+    #[serde(default)]
+    pub merge: bool,
+    /// The `llvm-profdata` used to merge them, e.g. the one of the Rust toolchain
+    #[serde(rename = "llvm-profdata")]
+    #[serde(default = "def_llvm_profdata")]
+    pub llvm_profdata: String,
+}
+
+/// Deserializes a marker that is searched for in the output, which can't be empty
+fn non_empty_marker<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let marker = String::deserialize(deserializer)?;
+    if marker.is_empty() {
+        return Err(serde::de::Error::custom("a marker can't be empty"));
+    }
+    Ok(marker)
+}
+
+fn def_coverage_begin() -> String {
+    "=== COVERAGE BEGIN ===".to_string()
+}
+
+fn def_coverage_end() -> String {
+    "=== COVERAGE END ===".to_string()
+}
+
+fn def_coverage_dir() -> String {
+    "target/image-runner/coverage".to_string()
+}
+
+fn def_llvm_profdata() -> String {
+    "llvm-profdata".to_string()
+}
+
+/// Whether the execution of the guest is recorded or replayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordReplayMode {
+    #[serde(rename = "record")]
+    Record,
+    #[serde(rename = "replay")]
+    Replay,
+}
+
+impl RecordReplayMode {
+    /// The name of the mode, as QEMU's `rr` option expects it
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordReplayMode::Record => "record",
+            RecordReplayMode::Replay => "replay",
+        }
+    }
+}
+
+/// Records the execution of the guest with QEMU's record/replay, so a nondeterministic failure can
+/// be replayed exactly, e.g. under GDB
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordReplayConfig {
+    pub mode: RecordReplayMode,
+    /// The file the execution is recorded to, relative to the workspace root
+    #[serde(default = "def_record_replay_file")]
+    pub file: String,
+}
+
+impl RecordReplayConfig {
+    /// Records or replays the default file
+    pub fn new(mode: RecordReplayMode) -> Self {
+        Self {
+            mode,
+            file: def_record_replay_file(),
+        }
+    }
+}
+
+fn def_record_replay_file() -> String {
+    "target/image-runner/replay.bin".to_string()
+}
+
+const fn def_gdb_port() -> u16 {
+    1234
+}
+
+const fn def_true() -> bool {
+    true
+}
+
+fn def_payload_kind() -> String {
+    "bin".to_string()
+}
+
+fn def_config_file() -> String {
+    "limine.conf".to_string()
+}
+
+fn def_limine_branch() -> String {
+    "v8.x-binary".to_string()
+}
+
 const fn def_test_success_exit_code() -> u32 {
     33
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ImageRunnerConfig {
     #[serde(rename = "config-file")]
+    #[serde(default = "def_config_file")]
     pub config_file: String,
     #[serde(default)]
     #[serde(rename = "extra-files")]
     pub extra_files: Vec<String>,
+    /// Directories merged in order into the root of the image, later ones overriding earlier ones
+    #[serde(default)]
+    pub overlays: Vec<String>,
     #[serde(rename = "limine-branch")]
+    #[serde(default = "def_limine_branch")]
     pub limine_branch: String,
+    #[serde(rename = "limine-fetch")]
+    #[serde(default)]
+    pub limine_fetch: GitFetchConfig,
+    #[serde(rename = "limine-source")]
+    #[serde(default)]
+    pub limine_source: LimineSource,
+    /// Defaults to a command for the architecture when empty
     #[serde(rename = "run-command")]
+    #[serde(default)]
     pub run_command: Vec<String>,
     #[serde(rename = "test-args")]
     #[serde(default)]
@@ -44,27 +1271,573 @@ pub struct ImageRunnerConfig {
     pub cmdline: String,
     #[serde(default)]
     pub vars: HashMap<String, String>,
+    #[serde(default)]
+    pub iso: IsoConfig,
+    /// Fails the run if any warnings were emitted
+    #[serde(rename = "deny-warnings")]
+    #[serde(default)]
+    pub deny_warnings: bool,
+    /// Prints more details while building the image, such as the copy throughput
+    #[serde(default)]
+    pub verbose: bool,
+    /// The accelerator QEMU uses, QEMU chooses if unset
+    #[serde(default)]
+    pub accel: Option<Accel>,
+    /// Deprecated, use `accel = "kvm"` instead
+    #[serde(default)]
+    pub kvm: bool,
+    /// Prints JSON messages on stdout instead of the guest output, which goes to stderr
+    #[serde(rename = "message-format")]
+    #[serde(default)]
+    pub message_format: MessageFormat,
+    #[serde(default)]
+    pub bootloader: BootloaderKind,
+    #[serde(rename = "external-bootloader")]
+    #[serde(default)]
+    pub external_bootloader: ExternalBootloaderConfig,
+    #[serde(rename = "systemd-boot")]
+    #[serde(default)]
+    pub systemd_boot: SystemdBootConfig,
+    #[serde(rename = "u-boot")]
+    #[serde(default)]
+    pub u_boot: UBootConfig,
+    /// Loads the executable with QEMU's `-kernel`, without a bootloader or an image
+    #[serde(rename = "direct-kernel")]
+    #[serde(default)]
+    pub direct_kernel: bool,
+    /// The initrds (or Multiboot modules) passed with `-initrd` when the kernel is loaded
+    /// directly, relative to the workspace root, variables are supported
+    #[serde(default)]
+    pub initrd: Vec<String>,
+    /// The format of the image, defaults to a directory without a bootloader and an ISO otherwise
+    #[serde(rename = "image-format")]
+    #[serde(default)]
+    pub image_format: Option<ImageFormat>,
+    /// Generates a `startup.nsh` that runs the executable from the UEFI shell, only used without
+    /// a bootloader
+    #[serde(rename = "startup-nsh")]
+    #[serde(default)]
+    pub startup_nsh: Option<StartupNshConfig>,
+    /// Stages the image in a tmpfs, see [`FastStaging`]
+    #[serde(rename = "fast-staging")]
+    #[serde(default)]
+    pub fast_staging: FastStaging,
+    /// Embeds a manifest of the files into the image, and writes it next to the image
+    #[serde(default)]
+    pub manifest: Option<ManifestConfig>,
+    /// Writes a CycloneDX listing of the third-party components of the image next to it
+    #[serde(default)]
+    pub sbom: bool,
+    /// Artifacts of other packages, which are rebuilt and copied into the image
+    #[serde(default)]
+    pub payloads: Vec<PayloadConfig>,
+    /// Destinations in the image of artifact dependencies, keyed by their variable name
+    #[serde(rename = "artifact-files")]
+    #[serde(default)]
+    pub artifact_files: HashMap<String, String>,
+    #[serde(default)]
+    pub run: RunConfig,
+    #[serde(default)]
+    pub test: TestConfig,
+    #[serde(flatten)]
+    pub stdin: SerialInputConfig,
+    #[serde(default)]
+    pub arch: Arch,
+    #[serde(default)]
+    pub debug: DebugConfig,
+    /// Records or replays the execution of the guest, which runs it with TCG
+    #[serde(rename = "record-replay")]
+    #[serde(default)]
+    pub record_replay: Option<RecordReplayConfig>,
+    /// Collects coverage profiles from the serial output of the guest
+    #[serde(default)]
+    pub coverage: Option<CoverageConfig>,
+    /// Writes the output of each run to a log file
+    #[serde(default)]
+    pub log: Option<LogConfig>,
+    /// Removes ANSI escape sequences from the serial output before it is matched, so colors
+    /// don't break patterns, they are still shown in the terminal
+    #[serde(rename = "strip-ansi")]
+    #[serde(default)]
+    pub strip_ansi: bool,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    /// Extra disks attached to the guest
+    #[serde(default)]
+    pub drives: Vec<DriveConfig>,
+    /// The network of the guest, QEMU adds its default network card if unset
+    #[serde(default)]
+    pub network: Option<NetworkConfig>,
+    /// Directories of the host shared with the guest
+    #[serde(default)]
+    pub shares: Vec<ShareConfig>,
+    #[serde(default)]
+    pub ovmf: OvmfConfig,
+    /// Starts QEMU with a QMP server on this port, see [`crate::qmp`]
+    #[serde(rename = "qmp-port")]
+    #[serde(default)]
+    pub qmp_port: Option<u16>,
+    /// Binds the serial port of the guest to a socket instead of stdio, `unix:<path>` or
+    /// `tcp:<port>`, see [`crate::serial::SerialSocket`]
+    #[serde(rename = "serial-socket")]
+    #[serde(default)]
+    pub serial_socket: Option<String>,
+    /// The stages of the boot in order, the last one reached is reported when a run fails
+    #[serde(rename = "boot-stages")]
+    #[serde(default)]
+    pub boot_stages: Vec<BootStageConfig>,
+    /// Stops the run when the guest resets more than this many times, e.g. in a triple fault loop
+    #[serde(rename = "reset-limit")]
+    #[serde(default)]
+    pub reset_limit: Option<u32>,
+    /// Kills QEMU as soon as the guest prints a line matching one of these patterns, e.g. a
+    /// kernel panic, and fails the run
+    #[serde(rename = "abort-patterns")]
+    #[serde(default)]
+    pub abort_patterns: Vec<String>,
+    /// Lets the guest ask for screenshots, checkpoints, tracing and log annotations on the serial
+    /// port
+    #[serde(rename = "host-actions")]
+    #[serde(default)]
+    pub host_actions: Option<HostActionsConfig>,
+    /// Replacements applied in order to the output before it is compared against snapshots or
+    /// another run, and written to log files, e.g. to mask addresses and timestamps
+    #[serde(default)]
+    pub scrub: Vec<ScrubConfig>,
+}
+
+/// Lines of the serial output that ask the runner to take an action, see
+/// [`crate::host_action::HostAction`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostActionsConfig {
+    /// What the lines start with, followed by the action
+    #[serde(default = "def_host_actions_prefix")]
+    pub prefix: String,
+}
+
+fn def_host_actions_prefix() -> String {
+    "@image-runner:".to_string()
+}
+
+impl ImageRunnerConfig {
+    /// Merges `test.overrides` over the config, see [`merge_config`]
+    pub fn apply_test_overrides(&mut self) -> Result<(), String> {
+        let Some(overrides) = self.test.overrides.take() else {
+            return Ok(());
+        };
+        let mut config = serde_json::to_value(&*self).unwrap();
+        merge_config(&mut config, overrides);
+        *self = serde_json::from_value(config)
+            .map_err(|err| format!("invalid test.overrides: {}", err))?;
+        Ok(())
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        self.image_format.unwrap_or(match self.bootloader {
+            BootloaderKind::Limine => ImageFormat::Iso,
+            BootloaderKind::None => ImageFormat::Directory,
+            BootloaderKind::External => ImageFormat::Iso,
+            // There is no El Torito boot image for systemd-boot
+            BootloaderKind::SystemdBoot => ImageFormat::Directory,
+            // U-Boot is the firmware, and reads extlinux.conf from a FAT drive
+            BootloaderKind::UBoot => ImageFormat::Directory,
+            // No image is built, the kernel is loaded directly
+            BootloaderKind::Multiboot => ImageFormat::Directory,
+        })
+    }
 }
 
 pub fn default_config() -> PackageMetadata {
     PackageMetadata {
         image_runner: ImageRunnerConfig {
-            config_file: "limine.conf".to_string(),
+            config_file: def_config_file(),
             extra_files: vec![],
-            limine_branch: "v8.x-binary".to_string(),
-            run_command: vec!["qemu-system-x86_64".to_string(), "-cdrom".to_string(), "{}".to_string()],
+            overlays: vec![],
+            limine_branch: def_limine_branch(),
+            limine_fetch: GitFetchConfig::default(),
+            limine_source: LimineSource::default(),
+            run_command: Arch::default().default_run_command(),
             test_args: vec![],
             run_args: vec![],
             test_success_exit_code: 33,
             boot_type: BootType::Bios,
             cmdline: "".to_string(),
             vars: HashMap::new(),
+            iso: IsoConfig::default(),
+            deny_warnings: false,
+            verbose: false,
+            accel: None,
+            kvm: false,
+            message_format: MessageFormat::Human,
+            bootloader: BootloaderKind::Limine,
+            external_bootloader: ExternalBootloaderConfig::default(),
+            systemd_boot: SystemdBootConfig::default(),
+            u_boot: UBootConfig::default(),
+            direct_kernel: false,
+            initrd: vec![],
+            image_format: None,
+            startup_nsh: None,
+            fast_staging: FastStaging::Never,
+            manifest: None,
+            sbom: false,
+            payloads: vec![],
+            artifact_files: HashMap::new(),
+            run: RunConfig::default(),
+            test: TestConfig::default(),
+            stdin: SerialInputConfig::default(),
+            arch: Arch::default(),
+            debug: DebugConfig::default(),
+            record_replay: None,
+            coverage: None,
+            log: None,
+            strip_ansi: false,
+            devices: vec![],
+            drives: vec![],
+            network: None,
+            shares: vec![],
+            ovmf: OvmfConfig::default(),
+            qmp_port: None,
+            serial_socket: None,
+            reset_limit: None,
+            abort_patterns: vec![],
+            host_actions: None,
+            scrub: vec![],
+            boot_stages: vec![],
         },
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PackageMetadata {
     #[serde(rename = "image-runner")]
     pub image_runner: ImageRunnerConfig,
 }
+
+/// The variables set by the runner, which win over variables of the same name
+///
+/// Templates like `{{ARCH}}` or `{{EXECUTABLE}}` are only replaced in specific strings, and
+/// aren't variables, so they don't shadow any.
+pub const BUILTIN_VARS: &[&str] = &["TEST_FILTER", "ARGS", "ARGS_JSON"];
+
+/// Whether `name` is one of the [`BUILTIN_VARS`], or one of the `ARG<n>` and `SHARE<n>`
+/// variables
+pub fn is_builtin_var(name: &str) -> bool {
+    let indexed = |prefix: &str| {
+        name.strip_prefix(prefix)
+            .is_some_and(|index| !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()))
+    };
+    BUILTIN_VARS.contains(&name) || indexed("ARG") || indexed("SHARE")
+}
+
+/// Joins `args` with spaces, quoting the ones with whitespace, quotes or shell metacharacters in
+/// double quotes, with `"`, `\`, `$` and `` ` `` escaped by a backslash
+pub fn quote_args(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+".contains(c));
+            if plain {
+                return arg.clone();
+            }
+            let mut quoted = String::from('"');
+            for c in arg.chars() {
+                if matches!(c, '"' | '\\' | '$' | '`') {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            quoted.push('"');
+            quoted
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Replaces `$name` in `str` with the variables, see [`substitute`]
+pub fn substitute_vars(str: &str, vars: &HashMap<String, String>) -> String {
+    substitute(str, vars, |name| format!("${}", name))
+}
+
+/// Replaces `{{name}}` in `str` with the variables, see [`substitute`]
+pub fn substitute_templates(str: &str, vars: &HashMap<String, String>) -> String {
+    substitute(str, vars, |name| format!("{{{{{}}}}}", name))
+}
+
+/// Replaces the variables in `str`, each is also available as `user.<name>`, which always
+/// refers to the variable of the config, even if a built-in one has the same name
+fn substitute(
+    str: &str,
+    vars: &HashMap<String, String>,
+    placeholder: impl Fn(&str) -> String,
+) -> String {
+    let mut str = str.to_string();
+    // The longest names are replaced first, so `$ARG1` doesn't match the prefix of `$ARG10`
+    let mut sorted: Vec<_> = vars.iter().collect();
+    sorted.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+    // A built-in variable is set under the name of a variable it shadows, which is moved to
+    // `user.<name>`, the namespaced names are replaced first so `user` can't match their prefix
+    for &(k, v) in &sorted {
+        let name = match k.strip_prefix("user.") {
+            Some(_) => k.clone(),
+            None if vars.contains_key(&format!("user.{}", k)) => continue,
+            None => format!("user.{}", k),
+        };
+        str = str.replace(&placeholder(&name), v);
+    }
+    for &(k, v) in sorted.iter().filter(|(k, _)| !k.starts_with("user.")) {
+        str = str.replace(&placeholder(k), v);
+    }
+    str
+}
+
+/// The files in the workspace root the config is also read from, the first one found is used
+pub const CONFIG_FILES: &[&str] = &["image-runner.toml", ".config/image-runner.toml"];
+
+/// Reads a config file, whose top-level keys are the ones of `[package.metadata.image-runner]`
+pub fn read_config_file(path: &Path) -> Result<serde_json::Value, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    toml::from_str(&contents).map_err(|err| format!("invalid {}: {}", path.display(), err))
+}
+
+/// Merges `overlay` into `base`, tables are merged key by key and other values are replaced
+pub fn merge_config(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge_config(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_merge_config() {
+    let mut base = serde_json::json!({
+        "boot-type": "bios",
+        "run-args": ["-m", "512M"],
+        "vars": { "machine": "q35", "memory": "512M" },
+    });
+    let overlay: serde_json::Value = toml::from_str(
+        "boot-type = \"uefi\"\nrun-args = [\"-s\"]\n[vars]\nmemory = \"1G\"\n",
+    )
+    .unwrap();
+    merge_config(&mut base, overlay);
+    assert_eq!(
+        base,
+        serde_json::json!({
+            "boot-type": "uefi",
+            "run-args": ["-s"],
+            "vars": { "machine": "q35", "memory": "1G" },
+        })
+    );
+}
+
+/// Expands `${env:NAME}` references in all the strings of a config value
+pub fn expand_env_vars(value: &mut serde_json::Value) -> Result<(), String> {
+    match value {
+        serde_json::Value::String(str) => {
+            *str = expand_env(str, &|name| std::env::var(name).ok())?;
+        }
+        serde_json::Value::Array(values) => {
+            for value in values.iter_mut() {
+                expand_env_vars(value)?;
+            }
+        }
+        serde_json::Value::Object(values) => {
+            for value in values.values_mut() {
+                expand_env_vars(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_env(str: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(str.len());
+    let mut rest = str;
+    while let Some(start) = rest.find("${env:") {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + "${env:".len()..];
+        let end = rest
+            .find('}')
+            .ok_or_else(|| format!("unterminated environment variable reference in {:?}", str))?;
+        let name = &rest[..end];
+        let value = lookup(name).ok_or_else(|| {
+            format!(
+                "environment variable {} is used in the config ({:?}), but is not set",
+                name, str
+            )
+        })?;
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+#[test]
+fn test_expand_env() {
+    let lookup = |name: &str| (name == "HOME").then(|| "/home/user".to_string());
+    assert_eq!(
+        expand_env("${env:HOME}/images/base.img", &lookup),
+        Ok("/home/user/images/base.img".to_string())
+    );
+    assert_eq!(expand_env("$machine", &lookup), Ok("$machine".to_string()));
+    assert!(expand_env("${env:UNSET}", &lookup).is_err());
+    assert!(expand_env("${env:HOME", &lookup).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_device_args() {
+    let net = DeviceKind::VirtioNet {
+        forwards: vec![PortForward {
+            protocol: def_forward_protocol(),
+            host: 8080,
+            guest: 80,
+        }],
+    };
+    assert_eq!(
+        net.qemu_args("device0"),
+        [
+            "-netdev",
+            "user,id=device0,hostfwd=tcp::8080-:80",
+            "-device",
+            "virtio-net-pci,netdev=device0"
+        ]
+    );
+    let exit: DeviceKind = serde_json::from_str(r#"{"type": "isa-debug-exit"}"#).unwrap();
+    assert_eq!(
+        exit.qemu_args("device1"),
+        ["-device", "isa-debug-exit,iobase=0xf4,iosize=0x4"]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_network_args() {
+    let mut network = NetworkConfig {
+        forwards: vec![PortForward {
+            protocol: "udp".to_string(),
+            host: 5353,
+            guest: 53,
+        }],
+        ..Default::default()
+    };
+    assert_eq!(
+        network.qemu_args().unwrap(),
+        [
+            "-netdev",
+            "user,id=net0,hostfwd=udp::5353-:53",
+            "-device",
+            "virtio-net-pci,netdev=net0"
+        ]
+    );
+    network.mode = NetworkMode::Tap;
+    assert!(network.qemu_args().is_err());
+    network.forwards.clear();
+    assert_eq!(
+        network.qemu_args().unwrap()[1],
+        "tap,id=net0,ifname=tap0,script=no,downscript=no"
+    );
+    network.mode = NetworkMode::None;
+    assert_eq!(network.qemu_args().unwrap(), ["-nic", "none"]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_apply_test_overrides() {
+    let mut config = default_config().image_runner;
+    config.accel = Some(Accel::Kvm);
+    config.run_args = vec!["-m".to_string(), "2G".to_string()];
+    config.vars.insert("machine".to_string(), "q35".to_string());
+    config.test.overrides = Some(serde_json::json!({
+        "accel": "tcg",
+        "run-args": ["-m", "512M"],
+        "test": { "retries": 2 },
+    }));
+    config.apply_test_overrides().unwrap();
+    assert_eq!(config.accel, Some(Accel::Tcg));
+    assert_eq!(config.run_args, ["-m", "512M"]);
+    assert_eq!(config.vars["machine"], "q35");
+    assert_eq!(config.test.retries, 2);
+
+    config.test.overrides = Some(serde_json::json!({ "accel": "fast" }));
+    assert!(config.apply_test_overrides().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_substitute_vars() {
+    let vars = HashMap::from([
+        ("TIMEOUT".to_string(), "5".to_string()),
+        ("TEST_FILTER".to_string(), "my_case".to_string()),
+        ("user.TEST_FILTER".to_string(), "mine".to_string()),
+        ("user".to_string(), "nobody".to_string()),
+    ]);
+    assert_eq!(
+        substitute_vars(
+            "-t $TIMEOUT $user.TIMEOUT $TEST_FILTER $user.TEST_FILTER $user",
+            &vars
+        ),
+        "-t 5 5 my_case mine nobody"
+    );
+    assert_eq!(
+        substitute_templates("timeout: {{user.TIMEOUT}} {{TIMEOUT}}", &vars),
+        "timeout: 5 5"
+    );
+    let args: HashMap<_, _> = (0..12)
+        .map(|index| (format!("ARG{}", index), format!("arg{}", index)))
+        .collect();
+    assert_eq!(
+        substitute_vars("$ARG1 $ARG10 $ARG11", &args),
+        "arg1 arg10 arg11"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_quote_args() {
+    let args: Vec<String> = ["--exact", "my case", "a\"b", "", "x=$y"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    assert_eq!(quote_args(&args), r#"--exact "my case" "a\"b" "" "x=\$y""#);
+    assert!(is_builtin_var("ARG12"));
+    assert!(!is_builtin_var("ARG"));
+    assert!(!is_builtin_var("ARGUMENT"));
+    assert!(!is_builtin_var("ARCH"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_arch_from_uefi_target() {
+    assert_eq!(
+        Arch::from_uefi_target("i686-unknown-uefi"),
+        Some(Arch::Ia32)
+    );
+    assert_eq!(Arch::from_uefi_target("x86_64-unknown-uefi"), None);
+    assert_eq!(Arch::from_uefi_target("i686-unknown-linux-gnu"), None);
+    assert_eq!(Arch::Ia32.uefi_boot_file(), "BOOTIA32.EFI");
+}
+
+#[cfg(test)]
+#[test]
+fn test_coverage_markers() {
+    let coverage: CoverageConfig = serde_json::from_str(r#"{"begin": "<<COV"}"#).unwrap();
+    assert_eq!(coverage.begin, "<<COV");
+    assert_eq!(coverage.end, def_coverage_end());
+    assert!(serde_json::from_str::<CoverageConfig>(r#"{"end": ""}"#).is_err());
+}
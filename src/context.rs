@@ -0,0 +1,2755 @@
+#[cfg(feature = "runner")]
+use regex::bytes::Regex;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+#[cfg(feature = "runner")]
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "runner")]
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+#[cfg(feature = "runner")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "runner")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "runner")]
+use crate::boot_snapshot::BootSnapshot;
+use crate::bootloader::{self, Bootloader, FileEntry};
+#[cfg(feature = "runner")]
+use crate::cases::{CaseEvent, CaseMatcher, CaseResults};
+use crate::config::{
+    Accel, Arch, BootType, BootloaderKind, ImageFormat, ImageRunnerConfig, MessageFormat,
+    PortForward, RecordReplayConfig, RecordReplayMode, is_builtin_var, quote_args, substitute_vars,
+};
+#[cfg(feature = "runner")]
+use crate::config::{RequirementPolicy, ShareDriver, ShutdownMethod};
+#[cfg(feature = "runner")]
+use crate::convert::{self, ConvertFormat};
+#[cfg(feature = "runner")]
+use crate::coverage;
+#[cfg(feature = "runner")]
+use crate::debug;
+use crate::diagnostics::Diagnostics;
+#[cfg(feature = "runner")]
+use crate::drive;
+#[cfg(feature = "runner")]
+use crate::host_action::{HostAction, HostActionRunner};
+use crate::iso::{StageFiles, overlay_entries, prepare_iso, stage_files};
+#[cfg(feature = "runner")]
+use crate::logfile::LogWriter;
+use crate::manifest::{BuildInfo, ManifestOptions, Provenance};
+use crate::message::Message;
+#[cfg(feature = "runner")]
+use crate::outcome::{self, CapturedOutput, ChunkTime, FailureKind, RunOutcome, ShutdownStage};
+use crate::payload::{artifact_dependencies, build_artifact, build_payload};
+#[cfg(feature = "runner")]
+use crate::qemu;
+#[cfg(feature = "runner")]
+use crate::qmp;
+use crate::requirements::{self, Requirement};
+use crate::sbom::{Component, Sbom};
+use crate::scrub::{ScrubChain, Scrubber};
+#[cfg(feature = "runner")]
+use crate::serial::{
+    ExpectStep, InputOptions, LineWait, OutputWatcher, Responder, SerialSocket, SharedWriter,
+    feed_input, respond, run_expect, strip_serial_stdio,
+};
+#[cfg(feature = "runner")]
+use crate::share;
+#[cfg(feature = "runner")]
+use crate::snapshot::{self, SnapshotOutcome};
+use crate::staging;
+#[cfg(feature = "runner")]
+use crate::stream::RunEvents;
+
+/// A typed map used to pass data between the stages of the pipeline
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any>>,
+}
+
+impl Extensions {
+    /// Inserts a value, returning the previous value of the same type
+    pub fn insert<T: 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_extensions() {
+    let mut extensions = Extensions::default();
+    assert_eq!(extensions.insert(1u32), None);
+    assert_eq!(extensions.insert("str"), None);
+    assert_eq!(extensions.insert(2u32), Some(1));
+    *extensions.get_mut::<u32>().unwrap() += 1;
+    assert_eq!(extensions.get::<u32>(), Some(&3));
+    assert_eq!(extensions.remove::<&str>(), Some("str"));
+    assert_eq!(extensions.get::<&str>(), None);
+}
+
+/// Builder for a [`Context`], created with [`Context::builder`]
+pub struct ContextBuilder {
+    config: ImageRunnerConfig,
+    target_src: PathBuf,
+    root_dir: PathBuf,
+    cache_dir: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
+    target_dir: Option<PathBuf>,
+    is_test: Option<bool>,
+    test_filter: Vec<String>,
+    manifest_path: Option<PathBuf>,
+    bootloader: Option<Box<dyn Bootloader>>,
+    prebuilt_image: bool,
+    scrubbers: ScrubChain,
+    warnings: Vec<(&'static str, String)>,
+    extensions: Extensions,
+}
+
+impl ContextBuilder {
+    /// Sets the directory bootloader files are downloaded to, defaults to `image-runner` in the
+    /// target directory
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the directory images are built in, defaults to `image-runner` in the target
+    /// directory
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the target directory of cargo, e.g. from `cargo metadata`, which the cache, the images
+    /// and the OVMF firmware go in, defaults to `target` in the workspace root
+    pub fn target_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.target_dir = Some(dir.into());
+        self
+    }
+
+    /// Overrides whether the executable is a test, which is otherwise detected from its name
+    pub fn is_test(mut self, is_test: bool) -> Self {
+        self.is_test = Some(is_test);
+        self
+    }
+
+    /// Sets the arguments cargo passes to a test, such as a filter and `--exact`, which are
+    /// available as the `TEST_FILTER` variable, see [`crate::config::TestConfig`]
+    pub fn test_filter(mut self, args: Vec<String>) -> Self {
+        self.test_filter = args;
+        self
+    }
+
+    /// Starts QEMU with a GDB server, see [`crate::config::DebugConfig`]
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.config.debug.enabled = debug;
+        self
+    }
+
+    /// Records the execution of the guest to `file`, relative to the workspace root, so a failing
+    /// run can be replayed, see [`crate::config::RecordReplayConfig`]
+    pub fn record(mut self, file: impl Into<String>) -> Self {
+        self.config.record_replay = Some(RecordReplayConfig {
+            mode: RecordReplayMode::Record,
+            file: file.into(),
+        });
+        self
+    }
+
+    /// Replays the execution recorded to `file` with [`Self::record`]
+    pub fn replay(mut self, file: impl Into<String>) -> Self {
+        self.config.record_replay = Some(RecordReplayConfig {
+            mode: RecordReplayMode::Replay,
+            file: file.into(),
+        });
+        self
+    }
+
+    /// Sets a variable, overriding the one from the config
+    pub fn var(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config.vars.insert(key.into(), value.into());
+        self
+    }
+
+    /// Forwards the TCP port `host` to the port `guest` of the guest, e.g. so a test can connect
+    /// to a server in it, see [`crate::config::NetworkConfig`]
+    pub fn port_forward(mut self, host: u16, guest: u16) -> Self {
+        let network = self.config.network.get_or_insert_with(Default::default);
+        network.forwards.push(PortForward {
+            protocol: "tcp".to_string(),
+            host,
+            guest,
+        });
+        self
+    }
+
+    /// Sets the manifest of the package, which is used to find its artifact dependencies
+    pub fn manifest_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.manifest_path = Some(path.into());
+        self
+    }
+
+    /// Sets the bootloader to use, defaults to limine
+    pub fn bootloader(mut self, bootloader: impl Bootloader + 'static) -> Self {
+        self.bootloader = Some(Box::new(bootloader));
+        self
+    }
+
+    /// Boots the executable path as an image that was built elsewhere, such as a third-party test
+    /// suite, so only the run is handled
+    pub fn prebuilt_image(mut self) -> Self {
+        self.prebuilt_image = true;
+        self
+    }
+
+    /// Adds a scrubber, applied to the output after those of the `scrub` config, see
+    /// [`Context::scrubbers`]
+    pub fn scrubber(mut self, scrubber: impl Scrubber + 'static) -> Self {
+        self.scrubbers.push(scrubber);
+        self
+    }
+
+    /// Adds a warning found before the context is built, e.g. about an override that is ignored,
+    /// which `deny-warnings` applies to like the others
+    pub fn warning(mut self, code: &'static str, message: impl Into<String>) -> Self {
+        self.warnings.push((code, message.into()));
+        self
+    }
+
+    /// Inserts custom data into the [`Extensions`] of the context
+    pub fn extension<T: 'static>(mut self, value: T) -> Self {
+        self.extensions.insert(value);
+        self
+    }
+
+    /// Builds the context, returning an error if the config can't be used, e.g. BIOS booting on
+    /// another architecture than x86_64
+    pub fn build(self) -> Result<Context, String> {
+        let mut config = self.config;
+        let root_dir = self.root_dir;
+        // The warnings of the config are collected too, so `deny-warnings` applies to them
+        let diagnostics = Diagnostics::default();
+        for (code, message) in self.warnings {
+            diagnostics.warn(code, message);
+        }
+
+        let target_src = root_dir.join(self.target_src);
+
+        let mut target_name = Path::new(&target_src)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap();
+
+        let mut is_test = false;
+        if let Some(name) = test_executable_name(target_name) {
+            target_name = name;
+            is_test = true;
+        }
+        let is_test = self.is_test.unwrap_or(is_test);
+        // The overrides may already be applied before the ones of the environment and the
+        // command line, which then win
+        if is_test {
+            config.apply_test_overrides()?;
+        }
+
+        // 32-bit UEFI applications can only be booted by 32-bit firmware
+        if config.arch == Arch::X86_64
+            && let Some(arch) = build_target(&target_src).and_then(|t| Arch::from_uefi_target(&t))
+        {
+            config.arch = arch;
+            config.boot_type = BootType::Uefi;
+        }
+        // U-Boot is the firmware itself, and QEMU can boot kernels directly without one, so the
+        // boot type doesn't apply to them
+        let needs_firmware = self.prebuilt_image
+            || (config.bootloader != BootloaderKind::UBoot && !config.direct_kernel);
+        if needs_firmware && config.arch != Arch::X86_64 && config.boot_type == BootType::Bios {
+            return Err(
+                "BIOS boot type is only supported on x86_64, use boot-type = \"uefi\"".to_string(),
+            );
+        }
+        // QEMU only loads Multiboot kernels itself with BIOS booting, and only those of the first
+        // version of the specification, those with only a Multiboot2 header are booted by limine
+        // from an image
+        let multiboot2 = config.bootloader == BootloaderKind::Multiboot
+            && !config.direct_kernel
+            && !self.prebuilt_image
+            && bootloader::kernel_multiboot_version(&target_src) == Some(2);
+        if multiboot2 {
+            config.image_format.get_or_insert(ImageFormat::Iso);
+        }
+        if config.bootloader == BootloaderKind::Multiboot
+            && (config.arch != Arch::X86_64 || (!multiboot2 && config.boot_type != BootType::Bios))
+        {
+            return Err(
+                "Multiboot kernels can only be booted on x86_64 with boot-type = \"bios\", \
+                 or also \"uefi\" if they only have a Multiboot2 header"
+                    .to_string(),
+            );
+        }
+        // Typed devices are turned into arguments, so they are handled like the others from here
+        for (index, device) in config.devices.iter_mut().enumerate() {
+            if let Some(kind) = &device.device {
+                let mut args = kind.qemu_args(&format!("device{}", index));
+                args.append(&mut device.args);
+                device.args = args;
+            }
+        }
+        if config.kvm {
+            diagnostics.warn(
+                "kvm-deprecated",
+                "`kvm = true` is deprecated, use `accel = \"kvm\"` instead",
+            );
+            config.accel.get_or_insert(Accel::Kvm);
+        }
+        if config.run_command.is_empty() {
+            // Kernels QEMU loads directly have no image to attach, and prebuilt images that
+            // aren't ISOs are disk images
+            let is_iso = target_src.extension().is_some_and(|ext| ext == "iso");
+            config.run_command = if self.prebuilt_image && !is_iso {
+                let mut command = config.arch.default_direct_run_command();
+                command.extend(["-drive".to_string(), "format=raw,file={}".to_string()]);
+                command
+            } else if !self.prebuilt_image
+                && (config.direct_kernel
+                    || (config.bootloader == BootloaderKind::Multiboot && !multiboot2))
+            {
+                config.arch.default_direct_run_command()
+            } else if !self.prebuilt_image && config.image_format() == ImageFormat::Directory {
+                config.arch.default_directory_run_command()
+            } else if config.record_replay.is_some() {
+                config.arch.replay_run_command()
+            } else {
+                config.arch.default_run_command()
+            };
+        }
+
+        #[cfg(not(feature = "bios"))]
+        if needs_firmware && config.boot_type == BootType::Bios {
+            return Err(
+                "BIOS boot type is not supported, enable the `bios` feature for this crate"
+                    .to_string(),
+            );
+        }
+        #[cfg(not(feature = "uefi"))]
+        if needs_firmware && config.boot_type == BootType::Uefi {
+            return Err(
+                "UEFI boot type is not supported, enable the `uefi` feature for this crate"
+                    .to_string(),
+            );
+        }
+
+        let target_dir = self.target_dir.unwrap_or_else(|| root_dir.join("target"));
+        let cache_dir = self
+            .cache_dir
+            .unwrap_or_else(|| target_dir.join("image-runner"));
+        let output_dir = self
+            .output_dir
+            .unwrap_or_else(|| target_dir.join("image-runner"));
+
+        if !self.test_filter.is_empty() && !is_test {
+            return Err(format!(
+                "{} is not a test, so it doesn't take the arguments {}",
+                target_src.display(),
+                quote_args(&self.test_filter)
+            ));
+        }
+        // Built-in variables win, so a variable of the same name is warned about, and moved to
+        // `user.<name>` where it can still be referred to
+        let mut shadowed: Vec<String> = config
+            .vars
+            .keys()
+            .filter(|name| is_builtin_var(name))
+            .cloned()
+            .collect();
+        shadowed.sort();
+        for name in shadowed {
+            diagnostics.warn(
+                "variable-shadowed",
+                format!(
+                    "the variable {} is shadowed by the built-in one, use user.{} to refer to it",
+                    name, name
+                ),
+            );
+            let value = config.vars.remove(&name).unwrap();
+            config.vars.insert(format!("user.{}", name), value);
+        }
+
+        // The filter is passed to the kernel through its command line
+        let test_filter = self.test_filter.join(" ");
+        if let (false, Some(template)) = (test_filter.is_empty(), &config.test.filter_arg_template)
+        {
+            let arg = template.replace("$TEST_FILTER", &test_filter);
+            if !config.cmdline.is_empty() {
+                config.cmdline.push(' ');
+            }
+            config.cmdline.push_str(&arg);
+        }
+        config.vars.insert("TEST_FILTER".to_string(), test_filter);
+        // The arguments are also available quoted, as JSON and one by one, for those that contain
+        // spaces
+        config
+            .vars
+            .insert("ARGS".to_string(), quote_args(&self.test_filter));
+        config.vars.insert(
+            "ARGS_JSON".to_string(),
+            serde_json::to_string(&self.test_filter).unwrap(),
+        );
+        for (index, arg) in self.test_filter.iter().enumerate() {
+            config.vars.insert(format!("ARG{}", index), arg.clone());
+        }
+        // The mount tags, e.g. to pass them to the kernel in the cmdline
+        for (index, share) in config.shares.iter().enumerate() {
+            config
+                .vars
+                .insert(format!("SHARE{}", index), share.tag(index));
+        }
+
+        let target_dst = root_dir.join(target_name);
+
+        let config_path = root_dir.join(config.config_file.as_str());
+
+        let mut scrubbers = ScrubChain::from_config(&config.scrub)?;
+        scrubbers.append(&self.scrubbers);
+
+        let bootloader = match self.bootloader {
+            _ if self.prebuilt_image => Box::new(bootloader::PrebuiltImage),
+            Some(bootloader) => bootloader,
+            None => bootloader::from_config(&config),
+        };
+
+        Ok(Context {
+            config,
+            target_src,
+            target_dst,
+            root_dir,
+            cache_dir,
+            output_dir,
+            target_dir,
+            config_path,
+            is_test,
+            manifest_path: self.manifest_path,
+            bootloader,
+            prebuilt_image: self.prebuilt_image,
+            scrubbers,
+            diagnostics,
+            #[cfg(feature = "runner")]
+            provenance_path: None,
+            extensions: self.extensions,
+        })
+    }
+
+    /// Builds the context and then only the image, without running it, returning its path
+    pub fn build_image_only(self) -> Result<PathBuf, NotRun> {
+        let mut ctx = self.build().map_err(NotRun::Failed)?;
+        ctx.prepare_bootloader()?;
+        Ok(ctx.prepare_image())
+    }
+}
+
+/// Why the image isn't run, the runner exits with [`NotRun::exit_code`] then
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotRun {
+    /// The requirements of the test that aren't met, it is skipped following `test.policy`
+    Skipped(Vec<String>),
+    /// The run can't start, e.g. because a device that isn't optional can't be added
+    Failed(String),
+}
+
+impl NotRun {
+    /// A skipped test still passes, as cargo only sees the exit code
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Skipped(_) => 0,
+            Self::Failed(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for NotRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Skipped(unmet) => write!(f, "the test was skipped: {}", unmet.join(", ")),
+            Self::Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// The state of a single run of the image runner
+pub struct Context {
+    config: ImageRunnerConfig,
+    target_src: PathBuf,
+    target_dst: PathBuf,
+    root_dir: PathBuf,
+    cache_dir: PathBuf,
+    output_dir: PathBuf,
+    target_dir: PathBuf,
+    config_path: PathBuf,
+    is_test: bool,
+    manifest_path: Option<PathBuf>,
+    bootloader: Box<dyn Bootloader>,
+    prebuilt_image: bool,
+    scrubbers: ScrubChain,
+    diagnostics: Diagnostics,
+    /// The provenance file of the built image, which gets the QEMU version once it is run
+    #[cfg(feature = "runner")]
+    provenance_path: Option<PathBuf>,
+    /// Custom data shared between the stages of the pipeline
+    pub extensions: Extensions,
+}
+
+impl Context {
+    pub fn builder(
+        config: ImageRunnerConfig,
+        target_src: PathBuf,
+        root_dir: PathBuf,
+    ) -> ContextBuilder {
+        ContextBuilder {
+            config,
+            target_src,
+            root_dir,
+            cache_dir: None,
+            output_dir: None,
+            target_dir: None,
+            is_test: None,
+            test_filter: Vec::new(),
+            manifest_path: None,
+            bootloader: None,
+            prebuilt_image: false,
+            scrubbers: ScrubChain::default(),
+            warnings: Vec::new(),
+            extensions: Extensions::default(),
+        }
+    }
+
+    pub fn is_test(&self) -> bool {
+        self.is_test
+    }
+
+    /// The root of the workspace, which relative paths in the config are relative to
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    pub fn output_dir(&self) -> &Path {
+        &self.output_dir
+    }
+
+    /// The target directory of cargo
+    pub fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    pub fn config(&self) -> &ImageRunnerConfig {
+        &self.config
+    }
+
+    /// The path of the executable that is being run
+    pub fn executable_path(&self) -> &Path {
+        &self.target_src
+    }
+
+    /// The name of the executable, without the hash cargo adds to test executables
+    pub fn executable_name(&self) -> &OsStr {
+        self.target_dst.file_name().unwrap()
+    }
+
+    /// The scrubbers applied to the output before it is compared, written to a log or reported
+    pub fn scrubbers(&self) -> &ScrubChain {
+        &self.scrubbers
+    }
+
+    /// The warnings emitted during this run
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+    /// What the bootloader and the firmware need from the host
+    pub fn requirements(&self) -> Vec<Requirement> {
+        let bootloader = self.bootloader.requirements();
+        // Without the runner, the image is only built, so no firmware is needed
+        #[cfg(feature = "runner")]
+        let bootloader = [
+            bootloader,
+            requirements::firmware_requirements(&self.config, &self.root_dir),
+        ]
+        .concat();
+        bootloader
+    }
+
+    /// Checks the requirements of the bootloader and the firmware, and returns all that aren't
+    /// met at once, so that everything missing can be reported before anything is fetched
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let qemu_binary = self.config.run_command.first().map_or("", String::as_str);
+        requirements::validate(&self.requirements(), qemu_binary)
+    }
+
+    /// Fetches and builds the bootloader, runs of other executables wait for it as the cache is
+    /// shared
+    ///
+    /// The requirements of tests are checked first, so nothing is built for a skipped test.
+    pub fn prepare_bootloader(&self) -> Result<(), NotRun> {
+        #[cfg(feature = "runner")]
+        if self.is_test {
+            self.check_requirements()?;
+        }
+        let _lock = lock(&self.cache_dir.join(".lock"));
+        self.bootloader.prepare(self);
+        Ok(())
+    }
+
+    /// The staging directory and the ISO path of the image of this executable
+    ///
+    /// Every executable has its own, so concurrent runs of different executables, such as
+    /// integration tests, don't clobber each other's images.
+    fn image_paths(&self) -> (PathBuf, PathBuf) {
+        if self.is_test {
+            // The names of test executables are unique, as they include a hash
+            let target_name = self.target_src.to_string_lossy();
+            let target_name = target_name.rsplit_once('/').unwrap().1;
+            let tests_dir = self.output_dir.join("tests");
+            let iso_path = tests_dir.join(format!("{}.iso", target_name));
+            let iso_dir = tests_dir.join(format!("{}_isoroot", target_name));
+            (iso_dir, iso_path)
+        } else {
+            let mut hasher = DefaultHasher::new();
+            self.target_src.hash(&mut hasher);
+            let image_dir = self.output_dir.join(format!(
+                "{}-{:016x}",
+                self.executable_name().to_string_lossy(),
+                hasher.finish()
+            ));
+            (image_dir.join("iso_root"), image_dir.join("image.iso"))
+        }
+    }
+
+    /// A directory for the files generated for the image of this executable, such as bootloader
+    /// configs, which isn't shared with other executables
+    pub fn scratch_dir(&self) -> PathBuf {
+        self.image_paths().0.with_extension("generated")
+    }
+
+    /// The target the executable was built for, derived from its path in the target directory
+    fn build_target(&self) -> Option<String> {
+        build_target(&self.target_src)
+    }
+
+    fn is_release(&self) -> bool {
+        self.target_src
+            .components()
+            .any(|c| c.as_os_str() == "release")
+    }
+
+    /// The build metadata recorded in the manifest
+    fn build_info(&self) -> BuildInfo {
+        BuildInfo {
+            runner: env!("CARGO_PKG_VERSION").to_string(),
+            executable: self.executable_name().to_string_lossy().into_owned(),
+            target: self.build_target(),
+            profile: if self.is_release() {
+                "release"
+            } else {
+                "debug"
+            }
+            .to_string(),
+            arch: serde_plain::to_string(&self.config.arch).unwrap(),
+            bootloader: serde_plain::to_string(&self.config.bootloader).unwrap(),
+            cmdline: self.config.cmdline.clone(),
+        }
+    }
+
+    /// Records what the image is built from the `sources` of its files, and the versions of the
+    /// tools
+    fn provenance(&self, sources: &[PathBuf]) -> Provenance {
+        // Maps are sorted in a JSON value, so the hash doesn't depend on their order
+        let config = serde_json::to_value(&self.config).unwrap().to_string();
+        let mut provenance =
+            Provenance::generate(self.build_info(), &config, &self.root_dir, sources);
+        provenance.bootloader_version = self.bootloader.version();
+        provenance.ovmf_release = self.ovmf_release();
+        provenance
+    }
+
+    /// The release of the prebuilt OVMF firmware the image is run with, if it is used
+    fn ovmf_release(&self) -> Option<String> {
+        #[cfg(feature = "runner")]
+        if cfg!(feature = "uefi")
+            && self.config.boot_type == BootType::Uefi
+            && self.config.ovmf.code.is_none()
+            && self.bootloader.firmware(self).is_none()
+        {
+            return Some(ovmf_prebuilt::Source::LATEST.tag.to_string());
+        }
+        None
+    }
+
+    /// Lists the third-party components of the image, those of the bootloader and the firmware
+    fn sbom(&self) -> Sbom {
+        let mut components = self.bootloader.components(self);
+        let uses_ovmf = self.config.boot_type == BootType::Uefi
+            && self.bootloader.firmware(self).is_none()
+            && self.bootloader.direct_boot_args(self).is_none();
+        if uses_ovmf {
+            let mut ovmf = Component::new("edk2-ovmf", "BSD-2-Clause-Patent");
+            ovmf.version = self.ovmf_release();
+            // A configured firmware can be hashed, the prebuilt one is only fetched to run
+            ovmf.files = self
+                .config
+                .ovmf
+                .code
+                .iter()
+                .map(|code| self.root_dir.join(code))
+                .collect();
+            ovmf.shipped = false;
+            components.push(ovmf);
+        }
+        Sbom::generate(&self.executable_name().to_string_lossy(), &components)
+    }
+
+    /// Builds the artifact dependencies of the package, setting a variable with the path of each,
+    /// and returns the ones configured in `artifact-files`
+    fn build_artifact_dependencies(&mut self) -> Vec<FileEntry> {
+        let Some(manifest_path) = &self.manifest_path else {
+            return Vec::new();
+        };
+        let dependencies = artifact_dependencies(manifest_path, self.build_target().as_deref())
+            .unwrap_or_else(|err| panic!("{}", err));
+        let release = self.is_release();
+
+        let mut files = Vec::new();
+        for dep in dependencies {
+            let path = build_artifact(
+                &self.root_dir,
+                &dep.package,
+                dep.name.as_deref(),
+                &dep.kind,
+                dep.target.as_deref(),
+                release,
+            );
+            if let Some(dest) = self.config.artifact_files.get(&dep.var) {
+                files.push(FileEntry::new(&path, dest));
+            }
+            self.config
+                .vars
+                .insert(dep.var, path.to_string_lossy().into_owned());
+        }
+        files
+    }
+
+    /// Prints `message` if JSON messages are enabled
+    fn message(&self, message: Message) {
+        if self.config.message_format == MessageFormat::Json {
+            message.emit();
+        }
+    }
+
+    /// Stages the files and builds the image in the configured format, returning its path
+    pub fn prepare_image(&mut self) -> PathBuf {
+        self.message(Message::BuildStarted {
+            executable: &self.target_src,
+            test: self.is_test,
+        });
+        let image = self.build_image();
+        self.message(Message::ImageBuilt { image: &image });
+        image
+    }
+
+    fn build_image(&mut self) -> PathBuf {
+        if self.prebuilt_image {
+            let image = self.target_src.clone();
+            self.substitute_args(&image);
+            return image;
+        }
+        // QEMU loads the kernel itself, but artifact dependencies can still be passed to it
+        if self.bootloader.direct_boot_args(self).is_some() {
+            self.build_artifact_dependencies();
+            let kernel = self.target_src.clone();
+            self.substitute_args(&kernel);
+            return kernel;
+        }
+
+        let (iso_dir, iso_path) = self.image_paths();
+        // Another run of the same executable would stage into the same directory
+        let _lock = lock(&iso_path.with_extension("lock"));
+        // On slow storage the image is staged in memory, and only the finished image is copied
+        let final_iso_path = iso_path.clone();
+        let fast_dir = staging::fast_staging_dir(self.config.fast_staging, &self.output_dir);
+        let (iso_dir, iso_path) = match &fast_dir {
+            Some(fast_dir) => (
+                fast_dir.join(iso_dir.strip_prefix(&self.output_dir).unwrap()),
+                fast_dir.join(iso_path.strip_prefix(&self.output_dir).unwrap()),
+            ),
+            None => (iso_dir, iso_path),
+        };
+
+        // Files of a previous bootloader version would linger in the staging directory, so it
+        // is staged from scratch when the version changes
+        let version_path = iso_dir.with_extension("bootloader");
+        let version = self.bootloader.version();
+        let old_version = std::fs::read_to_string(&version_path).ok();
+        if version.is_some() && old_version != version {
+            std::fs::remove_dir_all(&iso_dir).ok();
+        }
+
+        let mut payloads: Vec<FileEntry> = self
+            .config
+            .payloads
+            .iter()
+            .map(|payload| FileEntry::new(build_payload(&self.root_dir, payload), &payload.dest))
+            .collect();
+        payloads.extend(self.build_artifact_dependencies());
+        let mut overrides = Vec::new();
+        payloads.extend(overlay_entries(
+            &self.root_dir,
+            &self.config.overlays,
+            &mut overrides,
+        ));
+        for (dest, earlier, later) in overrides {
+            self.diagnostics.warn(
+                "overlay-override",
+                format!(
+                    "{} from overlay {} overrides the one from {}",
+                    dest.display(),
+                    later,
+                    earlier
+                ),
+            );
+        }
+        let bootloader_files = self.bootloader.files(self);
+        let config_file = self.bootloader.uses_config_file().then(|| {
+            let config_rel_path = self.config_path.strip_prefix(&self.root_dir).unwrap();
+            FileEntry::new(&self.config_path, config_rel_path)
+        });
+        let mut sources = vec![self.target_src.clone()];
+        sources.extend(config_file.iter().map(|file| file.source.clone()));
+        sources.extend(
+            self.config
+                .extra_files
+                .iter()
+                .map(|file| self.root_dir.join(file)),
+        );
+        sources.extend(payloads.iter().map(|file| file.source.clone()));
+        sources.extend(
+            bootloader_files
+                .files
+                .iter()
+                .map(|file| file.source.clone()),
+        );
+        let provenance = self.provenance(&sources);
+        let manifest = self
+            .config
+            .manifest
+            .as_ref()
+            .map(|manifest| ManifestOptions {
+                dest: PathBuf::from(&manifest.dest),
+                build: self.build_info(),
+            });
+        let files = StageFiles {
+            root_dir: &self.root_dir,
+            kernel: FileEntry::new(&self.target_src, self.bootloader.executable_dest(self)),
+            config_file,
+            extra_files: &self.config.extra_files,
+            extra_entries: &payloads,
+            bootloader_files: &bootloader_files,
+            cmdline: &self.config.cmdline,
+            vars: &self.config.vars,
+            manifest: manifest.as_ref(),
+            verbose: self.config.verbose,
+        };
+        let (rebuilt, image_path) = match self.config.image_format() {
+            ImageFormat::Iso => {
+                let rebuilt = prepare_iso(
+                    &iso_dir,
+                    &iso_path,
+                    &files,
+                    &self.config.iso,
+                    &self.diagnostics,
+                );
+                (rebuilt, iso_path)
+            }
+            // The staged directory is the image
+            ImageFormat::Directory => {
+                let rebuilt = stage_files(&iso_dir, &files, &self.diagnostics);
+                (rebuilt, iso_dir.clone())
+            }
+        };
+        if rebuilt {
+            self.bootloader.post_image(self, &image_path);
+        }
+        // A staged directory is used in place, as copying it back would defeat the purpose
+        let image_path = match (&fast_dir, self.config.image_format()) {
+            (Some(_), ImageFormat::Iso) => {
+                if rebuilt || !final_iso_path.exists() {
+                    std::fs::create_dir_all(final_iso_path.parent().unwrap()).unwrap();
+                    std::fs::copy(&image_path, &final_iso_path).expect("failed to copy the image");
+                }
+                final_iso_path
+            }
+            _ => image_path,
+        };
+        // The manifest is also written next to the image, for verifying it from the outside
+        if let Some(manifest) = &manifest {
+            std::fs::copy(
+                iso_dir.join(&manifest.dest),
+                image_path.with_extension("manifest.json"),
+            )
+            .expect("failed to copy the manifest");
+        }
+        let provenance_path = image_path.with_extension("provenance.json");
+        std::fs::write(&provenance_path, provenance.to_json())
+            .expect("failed to write the provenance file");
+        #[cfg(feature = "runner")]
+        {
+            self.provenance_path = Some(provenance_path);
+        }
+        if self.config.sbom {
+            std::fs::write(
+                image_path.with_extension("sbom.json"),
+                self.sbom().to_json(),
+            )
+            .expect("failed to write the SBOM");
+        }
+        if let Some(version) = version {
+            std::fs::write(&version_path, version).expect("failed to write the bootloader version");
+        }
+        self.substitute_args(&image_path);
+        image_path
+    }
+
+    /// Substitutes the image path and the variables into the arguments of QEMU
+    fn substitute_args(&mut self, image_path: &Path) {
+        let vars = &self.config.vars;
+        for arg in self.config.run_command.iter_mut() {
+            *arg = substitute_vars(&arg.replace("{}", &image_path.to_string_lossy()), vars);
+        }
+        for arg in self
+            .config
+            .devices
+            .iter_mut()
+            .flat_map(|device| device.args.iter_mut())
+        {
+            *arg = substitute_vars(arg, vars);
+        }
+        for drive in self.config.drives.iter_mut() {
+            drive.path = substitute_vars(&drive.path, vars);
+        }
+        for share in self.config.shares.iter_mut() {
+            share.path = substitute_vars(&share.path, vars);
+        }
+        for arg in self.config.run_args.iter_mut() {
+            *arg = substitute_vars(arg, vars);
+        }
+
+        for arg in self.config.test_args.iter_mut() {
+            *arg = substitute_vars(arg, vars);
+        }
+    }
+
+    /// The arguments of the configured devices, optional devices whose requirements aren't met
+    /// are skipped with a warning, and the run fails for the others
+    #[cfg(feature = "runner")]
+    fn device_args(&self) -> Result<Vec<String>, String> {
+        let qemu_binary = self.config.run_command.first().map_or("", String::as_str);
+        let mut args = Vec::new();
+        for device in self.config.devices.iter() {
+            let device_requirements: Vec<Requirement> = device
+                .requires
+                .iter()
+                .map(|requirement| {
+                    Requirement::parse(requirement).unwrap_or_else(|err| {
+                        panic!("invalid requirement of device {}: {}", device.name, err)
+                    })
+                })
+                .collect();
+            let Err(unmet) = requirements::validate(&device_requirements, qemu_binary) else {
+                args.extend(device.args.iter().cloned());
+                continue;
+            };
+            if device.optional {
+                self.diagnostics.warn(
+                    "optional-device-skipped",
+                    format!("device {} was skipped: {}", device.name, unmet.join(", ")),
+                );
+            } else {
+                return Err(format!(
+                    "device {} can't be added: {}",
+                    device.name,
+                    unmet.join(", ")
+                ));
+            }
+        }
+        Ok(args)
+    }
+
+    /// Checks the requirements of the tests, the test isn't run if any of them aren't met
+    #[cfg(feature = "runner")]
+    fn check_requirements(&self) -> Result<(), NotRun> {
+        let qemu_binary = self
+            .config
+            .run_command
+            .first()
+            .expect("no run command provided");
+        let test_requirements: Vec<Requirement> = self
+            .config
+            .test
+            .require
+            .iter()
+            .map(|requirement| {
+                Requirement::parse(requirement).unwrap_or_else(|err| panic!("{}", err))
+            })
+            .collect();
+        let Err(unmet) = requirements::validate(&test_requirements, qemu_binary) else {
+            return Ok(());
+        };
+
+        match self.config.test.policy {
+            RequirementPolicy::Skip => {
+                // Cargo only sees the exit code, which would fail the other tests if it wasn't 0,
+                // so the test is reported as ignored like libtest does
+                if self.config.message_format == MessageFormat::Human {
+                    println!(
+                        "test {} ... ignored, {}",
+                        self.target_dst.file_name().unwrap().to_string_lossy(),
+                        unmet.join(", ")
+                    );
+                }
+                self.message(Message::TestSkipped { unmet: &unmet });
+                Err(NotRun::Skipped(unmet))
+            }
+            RequirementPolicy::Fail => Err(NotRun::Failed(format!(
+                "test requirements not met: {}",
+                unmet.join(", ")
+            ))),
+        }
+    }
+
+    /// Runs the image, returning the exit code the runner exits with, 0 if the run succeeded
+    #[cfg(feature = "runner")]
+    pub fn run(self) -> i32 {
+        let result = match self.execute(false) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                return 1;
+            }
+        };
+        if let Some(kind) = result.failure {
+            let success_code = if self.is_test {
+                eprintln!("error: the test failed: {}", kind);
+                self.config.test_success_exit_code as i32
+            } else {
+                // Other failures of runs are only told by the exit code, as QEMU exits with it
+                if matches!(
+                    kind,
+                    FailureKind::FailurePattern
+                        | FailureKind::NoSuccessPattern
+                        | FailureKind::AbortPattern(_)
+                ) {
+                    eprintln!("error: the run failed: {}", kind);
+                }
+                0
+            };
+            // The guest may have exited with the success code, e.g. when only the snapshot
+            // differs, but the runner still has to fail
+            return match result.exit_code {
+                Some(code) if code != success_code => code,
+                _ => 1,
+            };
+        }
+        0
+    }
+
+    /// Runs the image like [`Context::run`], but returns how the run ended instead of the exit
+    /// code, the serial output and what QEMU printed on stderr are always captured
+    #[cfg(feature = "runner")]
+    pub fn run_captured(&self) -> Result<RunOutcome, String> {
+        self.execute(true)
+    }
+
+    /// Records the version of QEMU in the provenance file of the image, QEMU is only probed when
+    /// the image is run, so building an image doesn't need it
+    #[cfg(feature = "runner")]
+    fn record_qemu_version(&self) {
+        let Some(path) = &self.provenance_path else {
+            return;
+        };
+        let qemu_binary = self.config.run_command.first().map_or("", String::as_str);
+        let Some(info) = qemu::probe(qemu_binary) else {
+            return;
+        };
+        let (major, minor, patch) = info.version;
+        let version = format!("{}.{}.{}", major, minor, patch);
+        if let Err(err) = Provenance::record_qemu_version(path, &version) {
+            self.diagnostics.warn("provenance-failed", err);
+        }
+    }
+
+    /// Runs the image, capturing the output if `capture` is set even if nothing needs it
+    #[cfg(feature = "runner")]
+    fn execute(&self, capture: bool) -> Result<RunOutcome, String> {
+        let QemuCommand {
+            command: mut run_command,
+            firmware_log,
+            accelerator,
+            boot_snapshot,
+            ovmf_vars,
+        } = self.qemu_command()?;
+        self.record_qemu_version();
+        for stage in &self.config.boot_stages {
+            if let Some(channel) = &stage.channel
+                && !CapturedOutput::CHANNELS.contains(&channel.as_str())
+            {
+                panic!(
+                    "boot stage {} has an unknown channel {}, expected one of {}",
+                    stage.name,
+                    channel,
+                    CapturedOutput::CHANNELS.join(", ")
+                );
+            }
+        }
+        let serial_socket = self.config.serial_socket.is_some();
+        let input = self.config.stdin.file.as_ref().map(|file| {
+            let path = self.root_dir.join(file);
+            std::fs::read(&path)
+                .unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err))
+        });
+        let input_options = InputOptions {
+            line_delay: self.config.stdin.line_delay.map(Duration::from_millis),
+            prompt: self.config.stdin.prompt.clone(),
+            rate: self.config.stdin.rate,
+            wait_for_echo: self.config.stdin.wait_for_echo,
+            timeout: self.config.stdin.timeout.map(Duration::from_millis),
+        };
+        let expect = self
+            .config
+            .stdin
+            .expect
+            .iter()
+            .map(|step| ExpectStep {
+                wait: step.wait.clone(),
+                send: step.send.clone(),
+                timeout: step.timeout.map(Duration::from_millis),
+            })
+            .collect();
+        let responders: Vec<Responder> = self
+            .config
+            .stdin
+            .responders
+            .iter()
+            .map(|responder| {
+                let regex = Regex::new(&responder.pattern).unwrap_or_else(|err| {
+                    panic!("invalid responder pattern {}: {}", responder.pattern, err)
+                });
+                Responder {
+                    regex,
+                    response: responder.response.clone(),
+                    once: responder.once,
+                }
+            })
+            .collect();
+        // The output of tests is recorded as well, to classify failures, and with JSON messages
+        // the output is moved to stderr
+        let json = self.config.message_format == MessageFormat::Json;
+        let serial_input = SerialInput {
+            input,
+            options: input_options,
+            responders,
+            expect,
+        };
+        if serial_socket && serial_input.needs_stdin() {
+            panic!(
+                "stdin-file, stdin-responders and expect need the serial port on stdio, which serial-socket replaces"
+            );
+        }
+        let watch_output = capture
+            || serial_input.needs_output()
+            || self.is_test
+            || json
+            || self.config.reset_limit.is_some()
+            || !self.config.abort_patterns.is_empty()
+            || self.config.host_actions.is_some()
+            || self.config.coverage.is_some()
+            || self.config.log.is_some()
+            || self.config.run.success_pattern.is_some()
+            || self.config.run.failure_pattern.is_some()
+            || firmware_log.is_some();
+        // The serial shutdown method writes to the serial input of the guest, which is left to
+        // the terminal in interactive runs
+        if serial_input.needs_stdin()
+            || (self.is_test && self.config.test.shutdown.method == ShutdownMethod::Serial)
+        {
+            run_command.stdin(Stdio::piped());
+        }
+        if watch_output {
+            run_command.stdout(Stdio::piped());
+        }
+        if capture || self.is_test || self.config.log.is_some() {
+            run_command.stderr(Stdio::piped());
+        }
+
+        // Flaky tests are run again, only the last attempt is reported
+        let retries = if self.is_test {
+            self.config.test.retries
+        } else {
+            0
+        };
+        let mut retry = 0;
+        let (result, failure) = loop {
+            // A retry starts from the variables the firmware started with, not those the failed
+            // attempt left behind
+            copy_ovmf_vars(ovmf_vars.as_ref());
+            // virtiofsd exits when QEMU disconnects, so each attempt gets its own
+            let virtiofsd = self.start_virtiofsd();
+            let result = self.attempt(
+                &mut run_command,
+                &serial_input,
+                watch_output,
+                firmware_log.as_deref(),
+                boot_snapshot.as_ref().filter(|snapshot| !snapshot.restore),
+            );
+            share::stop_virtiofsd(virtiofsd);
+            let failure = self.failure(&result);
+            match failure {
+                // The debugger is only launched once
+                Some(kind) if retry < retries && !result.debugged => {
+                    retry += 1;
+                    eprintln!(
+                        "warning: the test failed: {}, retrying ({}/{})",
+                        kind, retry, retries
+                    );
+                }
+                _ => break (result, failure),
+            }
+        };
+        self.collect_coverage(result.output.serial());
+
+        self.diagnostics.report(json);
+        // The warnings of the run itself, e.g. a log that couldn't be written, fail it too
+        let failure = failure.or_else(|| {
+            (self.config.deny_warnings && !self.diagnostics.is_empty())
+                .then_some(FailureKind::WarningsDenied)
+        });
+        if let Some(cases) = &result.cases {
+            eprintln!("note: {}", self.scrubbers.scrub(&cases.describe()));
+        }
+        // How far a failed boot got, from the configured stages
+        let stages = &self.config.boot_stages;
+        let stage = (failure.is_some() && !stages.is_empty())
+            .then(|| outcome::reached_stage(stages, &result.output));
+        self.message(Message::RunFinished {
+            test: self.is_test,
+            success: failure.is_none(),
+            exit_code: result.status.code(),
+            failure: failure.as_ref().map(|kind| kind.to_string()),
+            stage: stage.flatten().map(|index| stages[index].name.as_str()),
+            shutdown: result.shutdown.map(|stage| stage.name()),
+            accelerator: accelerator.as_deref(),
+            duration_ms: result.duration.as_millis() as u64,
+            last_output_ms: result
+                .output
+                .last_received(CapturedOutput::SERIAL)
+                .map(|received| received.as_millis() as u64),
+        });
+        if failure.is_some() {
+            report_firmware_log(firmware_log.as_deref(), &result.output);
+            if let Some(reached) = stage {
+                eprintln!("note: {}", outcome::describe_stage(stages, reached));
+            }
+        }
+        Ok(RunOutcome {
+            exit_code: result.status.code(),
+            failure,
+            shutdown: result.shutdown,
+            duration: result.duration,
+            output: result.output,
+        })
+    }
+
+    /// Runs the image, streaming the output and the exit of QEMU as events instead of forwarding
+    /// them, e.g. for a GUI, after [`Context::prepare_image`]
+    ///
+    /// The serial input, the monitors and the test checks are left to the consumer. Receiving
+    /// the events blocks, an async consumer can receive them on a blocking task.
+    #[cfg(feature = "runner")]
+    pub fn run_streaming(self) -> Result<RunEvents, String> {
+        let mut qemu = self.qemu_command()?;
+        copy_ovmf_vars(qemu.ovmf_vars.as_ref());
+        let virtiofsd = self.start_virtiofsd();
+        RunEvents::spawn_with(&mut qemu.command, virtiofsd).map_err(|err| {
+            format!(
+                "failed to run {}: {}",
+                qemu.command.get_program().to_string_lossy(),
+                err
+            )
+        })
+    }
+
+    /// The socket `virtiofsd` listens on for the share at `index`
+    #[cfg(feature = "runner")]
+    fn virtiofs_socket(&self, index: usize) -> PathBuf {
+        self.scratch_dir().join(format!("virtiofs{}.sock", index))
+    }
+
+    /// Starts `virtiofsd` for the virtiofs shares, before each run of QEMU
+    #[cfg(feature = "runner")]
+    fn start_virtiofsd(&self) -> Vec<Child> {
+        let mut children = Vec::new();
+        for (index, share_config) in self.config.shares.iter().enumerate() {
+            if share_config.driver != ShareDriver::Virtiofs {
+                continue;
+            }
+            std::fs::create_dir_all(self.scratch_dir()).unwrap();
+            let path = self.root_dir.join(&share_config.path);
+            match share::start_virtiofsd(&path, share_config, &self.virtiofs_socket(index)) {
+                Ok(child) => children.push(child),
+                Err(err) => {
+                    share::stop_virtiofsd(children);
+                    panic!("{}", err);
+                }
+            }
+        }
+        children
+    }
+
+    /// The QEMU command that runs the image
+    #[cfg(feature = "runner")]
+    fn qemu_command(&self) -> Result<QemuCommand, String> {
+        let device_args = self.device_args()?;
+        self.deny_warnings()?;
+
+        let run_cmd = self
+            .config
+            .run_command
+            .first()
+            .expect("no run command provided");
+        let mut run_command = Command::new(run_cmd);
+        let boot_snapshot = self.boot_snapshot();
+
+        let mut firmware_log = None;
+        let mut ovmf_vars = None;
+        if let Some(firmware) = self.bootloader.firmware(self) {
+            run_command.arg("-bios").arg(firmware);
+        } else if cfg!(feature = "uefi") && self.config.boot_type == BootType::Uefi {
+            let mut prebuilt = None;
+            let mut fetch = |file_type| {
+                let ovmf = prebuilt.get_or_insert_with(|| {
+                    eprintln!("Fetching OVMF firmware...");
+                    let _lock = lock(&self.target_dir.join("ovmf.lock"));
+                    let dir = self.target_dir.join("ovmf");
+                    ovmf_prebuilt::Prebuilt::fetch(ovmf_prebuilt::Source::LATEST, dir).unwrap()
+                });
+                let arch = match self.config.arch {
+                    Arch::X86_64 => ovmf_prebuilt::Arch::X64,
+                    Arch::Aarch64 => ovmf_prebuilt::Arch::Aarch64,
+                    Arch::Riscv64 => ovmf_prebuilt::Arch::Riscv64,
+                    Arch::Ia32 => ovmf_prebuilt::Arch::Ia32,
+                };
+                ovmf.get_file(arch, file_type)
+            };
+            let ovmf_config = &self.config.ovmf;
+            let code = match &ovmf_config.code {
+                Some(code) => self.root_dir.join(code),
+                None => fetch(ovmf_prebuilt::FileType::Code),
+            };
+            let vars = match &ovmf_config.vars {
+                Some(vars) => self.root_dir.join(vars),
+                None => fetch(ovmf_prebuilt::FileType::Vars),
+            };
+            // The variable store is part of the saved state, so it has to support snapshots
+            let (vars, vars_format) = match &boot_snapshot {
+                Some(snapshot) => {
+                    let converted = snapshot.disk.with_file_name("ovmf-vars.qcow2");
+                    if !snapshot.restore || !converted.exists() {
+                        convert::convert_image(&vars, &converted, ConvertFormat::Qcow2)
+                            .unwrap_or_else(|err| panic!("{}", err));
+                    }
+                    (converted, "qcow2")
+                }
+                // QEMU writes to the variable store, so it is copied before every attempt, which
+                // starts each from the same variables and keeps the source intact
+                None => {
+                    let copy = self.scratch_dir().join("ovmf-vars.fd");
+                    ovmf_vars = Some((vars, copy.clone()));
+                    (copy, "raw")
+                }
+            };
+
+            if ovmf_config.debug_log {
+                if matches!(self.config.arch, Arch::X86_64 | Arch::Ia32) {
+                    // OVMF writes its debug output to I/O port 0x402
+                    let log = self.scratch_dir().join("ovmf.log");
+                    std::fs::create_dir_all(self.scratch_dir()).unwrap();
+                    let _ = std::fs::remove_file(&log);
+                    run_command
+                        .arg("-debugcon")
+                        .arg(format!("file:{}", log.display()))
+                        .arg("-global")
+                        .arg("isa-debugcon.iobase=0x402");
+                    firmware_log = Some(log);
+                } else {
+                    eprintln!(
+                        "note: the OVMF debug log is only captured on x86, on {} it is written to the serial port",
+                        serde_plain::to_string(&self.config.arch).unwrap()
+                    );
+                }
+            }
+
+            run_command
+                .arg("-drive")
+                .arg(format!(
+                    "if=pflash,format=raw,readonly=on,file={}",
+                    code.display()
+                ))
+                .arg("-drive")
+                .arg(format!(
+                    "if=pflash,format={},file={}",
+                    vars_format,
+                    vars.display()
+                ));
+        }
+
+        if let Some(args) = self.bootloader.direct_boot_args(self) {
+            run_command.args(args);
+        }
+        // The serial socket replaces `-serial stdio`, which is in the default run-args
+        let user_args = |args: &[String]| match &self.config.serial_socket {
+            Some(_) => strip_serial_stdio(args).unwrap_or_else(|err| panic!("{}", err)),
+            None => args.to_vec(),
+        };
+        run_command.args(user_args(
+            self.config.run_command.get(1..).unwrap_or_default(),
+        ));
+        run_command.args(device_args);
+        if let Some(network) = &self.config.network {
+            run_command.args(network.qemu_args().unwrap_or_else(|err| panic!("{}", err)));
+        }
+        let virtiofs = self
+            .config
+            .shares
+            .iter()
+            .find(|share| share.driver == ShareDriver::Virtiofs);
+        if let Some(share) = virtiofs {
+            // The memory backend has to have the size of the guest memory
+            let user_args = if self.is_test {
+                &self.config.test_args
+            } else {
+                &self.config.run_args
+            };
+            if self
+                .config
+                .run_command
+                .iter()
+                .chain(user_args)
+                .any(|arg| arg == "-m")
+            {
+                panic!(
+                    "virtiofs sets the memory of the guest, set `memory` of the share instead of passing -m"
+                );
+            }
+            run_command.args(share::shared_memory_args(&share.memory));
+        }
+        for (index, share_config) in self.config.shares.iter().enumerate() {
+            let path = self.root_dir.join(&share_config.path);
+            run_command.args(share::share_args(
+                &path,
+                share_config,
+                index,
+                &self.virtiofs_socket(index),
+            ));
+        }
+        for (index, drive) in self.config.drives.iter().enumerate() {
+            let path = self.root_dir.join(&drive.path);
+            drive::prepare_drive(&path, drive).unwrap_or_else(|err| panic!("{}", err));
+            run_command.args(drive::drive_args(&path, drive, &format!("drive{}", index)));
+        }
+        if self.is_test {
+            run_command.args(user_args(&self.config.test_args));
+        } else {
+            run_command.args(user_args(&self.config.run_args));
+        }
+
+        let accelerator = self.accelerator(run_cmd);
+        if let Some(accel) = &accelerator {
+            run_command.arg("-accel").arg(accel);
+            if self.config.verbose {
+                eprintln!("Using the {} accelerator", accel);
+            }
+        }
+        // Picking the accelerator may fall back to a slower one
+        self.deny_warnings()?;
+
+        if let Some(record_replay) = &self.config.record_replay {
+            let file = self.root_dir.join(&record_replay.file);
+            match record_replay.mode {
+                RecordReplayMode::Record => {
+                    if let Some(parent) = file.parent() {
+                        std::fs::create_dir_all(parent).unwrap();
+                    }
+                }
+                RecordReplayMode::Replay if !file.is_file() => {
+                    panic!("{} doesn't exist, record the run first", file.display())
+                }
+                RecordReplayMode::Replay => {}
+            }
+            run_command.arg("-icount").arg(format!(
+                "shift=auto,rr={},rrfile={}",
+                record_replay.mode.name(),
+                file.to_string_lossy().replace(',', ",,")
+            ));
+        }
+
+        let debug = &self.config.debug;
+        if debug.enabled {
+            run_command.args(debug::qemu_args(debug.port, debug.wait));
+            if debug.gdb.is_none() {
+                eprintln!(
+                    "Waiting for GDB on port {}, connect with `target remote :{}`",
+                    debug.port, debug.port
+                );
+            }
+        }
+
+        if let Some(port) = self.config.qmp_port {
+            run_command.args(qmp::qemu_args(port));
+        }
+        if let Some(socket) = &self.config.serial_socket {
+            let socket = SerialSocket::parse(socket).unwrap_or_else(|err| panic!("{}", err));
+            run_command.args(socket.qemu_args());
+            eprintln!("The serial port is at {}", socket);
+        }
+
+        if let Some(snapshot) = &boot_snapshot {
+            run_command.args(snapshot.qemu_args());
+        }
+
+        Ok(QemuCommand {
+            command: run_command,
+            firmware_log,
+            accelerator,
+            boot_snapshot,
+            ovmf_vars,
+        })
+    }
+
+    /// Fails if any warnings were emitted and `deny-warnings` is set, reporting them first
+    #[cfg(feature = "runner")]
+    fn deny_warnings(&self) -> Result<(), String> {
+        if self.config.deny_warnings && !self.diagnostics.is_empty() {
+            self.diagnostics
+                .report(self.config.message_format == MessageFormat::Json);
+            return Err(FailureKind::WarningsDenied.to_string());
+        }
+        Ok(())
+    }
+
+    /// Creates the log file of a run, if it is configured, the run doesn't fail if that doesn't
+    /// work
+    #[cfg(feature = "runner")]
+    fn create_log(&self) -> Option<LogWriter> {
+        let config = self.config.log.as_ref()?;
+        let markers = config
+            .index_markers
+            .iter()
+            .map(|marker| {
+                Regex::new(marker)
+                    .unwrap_or_else(|err| panic!("invalid index marker {}: {}", marker, err))
+            })
+            .collect();
+        let dir = match &config.dir {
+            Some(dir) => self.root_dir.join(dir),
+            None => self.target_dir.join("image-runner/logs"),
+        };
+        let name = self.executable_name().to_string_lossy();
+        match LogWriter::create(&dir, &name, config, markers, &self.scrubbers) {
+            Ok(log) => Some(log),
+            Err(err) => {
+                self.diagnostics.warn("log-failed", err);
+                None
+            }
+        }
+    }
+
+    /// Writes the coverage profiles in the serial output, the run doesn't fail if that doesn't
+    /// work
+    #[cfg(feature = "runner")]
+    fn collect_coverage(&self, serial: &[u8]) {
+        let Some(config) = &self.config.coverage else {
+            return;
+        };
+        let dir = self.root_dir.join(&config.dir);
+        let written =
+            coverage::extract_dumps(serial, &config.begin, &config.end).and_then(|dumps| {
+                let name = self.executable_name().to_string_lossy();
+                coverage::write_profiles(&dir, &name, &dumps)
+            });
+        match written {
+            Ok(profiles) if profiles.is_empty() => {
+                self.diagnostics
+                    .warn("coverage-missing", "the guest printed no coverage profile");
+                return;
+            }
+            Ok(profiles) => eprintln!(
+                "Wrote {} coverage profile(s) to {}",
+                profiles.len(),
+                dir.display()
+            ),
+            Err(err) => {
+                self.diagnostics.warn(
+                    "coverage-failed",
+                    format!("failed to collect the coverage: {}", err),
+                );
+                return;
+            }
+        }
+        if config.merge {
+            match coverage::merge_profiles(&dir, &config.llvm_profdata) {
+                Ok(profdata) => {
+                    eprintln!("Merged the coverage profiles into {}", profdata.display())
+                }
+                Err(err) => self.diagnostics.warn("coverage-merge-failed", err),
+            }
+        }
+    }
+
+    /// The boot snapshot of tests with `test.snapshot-boot`, which is saved again when the config
+    /// or the executable changed
+    #[cfg(feature = "runner")]
+    fn boot_snapshot(&self) -> Option<BootSnapshot> {
+        let config = self.config.test.snapshot_boot.as_ref()?;
+        if !self.is_test {
+            return None;
+        }
+        if self.config.qmp_port.is_none() {
+            panic!("test.snapshot-boot needs qmp-port, the snapshot is saved over QMP");
+        }
+        let executable = std::fs::read(&self.target_src)
+            .unwrap_or_else(|err| panic!("failed to read {}: {}", self.target_src.display(), err));
+        let mut key = serde_json::to_vec(&self.config).unwrap();
+        key.extend(executable);
+        std::fs::create_dir_all(self.scratch_dir()).unwrap();
+        let snapshot =
+            BootSnapshot::open(&self.scratch_dir(), config, crate::manifest::sha256(&key))
+                .unwrap_or_else(|err| panic!("failed to create the boot snapshot: {}", err));
+        Some(snapshot)
+    }
+
+    /// The configured accelerator, with `auto` resolved to the fastest one that QEMU and the host
+    /// support
+    #[cfg(feature = "runner")]
+    fn accelerator(&self, qemu_binary: &str) -> Option<String> {
+        // Only TCG can record and replay the execution
+        if self.config.record_replay.is_some() {
+            if self.config.accel.is_some_and(|accel| accel != Accel::Tcg) {
+                self.diagnostics.warn(
+                    "accel-ignored",
+                    "`accel` is ignored, record-replay runs the guest with TCG",
+                );
+            }
+            return Some(Accel::Tcg.name().to_string());
+        }
+        let accel = self.config.accel?;
+        if accel != Accel::Auto {
+            return Some(accel.name().to_string());
+        }
+        let Some(info) = qemu::probe(qemu_binary) else {
+            self.diagnostics.warn(
+                "accel-probe-failed",
+                format!(
+                    "{} could not be probed for accelerators, leaving the choice to QEMU",
+                    qemu_binary
+                ),
+            );
+            return None;
+        };
+        let (accel, fell_back) =
+            qemu::select_accelerator(&info, |accel| qemu::host_supports(accel).is_ok());
+        if fell_back {
+            // Say why the compiled-in hardware accelerators can't be used, e.g. /dev/kvm is missing
+            let reasons: Vec<String> = qemu::ACCELERATORS
+                .iter()
+                .filter(|&&accel| accel != "tcg" && info.has_accelerator(accel))
+                .filter_map(|accel| qemu::host_supports(accel).err())
+                .collect();
+            let reasons = if reasons.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", reasons.join(", "))
+            };
+            self.diagnostics.warn(
+                "accel-fallback",
+                format!(
+                    "no hardware accelerator is usable{}, falling back to tcg to emulate the guest",
+                    reasons
+                ),
+            );
+        }
+        Some(accel.to_string())
+    }
+
+    /// Runs QEMU once, stopping it when a monitor detects that the guest is stuck
+    #[cfg(feature = "runner")]
+    fn attempt(
+        &self,
+        run_command: &mut Command,
+        serial_input: &SerialInput,
+        watch_output: bool,
+        firmware_log: Option<&Path>,
+        save_snapshot: Option<&BootSnapshot>,
+    ) -> Attempt {
+        let json = self.config.message_format == MessageFormat::Json;
+        // The log is written while the output is forwarded, so it is there even if the runner
+        // is killed
+        let log = self.create_log();
+        let start = Instant::now();
+        let mut child = run_command.spawn().expect("run command failed");
+
+        let watcher = watch_output.then(|| {
+            if self.config.strip_ansi {
+                OutputWatcher::stripping_ansi()
+            } else {
+                OutputWatcher::default()
+            }
+        });
+        let forward = watcher.clone().map(|watcher| {
+            let stdout = child.stdout.take().unwrap();
+            let log = log.clone();
+            std::thread::spawn(move || {
+                let serial = CapturedOutput::SERIAL;
+                if json {
+                    watcher.forward_to(stdout, LogWriter::tee(log, serial, std::io::stderr()))
+                } else {
+                    watcher.forward_to(stdout, LogWriter::tee(log, serial, std::io::stdout()))
+                }
+            })
+        });
+        let stderr_watcher = child.stderr.take().map(|stderr| {
+            let watcher = OutputWatcher::default();
+            let forwarder = watcher.clone();
+            let to = LogWriter::tee(log.clone(), CapturedOutput::QEMU_STDERR, std::io::stderr());
+            let thread = std::thread::spawn(move || forwarder.forward_to(stderr, to));
+            (watcher, thread)
+        });
+
+        // A stuck guest would otherwise run until it is killed, so the monitors stop it, the
+        // first one to detect a problem decides how the run failed
+        let stop: Arc<Mutex<Option<FailureKind>>> = Arc::default();
+        let succeeded: Arc<Mutex<bool>> = Arc::default();
+        let mut monitored = false;
+        if let (Some(limit), Some(watcher)) = (self.config.reset_limit, watcher.clone()) {
+            monitored = true;
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                if watcher.wait_for_reset_loop(limit) {
+                    stop_guest(
+                        &stop,
+                        FailureKind::TripleFault,
+                        format!("the guest reset more than {} times, stopping it", limit),
+                    );
+                }
+            });
+        }
+        if let (true, Some(timeout), Some(watcher)) =
+            (self.is_test, self.config.test.idle_timeout, watcher.clone())
+        {
+            monitored = true;
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                if watcher.wait_for_idle(Duration::from_millis(timeout)) {
+                    stop_guest(
+                        &stop,
+                        FailureKind::Timeout,
+                        format!("the guest printed nothing for {} ms, stopping it", timeout),
+                    );
+                }
+            });
+        }
+
+        // A guest that printed an abort pattern, e.g. a panic, won't recover, so it isn't given
+        // the chance to shut down
+        let abort_patterns = self.abort_patterns();
+        if let (false, Some(watcher)) = (abort_patterns.is_empty(), watcher.clone()) {
+            monitored = true;
+            let stop = stop.clone();
+            let scrubbers = self.scrubbers.clone();
+            std::thread::spawn(move || {
+                let mut start = 0;
+                while let Some(line) = watcher.wait_for_line(start) {
+                    start += line.len();
+                    let line = line.trim_ascii_end();
+                    if abort_patterns.iter().any(|pattern| pattern.is_match(line)) {
+                        let line = scrubbers.scrub(&String::from_utf8_lossy(line));
+                        stop_guest(
+                            &stop,
+                            FailureKind::AbortPattern(line.clone()),
+                            format!("the guest printed {:?}, killing it", line),
+                        );
+                        break;
+                    }
+                }
+            });
+        }
+
+        // A test that is expected to panic has passed once it did, whatever it does next
+        if let (Some(pattern), Some(watcher)) = (self.expected_panic(), watcher.clone()) {
+            monitored = true;
+            let succeeded = succeeded.clone();
+            std::thread::spawn(move || {
+                if watcher
+                    .wait_until(0, |output| pattern.is_match(output).then_some(()))
+                    .is_some()
+                {
+                    *succeeded.lock().unwrap() = true;
+                }
+            });
+        }
+
+        // Runs judged by their output are stopped once it is clear how they went
+        let (success, failure) = self.run_patterns();
+        if let (true, Some(watcher)) = (success.is_some() || failure.is_some(), watcher.clone()) {
+            monitored = true;
+            let stop = stop.clone();
+            let succeeded = succeeded.clone();
+            std::thread::spawn(move || {
+                // The failure pattern wins when both match
+                let matched = watcher.wait_until(0, |output| {
+                    if failure
+                        .as_ref()
+                        .is_some_and(|failure| failure.is_match(output))
+                    {
+                        Some(false)
+                    } else if success
+                        .as_ref()
+                        .is_some_and(|success| success.is_match(output))
+                    {
+                        Some(true)
+                    } else {
+                        None
+                    }
+                });
+                match matched {
+                    Some(true) => *succeeded.lock().unwrap() = true,
+                    Some(false) => stop_guest(
+                        &stop,
+                        FailureKind::FailurePattern,
+                        "the guest printed the failure pattern, stopping it".to_string(),
+                    ),
+                    None => {}
+                }
+            });
+        }
+        // Test cases are matched line by line as they are printed, and the test is stopped once
+        // the summary is printed, or a case fails with fail-fast
+        let fail_fast = self
+            .config
+            .test
+            .cases
+            .as_ref()
+            .is_some_and(|cases| cases.fail_fast);
+        let cases = match (self.case_matcher(), watcher.clone()) {
+            (Some(matcher), Some(watcher)) => {
+                monitored = true;
+                let stop = stop.clone();
+                let succeeded = succeeded.clone();
+                let scrubbers = self.scrubbers.clone();
+                Some(std::thread::spawn(move || {
+                    let mut results = CaseResults::default();
+                    let mut start = 0;
+                    // The case that started and hasn't finished yet, with its deadline
+                    let mut running: Option<(String, Instant)> = None;
+                    loop {
+                        let deadline = running.as_ref().map(|(_, deadline)| *deadline);
+                        let line = match watcher.wait_for_line_until(start, deadline) {
+                            LineWait::Line(line) => line,
+                            LineWait::TimedOut => {
+                                let (name, _) = running.take().unwrap();
+                                let scrubbed = &scrubbers.scrub(&name);
+                                if json {
+                                    Message::CaseFinished {
+                                        name: scrubbed,
+                                        passed: false,
+                                    }
+                                    .emit();
+                                }
+                                stop_guest(
+                                    &stop,
+                                    FailureKind::CaseFailed,
+                                    format!(
+                                        "test case {} didn't finish within {} ms, stopping the guest",
+                                        scrubbed,
+                                        matcher.timeout().unwrap().as_millis()
+                                    ),
+                                );
+                                results.record(CaseEvent::Failed(name));
+                                break;
+                            }
+                            LineWait::Closed => break,
+                        };
+                        start += line.len();
+                        let Some(event) = matcher.match_line(line.trim_ascii_end()) else {
+                            continue;
+                        };
+                        match &event {
+                            CaseEvent::Started(name) => {
+                                running = matcher
+                                    .timeout()
+                                    .map(|timeout| (name.clone(), Instant::now() + timeout));
+                            }
+                            CaseEvent::Passed(name) | CaseEvent::Failed(name) => {
+                                running = None;
+                                let passed = matches!(event, CaseEvent::Passed(_));
+                                let name = &scrubbers.scrub(name);
+                                if json {
+                                    Message::CaseFinished { name, passed }.emit();
+                                }
+                                if !passed && fail_fast {
+                                    stop_guest(
+                                        &stop,
+                                        FailureKind::CaseFailed,
+                                        format!("test case {} failed, stopping the guest", name),
+                                    );
+                                } else if !passed {
+                                    eprintln!("note: test case {} failed", name);
+                                }
+                            }
+                            CaseEvent::Summary => *succeeded.lock().unwrap() = true,
+                        }
+                        results.record(event);
+                    }
+                    results
+                }))
+            }
+            _ => None,
+        };
+        // Actions the guest asks for are taken as it prints them, and added to the output
+        let host_actions = match (&self.config.host_actions, watcher.clone()) {
+            (Some(config), Some(watcher)) => {
+                let prefix = config.prefix.clone();
+                let log = log.clone();
+                let mut runner = HostActionRunner::new(
+                    self.config.qmp_port,
+                    self.scratch_dir().join("screenshots"),
+                );
+                Some(std::thread::spawn(move || {
+                    let mut annotations = Vec::new();
+                    let mut errors = Vec::new();
+                    let mut position = 0;
+                    while let Some(line) = watcher.wait_for_line(position) {
+                        position += line.len();
+                        let Some(action) = HostAction::parse(line.trim_ascii_end(), &prefix) else {
+                            continue;
+                        };
+                        match action.and_then(|action| runner.perform(&action)) {
+                            Ok(annotation) => {
+                                let elapsed = start.elapsed();
+                                if json {
+                                    Message::HostAction {
+                                        annotation: &annotation,
+                                        elapsed_ms: elapsed.as_millis() as u64,
+                                    }
+                                    .emit();
+                                }
+                                if let Some(log) = &log {
+                                    log.annotate(&annotation);
+                                }
+                                annotations.push((elapsed, annotation));
+                            }
+                            Err(err) => errors.push(format!("host action failed: {}", err)),
+                        }
+                    }
+                    (annotations, errors)
+                }))
+            }
+            _ => None,
+        };
+        // The guest is booted once it prints the marker, later runs start from the saved state
+        let snapshot_saver = if let (Some(snapshot), Some(watcher), Some(port)) =
+            (save_snapshot, watcher.clone(), self.config.qmp_port)
+        {
+            let snapshot = snapshot.clone();
+            Some(std::thread::spawn(move || {
+                watcher
+                    .wait_for(snapshot.config.marker.as_bytes(), 0)
+                    .and_then(|_| snapshot.save(port).err())
+            }))
+        } else {
+            None
+        };
+
+        // The input and the responders write to the serial input of the guest together
+        let stdin = child.stdin.take().map(SharedWriter::new);
+        let feed = match (&serial_input.input, stdin.clone()) {
+            (Some(input), Some(stdin)) => {
+                let input = input.clone();
+                let input_options = serial_input.options.clone();
+                let watcher = watcher.clone();
+                Some(std::thread::spawn(move || {
+                    feed_input(stdin, &input, &input_options, watcher.as_ref())
+                }))
+            }
+            _ => None,
+        };
+        if let (false, Some(stdin), Some(watcher)) = (
+            serial_input.responders.is_empty(),
+            stdin.clone(),
+            watcher.clone(),
+        ) {
+            let responders = serial_input.responders.clone();
+            std::thread::spawn(move || respond(stdin, &watcher, responders));
+        }
+        // A step that times out stops the guest, and the script fails as well if the guest
+        // stopped before it finished
+        let expect = match (
+            serial_input.expect.is_empty(),
+            stdin.clone(),
+            watcher.clone(),
+        ) {
+            (false, Some(stdin), Some(watcher)) => {
+                monitored = true;
+                let steps = serial_input.expect.clone();
+                let stop = stop.clone();
+                Some(std::thread::spawn(move || {
+                    let result = run_expect(stdin, &watcher, &steps);
+                    if let Err(err) = &result {
+                        stop_guest(&stop, FailureKind::ExpectFailed, err.clone());
+                    }
+                    result
+                }))
+            }
+            _ => None,
+        };
+
+        let debug = &self.config.debug;
+        let debugger = match (debug.enabled, &debug.gdb) {
+            (true, Some(gdb)) => {
+                let script_path = self.scratch_dir().join("gdbinit");
+                let status = debug::gdb_command(gdb, &self.target_src, debug.port, &script_path)
+                    .status()
+                    .unwrap_or_else(|err| panic!("failed to launch {}: {}", gdb, err));
+                // The guest is stopped along with the debugger
+                let _ = child.kill();
+                child.wait().unwrap();
+                Some(status)
+            }
+            _ => None,
+        };
+
+        let (status, stopped, shutdown) = loop {
+            if let Some(status) = debugger {
+                break (status, None, None);
+            }
+            if !monitored {
+                break (child.wait().unwrap(), None, None);
+            }
+            if let Some(status) = child.try_wait().unwrap() {
+                break (status, None, None);
+            }
+            if *succeeded.lock().unwrap() {
+                let (status, stage) = self.shut_down(&mut child, stdin.as_ref());
+                break (status, None, Some(stage));
+            }
+            if let Some(kind) = stop.lock().unwrap().clone() {
+                let (status, stage) = if matches!(kind, FailureKind::AbortPattern(_)) {
+                    stop_child(&mut child, Duration::ZERO, false)
+                } else {
+                    self.shut_down(&mut child, stdin.as_ref())
+                };
+                if stage != ShutdownStage::Killed {
+                    eprintln!("note: the guest was {}", stage);
+                }
+                break (status, Some(kind), Some(stage));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        if let Some(forward) = forward {
+            let _ = forward.join();
+        }
+        if let Some(Err(err)) = feed.map(|feed| feed.join().unwrap()) {
+            self.diagnostics.warn("input-timed-out", err);
+        }
+        let cases = cases.map(|cases| cases.join().unwrap());
+        let annotations = host_actions.map(|host_actions| {
+            let (annotations, errors) = host_actions.join().unwrap();
+            for err in errors {
+                self.diagnostics.warn("host-action-failed", err);
+            }
+            annotations
+        });
+        if let Some(Some(err)) = snapshot_saver.map(|saver| saver.join().unwrap()) {
+            self.diagnostics.warn(
+                "boot-snapshot-failed",
+                format!("failed to save the boot snapshot: {}", err),
+            );
+        }
+        let stopped = match expect.map(|expect| expect.join().unwrap()) {
+            Some(Err(_)) => stopped.or(Some(FailureKind::ExpectFailed)),
+            _ => stopped,
+        };
+        let duration = start.elapsed();
+        let mut output = CapturedOutput::default();
+        if let Some(watcher) = watcher {
+            output.insert(CapturedOutput::SERIAL, watcher.output());
+            output.insert_timestamps(CapturedOutput::SERIAL, watcher.timestamps(start));
+        }
+        if let Some((watcher, thread)) = stderr_watcher {
+            let _ = thread.join();
+            output.insert(CapturedOutput::QEMU_STDERR, watcher.output());
+            output.insert_timestamps(CapturedOutput::QEMU_STDERR, watcher.timestamps(start));
+        }
+        match log.map(|log| log.finish()) {
+            Some(Ok(path)) => eprintln!("Wrote the log to {}", path.display()),
+            Some(Err(err)) => self.diagnostics.warn("log-failed", err),
+            None => {}
+        }
+        if let Some(log) = firmware_log.and_then(|log| std::fs::read(log).ok()) {
+            output.insert(CapturedOutput::DEBUGCON, log);
+        }
+        if let Some(annotations) = annotations {
+            let mut bytes = Vec::new();
+            let mut timestamps = Vec::new();
+            for (received, annotation) in annotations {
+                timestamps.push(ChunkTime {
+                    offset: bytes.len(),
+                    received,
+                });
+                bytes.extend(annotation.bytes());
+                bytes.push(b'\n');
+            }
+            output.insert(CapturedOutput::ANNOTATIONS, bytes);
+            output.insert_timestamps(CapturedOutput::ANNOTATIONS, timestamps);
+        }
+        Attempt {
+            status,
+            debugged: debugger.is_some(),
+            stopped,
+            shutdown,
+            duration,
+            output,
+            cases,
+        }
+    }
+
+    /// Stops QEMU following `test.shutdown`, asking the guest to shut down first, then
+    /// terminating QEMU and finally killing it if it doesn't exit within the grace period
+    #[cfg(feature = "runner")]
+    fn shut_down(
+        &self,
+        child: &mut Child,
+        stdin: Option<&SharedWriter<ChildStdin>>,
+    ) -> (ExitStatus, ShutdownStage) {
+        let shutdown = &self.config.test.shutdown;
+        let grace = Duration::from_secs(shutdown.grace);
+        let asked = match shutdown.method {
+            ShutdownMethod::Kill => Ok(false),
+            ShutdownMethod::Qmp => match self.config.qmp_port {
+                Some(port) => qmp::QmpClient::connect(("127.0.0.1", port))
+                    .and_then(|mut client| client.system_powerdown())
+                    .map(|_| true),
+                None => Err("qmp-port isn't set".to_string()),
+            },
+            ShutdownMethod::Serial => match stdin {
+                Some(stdin) => stdin
+                    .clone()
+                    .write_all(shutdown.command.as_bytes())
+                    .map(|_| true)
+                    .map_err(|err| err.to_string()),
+                None => Err("the serial input isn't piped".to_string()),
+            },
+        };
+        match asked {
+            Ok(true) => {
+                if let Some(status) = wait_timeout(child, grace) {
+                    return (status, ShutdownStage::Graceful);
+                }
+            }
+            Ok(false) => {}
+            Err(err) => self.diagnostics.warn(
+                "shutdown-failed",
+                format!("failed to shut down the guest: {}", err),
+            ),
+        }
+        stop_child(child, grace, shutdown.method != ShutdownMethod::Kill)
+    }
+
+    /// Why an attempt failed, or `None` if it succeeded
+    #[cfg(feature = "runner")]
+    fn failure(&self, attempt: &Attempt) -> Option<FailureKind> {
+        // The guest is killed along with the debugger, so only the debugger can tell
+        if attempt.debugged {
+            return (!attempt.status.success()).then(|| {
+                attempt
+                    .status
+                    .code()
+                    .map_or(FailureKind::Killed, FailureKind::ExitCode)
+            });
+        }
+        // The panic may also have stopped the guest, e.g. through an abort pattern or the idle
+        // timeout, which is fine then
+        if let Some(pattern) = self.expected_panic() {
+            if pattern.is_match(attempt.output.serial()) {
+                return self.check_snapshot(attempt.output.serial());
+            }
+            return attempt.stopped.clone().or(Some(FailureKind::NoPanic));
+        }
+        if attempt.stopped.is_some() {
+            return attempt.stopped.clone();
+        }
+        // The guest may have exited before the pattern was noticed, or not ended the line
+        let serial = attempt.output.serial();
+        let abort_patterns = self.abort_patterns();
+        let aborted = serial
+            .split(|&byte| byte == b'\n')
+            .map(|line| line.trim_ascii_end())
+            .find(|line| abort_patterns.iter().any(|pattern| pattern.is_match(line)));
+        if let Some(line) = aborted {
+            let line = self.scrubbers.scrub(&String::from_utf8_lossy(line));
+            return Some(FailureKind::AbortPattern(line));
+        }
+        // The exit code doesn't matter once the summary is printed, as the guest is stopped then
+        if let Some(cases) = &attempt.cases {
+            if !cases.failed.is_empty() {
+                return Some(FailureKind::CaseFailed);
+            }
+            if cases.finished {
+                return self.check_snapshot(attempt.output.serial());
+            }
+        }
+        let (success, failure) = self.run_patterns();
+        if failure.is_some_and(|failure| failure.is_match(serial)) {
+            return Some(FailureKind::FailurePattern);
+        }
+        // The exit code of QEMU doesn't matter then, it is stopped once the pattern is printed
+        if let Some(success) = success {
+            return (!success.is_match(serial)).then_some(FailureKind::NoSuccessPattern);
+        }
+        let success = if self.is_test {
+            attempt
+                .status
+                .code()
+                .is_some_and(|code| code as u32 == self.config.test_success_exit_code)
+        } else {
+            attempt.status.success()
+        };
+        if success {
+            return self.check_snapshot(attempt.output.serial());
+        }
+        Some(attempt.output.classify(attempt.status.code()))
+    }
+
+    /// Matches the test cases of tests, if they are configured, see
+    /// [`crate::config::TestCasesConfig`]
+    #[cfg(feature = "runner")]
+    fn case_matcher(&self) -> Option<CaseMatcher> {
+        let cases = self.config.test.cases.as_ref().filter(|_| self.is_test)?;
+        Some(CaseMatcher::new(cases).unwrap_or_else(|err| panic!("{}", err)))
+    }
+
+    /// The pattern of the panic a test is expected to print, see `test.expect-panic-pattern`
+    #[cfg(feature = "runner")]
+    fn expected_panic(&self) -> Option<Regex> {
+        let pattern = self
+            .config
+            .test
+            .expect_panic_pattern
+            .as_ref()
+            .filter(|_| self.is_test)?;
+        Some(
+            Regex::new(pattern)
+                .unwrap_or_else(|err| panic!("invalid expect-panic-pattern {}: {}", pattern, err)),
+        )
+    }
+
+    /// The `abort-patterns`, which apply to runs and tests
+    #[cfg(feature = "runner")]
+    fn abort_patterns(&self) -> Vec<Regex> {
+        self.config
+            .abort_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .unwrap_or_else(|err| panic!("invalid abort pattern {}: {}", pattern, err))
+            })
+            .collect()
+    }
+
+    /// The success and failure patterns of runs that aren't tests, see
+    /// [`crate::config::RunConfig`]
+    #[cfg(feature = "runner")]
+    fn run_patterns(&self) -> (Option<Regex>, Option<Regex>) {
+        if self.is_test {
+            return (None, None);
+        }
+        let compile = |pattern: &Option<String>| {
+            pattern.as_ref().map(|pattern| {
+                Regex::new(pattern)
+                    .unwrap_or_else(|err| panic!("invalid pattern {}: {}", pattern, err))
+            })
+        };
+        let run = &self.config.run;
+        (compile(&run.success_pattern), compile(&run.failure_pattern))
+    }
+
+    /// Compares the serial output of a test against its snapshot, if it has one
+    #[cfg(feature = "runner")]
+    fn check_snapshot(&self, serial: &[u8]) -> Option<FailureKind> {
+        let test = &self.config.test;
+        let path = self.root_dir.join(test.snapshot.as_ref()?);
+        let serial = self.scrubbers.scrub(&String::from_utf8_lossy(serial));
+        let outcome = snapshot::normalize(serial.as_bytes(), &test.snapshot_filters)
+            .and_then(|output| snapshot::check_snapshot(&path, &output, test.update_snapshots))
+            .unwrap_or_else(|err| panic!("{}", err));
+        match outcome {
+            SnapshotOutcome::Matched => None,
+            SnapshotOutcome::Written => {
+                eprintln!("note: wrote the snapshot {}", path.display());
+                None
+            }
+            SnapshotOutcome::Mismatch(difference) => {
+                eprintln!(
+                    "error: the output differs from the snapshot {}, {}",
+                    path.display(),
+                    difference
+                );
+                Some(FailureKind::SnapshotMismatch)
+            }
+        }
+    }
+}
+
+/// The QEMU command of a run
+#[cfg(feature = "runner")]
+struct QemuCommand {
+    command: Command,
+    /// Where the firmware log is written, if it is captured
+    firmware_log: Option<PathBuf>,
+    /// The accelerator passed with `-accel`, if one is configured
+    accelerator: Option<String>,
+    /// The boot snapshot that is restored or saved by the run
+    boot_snapshot: Option<BootSnapshot>,
+    /// The variable store of the firmware, and the copy of it QEMU writes to, see
+    /// [`copy_ovmf_vars`]
+    ovmf_vars: Option<(PathBuf, PathBuf)>,
+}
+
+/// Copies the variable store of the firmware to where QEMU writes to it, before each attempt
+#[cfg(feature = "runner")]
+fn copy_ovmf_vars(ovmf_vars: Option<&(PathBuf, PathBuf)>) {
+    let Some((source, copy)) = ovmf_vars else {
+        return;
+    };
+    std::fs::create_dir_all(copy.parent().unwrap()).unwrap();
+    std::fs::copy(source, copy)
+        .unwrap_or_else(|err| panic!("failed to copy {}: {}", source.display(), err));
+}
+
+/// What is sent to the serial input of the guest
+#[cfg(feature = "runner")]
+struct SerialInput {
+    /// The contents of `stdin-file`
+    input: Option<Vec<u8>>,
+    options: InputOptions,
+    responders: Vec<Responder>,
+    expect: Vec<ExpectStep>,
+}
+
+#[cfg(feature = "runner")]
+impl SerialInput {
+    fn needs_stdin(&self) -> bool {
+        self.input.is_some() || !self.responders.is_empty() || !self.expect.is_empty()
+    }
+
+    fn needs_output(&self) -> bool {
+        (self.input.is_some() && self.options.needs_output())
+            || !self.responders.is_empty()
+            || !self.expect.is_empty()
+    }
+}
+
+/// The result of running QEMU once
+#[cfg(feature = "runner")]
+struct Attempt {
+    status: ExitStatus,
+    /// Whether the guest ran under the debugger, `status` is the one of the debugger then
+    debugged: bool,
+    /// Why the guest was stopped by the runner, if it was
+    stopped: Option<FailureKind>,
+    /// How the runner stopped the guest, if it did
+    shutdown: Option<ShutdownStage>,
+    /// How long QEMU ran
+    duration: Duration,
+    /// The output of the guest if it was watched, what QEMU wrote to stderr for tests, and the
+    /// firmware log if it was captured
+    output: CapturedOutput,
+    /// The test cases that finished, if they are matched
+    cases: Option<CaseResults>,
+}
+
+/// Asks the run to stop the guest because of `kind`, unless another monitor already did, which
+/// already reported why
+#[cfg(feature = "runner")]
+fn stop_guest(stop: &Mutex<Option<FailureKind>>, kind: FailureKind, reason: String) {
+    let mut stop = stop.lock().unwrap();
+    if stop.is_none() {
+        eprintln!("error: {}", reason);
+        *stop = Some(kind);
+    }
+}
+
+/// Waits up to `timeout` for `child` to exit
+#[cfg(feature = "runner")]
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        if start.elapsed() >= timeout {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Sends SIGTERM to `child` if `terminate` is set, then kills it if it doesn't exit within `grace`
+///
+/// Windows has no signals, so there the child is killed right away, with `TerminateProcess`.
+#[cfg(feature = "runner")]
+fn stop_child(child: &mut Child, grace: Duration, terminate: bool) -> (ExitStatus, ShutdownStage) {
+    if terminate
+        && send_sigterm(child)
+        && let Some(status) = wait_timeout(child, grace)
+    {
+        return (status, ShutdownStage::Terminated);
+    }
+    let _ = child.kill();
+    (child.wait().unwrap(), ShutdownStage::Killed)
+}
+
+/// Sends SIGTERM to `child`, returning whether it was sent, which is only supported on unix
+#[cfg(feature = "runner")]
+fn send_sigterm(child: &Child) -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: kill has no memory safety requirements, and the child wasn't waited for yet, so
+        // its pid wasn't reused
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = child;
+        false
+    }
+}
+
+#[cfg(all(test, feature = "runner"))]
+#[test]
+fn test_stop_child() {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("ping");
+        command.args(["-n", "30", "127.0.0.1"]);
+        command
+    } else {
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        command
+    };
+    let mut child = command.stdout(Stdio::null()).spawn().unwrap();
+    assert!(wait_timeout(&mut child, Duration::from_millis(50)).is_none());
+    let start = Instant::now();
+    let (status, stage) = stop_child(&mut child, Duration::from_secs(5), true);
+    assert!(!status.success());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let expected = if cfg!(unix) {
+        ShutdownStage::Terminated
+    } else {
+        ShutdownStage::Killed
+    };
+    assert_eq!(stage, expected);
+}
+
+#[cfg(all(test, feature = "runner", unix))]
+#[test]
+fn test_shut_down() {
+    let mut config = crate::config::default_config().image_runner;
+    config.direct_kernel = true;
+    config.test.shutdown.method = ShutdownMethod::Serial;
+    let ctx = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .is_test(true)
+        .build()
+        .unwrap();
+
+    // The guest powers off once it reads the shutdown command
+    let mut child = Command::new("sh")
+        .args(["-c", "read command && test \"$command\" = poweroff"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdin = SharedWriter::new(child.stdin.take().unwrap());
+    let (status, stage) = ctx.shut_down(&mut child, Some(&stdin));
+    assert!(status.success());
+    assert_eq!(stage, ShutdownStage::Graceful);
+
+    // Without the serial input, QEMU is terminated instead
+    let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+    let (status, stage) = ctx.shut_down(&mut child, None);
+    assert!(!status.success());
+    assert_eq!(stage, ShutdownStage::Terminated);
+}
+
+/// An attempt that exited with `code` after printing `serial`
+#[cfg(all(test, feature = "runner", unix))]
+fn exited_attempt(code: i32, serial: &str) -> Attempt {
+    use std::os::unix::process::ExitStatusExt;
+    let mut output = CapturedOutput::default();
+    output.insert(CapturedOutput::SERIAL, serial.as_bytes().to_vec());
+    Attempt {
+        status: ExitStatus::from_raw(code << 8),
+        debugged: false,
+        stopped: None,
+        shutdown: None,
+        duration: Duration::ZERO,
+        output,
+        cases: None,
+    }
+}
+
+#[cfg(all(test, feature = "runner", unix))]
+#[test]
+fn test_run_patterns() {
+    let mut config = crate::config::default_config().image_runner;
+    config.direct_kernel = true;
+    config.run.success_pattern = Some("boot ok".to_string());
+    config.run.failure_pattern = Some("FAIL".to_string());
+    let ctx = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .is_test(false)
+        .build()
+        .unwrap();
+
+    // The success pattern decides, whatever the exit code
+    assert_eq!(ctx.failure(&exited_attempt(1, "boot ok\n")), None);
+    assert_eq!(
+        ctx.failure(&exited_attempt(0, "booting\n")),
+        Some(FailureKind::NoSuccessPattern)
+    );
+    // The failure pattern wins when both are printed
+    assert_eq!(
+        ctx.failure(&exited_attempt(0, "boot ok\nFAIL\n")),
+        Some(FailureKind::FailurePattern)
+    );
+}
+
+#[cfg(all(test, feature = "runner", unix))]
+#[test]
+fn test_expected_panic() {
+    use std::os::unix::process::ExitStatusExt;
+    let mut config = crate::config::default_config().image_runner;
+    config.direct_kernel = true;
+    config.test.expect_panic_pattern = Some("panicked at".to_string());
+    let ctx = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .is_test(true)
+        .build()
+        .unwrap();
+
+    // The guest spins after panicking, so the runner kills it
+    let mut attempt = exited_attempt(0, "panicked at src/main.rs:1:1\n");
+    attempt.status = ExitStatus::from_raw(libc::SIGKILL);
+    attempt.shutdown = Some(ShutdownStage::Killed);
+    assert_eq!(ctx.failure(&attempt), None);
+    // An abort pattern may have stopped it too
+    attempt.stopped = Some(FailureKind::AbortPattern("panicked at".to_string()));
+    assert_eq!(ctx.failure(&attempt), None);
+
+    assert_eq!(
+        ctx.failure(&exited_attempt(0, "all good\n")),
+        Some(FailureKind::NoPanic)
+    );
+}
+
+#[cfg(all(test, feature = "runner", unix))]
+#[test]
+fn test_debugged_attempt() {
+    let config = crate::config::default_config().image_runner;
+    let ctx = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .is_test(true)
+        .build()
+        .unwrap();
+
+    // The exit code of the debugger decides, not the test success code
+    let mut attempt = exited_attempt(0, "panicked at src/main.rs:1:1\n");
+    attempt.debugged = true;
+    assert_eq!(ctx.failure(&attempt), None);
+    let mut attempt = exited_attempt(1, "");
+    attempt.debugged = true;
+    assert_eq!(ctx.failure(&attempt), Some(FailureKind::ExitCode(1)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_build_errors() {
+    let mut config = crate::config::default_config().image_runner;
+    config.arch = Arch::Aarch64;
+    config.boot_type = BootType::Bios;
+    let err = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .build()
+        .err()
+        .unwrap();
+    assert!(err.starts_with("BIOS boot type is only supported on x86_64"));
+
+    let mut config = crate::config::default_config().image_runner;
+    config.direct_kernel = true;
+    let err = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .is_test(false)
+        .test_filter(vec!["foo".to_string()])
+        .build()
+        .err()
+        .unwrap();
+    assert!(err.contains("doesn't take the arguments foo"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_directory_run_command() {
+    let run_command = |arch| {
+        let mut config = crate::config::default_config().image_runner;
+        config.bootloader = BootloaderKind::UBoot;
+        config.arch = arch;
+        // As parsed from a config without a run command
+        config.run_command.clear();
+        let ctx = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+            .build()
+            .unwrap();
+        ctx.config().run_command.clone()
+    };
+    assert_eq!(
+        run_command(Arch::X86_64),
+        ["qemu-system-x86_64", "-drive", "format=raw,file=fat:rw:{}"]
+    );
+    assert_eq!(
+        run_command(Arch::Riscv64),
+        [
+            "qemu-system-riscv64",
+            "-machine",
+            "virt",
+            "-drive",
+            "if=virtio,format=raw,file=fat:rw:{}"
+        ]
+    );
+}
+
+#[cfg(all(test, feature = "runner"))]
+#[test]
+fn test_deny_warnings() {
+    let mut config = crate::config::default_config().image_runner;
+    config.direct_kernel = true;
+    config.deny_warnings = true;
+    let ctx = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .build()
+        .unwrap();
+    assert_eq!(ctx.deny_warnings(), Ok(()));
+
+    // Warnings found before the context is built, e.g. ignored overrides, are denied too
+    let mut config = crate::config::default_config().image_runner;
+    config.direct_kernel = true;
+    config.deny_warnings = true;
+    let ctx = Context::builder(config, PathBuf::from("kernel"), std::env::temp_dir())
+        .warning(
+            "config-ignored",
+            "IMAGE_RUNNER_FOO is set, but it is unknown",
+        )
+        .build()
+        .unwrap();
+    assert_eq!(
+        ctx.deny_warnings(),
+        Err("warnings are denied by `deny-warnings`".to_string())
+    );
+}
+
+/// The number of lines of the firmware log that are printed when a run fails
+#[cfg(feature = "runner")]
+const FIRMWARE_LOG_LINES: usize = 20;
+
+/// Prints the end of the firmware log if the guest output nothing, as the boot then never reached
+/// the kernel and the log is the only hint of what went wrong
+#[cfg(feature = "runner")]
+fn report_firmware_log(log: Option<&Path>, output: &CapturedOutput) {
+    let Some(log) = log else {
+        return;
+    };
+    if !output.serial().trim_ascii().is_empty() || output.firmware_log().is_empty() {
+        return;
+    }
+    let contents = String::from_utf8_lossy(output.firmware_log());
+    let lines: Vec<&str> = contents.lines().collect();
+    eprintln!(
+        "note: the guest printed nothing, the last lines of the firmware log ({}) are:",
+        log.display()
+    );
+    for line in &lines[lines.len().saturating_sub(FIRMWARE_LOG_LINES)..] {
+        eprintln!("  {}", line);
+    }
+}
+
+/// Takes an exclusive lock on the file at `path`, which is held until the file is dropped
+fn lock(path: &Path) -> File {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    let file = File::create(path)
+        .unwrap_or_else(|err| panic!("failed to create {}: {}", path.display(), err));
+    file.lock()
+        .unwrap_or_else(|err| panic!("failed to lock {}: {}", path.display(), err));
+    file
+}
+
+/// Whether `executable` is a test, which cargo names `<name>-<hash>`
+pub fn is_test_executable(executable: &Path) -> bool {
+    executable
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(test_executable_name)
+        .is_some()
+}
+
+/// The name of a test executable without the hash cargo appends
+fn test_executable_name(name: &str) -> Option<&str> {
+    let (start, end) = name.rsplit_once('-')?;
+    u64::from_str_radix(end, 16).is_ok().then_some(start)
+}
+
+/// The target the executable at `path` was built for, derived from its path in the target
+/// directory
+fn build_target(path: &Path) -> Option<String> {
+    let mut components = path.components().rev().skip(1);
+    // The executable is in <target>/<profile>, or in <target>/<profile>/deps for tests
+    if components.next()?.as_os_str() == "deps" {
+        components.next()?;
+    }
+    let target = components.next()?.as_os_str().to_str()?;
+    // Triples always contain a dash, unlike the target directory itself
+    target.contains('-').then(|| target.to_string())
+}
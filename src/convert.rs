@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+
+/// The formats a built image can be converted to with `qemu-img`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConvertFormat {
+    /// For QEMU and KVM based hypervisors
+    #[serde(rename = "qcow2")]
+    Qcow2,
+    /// For VMware
+    #[serde(rename = "vmdk")]
+    Vmdk,
+    /// For Hyper-V
+    #[serde(rename = "vhdx")]
+    Vhdx,
+    /// For VirtualBox
+    #[serde(rename = "vdi")]
+    Vdi,
+    #[serde(rename = "raw")]
+    Raw,
+}
+
+impl ConvertFormat {
+    /// The name of the format, as `qemu-img` expects it
+    pub fn name(self) -> &'static str {
+        match self {
+            ConvertFormat::Qcow2 => "qcow2",
+            ConvertFormat::Vmdk => "vmdk",
+            ConvertFormat::Vhdx => "vhdx",
+            ConvertFormat::Vdi => "vdi",
+            ConvertFormat::Raw => "raw",
+        }
+    }
+
+    /// The extension of images in the format
+    pub fn extension(self) -> &'static str {
+        match self {
+            ConvertFormat::Raw => "img",
+            _ => self.name(),
+        }
+    }
+}
+
+/// Converts the raw image at `input`, such as a built ISO, to `format` at `output`
+pub fn convert_image(input: &Path, output: &Path, format: ConvertFormat) -> Result<(), String> {
+    if input.is_dir() {
+        return Err(format!(
+            "{} is a directory, only image files can be converted",
+            input.display()
+        ));
+    }
+    let status = Command::new("qemu-img")
+        .args(["convert", "-f", "raw", "-O", format.name()])
+        .arg(input)
+        .arg(output)
+        .status()
+        .map_err(|err| format!("failed to run qemu-img: {}", err))?;
+    if !status.success() {
+        return Err(format!(
+            "qemu-img failed to convert {}: {}",
+            input.display(),
+            status
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::serial::find;
+
+/// Finds the coverage dumps the guest printed between the `begin` and `end` markers, and decodes
+/// them from base64, e.g. the LLVM profiles written by `minicov`
+pub fn extract_dumps(output: &[u8], begin: &str, end: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut dumps = Vec::new();
+    let mut rest = output;
+    while let Some(start) = find(rest, begin.as_bytes()) {
+        rest = &rest[start + begin.len()..];
+        let Some(len) = find(rest, end.as_bytes()) else {
+            return Err(format!("a coverage dump isn't terminated by {:?}", end));
+        };
+        dumps.push(decode_base64(&rest[..len])?);
+        rest = &rest[len + end.len()..];
+    }
+    Ok(dumps)
+}
+
+/// Decodes standard base64, ignoring whitespace such as the line breaks of the serial output
+fn decode_base64(text: &[u8]) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &c in text {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            c => {
+                return Err(format!(
+                    "invalid base64 in a coverage dump: {:?}",
+                    c as char
+                ));
+            }
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Writes the dumps to `dir` as `<name>-<pid>-<index>.profraw`, so the profiles of earlier runs
+/// are kept
+pub fn write_profiles(dir: &Path, name: &str, dumps: &[Vec<u8>]) -> Result<Vec<PathBuf>, String> {
+    std::fs::create_dir_all(dir)
+        .map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+    dumps
+        .iter()
+        .enumerate()
+        .map(|(index, dump)| {
+            let path = dir.join(format!("{}-{}-{}.profraw", name, std::process::id(), index));
+            std::fs::write(&path, dump)
+                .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Merges all profiles in `dir` into `coverage.profdata` with `llvm-profdata`, which
+/// `llvm-cov` reads
+pub fn merge_profiles(dir: &Path, llvm_profdata: &str) -> Result<PathBuf, String> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|err| format!("failed to read {}: {}", dir.display(), err))?;
+    let profiles: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "profraw"))
+        .collect();
+    let output = dir.join("coverage.profdata");
+    let status = Command::new(llvm_profdata)
+        .args(["merge", "-sparse", "-o"])
+        .arg(&output)
+        .args(&profiles)
+        .status()
+        .map_err(|err| format!("failed to run {}: {}", llvm_profdata, err))?;
+    if !status.success() {
+        return Err(format!(
+            "{} failed to merge the profiles: {}",
+            llvm_profdata, status
+        ));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+#[test]
+fn test_extract_dumps() {
+    let output =
+        b"booting\nCOVERAGE-BEGIN\naGVs\r\nbG8=\nCOVERAGE-END\nok\nCOVERAGE-BEGIN AQI COVERAGE-END";
+    assert_eq!(
+        extract_dumps(output, "COVERAGE-BEGIN", "COVERAGE-END"),
+        Ok(vec![b"hello".to_vec(), vec![1, 2]])
+    );
+    assert!(extract_dumps(b"COVERAGE-BEGIN aGVs", "COVERAGE-BEGIN", "COVERAGE-END").is_err());
+    assert!(
+        extract_dumps(
+            b"COVERAGE-BEGIN a*b COVERAGE-END",
+            "COVERAGE-BEGIN",
+            "COVERAGE-END"
+        )
+        .is_err()
+    );
+}
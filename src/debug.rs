@@ -0,0 +1,42 @@
+use std::path::Path;
+use std::process::Command;
+
+/// The GDB init script that loads the symbols of the kernel and connects to QEMU
+pub fn gdb_script(kernel: &Path, port: u16) -> String {
+    format!(
+        "symbol-file {}\ntarget remote :{}\n",
+        kernel.display(),
+        port
+    )
+}
+
+/// The arguments that make QEMU listen for GDB on `port`, and optionally wait for it to connect
+pub fn qemu_args(port: u16, wait: bool) -> Vec<String> {
+    let mut args = vec!["-gdb".to_string(), format!("tcp::{}", port)];
+    if wait {
+        args.push("-S".to_string());
+    }
+    args
+}
+
+/// Writes the init script to `script_path`, and returns the command that launches `gdb` with it
+pub fn gdb_command(gdb: &str, kernel: &Path, port: u16, script_path: &Path) -> Command {
+    if let Some(parent) = script_path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    std::fs::write(script_path, gdb_script(kernel, port)).expect("failed to write gdb script");
+
+    let mut command = Command::new(gdb);
+    command.arg("-x").arg(script_path);
+    command
+}
+
+#[cfg(test)]
+#[test]
+fn test_gdb_script() {
+    assert_eq!(
+        gdb_script(Path::new("target/x86_64-unknown-none/debug/kernel"), 1234),
+        "symbol-file target/x86_64-unknown-none/debug/kernel\ntarget remote :1234\n"
+    );
+    assert_eq!(qemu_args(1234, true), ["-gdb", "tcp::1234", "-S"]);
+}
@@ -0,0 +1,52 @@
+use std::cell::RefCell;
+
+use serde::Serialize;
+
+use crate::message::Message;
+
+/// A warning emitted while preparing or running the image
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Warning {
+    /// A short identifier for the kind of warning, e.g. `uefi-app-not-pe`
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Collects the warnings of a run, so they can be reported together at the end
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    warnings: RefCell<Vec<Warning>>,
+}
+
+impl Diagnostics {
+    pub fn warn(&self, code: &'static str, message: impl Into<String>) {
+        self.warnings.borrow_mut().push(Warning {
+            code,
+            message: message.into(),
+        });
+    }
+
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.warnings.borrow().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.warnings.borrow().is_empty()
+    }
+
+    /// Prints all the collected warnings to stderr, or as `warning` messages on stdout with
+    /// `json`
+    pub fn report(&self, json: bool) {
+        for warning in self.warnings.borrow().iter() {
+            if json {
+                Message::Warning {
+                    code: warning.code,
+                    message: &warning.message,
+                }
+                .emit();
+            } else {
+                eprintln!("warning[{}]: {}", warning.code, warning.message);
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::config::{DriveConfig, DriveInterface};
+
+/// Parses a size such as `512`, `64K`, `64M` or `1G` into bytes, the suffixes are powers of 1024
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size, ""),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", size))?;
+    let shift = match unit.to_ascii_uppercase().trim_end_matches("IB") {
+        "" | "B" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("invalid size {:?}, expected e.g. 64M or 1G", size)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} is too large", size))
+}
+
+/// Creates a blank image at `path` if the drive has a `size` and it doesn't exist yet, or always
+/// with `fresh`, raw images are created directly and others with `qemu-img`
+pub fn prepare_drive(path: &Path, drive: &DriveConfig) -> Result<(), String> {
+    let Some(size) = &drive.size else {
+        return Ok(());
+    };
+    if path.exists() && !drive.fresh {
+        return Ok(());
+    }
+    let size = parse_size(size)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+    if drive.format == "raw" {
+        let file = std::fs::File::create(path)
+            .map_err(|err| format!("failed to create {}: {}", path.display(), err))?;
+        return file
+            .set_len(size)
+            .map_err(|err| format!("failed to resize {}: {}", path.display(), err));
+    }
+    let _ = std::fs::remove_file(path);
+    let status = Command::new("qemu-img")
+        .args(["create", "-q", "-f", &drive.format])
+        .arg(path)
+        .arg(size.to_string())
+        .status()
+        .map_err(|err| format!("failed to run qemu-img: {}", err))?;
+    if !status.success() {
+        return Err(format!(
+            "qemu-img failed to create {}: {}",
+            path.display(),
+            status
+        ));
+    }
+    Ok(())
+}
+
+/// The QEMU arguments that attach the drive at `path`, `id` identifies it
+pub fn drive_args(path: &Path, drive: &DriveConfig, id: &str) -> Vec<String> {
+    // Commas are escaped by doubling them in QEMU options
+    let file = format!(
+        "file={},format={}",
+        path.to_string_lossy().replace(',', ",,"),
+        drive.format
+    );
+    match drive.interface {
+        DriveInterface::Virtio => vec![
+            "-drive".to_string(),
+            format!("{},if=none,id={}", file, id),
+            "-device".to_string(),
+            format!("virtio-blk-pci,drive={}", id),
+        ],
+        DriveInterface::Ide => vec!["-drive".to_string(), format!("{},if=ide", file)],
+        DriveInterface::Nvme => vec![
+            "-drive".to_string(),
+            format!("{},if=none,id={}", file, id),
+            "-device".to_string(),
+            format!("nvme,serial={},drive={}", id, id),
+        ],
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("64M"), Ok(64 << 20));
+    assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+    assert!(parse_size("64X").is_err());
+    assert!(parse_size("M").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_prepare_drive() {
+    let path = std::env::temp_dir().join(format!(
+        "image-runner-drive-{}/scratch.img",
+        std::process::id()
+    ));
+    let mut drive = DriveConfig {
+        path: "scratch.img".to_string(),
+        format: "raw".to_string(),
+        interface: DriveInterface::Nvme,
+        size: Some("1M".to_string()),
+        fresh: false,
+    };
+    prepare_drive(&path, &drive).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 1 << 20);
+
+    // An existing image is kept, unless it is fresh
+    std::fs::write(&path, "data").unwrap();
+    prepare_drive(&path, &drive).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"data");
+    drive.fresh = true;
+    prepare_drive(&path, &drive).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 1 << 20);
+
+    assert_eq!(
+        drive_args(&path, &drive, "drive0")[3],
+        "nvme,serial=drive0,drive=drive0"
+    );
+    drive.interface = DriveInterface::Virtio;
+    assert_eq!(
+        drive_args(&path, &drive, "drive1")[3],
+        "virtio-blk-pci,drive=drive1"
+    );
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,81 @@
+use std::path::Path;
+
+/// The prefix of environment variables that override the configuration
+pub const ENV_PREFIX: &str = "CARGO_IMAGE_RUNNER_";
+
+/// The files in the workspace root that are checked for overrides, the first one found is used
+const ENV_FILES: &[&str] = &["image-runner.env", ".env"];
+
+/// An override of the configuration from the environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvOverride {
+    /// A configuration value, e.g. `CARGO_IMAGE_RUNNER_BOOT_TYPE` is `boot_type`
+    Config(String, String),
+    /// A variable, e.g. `CARGO_IMAGE_RUNNER_VAR_MACHINE` is `MACHINE`, its case is kept
+    Var(String, String),
+}
+
+/// Parses the `KEY=VALUE` lines of an env file, ignoring comments and blank lines
+fn parse_env_file(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn to_override(key: &str, value: String) -> Option<EnvOverride> {
+    let key = key.strip_prefix(ENV_PREFIX)?;
+    Some(match key.strip_prefix("VAR_") {
+        Some(var) => EnvOverride::Var(var.to_string(), value),
+        None => EnvOverride::Config(key.to_lowercase(), value),
+    })
+}
+
+/// Collects the overrides from the env file in the workspace root, followed by the ones from the
+/// environment, so that the environment takes precedence
+pub fn env_overrides(root_dir: &Path) -> Vec<EnvOverride> {
+    let file_vars = ENV_FILES
+        .iter()
+        .find_map(|file| std::fs::read_to_string(root_dir.join(file)).ok())
+        .map(|contents| parse_env_file(&contents))
+        .unwrap_or_default();
+
+    file_vars
+        .into_iter()
+        .chain(std::env::vars())
+        .filter_map(|(key, value)| to_override(&key, value))
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_env_file() {
+    let contents = "# local settings\n\
+        CARGO_IMAGE_RUNNER_BOOT_TYPE=uefi\n\
+        export CARGO_IMAGE_RUNNER_VAR_MACHINE=\"pc\"\n\
+        \n\
+        OTHER_TOOL=1\n";
+    let overrides: Vec<_> = parse_env_file(contents)
+        .into_iter()
+        .filter_map(|(key, value)| to_override(&key, value))
+        .collect();
+    assert_eq!(
+        overrides,
+        vec![
+            EnvOverride::Config("boot_type".to_string(), "uefi".to_string()),
+            EnvOverride::Var("MACHINE".to_string(), "pc".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,129 @@
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+use crate::qmp::QmpClient;
+
+/// An action the guest asks the runner to take, by printing a line that starts with the prefix
+/// of [`crate::config::HostActionsConfig`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostAction {
+    /// Saves a screenshot over QMP, named by the guest or otherwise numbered
+    Screenshot(Option<String>),
+    /// Notes that the guest reached a point of the run
+    Checkpoint(String),
+    /// Enables or disables the QEMU trace events that match a pattern, all of them by default
+    Trace { enable: bool, events: String },
+    /// Adds a line to the log
+    Annotate(String),
+}
+
+impl HostAction {
+    /// Parses `line`, which is `None` if it doesn't start with `prefix`
+    pub fn parse(line: &[u8], prefix: &str) -> Option<Result<Self, String>> {
+        let line = String::from_utf8_lossy(line);
+        let request = line.strip_prefix(prefix)?.trim();
+        let (action, argument) = request.split_once(' ').unwrap_or((request, ""));
+        let argument = argument.trim();
+        let events = || match argument {
+            "" => "*".to_string(),
+            events => events.to_string(),
+        };
+        Some(match action {
+            "screenshot" => Ok(Self::Screenshot(
+                (!argument.is_empty()).then(|| argument.to_string()),
+            )),
+            "checkpoint" if !argument.is_empty() => Ok(Self::Checkpoint(argument.to_string())),
+            "trace-start" => Ok(Self::Trace {
+                enable: true,
+                events: events(),
+            }),
+            "trace-stop" => Ok(Self::Trace {
+                enable: false,
+                events: events(),
+            }),
+            "annotate" => Ok(Self::Annotate(argument.to_string())),
+            _ => Err(format!("unknown host action {:?}", request)),
+        })
+    }
+}
+
+/// Takes the actions the guest asks for
+#[derive(Debug, Clone)]
+pub struct HostActionRunner {
+    pub qmp_port: Option<u16>,
+    /// Where screenshots are saved
+    pub screenshot_dir: PathBuf,
+    screenshots: usize,
+}
+
+impl HostActionRunner {
+    pub fn new(qmp_port: Option<u16>, screenshot_dir: PathBuf) -> Self {
+        Self {
+            qmp_port,
+            screenshot_dir,
+            screenshots: 0,
+        }
+    }
+
+    /// Takes `action`, returning the line it adds to the log
+    pub fn perform(&mut self, action: &HostAction) -> Result<String, String> {
+        match action {
+            HostAction::Screenshot(name) => {
+                let mut client = self.qmp("screenshots")?;
+                std::fs::create_dir_all(&self.screenshot_dir).map_err(|err| err.to_string())?;
+                self.screenshots += 1;
+                let name = match name {
+                    Some(name) => name.replace(['/', '\\'], "_"),
+                    None => format!("screenshot-{}", self.screenshots),
+                };
+                let path = self.screenshot_dir.join(format!("{}.ppm", name));
+                client.screendump(&path.to_string_lossy())?;
+                Ok(format!("saved a screenshot to {}", path.display()))
+            }
+            HostAction::Checkpoint(name) => Ok(format!("checkpoint {}", name)),
+            HostAction::Trace { enable, events } => {
+                self.qmp("tracing")?
+                    .trace_event_set_state(events, *enable)?;
+                let state = if *enable { "enabled" } else { "disabled" };
+                Ok(format!("{} the trace events {}", state, events))
+            }
+            HostAction::Annotate(text) => Ok(text.clone()),
+        }
+    }
+
+    fn qmp(&self, action: &str) -> Result<QmpClient<BufReader<TcpStream>, TcpStream>, String> {
+        let port = self
+            .qmp_port
+            .ok_or_else(|| format!("{} need qmp-port to be set", action))?;
+        QmpClient::connect(("127.0.0.1", port))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_host_action() {
+    let parse = |line: &str| HostAction::parse(line.as_bytes(), "@image-runner:");
+    assert_eq!(parse("booting"), None);
+    assert_eq!(
+        parse("@image-runner: screenshot boot-menu"),
+        Some(Ok(HostAction::Screenshot(Some("boot-menu".to_string()))))
+    );
+    assert_eq!(
+        parse("@image-runner: screenshot"),
+        Some(Ok(HostAction::Screenshot(None)))
+    );
+    assert_eq!(
+        parse("@image-runner: trace-start virtio_*"),
+        Some(Ok(HostAction::Trace {
+            enable: true,
+            events: "virtio_*".to_string()
+        }))
+    );
+    assert_eq!(
+        parse("@image-runner: annotate phase 2 begins"),
+        Some(Ok(HostAction::Annotate("phase 2 begins".to_string())))
+    );
+    assert!(matches!(parse("@image-runner: checkpoint"), Some(Err(_))));
+    assert!(matches!(parse("@image-runner: reboot"), Some(Err(_))));
+}
@@ -1,209 +1,857 @@
-use std::fs::File;
-use std::hash::{DefaultHasher, Hasher};
-use std::io::Read;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
 
 use hadris_iso::{
     BootEntryOptions, BootOptions, BootSectionOptions, EmulationType, FileInput, FileInterchange,
     FormatOptions, IsoImage, PartitionOptions, PlatformId, Strictness,
 };
 
-#[allow(clippy::too_many_arguments)]
-pub fn prepare_iso(
-    root_dir: &PathBuf,
-    iso_root: &PathBuf,
-    iso_path: &PathBuf,
-    target_exe_path: &PathBuf,
-    target_dst_path: &Path,
-    config_path: &PathBuf,
-    extra_files: &[String],
-    limine_branch: &str,
-    cmdline: &str,
-) {
+use crate::bootloader::{BootloaderFiles, FileEntry};
+use crate::config::{Emulation, IsoConfig, Platform, substitute_templates};
+use crate::diagnostics::Diagnostics;
+use crate::manifest::{Manifest, ManifestOptions, sha256, sha256_file};
+
+/// hadris-iso only names no-emulation, the other modes use their boot media type from the El Torito
+/// specification
+fn emulation_type(emulation: Emulation) -> EmulationType {
+    match emulation {
+        Emulation::None => EmulationType::NoEmulation,
+        Emulation::Floppy12M => EmulationType::Unknown(1),
+        Emulation::Floppy144M => EmulationType::Unknown(2),
+        Emulation::Floppy288M => EmulationType::Unknown(3),
+        Emulation::HardDisk => EmulationType::Unknown(4),
+    }
+}
+
+fn platform_id(platform: Platform) -> PlatformId {
+    match platform {
+        Platform::X86 => PlatformId::X80X86,
+        Platform::PowerPC => PlatformId::PowerPC,
+        Platform::Mac => PlatformId::Macintosh,
+        Platform::Uefi => PlatformId::UEFI,
+    }
+}
+
+/// The files staged into the root of the image
+pub struct StageFiles<'a> {
+    pub root_dir: &'a Path,
+    /// The executable, with its destination in the image
+    pub kernel: FileEntry,
+    /// The bootloader config file, which is processed before being copied
+    pub config_file: Option<FileEntry>,
+    pub extra_files: &'a [String],
+    /// Additional files that are not relative to the root directory, e.g. payloads
+    pub extra_entries: &'a [FileEntry],
+    pub bootloader_files: &'a BootloaderFiles,
+    pub cmdline: &'a str,
+    /// Variables substituted as `{{name}}` in the config file
+    pub vars: &'a HashMap<String, String>,
+    /// Generates a manifest of the staged files into the image
+    pub manifest: Option<&'a ManifestOptions>,
+    /// Reports how long copying the files took
+    pub verbose: bool,
+}
+
+/// Stages the files into `image_root`, returns whether any of the files changed
+pub fn stage_files(image_root: &Path, files: &StageFiles, diagnostics: &Diagnostics) -> bool {
     let mut files_changed = false;
 
-    let root_dir = PathBuf::from(root_dir);
-    std::fs::create_dir_all(iso_root).unwrap();
+    let root_dir = files.root_dir;
+    std::fs::create_dir_all(image_root).unwrap();
 
-    let target_dst_path = iso_root.join(target_dst_path.file_name().unwrap());
-    if !is_file_equal(target_exe_path, &target_dst_path) {
-        files_changed = true;
-        std::fs::copy(target_exe_path, &target_dst_path).unwrap_or_else(|_| {
-            panic!("failed to copy file {}", target_exe_path.to_string_lossy())
-        });
+    let mut entries = vec![files.kernel.clone()];
+    entries.extend(files.config_file.iter().cloned());
+    entries.extend(
+        files
+            .extra_files
+            .iter()
+            .map(|file| FileEntry::new(root_dir.join(file), file)),
+    );
+    entries.extend(files.extra_entries.iter().cloned());
+    entries.extend(files.bootloader_files.files.iter().cloned());
+    if let Err(err) = check_destinations(&mut entries) {
+        panic!("invalid file in the image root: {}", err);
     }
+    let manifest_dest = files.manifest.map(|manifest| {
+        let dest = normalize_dest(&manifest.dest)
+            .unwrap_or_else(|err| panic!("invalid manifest destination: {}", err));
+        let key = dest.to_string_lossy().to_lowercase();
+        if let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.dest.to_string_lossy().to_lowercase() == key)
+        {
+            panic!(
+                "{} is copied to {}, where the manifest is generated",
+                entry.source.display(),
+                dest.display()
+            );
+        }
+        dest
+    });
+    let dests: Vec<PathBuf> = entries.iter().map(|entry| entry.dest.clone()).collect();
+    let (kernel, entries) = entries.split_first().unwrap();
+    let (config_file, entries) = match files.config_file {
+        Some(_) => (entries.first(), &entries[1..]),
+        None => (None, entries),
+    };
 
-    let config_dest_path = iso_root.join(config_path.strip_prefix(&root_dir).unwrap());
-    if !is_file_equal(config_path, &config_dest_path) {
+    let cache_path = image_root.with_extension("hashes");
+    let mut cache = StageCache::load(&cache_path);
+    let mut stats = CopyStats::default();
+
+    let target_exe_path = &kernel.source;
+    let target_dst_path = image_root.join(&kernel.dest);
+    if !cache.is_up_to_date(target_exe_path, &kernel.dest, &target_dst_path) {
         files_changed = true;
+        if let Some(parent) = target_dst_path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        stats
+            .copy(target_exe_path, &target_dst_path)
+            .unwrap_or_else(|_| {
+                panic!("failed to copy file {}", target_exe_path.to_string_lossy())
+            });
+        cache.record(target_exe_path, &kernel.dest);
+    }
+
+    if let Some(config_file) = config_file {
+        let config_path = &config_file.source;
+        let config_dest_path = image_root.join(&config_file.dest);
         // We need to format the contents of the config file with the
         // executable name
         let mut config_file_contents = std::fs::read_to_string(config_path).unwrap();
-        config_file_contents = config_file_contents.replace(
-            "{{BINARY_NAME}}",
-            &target_dst_path.file_name().unwrap().to_string_lossy(),
-        );
-        config_file_contents = config_file_contents.replace("{{CMDLINE}}", cmdline);
-        std::fs::write(config_dest_path, config_file_contents).unwrap();
+        config_file_contents =
+            config_file_contents.replace("{{BINARY_NAME}}", &kernel.dest.to_string_lossy());
+        config_file_contents = config_file_contents.replace("{{CMDLINE}}", files.cmdline);
+        config_file_contents = substitute_templates(&config_file_contents, files.vars);
+        for (line, name) in find_placeholders(&config_file_contents) {
+            diagnostics.warn(
+                "unknown-template-variable",
+                format!(
+                    "{}:{}: unknown template variable {{{{{}}}}}",
+                    config_path.display(),
+                    line,
+                    name
+                ),
+            );
+        }
+        // The processed contents are compared, as they always differ from the template
+        let staged = std::fs::read_to_string(&config_dest_path).ok();
+        if staged.as_deref() != Some(config_file_contents.as_str()) {
+            files_changed = true;
+            if let Some(parent) = config_dest_path.parent() {
+                std::fs::create_dir_all(parent).unwrap();
+            }
+            std::fs::write(config_dest_path, config_file_contents).unwrap();
+        }
     }
 
-    for file in extra_files.iter() {
-        let file_path = root_dir.join(file);
-        let file_dest_path = iso_root.join(file);
-        if !is_file_equal(&file_path, &file_dest_path) {
+    // The extra files, payloads and bootloader files are copied as is
+    for file in entries.iter() {
+        let file_dest_path = image_root.join(&file.dest);
+        if !cache.is_up_to_date(&file.source, &file.dest, &file_dest_path) {
+            if let Some(parent) = file_dest_path.parent() {
+                std::fs::create_dir_all(parent).unwrap();
+            }
+            #[cfg(unix)]
+            make_writable(&file_dest_path);
+            stats
+                .copy(&file.source, &file_dest_path)
+                .unwrap_or_else(|_| panic!("failed to copy file {}", file.source.display()));
+            cache.record(&file.source, &file.dest);
             files_changed = true;
-            std::fs::copy(&file_path, file_dest_path)
-                .unwrap_or_else(|_| panic!("failed to copy file {}", file_path.display()));
+        }
+        // The mode only matters for directory images, an ISO has no permissions
+        #[cfg(unix)]
+        if let Some(mode) = file.attributes.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&file_dest_path, std::fs::Permissions::from_mode(mode))
+                .unwrap_or_else(|_| panic!("failed to set permissions of {}", file.dest.display()));
         }
     }
 
-    let plain_iso_file = std::path::Path::new(iso_path)
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap();
+    // Files that were staged before but aren't anymore would still be shipped
+    let mut keep = dests.clone();
+    keep.extend(manifest_dest.clone());
+    if remove_stale_files(image_root, &keep, &mut cache) {
+        files_changed = true;
+    }
 
-    let limine_sys_file;
-    let limine_bios_cd_file;
-    let limine_uefi_cd_file;
-    if limine_branch.split_once('-').unwrap().0 == "v4.x" {
-        limine_sys_file = "limine.sys";
-        limine_bios_cd_file = "limine-cd.bin";
-        limine_uefi_cd_file = "limine-cd-efi.bin";
-    } else {
-        limine_sys_file = "limine-bios.sys";
-        limine_bios_cd_file = "limine-bios-cd.bin";
-        limine_uefi_cd_file = "limine-uefi-cd.bin";
+    // The manifest hashes the staged files, so processed files are recorded as shipped
+    if let (Some(manifest), Some(dest)) = (files.manifest, manifest_dest) {
+        let contents = Manifest::generate(image_root, &dests, manifest.build.clone()).to_json();
+        let manifest_path = image_root.join(dest);
+        if std::fs::read_to_string(&manifest_path).ok().as_deref() != Some(contents.as_str()) {
+            if let Some(parent) = manifest_path.parent() {
+                std::fs::create_dir_all(parent).unwrap();
+            }
+            std::fs::write(&manifest_path, contents).expect("failed to write the manifest");
+            files_changed = true;
+        }
     }
 
-    // TODO: Make proper
+    cache.save(&cache_path);
+    if files.verbose && stats.files > 0 {
+        eprintln!("Staged {}", stats.summary());
+    }
+    files_changed
+}
 
-    let limine_dir = root_dir.join("target/image-runner/limine");
-    if !limine_dir.join(format!("{}_done", plain_iso_file)).exists() {
-        std::fs::copy(
-            limine_dir.join(limine_sys_file),
-            iso_root.join(limine_sys_file),
-        )
-        .unwrap_or_else(|_| {
-            panic!(
-                "failed to copy file {}",
-                limine_dir.join(limine_sys_file).to_string_lossy()
-            )
-        });
-        std::fs::copy(
-            limine_dir.join(limine_bios_cd_file),
-            iso_root.join(limine_bios_cd_file),
-        )
-        .unwrap_or_else(|_| {
-            panic!(
-                "failed to copy file {}",
-                limine_dir.join(limine_bios_cd_file).to_string_lossy()
-            )
-        });
-        std::fs::copy(
-            limine_dir.join(limine_uefi_cd_file),
-            iso_root.join(limine_uefi_cd_file),
+/// Removes the files in `image_root` that aren't in `keep`, e.g. one that was removed from
+/// `extra-files`, so it isn't shipped in the image anymore, returns whether any were removed
+fn remove_stale_files(image_root: &Path, keep: &[PathBuf], cache: &mut StageCache) -> bool {
+    let mut staged = Vec::new();
+    walk_dir(image_root, Path::new(""), &mut staged);
+    let mut removed = false;
+    for (path, dest) in staged {
+        if keep.contains(&dest) {
+            continue;
+        }
+        std::fs::remove_file(&path)
+            .unwrap_or_else(|err| panic!("failed to remove {}: {}", path.display(), err));
+        cache.files.remove(dest.to_string_lossy().as_ref());
+        removed = true;
+        // The directories it leaves empty are removed too, removing one that isn't empty fails
+        let mut dir = path.parent();
+        while let Some(parent) = dir
+            && parent != image_root
+            && std::fs::remove_dir(parent).is_ok()
+        {
+            dir = parent.parent();
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+#[test]
+fn test_remove_stale_files() {
+    let root = std::env::temp_dir().join(format!("image-runner-stale-{}", std::process::id()));
+    for file in ["kernel", "boot/limine.conf", "extra/old/file"] {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, file).unwrap();
+    }
+    let mut cache = StageCache::default();
+    let keep = [PathBuf::from("kernel"), PathBuf::from("boot/limine.conf")];
+    assert!(remove_stale_files(&root, &keep, &mut cache));
+    let extra_removed = !root.join("extra").exists();
+    assert!(!remove_stale_files(&root, &keep, &mut cache));
+    let kept = root.join("boot/limine.conf").is_file();
+    std::fs::remove_dir_all(&root).unwrap();
+    assert!(extra_removed);
+    assert!(kept);
+}
+
+/// Lets the staged file at `path` be overwritten, which a read-only `mode` of its entry prevents
+#[cfg(unix)]
+fn make_writable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = path.metadata()
+        && meta.permissions().readonly()
+    {
+        let mode = meta.permissions().mode() | 0o200;
+        let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode));
+    }
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn test_make_writable() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!("image-runner-mode-{}", std::process::id()));
+    std::fs::write(&path, "v1").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).unwrap();
+    make_writable(&path);
+    let written = std::fs::write(&path, "v2");
+    std::fs::remove_file(&path).unwrap();
+    written.unwrap();
+}
+
+/// Totals of the files copied while staging
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CopyStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl CopyStats {
+    /// Copies `source` to `dest`, adding it to the totals
+    ///
+    /// This uses [`std::fs::copy`], which copies within the kernel where possible (e.g. with
+    /// `copy_file_range` on Linux), so it is faster than a copy through userspace buffers.
+    pub fn copy(&mut self, source: &Path, dest: &Path) -> std::io::Result<u64> {
+        let start = Instant::now();
+        let bytes = std::fs::copy(source, dest)?;
+        self.files += 1;
+        self.bytes += bytes;
+        self.elapsed += start.elapsed();
+        Ok(bytes)
+    }
+
+    /// Describes the totals, e.g. `3 files (12.0 MiB) in 0.020s (600.0 MiB/s)`
+    pub fn summary(&self) -> String {
+        let mib = self.bytes as f64 / (1024.0 * 1024.0);
+        let secs = self.elapsed.as_secs_f64();
+        let throughput = if secs > 0.0 {
+            format!("{:.1} MiB/s", mib / secs)
+        } else {
+            "instant".to_string()
+        };
+        format!(
+            "{} file{} ({:.1} MiB) in {:.3}s ({})",
+            self.files,
+            if self.files == 1 { "" } else { "s" },
+            mib,
+            secs,
+            throughput
         )
-        .unwrap_or_else(|_| {
-            panic!(
-                "failed to copy file {}",
-                limine_dir.join(limine_uefi_cd_file).to_string_lossy()
-            )
-        });
-        files_changed = true;
     }
+}
 
-    let boot_dir = iso_root.join("EFI/BOOT");
-    if !boot_dir.exists() {
-        std::fs::create_dir_all(&boot_dir).unwrap();
-        files_changed = true;
+/// The size and modification time of a source file, which are cheap to check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    mtime: u128,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let meta = path.metadata().ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: meta.len(),
+            mtime: mtime.as_nanos(),
+        })
     }
-    // TODO: Support other platforms
-    let uefi_img_dest_path = boot_dir.join("BOOTX64.EFI");
-    let uefi_img_src_path = limine_dir.join("BOOTX64.EFI");
-    if !is_file_equal(&uefi_img_src_path, &uefi_img_dest_path) {
-        std::fs::copy(&uefi_img_src_path, uefi_img_dest_path)
-            .unwrap_or_else(|_| panic!("failed to copy file {}", uefi_img_src_path.display()));
-        files_changed = true;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedFile {
+    stamp: FileStamp,
+    sha256: String,
+}
+
+/// The sources of the staged files at the time they were copied, keyed by their destination,
+/// so unchanged files are detected without hashing them on every run
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StageCache {
+    files: HashMap<String, CachedFile>,
+    /// The hash of the options the image was last built with, see [`StageCache::update_options`]
+    #[serde(default)]
+    options: Option<String>,
+}
+
+impl StageCache {
+    fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|cache| serde_json::from_str(&cache).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) {
+        let cache = serde_json::to_string(self).unwrap();
+        std::fs::write(path, cache).expect("failed to write the staging cache");
+    }
+
+    /// Whether the staged file at `staged` is a copy of `source`
+    fn is_up_to_date(&mut self, source: &Path, dest: &Path, staged: &Path) -> bool {
+        let (Some(stamp), Ok(staged_meta)) = (FileStamp::of(source), staged.metadata()) else {
+            return false;
+        };
+        if staged_meta.len() != stamp.size {
+            return false;
+        }
+        let key = dest.to_string_lossy().into_owned();
+        if let Some(cached) = self.files.get_mut(&key) {
+            if cached.stamp == stamp {
+                return true;
+            }
+            // The source was touched, e.g. by a rebuild, but its contents may not have changed
+            let Ok((_, sha256)) = sha256_file(source) else {
+                return false;
+            };
+            if sha256 != cached.sha256 {
+                return false;
+            }
+            cached.stamp = stamp;
+            return true;
+        }
+        // Nothing is cached, e.g. on the first run, so the staged file itself is compared
+        match (sha256_file(source), sha256_file(staged)) {
+            (Ok((_, source_hash)), Ok((_, staged_hash))) if source_hash == staged_hash => {
+                self.files.insert(
+                    key,
+                    CachedFile {
+                        stamp,
+                        sha256: source_hash,
+                    },
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the hash of the options the image is built with in the cache at `path`, returns
+    /// whether they changed since the last build
+    fn update_options(path: &Path, options: String) -> bool {
+        let mut cache = Self::load(path);
+        if cache.options.as_ref() == Some(&options) {
+            return false;
+        }
+        cache.options = Some(options);
+        cache.save(path);
+        true
+    }
+
+    /// Records that `source` was copied to `dest`
+    fn record(&mut self, source: &Path, dest: &Path) {
+        let (Some(stamp), Ok((_, sha256))) = (FileStamp::of(source), sha256_file(source)) else {
+            return;
+        };
+        self.files.insert(
+            dest.to_string_lossy().into_owned(),
+            CachedFile { stamp, sha256 },
+        );
+    }
+}
+
+/// Stages the files into `iso_root` and builds the ISO, returns whether the ISO was rebuilt
+pub fn prepare_iso(
+    iso_root: &Path,
+    iso_path: &Path,
+    files: &StageFiles,
+    iso_config: &IsoConfig,
+    diagnostics: &Diagnostics,
+) -> bool {
+    let files_changed = stage_files(iso_root, files, diagnostics);
+    let bootloader_files = files.bootloader_files;
+    let bios_boot_image = bootloader_files
+        .bios_boot_image
+        .as_ref()
+        .map(|path| path.to_string_lossy());
+    let uefi_boot_image = bootloader_files
+        .uefi_boot_image
+        .as_ref()
+        .map(|path| path.to_string_lossy());
+    // The boot catalogue depends on the options too, not only on the staged files
+    let options = serde_json::to_string(&(iso_config, &bios_boot_image, &uefi_boot_image)).unwrap();
+    let options_changed = StageCache::update_options(
+        &iso_root.with_extension("hashes"),
+        sha256(options.as_bytes()),
+    );
+    if !files_changed && !options_changed && iso_path.exists() {
+        eprintln!("No files changed, skipping iso creation");
+        return false;
     }
 
-    if !files_changed {
-        println!("No files changed, skipping iso creation");
-        return;
+    if let Err(err) = build_iso(
+        iso_root,
+        iso_path,
+        &IsoOptions {
+            volume_name: "LIMINE",
+            bios_boot_image: bios_boot_image.as_deref(),
+            uefi_boot_image: uefi_boot_image.as_deref(),
+            config: iso_config,
+        },
+    ) {
+        panic!("{}", err);
     }
+    true
+}
+
+/// Options for building an ISO image with [`build_iso`]
+pub struct IsoOptions<'a> {
+    pub volume_name: &'a str,
+    /// The BIOS boot image, relative to the ISO root
+    pub bios_boot_image: Option<&'a str>,
+    /// The UEFI boot image, relative to the ISO root
+    pub uefi_boot_image: Option<&'a str>,
+    pub config: &'a IsoConfig,
+}
+
+/// Builds an ISO image at `iso_path` from the contents of `iso_root`
+///
+/// This is independent of the bootloader and the runner, so it can be used by other tools
+/// that already have a populated ISO root.
+pub fn build_iso(
+    iso_root: &Path,
+    iso_path: &Path,
+    options: &IsoOptions,
+) -> Result<PathBuf, String> {
+    let config = options.config;
+    for path in options
+        .bios_boot_image
+        .iter()
+        .chain(&options.uefi_boot_image)
+    {
+        check_boot_image_path(path)?;
+    }
+    let bios_entry = options.bios_boot_image.map(|path| BootEntryOptions {
+        emulation: emulation_type(config.bios.emulation),
+        load_size: config.bios.load_size.unwrap_or(4),
+        boot_image_path: path.to_string(),
+        // The boot info table is patched into the boot image, which would corrupt an emulated disk
+        boot_info_table: config.bios.emulation == Emulation::None,
+        grub2_boot_info: false,
+    });
+    let uefi_entry = options.uefi_boot_image.map(|path| BootEntryOptions {
+        emulation: emulation_type(config.uefi.emulation),
+        // 0 means the size of the file
+        load_size: config.uefi.load_size.unwrap_or(0),
+        boot_image_path: path.to_string(),
+        boot_info_table: false,
+        grub2_boot_info: false,
+    });
 
-    let entries = if cfg!(feature = "uefi") {
-        vec![(
+    let mut entries = Vec::new();
+    for entry in config.boot_entries.iter() {
+        check_boot_image_path(&entry.path)?;
+        if !iso_root.join(&entry.path).exists() {
+            return Err(format!(
+                "boot entry {} does not exist in the iso root, add it to extra-files",
+                entry.path
+            ));
+        }
+        entries.push((
             BootSectionOptions {
-                platform_id: PlatformId::UEFI,
+                platform_id: platform_id(entry.platform),
             },
             BootEntryOptions {
-                emulation: EmulationType::NoEmulation,
-                // 0 means the size of the file
-                load_size: 0,
-                boot_image_path: limine_uefi_cd_file.to_string(),
+                emulation: emulation_type(entry.options.emulation),
+                load_size: entry.options.load_size.unwrap_or(0),
+                boot_image_path: entry.path.clone(),
                 boot_info_table: false,
                 grub2_boot_info: false,
             },
-        )]
-    } else {
-        vec![]
+        ));
+    }
+
+    // El Torito needs a default entry, which is the BIOS entry if there is one. The default entry
+    // is always for x86, so a UEFI image also gets its own UEFI section for the firmware to find
+    let uefi_section = BootSectionOptions {
+        platform_id: PlatformId::UEFI,
     };
+    let default = match (bios_entry, uefi_entry) {
+        (Some(default), Some(uefi_entry)) => {
+            entries.insert(0, (uefi_section, uefi_entry));
+            Some(default)
+        }
+        (None, Some(uefi_entry)) => {
+            entries.insert(0, (uefi_section, uefi_entry.clone()));
+            Some(uefi_entry)
+        }
+        (Some(default), None) => Some(default),
+        // The first extra entry is also the default then, like a UEFI only image
+        (None, None) => entries.first().map(|(_, entry)| entry.clone()),
+    };
+    let boot = default.map(|default| BootOptions {
+        write_boot_catalogue: true,
+        default,
+        entries,
+    });
 
     let options = FormatOptions {
-        volume_name: "LIMINE".to_string(),
+        volume_name: options.volume_name.to_string(),
         strictness: Strictness::Strict,
-        files: FileInput::from_fs(iso_root.clone()).unwrap(),
+        files: FileInput::from_fs(iso_root.to_path_buf()).map_err(|err| {
+            format!(
+                "failed to read the iso root {}: {}",
+                iso_root.display(),
+                err
+            )
+        })?,
         // Only going to be used as CD/DVD boot, so we dont need MBR/GPT
         format: PartitionOptions::empty(),
         level: FileInterchange::NonConformant,
         system_area: None,
-        // We need to include the BIOS bootloader, because thats how El Torito boots
-        boot: Some(BootOptions {
-            write_boot_catalogue: true,
-            default: BootEntryOptions {
-                emulation: EmulationType::NoEmulation,
-                load_size: 4,
-                boot_image_path: limine_bios_cd_file.to_string(),
-                boot_info_table: true,
-                grub2_boot_info: false,
-            },
-            entries,
-        }),
+        boot,
     };
-    IsoImage::format_file(iso_path, options).unwrap();
+    IsoImage::format_file(iso_path, options)
+        .map_err(|err| format!("failed to build the iso {}: {}", iso_path.display(), err))?;
+    Ok(iso_path.to_path_buf())
 }
 
-fn hash_file(path: &PathBuf) -> Option<u64> {
-    let mut file = File::open(path).ok()?;
-    let mut hasher = DefaultHasher::new();
-    let mut buffer = [0; 8192]; // Larger buffer for better performance
+/// hadris-iso only looks up boot images in the root directory of the image
+fn check_boot_image_path(path: &str) -> Result<(), String> {
+    if path.contains(['/', '\\']) {
+        return Err(format!(
+            "boot image {} must be in the root of the iso, nested boot images aren't supported",
+            path
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_check_boot_image_path() {
+    assert!(check_boot_image_path("memtest.bin").is_ok());
+    assert!(check_boot_image_path("boot/memtest.bin").is_err());
+    assert!(check_boot_image_path("boot\\memtest.bin").is_err());
+}
 
-    loop {
-        match file.read(&mut buffer) {
-            Ok(0) => break, // EOF
-            Ok(n) => {
-                hasher.write(&buffer[..n]);
+/// Finds the `{{IDENT}}` placeholders left in a processed config file, with their line numbers
+fn find_placeholders(contents: &str) -> Vec<(usize, &str)> {
+    let mut placeholders = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let mut rest = line;
+        while let Some(start) = rest.find("{{") {
+            rest = &rest[start + 2..];
+            let Some(end) = rest.find("}}") else {
+                break;
+            };
+            let name = &rest[..end];
+            if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+            {
+                placeholders.push((i + 1, name));
+                rest = &rest[end + 2..];
             }
-            Err(_) => return None,
         }
     }
+    placeholders
+}
 
-    Some(hasher.finish())
+#[cfg(test)]
+#[test]
+fn test_find_placeholders() {
+    let contents = "timeout: {{TIMEOUT}}\ncmdline: {{ not a var }} {{CMDLINE}}\n{{unclosed";
+    assert_eq!(
+        find_placeholders(contents),
+        vec![(1, "TIMEOUT"), (2, "CMDLINE")]
+    );
 }
 
-fn is_file_equal(file1: &PathBuf, file2: &PathBuf) -> bool {
-    // Quick rejection, if the files do not both exist
-    if let (Ok(meta1), Ok(meta2)) = (file1.metadata(), file2.metadata()) {
-        if meta1.len() != meta2.len() {
-            // Again, quick rejection, if the files are different sizes
-            return false;
+/// Characters that are not allowed in Joliet file names, on top of control characters
+const ILLEGAL_CHARS: &[char] = &['*', ':', ';', '?', '"', '<', '>', '|'];
+
+/// Normalizes a destination path, so configs written on Windows produce the same image
+///
+/// Both `/` and `\` are treated as separators, and `.` components are removed.
+fn normalize_dest(dest: &Path) -> Result<PathBuf, String> {
+    let dest_str = dest.to_string_lossy();
+    let mut normalized = PathBuf::new();
+    for component in dest_str.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => return Err(format!("{} points outside of the image root", dest_str)),
+            name => {
+                if let Some(c) = name
+                    .chars()
+                    .find(|c| c.is_control() || ILLEGAL_CHARS.contains(c))
+                {
+                    return Err(format!(
+                        "{} contains the illegal character {:?}",
+                        dest_str, c
+                    ));
+                }
+                normalized.push(name);
+            }
         }
-        match (hash_file(file1), hash_file(file2)) {
-            (Some(hash1), Some(hash2)) => hash1 == hash2,
-            _ => false,
+    }
+    if normalized.as_os_str().is_empty() {
+        return Err(format!("{} is not a valid file name", dest_str));
+    }
+    Ok(normalized)
+}
+
+/// Normalizes the destination of every entry, and checks that no two entries are copied to the
+/// same destination
+///
+/// Destinations that only differ in case are rejected too, as they collide on FAT and Joliet.
+fn check_destinations(entries: &mut [FileEntry]) -> Result<(), String> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (i, entry) in entries.iter_mut().enumerate() {
+        entry.dest = normalize_dest(&entry.dest)?;
+        let key = entry.dest.to_string_lossy().to_lowercase();
+        if let Some(first) = seen.insert(key, i) {
+            let first = &entries[first];
+            let second = &entries[i];
+            return Err(format!(
+                "{} and {} are both copied to {}",
+                first.source.display(),
+                second.source.display(),
+                second.dest.display()
+            ));
         }
-    } else {
-        false
     }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_check_destinations() {
+    let mut entries = vec![
+        FileEntry::new("kernel", "kernel"),
+        FileEntry::new("limine.conf", "boot\\limine.conf"),
+        FileEntry::new("extra/limine.conf", "./boot/LIMINE.CONF"),
+    ];
+    assert!(check_destinations(&mut entries[..2]).is_ok());
+    assert_eq!(entries[1].dest, PathBuf::from("boot/limine.conf"));
+    let err = check_destinations(&mut entries).unwrap_err();
+    assert!(err.contains("extra/limine.conf"));
+
+    assert!(normalize_dest(Path::new("../kernel")).is_err());
+    assert!(normalize_dest(Path::new("boot/kernel?")).is_err());
+    assert!(normalize_dest(Path::new("./")).is_err());
+}
+
+/// Collects the files of the overlay directories, relative to `root_dir`, later overlays
+/// overriding the files of earlier ones
+///
+/// Overridden files are reported in `overrides`, as `(dest, earlier overlay, later overlay)`.
+pub fn overlay_entries(
+    root_dir: &Path,
+    overlays: &[String],
+    overrides: &mut Vec<(PathBuf, String, String)>,
+) -> Vec<FileEntry> {
+    let mut entries: Vec<(FileEntry, &String)> = Vec::new();
+    // Destinations are compared like check_destinations does, ignoring case
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for overlay in overlays {
+        let overlay_dir = root_dir.join(overlay);
+        if !overlay_dir.is_dir() {
+            panic!("overlay {} is not a directory", overlay_dir.display());
+        }
+        let mut files = Vec::new();
+        walk_dir(&overlay_dir, Path::new(""), &mut files);
+        for (source, dest) in files {
+            let key = dest.to_string_lossy().to_lowercase();
+            let entry = FileEntry::new(source, dest);
+            match seen.get(&key) {
+                Some(&i) => {
+                    overrides.push((entry.dest.clone(), entries[i].1.clone(), overlay.clone()));
+                    entries[i] = (entry, overlay);
+                }
+                None => {
+                    seen.insert(key, entries.len());
+                    entries.push((entry, overlay));
+                }
+            }
+        }
+    }
+    entries.into_iter().map(|(entry, _)| entry).collect()
+}
+
+/// Collects the files in `dir` recursively, with their path relative to it
+fn walk_dir(dir: &Path, prefix: &Path, files: &mut Vec<(PathBuf, PathBuf)>) {
+    let mut children: Vec<_> = std::fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", dir.display(), err))
+        .map(|entry| entry.unwrap())
+        .collect();
+    children.sort_by_key(|entry| entry.file_name());
+    for child in children {
+        let path = child.path();
+        let dest = prefix.join(child.file_name());
+        if path.is_dir() {
+            walk_dir(&path, &dest, files);
+        } else {
+            files.push((path, dest));
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_overlay_entries() {
+    let root = std::env::temp_dir().join(format!("image-runner-overlays-{}", std::process::id()));
+    for (file, contents) in [
+        ("base/etc/motd", "base"),
+        ("base/bin/init", "init"),
+        ("dev/etc/MOTD", "dev"),
+    ] {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    let mut overrides = Vec::new();
+    let entries = overlay_entries(
+        &root,
+        &["base".to_string(), "dev".to_string()],
+        &mut overrides,
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let dests: Vec<_> = entries.iter().map(|entry| entry.dest.clone()).collect();
+    assert_eq!(
+        dests,
+        [PathBuf::from("bin/init"), PathBuf::from("etc/MOTD")]
+    );
+    assert_eq!(entries[1].source, root.join("dev/etc/MOTD"));
+    assert_eq!(
+        overrides,
+        [(
+            PathBuf::from("etc/MOTD"),
+            "base".to_string(),
+            "dev".to_string()
+        )]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_stage_cache() {
+    let root = std::env::temp_dir().join(format!("image-runner-cache-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let source = root.join("kernel");
+    let staged = root.join("staged");
+    let dest = Path::new("kernel");
+    let touch = |path: &Path, secs: u64| {
+        let time = UNIX_EPOCH + Duration::from_secs(secs);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    };
+
+    std::fs::write(&source, "v1").unwrap();
+    touch(&source, 1);
+    let mut cache = StageCache::default();
+    assert!(!cache.is_up_to_date(&source, dest, &staged));
+    std::fs::copy(&source, &staged).unwrap();
+    cache.record(&source, dest);
+    assert!(cache.is_up_to_date(&source, dest, &staged));
+
+    // Rebuilt with the same contents
+    touch(&source, 2);
+    assert!(cache.is_up_to_date(&source, dest, &staged));
+    // Rebuilt with different contents of the same size
+    std::fs::write(&source, "v2").unwrap();
+    touch(&source, 3);
+    let up_to_date = cache.is_up_to_date(&source, dest, &staged);
+
+    // Changed options rebuild the image even if the files didn't change
+    let cache_path = root.join("hashes");
+    assert!(StageCache::update_options(&cache_path, "a".to_string()));
+    assert!(!StageCache::update_options(&cache_path, "a".to_string()));
+    assert!(StageCache::update_options(&cache_path, "b".to_string()));
+    std::fs::remove_dir_all(&root).unwrap();
+    assert!(!up_to_date);
+}
+
+#[cfg(test)]
+#[test]
+fn test_copy_stats_summary() {
+    let stats = CopyStats {
+        files: 3,
+        bytes: 12 * 1024 * 1024,
+        elapsed: Duration::from_millis(20),
+    };
+    assert_eq!(
+        stats.summary(),
+        "3 files (12.0 MiB) in 0.020s (600.0 MiB/s)"
+    );
 }
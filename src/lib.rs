@@ -0,0 +1,69 @@
+//! Building blocks of the cargo image runner
+//!
+//! The runner itself is the `cargo-image-runner` binary, but the bootloader and image
+//! building steps are exposed here so they can be reused by other tools.
+//! Without the `runner` feature, QEMU and OVMF support is left out and only images are built,
+//! see [`context::ContextBuilder::build_image_only`].
+
+#[cfg(feature = "runner")]
+pub mod boot_snapshot;
+pub mod bootloader;
+#[cfg(feature = "runner")]
+pub mod cases;
+#[cfg(feature = "runner")]
+pub mod check;
+#[cfg(feature = "runner")]
+pub mod compare;
+pub mod config;
+pub mod context;
+#[cfg(feature = "runner")]
+pub mod convert;
+#[cfg(feature = "runner")]
+pub mod coverage;
+#[cfg(feature = "runner")]
+pub mod debug;
+pub mod diagnostics;
+#[cfg(feature = "runner")]
+pub mod drive;
+pub mod env;
+#[cfg(feature = "runner")]
+pub mod host_action;
+pub mod iso;
+#[cfg(feature = "runner")]
+pub mod logfile;
+pub mod manifest;
+pub mod message;
+#[cfg(feature = "runner")]
+pub mod outcome;
+pub mod payload;
+pub mod qemu;
+#[cfg(feature = "runner")]
+pub mod qmp;
+pub mod requirements;
+pub mod sbom;
+pub mod scrub;
+#[cfg(feature = "runner")]
+pub mod serial;
+#[cfg(feature = "runner")]
+pub mod share;
+#[cfg(feature = "runner")]
+pub mod snapshot;
+pub mod staging;
+#[cfg(feature = "runner")]
+pub mod stream;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+/// The types that are needed to use the runner as a library or to implement a custom bootloader
+pub mod prelude {
+    pub use crate::bootloader::{Bootloader, BootloaderFiles, FileAttributes, FileEntry};
+    pub use crate::config::{
+        Accel, Arch, BootType, BootloaderKind, ImageFormat, ImageRunnerConfig,
+    };
+    pub use crate::context::{Context, ContextBuilder, Extensions};
+    pub use crate::diagnostics::Diagnostics;
+    #[cfg(feature = "runner")]
+    pub use crate::outcome::{CapturedOutput, FailureKind};
+    #[cfg(feature = "runner")]
+    pub use crate::stream::{RunController, RunEvent, RunEvents};
+}
@@ -0,0 +1,377 @@
+use regex::bytes::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::LogConfig;
+use crate::outcome::CapturedOutput;
+use crate::scrub::ScrubChain;
+use crate::serial::strip_ansi;
+
+/// Writes the serial output, what QEMU printed on stderr and the annotations of host actions to a
+/// log file while they are forwarded, so runs that are killed still leave a log
+///
+/// Each line is written once it ends, the lines of other channels than the serial port are
+/// prefixed with it, and the scrubbers are applied to each line.
+#[derive(Clone)]
+pub struct LogWriter {
+    inner: Arc<Mutex<LogState>>,
+}
+
+struct LogState {
+    path: PathBuf,
+    file: BufWriter<File>,
+    start: Instant,
+    timestamps: bool,
+    strip_ansi: bool,
+    scrubbers: ScrubChain,
+    index_threshold: Option<usize>,
+    markers: Vec<Regex>,
+    /// The start of the line each channel is in the middle of, and when it was received
+    partial: HashMap<&'static str, (Duration, Vec<u8>)>,
+    written: usize,
+    index: LogIndex,
+    /// The first error writing the log, nothing is written after it
+    error: Option<String>,
+}
+
+impl LogWriter {
+    /// Creates `<name>-<unix time>.log` in `dir`, and removes the oldest logs of `name` so only
+    /// `keep` of them are left
+    ///
+    /// The lines that match the `markers` are indexed, see [`LogIndex`].
+    pub fn create(
+        dir: &Path,
+        name: &str,
+        config: &LogConfig,
+        markers: Vec<Regex>,
+        scrubbers: &ScrubChain,
+    ) -> Result<Self, String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let path = dir.join(format!("{}-{}.log", name, now.as_millis()));
+        let file = File::create(&path)
+            .map_err(|err| format!("failed to create {}: {}", path.display(), err))?;
+        remove_old_logs(dir, name, config.keep)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(LogState {
+                path,
+                file: BufWriter::new(file),
+                start: Instant::now(),
+                timestamps: config.timestamps,
+                strip_ansi: config.strip_ansi,
+                scrubbers: scrubbers.clone(),
+                index_threshold: config.index_threshold,
+                markers,
+                partial: HashMap::new(),
+                written: 0,
+                index: LogIndex::default(),
+                error: None,
+            })),
+        })
+    }
+
+    /// Writes the lines of `bytes` that ended to the log, the rest once its line ends
+    pub fn write(&self, channel: &'static str, bytes: &[u8]) {
+        let mut state = self.inner.lock().unwrap();
+        let received = state.start.elapsed();
+        state.write(channel, bytes, received);
+    }
+
+    /// Writes an annotation of a host action as a line of its own
+    pub fn annotate(&self, annotation: &str) {
+        self.write(
+            CapturedOutput::ANNOTATIONS,
+            format!("{}\n", annotation).as_bytes(),
+        );
+    }
+
+    /// A writer that forwards to `inner` and also writes to the log as `channel`
+    pub fn tee<W: Write>(log: Option<LogWriter>, channel: &'static str, inner: W) -> LogTee<W> {
+        LogTee {
+            inner,
+            log,
+            channel,
+        }
+    }
+
+    /// Writes the lines that didn't end yet and the index if the log is large enough, returning
+    /// the path of the log
+    pub fn finish(&self) -> Result<PathBuf, String> {
+        let mut state = self.inner.lock().unwrap();
+        let mut partial: Vec<_> = state.partial.drain().collect();
+        partial.sort_by_key(|(_, (received, _))| *received);
+        for (channel, (received, line)) in partial {
+            state.write_line(channel, received, &line);
+        }
+        if let Err(err) = state.file.flush() {
+            state.fail(err);
+        }
+        if let Some(err) = &state.error {
+            return Err(err.clone());
+        }
+        if state
+            .index_threshold
+            .is_some_and(|threshold| state.written >= threshold)
+        {
+            write_index(&state.path, &state.index)?;
+        }
+        Ok(state.path.clone())
+    }
+}
+
+impl LogState {
+    fn write(&mut self, channel: &'static str, bytes: &[u8], received: Duration) {
+        for piece in bytes.split_inclusive(|&byte| byte == b'\n') {
+            let (_, line) = self
+                .partial
+                .entry(channel)
+                .or_insert_with(|| (received, Vec::new()));
+            line.extend_from_slice(piece);
+            if piece.ends_with(b"\n") {
+                let (received, line) = self.partial.remove(channel).unwrap();
+                self.write_line(channel, received, &line);
+            }
+        }
+    }
+
+    fn write_line(&mut self, channel: &str, received: Duration, line: &[u8]) {
+        let mut formatted = Vec::new();
+        if self.timestamps {
+            formatted.extend(format!("[{:>10.3}] ", received.as_secs_f64()).bytes());
+        }
+        if channel != CapturedOutput::SERIAL {
+            formatted.extend(format!("{}: ", channel).bytes());
+        }
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = if self.strip_ansi {
+            strip_ansi(line)
+        } else {
+            line.to_vec()
+        };
+        let contents = if self.scrubbers.is_empty() {
+            line.clone()
+        } else {
+            self.scrubbers
+                .scrub(&String::from_utf8_lossy(&line))
+                .into_bytes()
+        };
+        formatted.extend(&contents);
+
+        let offset = self.written;
+        while self.index.seconds.len() as u64 <= received.as_secs() {
+            self.index.seconds.push(offset);
+        }
+        // The markers are matched against the line the guest printed, without the timestamp and
+        // the prefix, so they can be anchored
+        for marker in self.markers.iter().filter(|marker| marker.is_match(&line)) {
+            self.index.markers.push(IndexedMarker {
+                marker: marker.as_str().to_string(),
+                offset,
+                received_ms: received.as_millis() as u64,
+                line: String::from_utf8_lossy(&contents).into_owned(),
+            });
+        }
+
+        formatted.push(b'\n');
+        self.written += formatted.len();
+        if self.error.is_none()
+            && let Err(err) = self.file.write_all(&formatted)
+        {
+            self.fail(err);
+        }
+    }
+
+    fn fail(&mut self, err: std::io::Error) {
+        self.error
+            .get_or_insert_with(|| format!("failed to write {}: {}", self.path.display(), err));
+    }
+}
+
+/// Forwards output and writes it to a log, see [`LogWriter::tee`]
+pub struct LogTee<W> {
+    inner: W,
+    log: Option<LogWriter>,
+    channel: &'static str,
+}
+
+impl<W: Write> Write for LogTee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(log) = &self.log {
+            log.write(self.channel, &buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where to seek in a large log, written next to it with the `.index.json` extension
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LogIndex {
+    /// The offset of the first line received in each second since QEMU started, or of the next
+    /// line if nothing was received in that second
+    pub seconds: Vec<usize>,
+    /// The lines that matched the markers, in order
+    pub markers: Vec<IndexedMarker>,
+}
+
+/// A line of the log that matched a marker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IndexedMarker {
+    /// The pattern of the marker
+    pub marker: String,
+    pub offset: usize,
+    /// When the line was received, in milliseconds since QEMU started
+    pub received_ms: u64,
+    pub line: String,
+}
+
+/// Writes `index` next to the log at `log_path`
+pub fn write_index(log_path: &Path, index: &LogIndex) -> Result<PathBuf, String> {
+    let path = log_path.with_extension("index.json");
+    std::fs::write(&path, serde_json::to_string(index).unwrap())
+        .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+    Ok(path)
+}
+
+/// Removes the oldest logs of `name` in `dir`, so only `keep` of them are left
+fn remove_old_logs(dir: &Path, name: &str, keep: usize) -> Result<(), String> {
+    let prefix = format!("{}-", name);
+    let mut logs: Vec<(u128, PathBuf)> = std::fs::read_dir(dir)
+        .map_err(|err| format!("failed to read {}: {}", dir.display(), err))?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let time = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(&prefix)?
+                .strip_suffix(".log")?
+                .parse()
+                .ok()?;
+            Some((time, path))
+        })
+        .collect();
+    logs.sort();
+    for (_, old) in logs.iter().take(logs.len().saturating_sub(keep)) {
+        let _ = std::fs::remove_file(old);
+        let _ = std::fs::remove_file(old.with_extension("index.json"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_log_writer() {
+    let dir = std::env::temp_dir().join(format!("image-runner-logs-{}", std::process::id()));
+    let mut config = LogConfig {
+        dir: None,
+        timestamps: true,
+        strip_ansi: true,
+        keep: 1,
+        index_threshold: None,
+        index_markers: vec![],
+    };
+    let write = |config: &LogConfig| {
+        let log =
+            LogWriter::create(&dir, "kernel", config, vec![], &ScrubChain::default()).unwrap();
+        {
+            let mut state = log.inner.lock().unwrap();
+            let serial = CapturedOutput::SERIAL;
+            state.write(
+                serial,
+                b"\x1b[32mboot\x1b[0m\r\n",
+                Duration::from_millis(100),
+            );
+            state.write(serial, b"o", Duration::from_millis(300));
+            state.write(
+                CapturedOutput::QEMU_STDERR,
+                b"warning\n",
+                Duration::from_millis(200),
+            );
+            state.write(serial, b"k\nhalt", Duration::from_millis(400));
+        }
+        std::fs::read_to_string(log.finish().unwrap()).unwrap()
+    };
+    assert_eq!(
+        write(&config),
+        "[     0.100] boot\n[     0.200] qemu-stderr: warning\n[     0.300] ok\n[     0.400] halt\n"
+    );
+    config.timestamps = false;
+    config.strip_ansi = false;
+    assert_eq!(
+        write(&config),
+        "\x1b[32mboot\x1b[0m\nqemu-stderr: warning\nok\nhalt\n"
+    );
+    // Only the newest log is kept
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_index_log() {
+    let dir = std::env::temp_dir().join(format!("image-runner-index-{}", std::process::id()));
+    let mut config = LogConfig {
+        dir: None,
+        timestamps: false,
+        strip_ansi: false,
+        keep: 1,
+        index_threshold: Some(0),
+        index_markers: vec![],
+    };
+    let markers = vec![Regex::new("start$").unwrap()];
+    let log = LogWriter::create(&dir, "kernel", &config, markers, &ScrubChain::default()).unwrap();
+    let index = {
+        let mut state = log.inner.lock().unwrap();
+        let serial = CapturedOutput::SERIAL;
+        state.write(serial, b"boot\n", Duration::from_millis(500));
+        state.write(serial, b"test_foo start\n", Duration::from_millis(2500));
+        state.write(serial, b"test_foo ok\n", Duration::from_millis(2700));
+        state.index.clone()
+    };
+    assert_eq!(index.seconds, [0, 5, 5]);
+    assert_eq!(
+        index.markers,
+        [IndexedMarker {
+            marker: "start$".to_string(),
+            offset: 5,
+            received_ms: 2500,
+            line: "test_foo start".to_string(),
+        }]
+    );
+    let path = log.finish().unwrap();
+    assert!(path.with_extension("index.json").is_file());
+
+    // Anchored markers still match with the timestamps in front of the lines
+    config.timestamps = true;
+    let markers = vec![Regex::new(r"^test \S+ start$").unwrap()];
+    let log = LogWriter::create(&dir, "kernel", &config, markers, &ScrubChain::default()).unwrap();
+    let index = {
+        let mut state = log.inner.lock().unwrap();
+        let serial = CapturedOutput::SERIAL;
+        state.write(serial, b"boot\n", Duration::from_millis(500));
+        state.write(serial, b"test foo start\n", Duration::from_millis(2500));
+        state.index.clone()
+    };
+    assert_eq!(
+        index.markers,
+        [IndexedMarker {
+            marker: r"^test \S+ start$".to_string(),
+            offset: 18,
+            received_ms: 2500,
+            line: "test foo start".to_string(),
+        }]
+    );
+    log.finish().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
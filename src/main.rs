@@ -1,14 +1,16 @@
-use bootloader::prepare_bootloader;
-use iso::prepare_iso;
+use cargo_image_runner::check::{CheckReport, check_config, check_environment};
+use cargo_image_runner::compare::{ComparedRun, compare_runs};
+use cargo_image_runner::config::{
+    BootType, CONFIG_FILES, ImageRunnerConfig, MessageFormat, PackageMetadata, RecordReplayConfig,
+    RecordReplayMode, default_config, expand_env_vars, merge_config, read_config_file,
+};
+use cargo_image_runner::context::{Context, NotRun, is_test_executable};
+use cargo_image_runner::convert::{ConvertFormat, convert_image};
+use cargo_image_runner::env::{ENV_PREFIX, EnvOverride, env_overrides};
+use cargo_image_runner::manifest::{ConvertedImage, Provenance, sha256_file};
+use clap::{Args, Parser, Subcommand};
 use std::path::{Path, PathBuf};
-use std::process::{Command, exit};
-
-mod bootloader;
-mod config;
-mod iso;
-use config::{BootType, PackageMetadata, default_config};
-
-use crate::config::ImageRunnerConfig;
+use std::process::exit;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
@@ -47,238 +49,783 @@ impl Value {
         (key.to_string(), value.into())
     }
 
-    pub fn as_string(self) -> Result<String, ()> {
+    pub fn as_string(self) -> Option<String> {
         match self {
-            Self::String(str) => Ok(str),
-            _ => Err(()),
+            Self::String(str) => Some(str),
+            _ => None,
         }
     }
 }
 
-struct ParseCtx {
-    config: ImageRunnerConfig,
-    target_src: PathBuf,
-    target_dst: PathBuf,
-    root_dir: PathBuf,
-    file_dir: PathBuf,
-    config_path: PathBuf,
-    is_test: bool,
-}
-
-impl ParseCtx {
-    pub fn new(config: ImageRunnerConfig, target_src: PathBuf, root_dir: PathBuf) -> ParseCtx {
-        #[cfg(not(feature = "bios"))]
-        if config.boot_type == BootType::Bios {
-            panic!("BIOS boot type is not supported, enable the `bios` feature for this crate");
+/// Overrides a single configuration value, from the command line or the environment, fails if
+/// `key` is neither a config value nor a variable
+fn apply_override(config: &mut ImageRunnerConfig, key: &str, v: Value) -> Result<(), String> {
+    match key {
+        "boot-type" | "boot_type" => {
+            let ty: BootType =
+                serde_plain::from_str(&v.as_string().expect("boot_type expects a string"))
+                    .expect("invalid boot_type");
+            config.boot_type = ty;
         }
-        #[cfg(not(feature = "uefi"))]
-        if config.boot_type == BootType::Uefi {
-            panic!("UEFI boot type is not supported, enable the `uefi` feature for this crate");
+        "limine-branch" | "limine_branch" => {
+            config.limine_branch = v.as_string().expect("limine_branch expects a string");
         }
+        "config-file" | "config_file" => {
+            config.config_file = v.as_string().expect("config_file expects a string");
+        }
+        "arch" => {
+            config.arch = serde_plain::from_str(&v.as_string().expect("arch expects a string"))
+                .expect("invalid arch");
+        }
+        "bootloader" => {
+            config.bootloader =
+                serde_plain::from_str(&v.as_string().expect("bootloader expects a string"))
+                    .expect("invalid bootloader");
+        }
+        "image-format" | "image_format" => {
+            config.image_format = Some(
+                serde_plain::from_str(&v.as_string().expect("image_format expects a string"))
+                    .expect("invalid image_format"),
+            );
+        }
+        "deny-warnings" | "deny_warnings" => {
+            config.deny_warnings = match v {
+                Value::Bool(deny) => deny,
+                Value::String(_) => panic!("deny_warnings expects a boolean"),
+            };
+        }
+        "direct-kernel" | "direct_kernel" => {
+            config.direct_kernel = match v {
+                Value::Bool(direct) => direct,
+                Value::String(_) => panic!("direct_kernel expects a boolean"),
+            };
+        }
+        "message-format" | "message_format" => {
+            config.message_format =
+                serde_plain::from_str(&v.as_string().expect("message_format expects a string"))
+                    .expect("invalid message_format");
+        }
+        "update-snapshots" | "update_snapshots" => {
+            config.test.update_snapshots = match v {
+                Value::Bool(update) => update,
+                Value::String(_) => panic!("update_snapshots expects a boolean"),
+            };
+        }
+        "expect-panic-pattern" | "expect_panic_pattern" => {
+            config.test.expect_panic_pattern = Some(
+                v.as_string()
+                    .expect("expect_panic_pattern expects a string"),
+            );
+        }
+        "accel" => {
+            config.accel = Some(
+                serde_plain::from_str(&v.as_string().expect("accel expects a string"))
+                    .expect("invalid accel"),
+            );
+        }
+        "verbose" => {
+            config.verbose = match v {
+                Value::Bool(verbose) => verbose,
+                Value::String(_) => panic!("verbose expects a boolean"),
+            };
+        }
+        "record" | "replay" => {
+            let mode = if key == "record" {
+                RecordReplayMode::Record
+            } else {
+                RecordReplayMode::Replay
+            };
+            config.record_replay = match v {
+                Value::Bool(true) => Some(RecordReplayConfig::new(mode)),
+                Value::Bool(false) => None,
+                Value::String(file) => Some(RecordReplayConfig { mode, file }),
+            };
+        }
+        "debug" => {
+            config.debug.enabled = match v {
+                Value::Bool(debug) => debug,
+                Value::String(_) => panic!("debug expects a boolean"),
+            };
+        }
+        "gdb" => {
+            config.debug.enabled = true;
+            config.debug.gdb = Some(v.as_string().expect("gdb expects a string"));
+        }
+        var if config.vars.contains_key(var) => {
+            config.vars.insert(
+                var.to_string(),
+                v.as_string().expect("variables should be strings"),
+            );
+        }
+        other => return Err(format!("{} is not a valid config value", other)),
+    }
+    Ok(())
+}
 
-        let file_dir = root_dir.join("target/image-runner");
-
-        let target_src = root_dir.join(target_src);
+/// Loads the config of the package being run, with the overrides from the environment and `args`,
+/// and for tests `test.overrides` below them
+///
+/// Also returns the errors of image-runner tables that could not be parsed, in which case the
+/// defaults are used.
+fn load_config(
+    args: Vec<(String, Value)>,
+    config_files: &[PathBuf],
+    is_test: bool,
+) -> (ImageRunnerConfig, PathBuf, PathBuf, PathBuf, Vec<String>) {
+    try_load_config(args, config_files, is_test).unwrap_or_else(|err| panic!("{}", err))
+}
 
-        let mut target_name = Path::new(&target_src)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap();
-
-        let mut is_test = false;
-        if let Some((start, end)) = target_name.rsplit_once('-') {
-            if u64::from_str_radix(end, 16).is_ok() {
-                target_name = start;
-                is_test = true;
-            }
-        }
+/// Loads the config like [`load_config`], but returns an error if there is no cargo project
+fn try_load_config(
+    args: Vec<(String, Value)>,
+    config_files: &[PathBuf],
+    is_test: bool,
+) -> Result<(ImageRunnerConfig, PathBuf, PathBuf, PathBuf, Vec<String>), String> {
+    let manifest_path = std::env::var("CARGO_MANIFEST_PATH").ok();
+    let pkg_name = std::env::var("CARGO_PKG_NAME").ok();
 
-        let target_dst = root_dir.join(target_name);
+    let mut cmd = cargo_metadata::MetadataCommand::new();
+    if let Some(manifest_path) = manifest_path {
+        cmd.manifest_path(manifest_path);
+    }
 
-        let config_path = root_dir.join(config.config_file.as_str());
+    let metadata = cmd
+        .exec()
+        .map_err(|err| format!("failed to read the cargo metadata: {}", err))?;
 
-        Self {
-            config,
-            target_src,
-            target_dst,
-            root_dir,
-            file_dir,
-            config_path,
-            is_test,
+    let package = match pkg_name {
+        Some(pkg_name) => Some(
+            metadata
+                .packages
+                .iter()
+                .find(|p| p.name == pkg_name)
+                .ok_or_else(|| format!("package {} is not in the workspace", pkg_name))?,
+        ),
+        // A virtual workspace has no root package, so only its metadata is used
+        None => metadata.root_package(),
+    };
+    let root_dir = metadata.workspace_root.as_std_path();
+
+    let mut package_metadata =
+        package.map_or(serde_json::Value::Null, |package| package.metadata.clone());
+    let mut workspace_metadata = metadata.workspace_metadata.clone();
+    for value in [&mut package_metadata, &mut workspace_metadata] {
+        if let Some(image_runner) = value.get_mut("image-runner") {
+            expand_env_vars(image_runner).unwrap_or_else(|err| panic!("{}", err));
         }
     }
 
-    fn prepare_bootloader(&self) {
-        prepare_bootloader(&self.config.limine_branch, &self.file_dir);
+    // A config file in the workspace root is layered over the metadata, for projects that
+    // prefer to keep the config out of Cargo.toml, followed by the files passed with --config
+    let mut config_errors = Vec::new();
+    let mut file_configs = Vec::new();
+    let root_config_file = CONFIG_FILES
+        .iter()
+        .map(|file| root_dir.join(file))
+        .find(|path| path.is_file());
+    if let Some(path) = root_config_file {
+        match read_config_file(&path) {
+            Ok(file_config) => file_configs.push(file_config),
+            Err(err) => config_errors.push(err),
+        }
+    }
+    for path in config_files {
+        file_configs.push(read_config_file(path).unwrap_or_else(|err| panic!("{}", err)));
+    }
+    for mut file_config in file_configs {
+        expand_env_vars(&mut file_config).unwrap_or_else(|err| panic!("{}", err));
+        if let Some(image_runner) = package_metadata.get_mut("image-runner") {
+            merge_config(image_runner, file_config.clone());
+        }
+        if !workspace_metadata.is_object() {
+            workspace_metadata = serde_json::json!({});
+        }
+        let image_runner = workspace_metadata
+            .as_object_mut()
+            .unwrap()
+            .entry("image-runner")
+            .or_insert_with(|| serde_json::json!({}));
+        merge_config(image_runner, file_config);
     }
 
-    fn prepare_iso(&mut self) {
-        let (iso_dir, iso_path) = if self.is_test {
-            let target_name = self.target_src.to_string_lossy();
-            let target_name = target_name.rsplit_once('/').unwrap().1;
-            let tests_dir = self.file_dir.join("tests");
-            let iso_path = tests_dir.join(format!("{}.iso", target_name));
-            let iso_dir = tests_dir.join(format!("{}_isoroot", target_name));
-            (iso_dir, iso_path)
-        } else {
-            let iso_path = self.file_dir.join("image.iso");
-            let iso_dir = self.file_dir.join("iso_root");
-            (iso_dir, iso_path)
-        };
+    // Metadata without an image-runner table falls through silently, but an invalid table is
+    // reported, as its values would otherwise be ignored
+    let mut parse = |value: serde_json::Value, source: &str| {
+        let present = value.get("image-runner").is_some();
+        serde_json::from_value::<PackageMetadata>(value)
+            .inspect_err(|err| {
+                if present {
+                    config_errors.push(format!("invalid {} metadata: {}", source, err));
+                }
+            })
+            .ok()
+    };
+    let mut data = parse(package_metadata, "package")
+        .or_else(|| parse(workspace_metadata, "workspace"))
+        .unwrap_or_else(default_config);
+
+    // The test overrides are part of the config, so those of the environment and the command
+    // line win over them
+    if is_test {
+        data.image_runner
+            .apply_test_overrides()
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
 
-        prepare_iso(
-            &self.root_dir,
-            &iso_dir,
-            &iso_path,
-            &self.target_src,
-            &self.target_dst,
-            &self.config_path,
-            &self.config.extra_files,
-            &self.config.limine_branch,
-            &self.config.cmdline,
-        );
-        for arg in self.config.run_command.iter_mut() {
-            *arg = arg.replace("{}", &iso_path.to_string_lossy());
-            for (k, v) in self.config.vars.iter() {
-                *arg = arg.replace(&format!("${}", k), v);
+    // Overrides from the environment (and the env file) are applied before the command line,
+    // other tools may use the same prefix, so unknown keys are only warned about
+    for env_override in env_overrides(root_dir) {
+        match env_override {
+            EnvOverride::Config(key, value) => {
+                if let Err(err) = apply_override(&mut data.image_runner, &key, value.into()) {
+                    config_errors.push(format!(
+                        "{}{} is set, but {}",
+                        ENV_PREFIX,
+                        key.to_uppercase(),
+                        err
+                    ));
+                }
             }
-        }
-        for arg in self.config.run_args.iter_mut() {
-            for (k, v) in self.config.vars.iter() {
-                *arg = arg.replace(&format!("${}", k), v);
+            EnvOverride::Var(var, value) => {
+                data.image_runner.vars.insert(var, value);
             }
         }
+    }
 
-        for arg in self.config.test_args.iter_mut() {
-            for (k, v) in self.config.vars.iter() {
-                *arg = arg.replace(&format!("${}", k), v);
-            }
-        }
+    // Parse CLI arguments are key-value pairs
+    for (k, v) in args {
+        apply_override(&mut data.image_runner, &k, v)
+            .unwrap_or_else(|err| panic!("{}, arguments should be in the form key=value", err));
     }
 
-    fn run(self) {
-        let run_cmd = self
-            .config
-            .run_command
-            .first()
-            .expect("no run command provided");
-        let mut run_command = Command::new(run_cmd);
-
-        if cfg!(feature = "uefi") && self.config.boot_type == BootType::Uefi {
-            println!("Fetching OVMF firmware...");
-            let ovmf = ovmf_prebuilt::Prebuilt::fetch(ovmf_prebuilt::Source::LATEST, "target/ovmf")
-                .unwrap();
-            let code = ovmf.get_file(ovmf_prebuilt::Arch::X64, ovmf_prebuilt::FileType::Code);
-            let vars = ovmf.get_file(ovmf_prebuilt::Arch::X64, ovmf_prebuilt::FileType::Vars);
-
-            run_command
-                .arg("-drive")
-                .arg(format!(
-                    "if=pflash,format=raw,readonly=on,file={}",
-                    code.display()
-                ))
-                .arg("-drive")
-                .arg(format!("if=pflash,format=raw,file={}", vars.display()));
-        }
-
-        run_command.args(self.config.run_command.iter().skip(1));
-        if self.is_test {
-            run_command.args(self.config.test_args);
-        } else {
-            run_command.args(self.config.run_args);
+    let manifest_path = package.map_or_else(
+        || root_dir.join("Cargo.toml"),
+        |package| package.manifest_path.clone().into_std_path_buf(),
+    );
+    Ok((
+        data.image_runner,
+        root_dir.to_path_buf(),
+        manifest_path,
+        metadata.target_directory.clone().into_std_path_buf(),
+        config_errors,
+    ))
+}
+
+/// Runs kernels and other bootable executables in QEMU, as a cargo runner or on its own
+#[derive(Debug, Parser)]
+#[command(name = "cargo image-runner", bin_name = "cargo image-runner", version)]
+struct Cli {
+    /// Config files layered over the metadata, in order, with the same keys as the
+    /// image-runner table
+    #[arg(long = "config", global = true)]
+    config_files: Vec<PathBuf>,
+    #[command(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Builds the image of an executable without running it
+    Build(ExecutableArgs),
+    /// Builds and runs the image of an executable
+    Run(ExecutableArgs),
+    /// Builds and runs the image of a test executable, checking its exit code
+    Test(ExecutableArgs),
+    /// Runs an image that was built elsewhere, such as a third-party test suite, with the same
+    /// timeout, output capture and reporting as executables
+    Exec(ImageArgs),
+    /// Runs the image of an executable twice with different overrides, e.g. `accel=kvm` and
+    /// `accel=tcg`, and reports where the runs diverge
+    Compare(CompareArgs),
+    /// Checks the config, the cargo runner wiring and the tools the runner needs
+    Check {
+        /// Prints the report as JSON, for CI
+        #[arg(long)]
+        json: bool,
+        /// Config overrides, in the form key=value
+        overrides: Vec<String>,
+    },
+    /// Converts a built image to the format of another hypervisor, without rebuilding it
+    Convert {
+        /// The image to convert, e.g. `target/image-runner/kernel-<hash>/image.iso`
+        image: PathBuf,
+        /// The format to convert to, `qcow2`, `vmdk`, `vhdx`, `vdi` or `raw`
+        #[arg(long)]
+        to: String,
+        /// Where the converted image is written, defaults to the image with the extension of the
+        /// format
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Removes the fetched bootloaders and firmware, and the built images
+    Clean,
+    /// Runs an executable followed by config overrides, as cargo does when this is the runner
+    #[command(external_subcommand)]
+    Runner(Vec<String>),
+}
+
+#[derive(Debug, Args)]
+struct ExecutableArgs {
+    /// The executable built by cargo
+    executable: PathBuf,
+    /// Prints JSON messages on stdout, `human` or `json`
+    #[arg(long)]
+    message_format: Option<String>,
+    /// Shows an interactive dashboard of the run instead of its output
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    tui: bool,
+    /// Config overrides, in the form key=value, other arguments are passed to tests (after `--`
+    /// if they start with a dash)
+    overrides: Vec<String>,
+}
+
+impl ExecutableArgs {
+    /// The overrides, with the message format as an override
+    fn overrides(&self) -> Vec<String> {
+        let mut overrides = self.overrides.clone();
+        if let Some(format) = &self.message_format {
+            overrides.push(format!("message-format={}", format));
         }
+        overrides
+    }
+}
 
-        let mut run_command = run_command.spawn().expect("run command failed");
-        let status = run_command.wait().unwrap();
-        if !self.is_test {
-            if !status.success() {
-                exit(status.code().unwrap_or(1));
-            }
+#[derive(Debug, Args)]
+struct ImageArgs {
+    /// The bootable ISO or disk image
+    #[arg(long)]
+    image: PathBuf,
+    /// Runs the image as a test, checking its exit code
+    #[arg(long)]
+    test: bool,
+    /// Prints JSON messages on stdout, `human` or `json`
+    #[arg(long)]
+    message_format: Option<String>,
+    /// Config overrides, in the form key=value
+    overrides: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct CompareArgs {
+    /// The executable built by cargo
+    executable: PathBuf,
+    /// An override of the first run, in the form key=value, can be repeated
+    #[arg(long)]
+    left: Vec<String>,
+    /// An override of the second run, in the form key=value, can be repeated
+    #[arg(long)]
+    right: Vec<String>,
+    /// A config file layered over the others for the first run, e.g. with the `run-command` of
+    /// another QEMU, can be repeated
+    #[arg(long)]
+    left_config: Vec<PathBuf>,
+    /// A config file layered over the others for the second run, can be repeated
+    #[arg(long)]
+    right_config: Vec<PathBuf>,
+    /// Config overrides of both runs, in the form key=value
+    overrides: Vec<String>,
+}
+
+fn parse_overrides(overrides: &[String]) -> Vec<(String, Value)> {
+    overrides.iter().map(|s| Value::parse_pair(s)).collect()
+}
+
+/// The boolean overrides, which can also be given without a value, e.g. `debug`
+const FLAG_OVERRIDES: &[&str] = &[
+    "deny-warnings",
+    "deny_warnings",
+    "direct-kernel",
+    "direct_kernel",
+    "update-snapshots",
+    "update_snapshots",
+    "verbose",
+    "record",
+    "replay",
+    "debug",
+];
+
+/// Splits the arguments after the executable into config overrides and the arguments cargo
+/// passes to tests, such as a filter or `--exact`
+///
+/// Only `key=value` arguments and the flags in [`FLAG_OVERRIDES`] are overrides, flags like
+/// `--test-threads=1` and everything after `--` are passed to the test.
+fn split_test_args(args: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut overrides = Vec::new();
+    let mut test_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            test_args.extend(args.cloned());
+            break;
+        }
+        if !arg.starts_with('-') && arg.contains('=') {
+            overrides.push(arg.clone());
+        } else if FLAG_OVERRIDES.contains(&arg.as_str()) {
+            overrides.push(format!("{}=true", arg));
         } else {
-            let code = status.code().unwrap_or(i32::MAX);
-            if code as u32 != self.config.test_success_exit_code {
-                exit(code);
-            }
+            test_args.push(arg.clone());
         }
     }
+    (overrides, test_args)
 }
 
-fn main() {
-    let mut args_iter = std::env::args().skip(2);
-
-    // We allow passing arguments as key value pairs such as
-    //let target = std::env::var("TARGET").unwrap_or("x86_64".to_string());
-    let manifest_path = std::env::var("CARGO_MANIFEST_PATH").ok();
-    let pkg_name = std::env::var("CARGO_PKG_NAME").ok();
+#[cfg(test)]
+#[test]
+fn test_split_test_args() {
+    let args = [
+        "my_case",
+        "--exact",
+        "--test-threads=1",
+        "debug=true",
+        "arch=aarch64",
+        "verbose",
+        "deny-warnings",
+        "--",
+        "name=value",
+        "debug",
+    ]
+    .map(String::from);
+    let (overrides, test_args) = split_test_args(&args);
+    assert_eq!(
+        overrides,
+        [
+            "debug=true",
+            "arch=aarch64",
+            "verbose=true",
+            "deny-warnings=true"
+        ]
+    );
+    assert_eq!(
+        test_args,
+        [
+            "my_case",
+            "--exact",
+            "--test-threads=1",
+            "name=value",
+            "debug"
+        ]
+    );
+}
 
-    let target_exe_path = args_iter
-        .next()
-        .expect("expected path to target executable");
+/// Builds the context for `executable`, `is_test` is detected from its path if not given
+fn build_context(
+    executable: PathBuf,
+    args: &[String],
+    config_files: &[PathBuf],
+    is_test: Option<bool>,
+    prebuilt_image: bool,
+) -> Context {
+    let (overrides, test_filter) = split_test_args(args);
+    let is_test = is_test.unwrap_or_else(|| is_test_executable(&executable));
+    // Only tests take other arguments than overrides
+    if let (false, Some(arg)) = (is_test, test_filter.first()) {
+        eprintln!(
+            "error: {} is not a valid config value, arguments should be in the form key=value",
+            arg
+        );
+        exit(1);
+    }
+    let (config, root_dir, manifest_path, target_dir, config_errors) =
+        load_config(parse_overrides(&overrides), config_files, is_test);
+    let mut builder = Context::builder(config, executable, root_dir)
+        .manifest_path(manifest_path)
+        .target_dir(target_dir)
+        .test_filter(test_filter)
+        .is_test(is_test);
+    for error in config_errors {
+        builder = builder.warning("config-ignored", format!("{}, it is ignored", error));
+    }
+    if prebuilt_image {
+        builder = builder.prebuilt_image();
+    }
+    builder.build().unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        exit(1);
+    })
+}
 
-    let args: Vec<(String, Value)> = args_iter.map(|s| Value::parse_pair(&s)).collect();
+/// Prepares the bootloader of `ctx`, exiting if the test is skipped or the image can't be run
+fn prepare_bootloader(ctx: &Context) {
+    if let Err(not_run) = ctx.prepare_bootloader() {
+        if let NotRun::Failed(err) = &not_run {
+            eprintln!("error: {}", err);
+        }
+        exit(not_run.exit_code());
+    }
+}
 
-    let mut cmd = cargo_metadata::MetadataCommand::new();
-    if let Some(manifest_path) = manifest_path {
-        cmd.manifest_path(manifest_path);
+fn check(overrides: &[String], config_files: &[PathBuf], json: bool) {
+    let dir = std::env::current_dir().unwrap();
+    // Outside of a cargo project, the rest of the setup is still checked with the default config
+    let (config, root_dir, _, target_dir, config_errors) =
+        try_load_config(parse_overrides(overrides), config_files, false).unwrap_or_else(|err| {
+            let config = default_config().image_runner;
+            (
+                config,
+                dir.clone(),
+                PathBuf::new(),
+                dir.join("target"),
+                vec![err],
+            )
+        });
+    let mut report = CheckReport::default();
+    check_config(&config_errors, &mut report);
+    report
+        .items
+        .extend(check_environment(&config, &dir, &root_dir, &target_dir).items);
+
+    if json {
+        println!("{}", report.to_json());
+    } else {
+        report.print();
+    }
+    if report.has_errors() {
+        exit(1);
     }
+}
 
-    let metadata = cmd.exec().unwrap();
+fn convert(image: &Path, to: &str, output: Option<PathBuf>) {
+    let format: ConvertFormat = serde_plain::from_str(to).unwrap_or_else(|_| {
+        panic!(
+            "invalid format {}, expected qcow2, vmdk, vhdx, vdi or raw",
+            to
+        )
+    });
+    let output = output.unwrap_or_else(|| image.with_extension(format.extension()));
+    convert_image(image, &output, format).unwrap_or_else(|err| panic!("{}", err));
+    println!("Converted {} to {}", image.display(), output.display());
+
+    // The checksum is recorded next to those of the inputs of the image, if it was built here
+    let provenance_path = image.with_extension("provenance.json");
+    if provenance_path.is_file() {
+        let (_, sha256) = sha256_file(&output)
+            .unwrap_or_else(|err| panic!("failed to hash {}: {}", output.display(), err));
+        let dir = provenance_path.parent().unwrap();
+        let converted = ConvertedImage {
+            path: output
+                .strip_prefix(dir)
+                .unwrap_or(&output)
+                .to_string_lossy()
+                .into_owned(),
+            format: format.name().to_string(),
+            sha256,
+        };
+        Provenance::record_conversion(&provenance_path, converted)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+}
 
-    let package = match pkg_name {
-        Some(pkg_name) => metadata
-            .packages
+fn compare(args: &CompareArgs, config_files: &[PathBuf]) {
+    let sides = [
+        ("left", &args.left, &args.left_config),
+        ("right", &args.right, &args.right_config),
+    ];
+    let mut runs = Vec::new();
+    for (side, overrides, side_config_files) in sides {
+        let overrides: Vec<String> = args.overrides.iter().chain(overrides).cloned().collect();
+        let config_files: Vec<PathBuf> = config_files
             .iter()
-            .find(|p| p.name == pkg_name)
-            .unwrap(),
-        None => metadata.root_package().unwrap(),
-    };
-    let root_dir = metadata.workspace_root.as_str();
-
-    // TODO: This gives a wrong error message if the metadata is not found
-    let mut data: PackageMetadata = serde_json::from_value(package.metadata.clone())
-        .unwrap_or_else(|_| {
-            serde_json::from_value(metadata.workspace_metadata.clone())
-                .unwrap_or_else(|_e| default_config())
+            .chain(side_config_files)
+            .cloned()
+            .collect();
+        eprintln!("Running the {} side", side);
+        let mut ctx = build_context(
+            args.executable.clone(),
+            &overrides,
+            &config_files,
+            None,
+            false,
+        );
+        prepare_bootloader(&ctx);
+        ctx.prepare_image();
+        let outcome = ctx.run_captured().unwrap_or_else(|err| {
+            eprintln!("error: {}", err);
+            exit(1);
         });
+        let run = ComparedRun::new(
+            outcome,
+            ctx.scrubbers(),
+            &ctx.config().test.snapshot_filters,
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+        runs.push(run);
+    }
 
-    // Parse CLI arguments are key-value pairs
-    for (k, v) in args {
-        match k.as_str() {
-            "boot-type" | "boot_type" => {
-                let ty: BootType =
-                    serde_plain::from_str(&v.as_string().expect("boot_type expects a string"))
-                        .expect("invalid boot_type");
-                data.image_runner.boot_type = ty;
+    let divergences = compare_runs(&runs[0], &runs[1]);
+    if divergences.is_empty() {
+        println!("The runs match");
+        return;
+    }
+    for divergence in divergences {
+        eprintln!("divergence: {}", divergence);
+    }
+    exit(1);
+}
+
+fn clean() {
+    // The target directory is the one cargo uses, e.g. from CARGO_TARGET_DIR
+    let (_, _, _, target_dir, _) = load_config(Vec::new(), &[], false);
+    // The default cache and output directory, and the OVMF firmware
+    for dir in [target_dir.join("image-runner"), target_dir.join("ovmf")] {
+        if dir.is_dir() {
+            std::fs::remove_dir_all(&dir)
+                .unwrap_or_else(|err| panic!("failed to remove {}: {}", dir.display(), err));
+            println!("Removed {}", dir.display());
+        }
+    }
+}
+
+fn main() {
+    // cargo passes the name of the subcommand as the first argument
+    let cli = Cli::parse_from(std::env::args_os().skip(1));
+
+    match cli.command {
+        CliCommand::Build(args) => {
+            let mut ctx = build_context(
+                args.executable.clone(),
+                &args.overrides(),
+                &cli.config_files,
+                None,
+                false,
+            );
+            prepare_bootloader(&ctx);
+            let image_path = ctx.prepare_image();
+            // Runs report the warnings once QEMU exits, only building the image reports them here
+            let json = ctx.config().message_format == MessageFormat::Json;
+            ctx.diagnostics().report(json);
+            if !json {
+                println!("Built {}", image_path.display());
             }
-            "limine-branch" | "limine_branch" => {
-                data.image_runner.limine_branch =
-                    v.as_string().expect("limine_branch expects a string");
+        }
+        CliCommand::Run(args) => {
+            let mut ctx = build_context(
+                args.executable.clone(),
+                &args.overrides(),
+                &cli.config_files,
+                Some(false),
+                false,
+            );
+            prepare_bootloader(&ctx);
+            ctx.prepare_image();
+            #[cfg(feature = "tui")]
+            if args.tui {
+                exit(cargo_image_runner::tui::run(ctx));
             }
-            "config-file" | "config_file" => {
-                data.image_runner.config_file =
-                    v.as_string().expect("config_file expects a string");
+            exit(ctx.run());
+        }
+        CliCommand::Test(args) => {
+            let mut ctx = build_context(
+                args.executable.clone(),
+                &args.overrides(),
+                &cli.config_files,
+                Some(true),
+                false,
+            );
+            prepare_bootloader(&ctx);
+            ctx.prepare_image();
+            #[cfg(feature = "tui")]
+            if args.tui {
+                exit(cargo_image_runner::tui::run(ctx));
             }
-            var if data.image_runner.vars.contains_key(var) => {
-                data.image_runner.vars.insert(
-                    var.to_string(),
-                    v.as_string().expect("variables should be strings"),
-                );
+            exit(ctx.run());
+        }
+        CliCommand::Exec(args) => {
+            let mut overrides = args.overrides.clone();
+            if let Some(format) = &args.message_format {
+                overrides.push(format!("message-format={}", format));
             }
-            other => panic!(
-                "{} is not a valid config value, arguments should be in the form key=value",
-                other
-            ),
+            // The image is given relative to the working directory, not the workspace root
+            let image = std::path::absolute(&args.image)
+                .unwrap_or_else(|err| panic!("invalid image path: {}", err));
+            let mut ctx =
+                build_context(image, &overrides, &cli.config_files, Some(args.test), true);
+            prepare_bootloader(&ctx);
+            ctx.prepare_image();
+            exit(ctx.run());
+        }
+        CliCommand::Compare(args) => compare(&args, &cli.config_files),
+        CliCommand::Check { json, overrides } => check(&overrides, &cli.config_files, json),
+        CliCommand::Convert { image, to, output } => convert(&image, &to, output),
+        CliCommand::Clean => clean(),
+        CliCommand::Runner(args) => {
+            let (executable, overrides) = args.split_first().expect("expected an executable");
+            let mut ctx = build_context(
+                PathBuf::from(executable),
+                overrides,
+                &cli.config_files,
+                None,
+                false,
+            );
+            prepare_bootloader(&ctx);
+            ctx.prepare_image();
+            exit(ctx.run());
         }
     }
+}
 
-    let mut parse_ctx = ParseCtx::new(
-        data.image_runner,
-        PathBuf::from(target_exe_path.as_str()),
-        PathBuf::from(root_dir),
+#[cfg(test)]
+#[test]
+fn test_cli_runner_protocol() {
+    let cli = Cli::try_parse_from([
+        "image-runner",
+        "target/x86_64-unknown-none/debug/kernel",
+        "machine=q35",
+    ])
+    .unwrap();
+    let CliCommand::Runner(args) = cli.command else {
+        panic!("expected the runner protocol");
+    };
+    assert_eq!(
+        args,
+        ["target/x86_64-unknown-none/debug/kernel", "machine=q35"]
     );
 
-    parse_ctx.prepare_bootloader();
-    parse_ctx.prepare_iso();
-    parse_ctx.run();
+    let cli = Cli::try_parse_from([
+        "image-runner",
+        "--config",
+        "base.toml",
+        "--config",
+        "ci.toml",
+        "target/debug/kernel",
+    ])
+    .unwrap();
+    assert_eq!(
+        cli.config_files,
+        ["base.toml", "ci.toml"].map(PathBuf::from)
+    );
+    assert!(matches!(cli.command, CliCommand::Runner(args) if args == ["target/debug/kernel"]));
+
+    let cli = Cli::try_parse_from(["image-runner", "build", "kernel", "debug"]).unwrap();
+    assert!(matches!(cli.command, CliCommand::Build(args) if args.overrides == ["debug"]));
+
+    let cli =
+        Cli::try_parse_from(["image-runner", "test", "--message-format=json", "kernel"]).unwrap();
+    assert!(
+        matches!(cli.command, CliCommand::Test(args) if args.overrides() == ["message-format=json"])
+    );
+
+    let cli =
+        Cli::try_parse_from(["image-runner", "exec", "--image", "sct.iso", "--test"]).unwrap();
+    assert!(
+        matches!(cli.command, CliCommand::Exec(args) if args.image.as_os_str() == "sct.iso" && args.test)
+    );
+
+    let cli = Cli::try_parse_from([
+        "image-runner",
+        "compare",
+        "kernel",
+        "--left",
+        "accel=kvm",
+        "--right",
+        "accel=tcg",
+        "verbose",
+    ])
+    .unwrap();
+    let CliCommand::Compare(args) = cli.command else {
+        panic!("expected compare");
+    };
+    assert_eq!(args.left, ["accel=kvm"]);
+    assert_eq!(args.right, ["accel=tcg"]);
+    assert_eq!(args.overrides, ["verbose"]);
 }
@@ -0,0 +1,355 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// How the image was built, recorded in the manifest
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    /// The version of the runner that built the image
+    pub runner: String,
+    pub executable: String,
+    /// The target triple, if the executable was built for one
+    pub target: Option<String>,
+    /// Either `debug` or `release`
+    pub profile: String,
+    pub arch: String,
+    pub bootloader: String,
+    pub cmdline: String,
+}
+
+/// Where the manifest is placed in the image, and the metadata it records
+#[derive(Debug, Clone)]
+pub struct ManifestOptions {
+    pub dest: PathBuf,
+    pub build: BuildInfo,
+}
+
+/// A file in the image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ManifestFile {
+    /// The path of the file, relative to the root of the image
+    pub path: String,
+    pub size: u64,
+    /// The SHA-256 of the contents, in lowercase hex
+    pub sha256: String,
+}
+
+/// Lists the files shipped in an image with their checksums, so their integrity can be verified
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub build: BuildInfo,
+    pub files: Vec<ManifestFile>,
+}
+
+impl Manifest {
+    /// Hashes the files at `dests` in `image_root`, they are listed sorted by path
+    pub fn generate(image_root: &Path, dests: &[PathBuf], build: BuildInfo) -> Self {
+        let mut files: Vec<ManifestFile> = dests
+            .iter()
+            .map(|dest| {
+                let path = image_root.join(dest);
+                let (size, sha256) = sha256_file(&path)
+                    .unwrap_or_else(|err| panic!("failed to hash {}: {}", path.display(), err));
+                ManifestFile {
+                    // Forward slashes, so the manifest is the same on every host
+                    path: dest
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    size,
+                    sha256,
+                }
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Self { build, files }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap() + "\n"
+    }
+}
+
+/// A file the image was built from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InputFile {
+    /// The path of the file, relative to the package if it is inside of it
+    pub path: String,
+    pub sha256: String,
+}
+
+/// The platform the image was built on
+#[derive(Debug, Clone, Serialize)]
+pub struct HostInfo {
+    pub os: String,
+    pub arch: String,
+}
+
+impl HostInfo {
+    pub fn current() -> Self {
+        Self {
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+/// Records what an image was built from and with which tools, written next to the image as
+/// `<image>.provenance.json`
+#[derive(Debug, Clone, Serialize)]
+pub struct Provenance {
+    /// Identifies the build, it is the same for images built from the same configuration and
+    /// inputs
+    pub build_id: String,
+    pub build: BuildInfo,
+    /// The SHA-256 of the configuration, after overrides and variables were applied
+    pub config_sha256: String,
+    /// The files staged into the image, sorted by path
+    pub inputs: Vec<InputFile>,
+    /// The version of the bootloader, such as the Limine ref, if it is fetched
+    pub bootloader_version: Option<String>,
+    /// The version of QEMU the image was last run with, if it was run
+    pub qemu_version: Option<String>,
+    /// The release of the prebuilt OVMF firmware, if it is used
+    pub ovmf_release: Option<String>,
+    pub host: HostInfo,
+    /// The images converted from this one with `cargo image-runner convert`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conversions: Vec<ConvertedImage>,
+}
+
+/// An image converted from the built one, e.g. for another hypervisor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConvertedImage {
+    /// The path of the converted image, relative to the provenance file if it is next to it
+    pub path: String,
+    pub format: String,
+    pub sha256: String,
+}
+
+impl Provenance {
+    /// Hashes the `config`, which is any serialized form of it, and the files at `sources`, the
+    /// paths of those in `root_dir` are recorded relative to it
+    pub fn generate(build: BuildInfo, config: &str, root_dir: &Path, sources: &[PathBuf]) -> Self {
+        let mut inputs: Vec<InputFile> = sources
+            .iter()
+            .filter(|source| source.is_file())
+            .map(|source| {
+                let (_, sha256) = sha256_file(source)
+                    .unwrap_or_else(|err| panic!("failed to hash {}: {}", source.display(), err));
+                let path = source.strip_prefix(root_dir).unwrap_or(source);
+                InputFile {
+                    path: path
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    sha256,
+                }
+            })
+            .collect();
+        inputs.sort_by(|a, b| a.path.cmp(&b.path));
+        inputs.dedup();
+
+        let config_sha256 = sha256(config.as_bytes());
+        let mut id = config_sha256.clone();
+        for input in &inputs {
+            id.push_str(&input.sha256);
+        }
+        Self {
+            build_id: sha256(id.as_bytes())[..16].to_string(),
+            build,
+            config_sha256,
+            inputs,
+            bootloader_version: None,
+            qemu_version: None,
+            ovmf_release: None,
+            host: HostInfo::current(),
+            conversions: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap() + "\n"
+    }
+
+    /// Adds a converted image to the provenance file at `path`, replacing an earlier conversion
+    /// to the same path
+    pub fn record_conversion(path: &Path, converted: ConvertedImage) -> Result<(), String> {
+        Self::update(path, |object| {
+            let conversions = object
+                .entry("conversions")
+                .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+            if let Some(conversions) = conversions.as_array_mut() {
+                conversions.retain(|conversion| conversion["path"] != converted.path.as_str());
+                conversions.push(serde_json::to_value(&converted).unwrap());
+            }
+        })
+    }
+
+    /// Sets the version of QEMU in the provenance file at `path`, which is only known once the
+    /// image is run
+    pub fn record_qemu_version(path: &Path, version: &str) -> Result<(), String> {
+        Self::update(path, |object| {
+            object.insert("qemu_version".to_string(), version.into());
+        })
+    }
+
+    /// Changes the provenance file at `path` with `update`, keeping the fields it doesn't know
+    fn update(
+        path: &Path,
+        update: impl FnOnce(&mut serde_json::Map<String, serde_json::Value>),
+    ) -> Result<(), String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let mut provenance: serde_json::Value = serde_json::from_str(&json)
+            .map_err(|err| format!("invalid provenance file {}: {}", path.display(), err))?;
+        let Some(object) = provenance.as_object_mut() else {
+            return Err(format!("invalid provenance file {}", path.display()));
+        };
+        update(object);
+        std::fs::write(
+            path,
+            serde_json::to_string_pretty(&provenance).unwrap() + "\n",
+        )
+        .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+    }
+}
+
+/// Returns the SHA-256 of `bytes`, in lowercase hex
+pub fn sha256(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns the size and the SHA-256 of the file at `path`
+pub fn sha256_file(path: &Path) -> std::io::Result<(u64, String)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = [0u8; 8192];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+    Ok((size, to_hex(&hasher.finalize())))
+}
+
+#[cfg(test)]
+#[test]
+fn test_generate_manifest() {
+    let root = std::env::temp_dir().join(format!("image-runner-manifest-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("boot")).unwrap();
+    std::fs::write(root.join("boot/kernel"), "abc").unwrap();
+    std::fs::write(root.join("README"), "").unwrap();
+
+    let build = BuildInfo {
+        runner: "0.1.2".to_string(),
+        executable: "kernel".to_string(),
+        target: None,
+        profile: "debug".to_string(),
+        arch: "x86_64".to_string(),
+        bootloader: "limine".to_string(),
+        cmdline: String::new(),
+    };
+    let manifest = Manifest::generate(
+        &root,
+        &[PathBuf::from("boot/kernel"), PathBuf::from("README")],
+        build,
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        manifest.files,
+        [
+            ManifestFile {
+                path: "README".to_string(),
+                size: 0,
+                sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                    .to_string(),
+            },
+            ManifestFile {
+                path: "boot/kernel".to_string(),
+                size: 3,
+                sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_string(),
+            },
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_provenance() {
+    let root = std::env::temp_dir().join(format!("image-runner-provenance-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("kernel"), "abc").unwrap();
+
+    let build = BuildInfo {
+        runner: "0.1.2".to_string(),
+        executable: "kernel".to_string(),
+        target: None,
+        profile: "debug".to_string(),
+        arch: "x86_64".to_string(),
+        bootloader: "limine".to_string(),
+        cmdline: String::new(),
+    };
+    let sources = [
+        root.join("kernel"),
+        root.join("missing"),
+        root.join("kernel"),
+    ];
+    let provenance = Provenance::generate(build.clone(), "{}", &root, &sources);
+    let changed = Provenance::generate(build, r#"{"cmdline":"quiet"}"#, &root, &sources);
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        provenance.inputs,
+        [InputFile {
+            path: "kernel".to_string(),
+            sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+        }]
+    );
+    assert_eq!(
+        provenance.config_sha256,
+        "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+    );
+    assert_eq!(provenance.build_id.len(), 16);
+    assert_ne!(provenance.build_id, changed.build_id);
+}
+
+#[cfg(test)]
+#[test]
+fn test_record_conversion() {
+    let path = std::env::temp_dir().join(format!(
+        "image-runner-conversion-{}.provenance.json",
+        std::process::id()
+    ));
+    std::fs::write(&path, r#"{"build_id": "0123456789abcdef"}"#).unwrap();
+    let converted = |sha256: &str| ConvertedImage {
+        path: "image.qcow2".to_string(),
+        format: "qcow2".to_string(),
+        sha256: sha256.to_string(),
+    };
+    Provenance::record_conversion(&path, converted("aa")).unwrap();
+    // Converting to the same path again replaces the earlier conversion
+    Provenance::record_conversion(&path, converted("bb")).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(json["build_id"], "0123456789abcdef");
+    assert_eq!(
+        json["conversions"],
+        serde_json::json!([{ "path": "image.qcow2", "format": "qcow2", "sha256": "bb" }])
+    );
+}
@@ -0,0 +1,98 @@
+use serde::Serialize;
+use std::path::Path;
+
+/// A machine-readable message, printed as a line of JSON with `message-format = "json"`,
+/// similar to the JSON messages of cargo
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "reason")]
+pub enum Message<'a> {
+    /// The image of `executable` is being built
+    #[serde(rename = "build-started")]
+    BuildStarted { executable: &'a Path, test: bool },
+    /// The image was built, or the kernel that is booted directly was prepared
+    #[serde(rename = "image-built")]
+    ImageBuilt { image: &'a Path },
+    /// The requirements of a test weren't met, so it was skipped
+    #[serde(rename = "test-skipped")]
+    TestSkipped { unmet: &'a [String] },
+    /// A test case finished, see [`crate::config::TestCasesConfig`]
+    #[serde(rename = "case-finished")]
+    CaseFinished { name: &'a str, passed: bool },
+    /// The runner took an action the guest asked for, see [`crate::host_action::HostAction`]
+    #[serde(rename = "host-action")]
+    HostAction {
+        /// What was done, as added to the log
+        annotation: &'a str,
+        /// When, in milliseconds since QEMU started
+        elapsed_ms: u64,
+    },
+    /// A warning of the run, see [`crate::diagnostics::Diagnostics`]
+    #[serde(rename = "warning")]
+    Warning { code: &'a str, message: &'a str },
+    /// QEMU exited, `exit_code` is `None` if it was killed by a signal
+    #[serde(rename = "run-finished")]
+    RunFinished {
+        test: bool,
+        success: bool,
+        exit_code: Option<i32>,
+        /// Why the run failed, see [`crate::outcome::FailureKind`]
+        failure: Option<String>,
+        /// The last of the configured boot stages that was reached, if the run failed
+        stage: Option<&'a str>,
+        /// How the runner stopped the guest, see [`crate::outcome::ShutdownStage`], if it did
+        shutdown: Option<&'a str>,
+        /// The accelerator QEMU was started with, if one is configured
+        accelerator: Option<&'a str>,
+        /// How long QEMU ran, in milliseconds
+        duration_ms: u64,
+        /// When the guest last printed something, in milliseconds since the start, if its
+        /// output was watched, e.g. to tell how long a hung guest was stuck
+        last_output_ms: Option<u64>,
+    },
+}
+
+impl Message<'_> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Prints the message to stdout
+    pub fn emit(&self) {
+        println!("{}", self.to_json());
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_message_to_json() {
+    assert_eq!(
+        Message::ImageBuilt {
+            image: Path::new("target/image.iso")
+        }
+        .to_json(),
+        r#"{"reason":"image-built","image":"target/image.iso"}"#
+    );
+    assert_eq!(
+        Message::Warning {
+            code: "log-failed",
+            message: "failed to write kernel.log"
+        }
+        .to_json(),
+        r#"{"reason":"warning","code":"log-failed","message":"failed to write kernel.log"}"#
+    );
+    assert_eq!(
+        Message::RunFinished {
+            test: true,
+            success: false,
+            exit_code: Some(3),
+            failure: Some("guest panic".to_string()),
+            stage: Some("kernel entry"),
+            shutdown: Some("killed"),
+            accelerator: Some("kvm"),
+            duration_ms: 1500,
+            last_output_ms: Some(1200),
+        }
+        .to_json(),
+        r#"{"reason":"run-finished","test":true,"success":false,"exit_code":3,"failure":"guest panic","stage":"kernel entry","shutdown":"killed","accelerator":"kvm","duration_ms":1500,"last_output_ms":1200}"#
+    );
+}
@@ -0,0 +1,417 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::config::BootStageConfig;
+
+/// Why a run failed, as far as it can be told from the exit status and the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The kernel panicked, e.g. it printed a Rust panic message
+    GuestPanic,
+    /// The CPU triple faulted, or the guest kept resetting
+    TripleFault,
+    /// An assertion in the firmware failed, such as an EDK2 `ASSERT`
+    FirmwareAssertion,
+    /// QEMU itself failed, e.g. because of an invalid argument or a KVM error
+    QemuError,
+    /// The guest output nothing for too long, so it was stopped
+    Timeout,
+    /// QEMU was killed by a signal
+    Killed,
+    /// The test succeeded, but its serial output differs from the snapshot
+    SnapshotMismatch,
+    /// A step of the expect script timed out, or the guest stopped before it finished
+    ExpectFailed,
+    /// The guest printed the `run.failure-pattern`
+    FailurePattern,
+    /// The guest didn't print the `run.success-pattern`
+    NoSuccessPattern,
+    /// A test case failed, see `test.cases`
+    CaseFailed,
+    /// The guest printed this line, which matched one of the `abort-patterns`
+    AbortPattern(String),
+    /// The test was expected to panic, but it didn't print `test.expect-panic-pattern`
+    NoPanic,
+    /// The guest exited with an unexpected exit code, without any other sign of what went wrong
+    ExitCode(i32),
+    /// The run succeeded, but it emitted warnings, which `deny-warnings` turns into a failure
+    WarningsDenied,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GuestPanic => write!(f, "guest panic"),
+            Self::TripleFault => write!(f, "triple fault or reset loop"),
+            Self::FirmwareAssertion => write!(f, "firmware assertion"),
+            Self::QemuError => write!(f, "QEMU error"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Killed => write!(f, "killed by a signal"),
+            Self::SnapshotMismatch => write!(f, "the output differs from the snapshot"),
+            Self::ExpectFailed => write!(f, "the expect script failed"),
+            Self::FailurePattern => write!(f, "the failure pattern was printed"),
+            Self::NoSuccessPattern => write!(f, "the success pattern wasn't printed"),
+            Self::CaseFailed => write!(f, "a test case failed"),
+            Self::AbortPattern(line) => write!(f, "the guest printed {:?}", line),
+            Self::NoPanic => write!(f, "the expected panic didn't happen"),
+            Self::ExitCode(code) => write!(f, "exit code {}", code),
+            Self::WarningsDenied => write!(f, "warnings are denied by `deny-warnings`"),
+        }
+    }
+}
+
+/// The step of the shutdown sequence that stopped the guest, see `test.shutdown`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownStage {
+    /// The guest shut down when asked to over QMP or the serial port
+    Graceful,
+    /// QEMU exited on SIGTERM
+    Terminated,
+    Killed,
+}
+
+impl ShutdownStage {
+    /// The name of the stage in JSON messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Graceful => "graceful",
+            Self::Terminated => "terminated",
+            Self::Killed => "killed",
+        }
+    }
+}
+
+impl fmt::Display for ShutdownStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Graceful => write!(f, "shut down gracefully"),
+            Self::Terminated => write!(f, "terminated"),
+            Self::Killed => write!(f, "killed"),
+        }
+    }
+}
+
+/// How a run ended, see `Context::run_captured`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    /// The exit code of QEMU, `None` if it was killed by a signal
+    pub exit_code: Option<i32>,
+    /// Why the run failed, if it did
+    pub failure: Option<FailureKind>,
+    /// How the runner stopped the guest, if it did
+    pub shutdown: Option<ShutdownStage>,
+    /// How long QEMU ran
+    pub duration: Duration,
+    pub output: CapturedOutput,
+}
+
+/// When a chunk of output was received by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkTime {
+    /// The position in the output the chunk starts at
+    pub offset: usize,
+    /// The time since the start of the run
+    pub received: Duration,
+}
+
+/// The output of a run, recorded by channel, such as the serial port or what QEMU wrote to stderr
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedOutput {
+    channels: BTreeMap<String, Vec<u8>>,
+    /// When the chunks of each channel were received, if it was recorded
+    timestamps: BTreeMap<String, Vec<ChunkTime>>,
+}
+
+impl CapturedOutput {
+    /// The first serial port of the guest
+    pub const SERIAL: &str = "serial0";
+    /// The debug console of the firmware, see `ovmf.debug-log`
+    pub const DEBUGCON: &str = "debugcon";
+    pub const QEMU_STDERR: &str = "qemu-stderr";
+    /// What the runner did on behalf of the guest, see `host-actions`
+    pub const ANNOTATIONS: &str = "annotations";
+    /// The channels that are captured
+    pub const CHANNELS: &[&str] = &[
+        Self::SERIAL,
+        Self::DEBUGCON,
+        Self::QEMU_STDERR,
+        Self::ANNOTATIONS,
+    ];
+
+    pub fn insert(&mut self, channel: impl Into<String>, output: Vec<u8>) {
+        self.channels.insert(channel.into(), output);
+    }
+
+    /// Records when the chunks of `channel` were received, in order
+    pub fn insert_timestamps(&mut self, channel: impl Into<String>, timestamps: Vec<ChunkTime>) {
+        self.timestamps.insert(channel.into(), timestamps);
+    }
+
+    /// When the chunks of `channel` were received, which is empty if it wasn't recorded, e.g. for
+    /// the firmware log, which is read from a file
+    pub fn timestamps(&self, channel: &str) -> &[ChunkTime] {
+        self.timestamps.get(channel).map_or(&[], Vec::as_slice)
+    }
+
+    /// When the byte at `offset` in `channel` was received
+    pub fn received_at(&self, channel: &str, offset: usize) -> Option<Duration> {
+        let timestamps = self.timestamps(channel);
+        let index = timestamps.partition_point(|chunk| chunk.offset <= offset);
+        index.checked_sub(1).map(|index| timestamps[index].received)
+    }
+
+    /// When the last chunk of `channel` was received, e.g. to tell how long the guest was stuck
+    pub fn last_received(&self, channel: &str) -> Option<Duration> {
+        self.timestamps(channel).last().map(|chunk| chunk.received)
+    }
+
+    /// The output of `channel`, which is empty if it wasn't captured
+    pub fn channel(&self, channel: &str) -> &[u8] {
+        self.channels.get(channel).map_or(&[], Vec::as_slice)
+    }
+
+    /// The captured channels, ordered by name
+    pub fn channels(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.channels
+            .iter()
+            .map(|(channel, output)| (channel.as_str(), output.as_slice()))
+    }
+
+    pub fn serial(&self) -> &[u8] {
+        self.channel(Self::SERIAL)
+    }
+
+    pub fn stderr(&self) -> &[u8] {
+        self.channel(Self::QEMU_STDERR)
+    }
+
+    pub fn firmware_log(&self) -> &[u8] {
+        self.channel(Self::DEBUGCON)
+    }
+
+    /// Classifies a failed run with this output, see [`classify`]
+    pub fn classify(&self, code: Option<i32>) -> FailureKind {
+        classify(code, self.stderr(), self.serial(), self.firmware_log())
+    }
+}
+
+/// The number of times the first line of the serial output has to repeat to count as a reset
+/// loop, as the guest prints its boot banner again after every reset
+const RESET_LOOP_BANNERS: usize = 3;
+
+/// Classifies a failed run by its exit `code` (`None` if QEMU was killed by a signal), what QEMU
+/// wrote to `stderr`, the `serial` output of the guest and the debug log of the firmware, if it
+/// was captured
+pub fn classify(
+    code: Option<i32>,
+    stderr: &[u8],
+    serial: &[u8],
+    firmware_log: &[u8],
+) -> FailureKind {
+    let stderr = String::from_utf8_lossy(stderr);
+    let serial = String::from_utf8_lossy(serial);
+    let firmware_log = String::from_utf8_lossy(firmware_log);
+
+    // QEMU may also report errors after the guest panicked, e.g. when the panic handler resets
+    if serial.contains("panicked at") || serial.contains("Kernel panic") {
+        return FailureKind::GuestPanic;
+    }
+    if stderr.lines().any(is_qemu_error) {
+        // A triple fault is reported as a fatal error with `-d guest_errors` or `-no-reboot`
+        if stderr.to_lowercase().contains("triple fault") {
+            return FailureKind::TripleFault;
+        }
+        return FailureKind::QemuError;
+    }
+    if [&serial, &firmware_log]
+        .iter()
+        .any(|output| output.contains("ASSERT [") || output.contains("ASSERT_EFI_ERROR"))
+    {
+        return FailureKind::FirmwareAssertion;
+    }
+    if let Some(banner) = serial.lines().map(str::trim).find(|line| !line.is_empty()) {
+        let repeats = serial.lines().filter(|line| line.trim() == banner).count();
+        if repeats >= RESET_LOOP_BANNERS {
+            return FailureKind::TripleFault;
+        }
+    }
+    match code {
+        Some(code) => FailureKind::ExitCode(code),
+        None => FailureKind::Killed,
+    }
+}
+
+/// Whether a line QEMU wrote to stderr is an error, like
+/// `qemu-system-x86_64: -drive file=x: Could not open 'x'`, and not a warning or a note, which
+/// QEMU prints with the same prefix
+fn is_qemu_error(line: &str) -> bool {
+    if line.contains("KVM internal error") || line.starts_with("qemu: fatal") {
+        return true;
+    }
+    line.starts_with("qemu-system-")
+        && ![": warning: ", ": info: ", ": terminating on signal"]
+            .iter()
+            .any(|note| line.contains(note))
+}
+
+/// The index of the last of the boot `stages` whose pattern is in the `output`, in the channel of
+/// the stage, or otherwise in the serial output or the firmware log
+pub fn reached_stage(stages: &[BootStageConfig], output: &CapturedOutput) -> Option<usize> {
+    stages.iter().rposition(|stage| {
+        let channels = match &stage.channel {
+            Some(channel) => vec![channel.as_str()],
+            None => vec![CapturedOutput::SERIAL, CapturedOutput::DEBUGCON],
+        };
+        channels.into_iter().any(|channel| {
+            String::from_utf8_lossy(output.channel(channel)).contains(stage.pattern.as_str())
+        })
+    })
+}
+
+/// Describes how far the boot got, given the index of the last `reached` stage
+pub fn describe_stage(stages: &[BootStageConfig], reached: Option<usize>) -> String {
+    match reached {
+        None => format!("the boot didn't reach the first stage, {}", stages[0].name),
+        Some(index) => match stages.get(index + 1) {
+            Some(next) => format!(
+                "the boot reached {}, but not {}",
+                stages[index].name, next.name
+            ),
+            None => format!("the boot reached the last stage, {}", stages[index].name),
+        },
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_reached_stage() {
+    let mut stages: Vec<BootStageConfig> = [
+        ("firmware", "BdsDxe: loading"),
+        ("bootloader", "limine: Loading"),
+        ("kernel entry", "kernel: hello"),
+    ]
+    .into_iter()
+    .map(|(name, pattern)| BootStageConfig {
+        name: name.to_string(),
+        pattern: pattern.to_string(),
+        channel: None,
+    })
+    .collect();
+    let mut output = CapturedOutput::default();
+    output.insert(CapturedOutput::SERIAL, b"limine: Loading kernel\n".to_vec());
+    output.insert(
+        CapturedOutput::DEBUGCON,
+        b"BdsDxe: loading Boot0001\n".to_vec(),
+    );
+    let reached = reached_stage(&stages, &output);
+    assert_eq!(reached, Some(1));
+    assert_eq!(
+        describe_stage(&stages, reached),
+        "the boot reached bootloader, but not kernel entry"
+    );
+    assert_eq!(reached_stage(&stages, &CapturedOutput::default()), None);
+
+    // A stage bound to a channel ignores the others
+    stages[1].channel = Some(CapturedOutput::DEBUGCON.to_string());
+    assert_eq!(reached_stage(&stages, &output), Some(0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_classify() {
+    assert_eq!(
+        classify(
+            Some(1),
+            b"qemu-system-x86_64: -drive file=x: Could not open 'x'\n",
+            b"",
+            b""
+        ),
+        FailureKind::QemuError
+    );
+    assert_eq!(
+        classify(
+            Some(3),
+            b"",
+            b"Booting...\npanicked at src/main.rs:10:5:\nexplicit panic\n",
+            b""
+        ),
+        FailureKind::GuestPanic
+    );
+    assert_eq!(
+        classify(None, b"", b"Booting...\nBooting...\nBooting...\n", b""),
+        FailureKind::TripleFault
+    );
+    assert_eq!(
+        classify(
+            Some(3),
+            b"",
+            b"",
+            b"ASSERT [DxeCore] Dispatcher.c(100): !EFI_ERROR\n"
+        ),
+        FailureKind::FirmwareAssertion
+    );
+    assert_eq!(
+        classify(Some(5), b"", b"Booting...\n", b""),
+        FailureKind::ExitCode(5)
+    );
+    assert_eq!(
+        classify(
+            Some(5),
+            b"qemu-system-x86_64: warning: host doesn't support requested feature\n",
+            b"Booting...\n",
+            b""
+        ),
+        FailureKind::ExitCode(5)
+    );
+    assert_eq!(
+        classify(
+            Some(1),
+            b"qemu-system-x86_64: terminating on signal 15\n",
+            b"panicked at src/main.rs:10:5:\n",
+            b""
+        ),
+        FailureKind::GuestPanic
+    );
+    assert_eq!(
+        classify(
+            None,
+            b"qemu-system-x86_64: terminating on signal 15 from pid 1234\n",
+            b"Booting...\n",
+            b""
+        ),
+        FailureKind::Killed
+    );
+
+    let mut output = CapturedOutput::default();
+    output.insert(CapturedOutput::SERIAL, b"Booting...\n".to_vec());
+    output.insert(
+        CapturedOutput::QEMU_STDERR,
+        b"qemu: fatal: KVM internal error\n".to_vec(),
+    );
+    assert_eq!(output.classify(Some(1)), FailureKind::QemuError);
+    assert_eq!(output.firmware_log(), b"");
+
+    output.insert_timestamps(
+        CapturedOutput::SERIAL,
+        vec![
+            ChunkTime {
+                offset: 0,
+                received: Duration::from_millis(10),
+            },
+            ChunkTime {
+                offset: 5,
+                received: Duration::from_millis(250),
+            },
+        ],
+    );
+    assert_eq!(
+        output.received_at(CapturedOutput::SERIAL, 7),
+        Some(Duration::from_millis(250))
+    );
+    assert_eq!(
+        output.received_at(CapturedOutput::SERIAL, 2),
+        Some(Duration::from_millis(10))
+    );
+    assert_eq!(output.received_at(CapturedOutput::DEBUGCON, 0), None);
+}
@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use cargo_metadata::{Message, MetadataCommand};
+
+use crate::config::PayloadConfig;
+
+/// Builds a payload with cargo, returning the path of the built artifact
+pub fn build_payload(root_dir: &Path, payload: &PayloadConfig) -> PathBuf {
+    build_artifact(
+        root_dir,
+        &payload.package,
+        payload.name.as_deref(),
+        &payload.kind,
+        payload.target.as_deref(),
+        payload.release,
+    )
+}
+
+/// Builds an artifact of a package with cargo, returning its path
+pub fn build_artifact(
+    root_dir: &Path,
+    package: &str,
+    name: Option<&str>,
+    kind: &str,
+    target: Option<&str>,
+    release: bool,
+) -> PathBuf {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let name = name.unwrap_or(package);
+
+    let mut command = Command::new(cargo);
+    command
+        .current_dir(root_dir)
+        .arg("build")
+        .arg("--message-format=json-render-diagnostics")
+        .arg("--package")
+        .arg(package);
+    if kind == "bin" {
+        command.arg("--bin").arg(name);
+    }
+    if let Some(target) = target {
+        command.arg("--target").arg(target);
+    }
+    if release {
+        command.arg("--release");
+    }
+
+    eprintln!("Building {}...", package);
+    let mut child = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run cargo build");
+
+    let mut artifact_path = None;
+    let stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    for message in Message::parse_stream(stdout) {
+        if let Message::CompilerArtifact(artifact) = message.unwrap() {
+            // Library targets use underscores in their names
+            let name_matches =
+                artifact.target.name == name || artifact.target.name == name.replace('-', "_");
+            if name_matches && artifact.target.kind.iter().any(|k| k == kind) {
+                artifact_path = artifact
+                    .executable
+                    .or_else(|| artifact.filenames.into_iter().next());
+            }
+        }
+    }
+
+    let status = child.wait().unwrap();
+    if !status.success() {
+        panic!("failed to build {}", package);
+    }
+    artifact_path
+        .unwrap_or_else(|| {
+            panic!(
+                "{} did not produce a {} artifact named {}",
+                package, kind, name
+            )
+        })
+        .into_std_path_buf()
+}
+
+/// An artifact dependency (bindep) declared by the kernel package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactDependency {
+    /// The variable holding the path of the artifact, named like the environment variable cargo
+    /// sets at compile time, e.g. `CARGO_BIN_FILE_INIT`
+    pub var: String,
+    pub package: String,
+    pub name: Option<String>,
+    pub kind: String,
+    pub target: Option<String>,
+}
+
+/// Finds the artifact dependencies of the package at `manifest_path` with `cargo metadata`, which
+/// resolves workspace inheritance and renamed packages like cargo does when it builds them
+///
+/// A dependency with `target = "target"` is built for `kernel_target`, like cargo does.
+pub fn artifact_dependencies(
+    manifest_path: &Path,
+    kernel_target: Option<&str>,
+) -> Result<Vec<ArtifactDependency>, String> {
+    // The artifact of a dependency isn't part of the parsed metadata, so the output is read as
+    // JSON
+    let mut command = MetadataCommand::new();
+    command.manifest_path(manifest_path).no_deps();
+    let output = command
+        .cargo_command()
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("failed to run cargo metadata: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "cargo metadata failed for {}",
+            manifest_path.display()
+        ));
+    }
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("invalid output of cargo metadata: {}", err))?;
+    let package = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|package| package["manifest_path"].as_str().map(Path::new) == Some(manifest_path))
+        .ok_or_else(|| {
+            format!(
+                "cargo metadata has no package for {}",
+                manifest_path.display()
+            )
+        })?;
+    Ok(package_artifact_dependencies(package, kernel_target))
+}
+
+/// Finds the artifact dependencies of a package of the `cargo metadata` output
+fn package_artifact_dependencies(
+    package: &serde_json::Value,
+    kernel_target: Option<&str>,
+) -> Vec<ArtifactDependency> {
+    let mut dependencies = Vec::new();
+    for dep in package["dependencies"].as_array().into_iter().flatten() {
+        let artifact = &dep["artifact"];
+        let Some(kinds) = artifact["kinds"].as_array() else {
+            continue;
+        };
+        let Some(package) = dep["name"].as_str() else {
+            continue;
+        };
+        // The variables are named after the dependency, which is the package unless renamed
+        let dep_name = dep["rename"].as_str().unwrap_or(package);
+        let target = match artifact["target"].as_str() {
+            Some("target") => kernel_target.map(str::to_string),
+            target => target.map(str::to_string),
+        };
+
+        let dep_var = dep_name.to_uppercase().replace('-', "_");
+        for artifact in kinds.iter().filter_map(|kind| kind.as_str()) {
+            let (kind, name) = match artifact.split_once(':') {
+                Some((kind, name)) => (kind, Some(name)),
+                None => (artifact, None),
+            };
+            let mut var = format!("CARGO_{}_FILE_{}", kind.to_uppercase(), dep_var);
+            if let Some(name) = name {
+                var.push('_');
+                var.push_str(name);
+            }
+            dependencies.push(ArtifactDependency {
+                var,
+                package: package.to_string(),
+                name: name.map(str::to_string),
+                kind: kind.to_string(),
+                target: target.clone(),
+            });
+        }
+    }
+    dependencies
+}
+
+#[cfg(test)]
+#[test]
+fn test_artifact_dependencies() {
+    let package = serde_json::json!({
+        "name": "kernel",
+        "manifest_path": "/work/kernel/Cargo.toml",
+        "dependencies": [
+            {
+                "name": "init",
+                "rename": null,
+                "kind": null,
+                "artifact": { "kinds": ["bin"], "lib": false, "target": "target" },
+            },
+            {
+                "name": "os-servers",
+                "rename": "servers",
+                "kind": "build",
+                "artifact": { "kinds": ["bin:net"], "lib": false },
+            },
+            { "name": "log", "rename": null, "kind": null },
+        ],
+    });
+    assert_eq!(
+        package_artifact_dependencies(&package, Some("x86_64-unknown-none")),
+        vec![
+            ArtifactDependency {
+                var: "CARGO_BIN_FILE_INIT".to_string(),
+                package: "init".to_string(),
+                name: None,
+                kind: "bin".to_string(),
+                target: Some("x86_64-unknown-none".to_string()),
+            },
+            ArtifactDependency {
+                var: "CARGO_BIN_FILE_SERVERS_net".to_string(),
+                package: "os-servers".to_string(),
+                name: Some("net".to_string()),
+                kind: "bin".to_string(),
+                target: None,
+            },
+        ]
+    );
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// The capabilities of a QEMU binary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QemuInfo {
+    /// The version, e.g. `(8, 2, 0)`
+    pub version: (u32, u32, u32),
+    /// The accelerators compiled into the binary, e.g. `kvm` and `tcg`
+    pub accelerators: Vec<String>,
+    /// The supported machine types, e.g. `q35`
+    pub machines: Vec<String>,
+    /// The supported devices, e.g. `isa-debug-exit`
+    pub devices: Vec<String>,
+}
+
+impl QemuInfo {
+    pub fn has_accelerator(&self, name: &str) -> bool {
+        self.accelerators.iter().any(|accel| accel == name)
+    }
+
+    pub fn has_machine(&self, name: &str) -> bool {
+        self.machines.iter().any(|machine| machine == name)
+    }
+
+    pub fn has_device(&self, name: &str) -> bool {
+        self.devices.iter().any(|device| device == name)
+    }
+}
+
+/// The accelerators `accel = "auto"` chooses from, fastest first, TCG emulates the guest so it is
+/// always available
+pub const ACCELERATORS: &[&str] = &["kvm", "hvf", "whpx", "tcg"];
+
+/// Checks whether the host can use `accel`, returning the reason if it can't, e.g. KVM needs
+/// `/dev/kvm` to be accessible, and HVF and WHPX are only available on macOS and Windows
+pub fn host_supports(accel: &str) -> Result<(), String> {
+    match accel {
+        "kvm" => std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/kvm")
+            .map(|_| ())
+            .map_err(|err| format!("/dev/kvm is not accessible: {}", err)),
+        "hvf" if !cfg!(target_os = "macos") => Err("hvf is only available on macOS".to_string()),
+        "whpx" if !cfg!(windows) => Err("whpx is only available on Windows".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Chooses the fastest of [`ACCELERATORS`] that is compiled into QEMU and `usable` on the host
+///
+/// Also returns whether it fell back to TCG, because no hardware accelerator was usable.
+pub fn select_accelerator(info: &QemuInfo, usable: impl Fn(&str) -> bool) -> (&'static str, bool) {
+    let accel = ACCELERATORS
+        .iter()
+        .copied()
+        .find(|&accel| accel == "tcg" || (info.has_accelerator(accel) && usable(accel)))
+        .unwrap();
+    (accel, accel == "tcg")
+}
+
+type ProbeCache = Mutex<HashMap<PathBuf, Option<Arc<QemuInfo>>>>;
+
+/// Probes a QEMU binary for its version and capabilities, returning `None` if it can't be run
+///
+/// The result is cached per binary path, so this can be called freely, e.g. to skip tests
+/// that need a capability the installed QEMU doesn't have.
+pub fn probe(binary: impl AsRef<Path>) -> Option<Arc<QemuInfo>> {
+    static CACHE: OnceLock<ProbeCache> = OnceLock::new();
+    let binary = binary.as_ref();
+    let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+    cache
+        .entry(binary.to_path_buf())
+        .or_insert_with(|| probe_uncached(binary).map(Arc::new))
+        .clone()
+}
+
+fn probe_uncached(binary: &Path) -> Option<QemuInfo> {
+    let version = parse_version(&help_output(binary, &["--version"])?)?;
+    Some(QemuInfo {
+        version,
+        accelerators: parse_accelerators(&help_output(binary, &["-accel", "help"])?),
+        machines: parse_machines(&help_output(binary, &["-machine", "help"])?),
+        devices: parse_devices(&help_output(binary, &["-device", "help"])?),
+    })
+}
+
+fn help_output(binary: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new(binary).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the output of `--version`, e.g. `QEMU emulator version 8.2.0 (Debian 1:8.2.0)`
+fn parse_version(output: &str) -> Option<(u32, u32, u32)> {
+    let version = output
+        .split_once("version ")?
+        .1
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?;
+    let mut parts = version.split('.').map(|part| part.parse().ok());
+    Some((
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
+
+/// Parses the output of `-accel help`, a header followed by one accelerator per line
+fn parse_accelerators(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses the output of `-machine help`, a header followed by `name  description` lines
+fn parse_machines(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses the output of `-device help`, which lists devices as `name "isa-debug-exit", bus ISA`
+fn parse_devices(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("name \""))
+        .filter_map(|line| line.split_once('"'))
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_help() {
+    assert_eq!(
+        parse_version("QEMU emulator version 8.2.0 (Debian 1:8.2.0+ds-1)\nCopyright"),
+        Some((8, 2, 0))
+    );
+    assert_eq!(parse_version("QEMU emulator version 7.1"), Some((7, 1, 0)));
+    assert_eq!(
+        parse_accelerators("Accelerators supported in QEMU binary:\ntcg\nkvm\n"),
+        vec!["tcg", "kvm"]
+    );
+    assert_eq!(
+        parse_machines(
+            "Supported machines are:\npc                   Standard PC (alias of pc-i440fx-8.2)\nq35                  Standard PC (Q35 + ICH9, 2009)\n"
+        ),
+        vec!["pc", "q35"]
+    );
+    assert_eq!(
+        parse_devices(
+            "Misc devices:\nname \"isa-debug-exit\", bus ISA\nname \"pvpanic\", bus ISA, desc \"...\"\n"
+        ),
+        vec!["isa-debug-exit", "pvpanic"]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_select_accelerator() {
+    let info = QemuInfo {
+        version: (8, 2, 0),
+        accelerators: vec!["tcg".to_string(), "kvm".to_string()],
+        machines: Vec::new(),
+        devices: Vec::new(),
+    };
+    assert_eq!(select_accelerator(&info, |_| true), ("kvm", false));
+    assert_eq!(
+        select_accelerator(&info, |accel| accel != "kvm"),
+        ("tcg", true)
+    );
+}
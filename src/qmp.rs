@@ -0,0 +1,167 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// The arguments that make QEMU listen for QMP connections on `port`
+pub fn qemu_args(port: u16) -> Vec<String> {
+    vec![
+        "-qmp".to_string(),
+        format!("tcp:127.0.0.1:{},server=on,wait=off", port),
+    ]
+}
+
+/// The result of `query-status`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct StatusInfo {
+    pub running: bool,
+    /// The run state, e.g. `running`, `paused` or `shutdown`
+    pub status: String,
+}
+
+/// A client for the QEMU Machine Protocol
+pub struct QmpClient<R, W> {
+    reader: R,
+    writer: W,
+    /// Events received while waiting for the result of a command
+    events: Vec<Value>,
+}
+
+impl QmpClient<BufReader<TcpStream>, TcpStream> {
+    /// Connects to the QMP server of a running QEMU, e.g. at `127.0.0.1:4444`
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, String> {
+        let stream = TcpStream::connect(addr).map_err(|err| err.to_string())?;
+        let reader = BufReader::new(stream.try_clone().map_err(|err| err.to_string())?);
+        Self::new(reader, stream)
+    }
+}
+
+impl<R: BufRead, W: Write> QmpClient<R, W> {
+    /// Reads the greeting of the server and negotiates the capabilities
+    pub fn new(reader: R, writer: W) -> Result<Self, String> {
+        let mut client = Self {
+            reader,
+            writer,
+            events: Vec::new(),
+        };
+        let greeting = client.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(format!("unexpected QMP greeting: {}", greeting));
+        }
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    fn read_message(&mut self) -> Result<Value, String> {
+        let mut line = String::new();
+        let read = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|err| err.to_string())?;
+        if read == 0 {
+            return Err("the QMP connection was closed".to_string());
+        }
+        serde_json::from_str(&line).map_err(|err| format!("invalid QMP message: {}", err))
+    }
+
+    /// Executes a command, returning its result
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, String> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        writeln!(self.writer, "{}", request).map_err(|err| err.to_string())?;
+        self.writer.flush().map_err(|err| err.to_string())?;
+
+        loop {
+            let mut message = self.read_message()?;
+            if let Some(result) = message.get_mut("return") {
+                return Ok(result.take());
+            }
+            if let Some(error) = message.get("error") {
+                return Err(format!(
+                    "{} failed: {}",
+                    command,
+                    error["desc"].as_str().unwrap_or("unknown error")
+                ));
+            }
+            if message.get("event").is_some() {
+                self.events.push(message);
+            }
+        }
+    }
+
+    /// Takes the events that were received so far
+    pub fn take_events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn query_status(&mut self) -> Result<StatusInfo, String> {
+        let status = self.execute("query-status", None)?;
+        serde_json::from_value(status).map_err(|err| err.to_string())
+    }
+
+    /// Asks the guest to shut down, like pressing the power button
+    pub fn system_powerdown(&mut self) -> Result<(), String> {
+        self.execute("system_powerdown", None).map(|_| ())
+    }
+
+    /// Saves the screen to `filename`, as a PPM image
+    pub fn screendump(&mut self, filename: &str) -> Result<(), String> {
+        self.execute("screendump", Some(json!({ "filename": filename })))
+            .map(|_| ())
+    }
+
+    /// Enables or disables the trace events matching `name`, which can contain wildcards
+    pub fn trace_event_set_state(&mut self, name: &str, enable: bool) -> Result<(), String> {
+        self.execute(
+            "trace-event-set-state",
+            Some(json!({ "name": name, "enable": enable })),
+        )
+        .map(|_| ())
+    }
+
+    /// Hot-plugs a device, `properties` are passed as additional arguments
+    pub fn device_add(&mut self, driver: &str, id: &str, properties: Value) -> Result<(), String> {
+        let mut arguments = json!({ "driver": driver, "id": id });
+        if let (Some(arguments), Value::Object(properties)) =
+            (arguments.as_object_mut(), properties)
+        {
+            arguments.extend(properties);
+        }
+        self.execute("device_add", Some(arguments)).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_qmp_client() {
+    let responses = concat!(
+        r#"{"QMP": {"version": {"qemu": {"major": 8, "minor": 2, "micro": 0}}, "capabilities": []}}"#,
+        "\n",
+        r#"{"return": {}}"#,
+        "\n",
+        r#"{"event": "RESUME", "timestamp": {"seconds": 0, "microseconds": 0}}"#,
+        "\n",
+        r#"{"return": {"running": true, "singlestep": false, "status": "running"}}"#,
+        "\n",
+        r#"{"error": {"class": "GenericError", "desc": "no such device"}}"#,
+        "\n",
+    );
+    let mut written = Vec::new();
+    let mut client = QmpClient::new(responses.as_bytes(), &mut written).unwrap();
+    assert_eq!(
+        client.query_status(),
+        Ok(StatusInfo {
+            running: true,
+            status: "running".to_string()
+        })
+    );
+    assert_eq!(client.take_events().len(), 1);
+    assert!(client.system_powerdown().is_err());
+    drop(client);
+    assert_eq!(
+        String::from_utf8(written).unwrap(),
+        "{\"execute\":\"qmp_capabilities\"}\n{\"execute\":\"query-status\"}\n{\"execute\":\"system_powerdown\"}\n"
+    );
+}
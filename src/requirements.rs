@@ -0,0 +1,260 @@
+use crate::config::{BootType, ImageRunnerConfig};
+use crate::qemu;
+use std::fmt;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where the OVMF firmware is fetched from
+const OVMF_URL: &str = "https://github.com/rust-osdev/ovmf-prebuilt";
+
+/// Something the host needs, either for a test to run (as written in `test.require`) or for a
+/// component of the runner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    /// KVM acceleration, both compiled into QEMU and accessible through `/dev/kvm`
+    Kvm,
+    /// QEMU, optionally with a minimum version
+    Qemu(Option<(u32, u32, u32)>),
+    /// A program that needs to be in the `PATH`, e.g. `swtpm`
+    Program(String),
+    /// Network access to `url`, for the given purpose, e.g. fetching limine on the first run
+    Network { purpose: String, url: String },
+    /// A file that needs to exist, e.g. `/dev/net/tun`
+    Path(PathBuf),
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kvm => write!(f, "kvm"),
+            Self::Qemu(None) => write!(f, "qemu"),
+            Self::Qemu(Some((major, minor, patch))) => {
+                write!(f, "qemu>={}.{}.{}", major, minor, patch)
+            }
+            Self::Program(program) => write!(f, "{}", program),
+            Self::Network { purpose, url } => {
+                write!(f, "network access to {} for {}", url, purpose)
+            }
+            Self::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Requirement {
+    /// Parses a requirement such as `kvm`, `qemu>=8.0`, `swtpm` or `/dev/net/tun`
+    pub fn parse(str: &str) -> Result<Self, String> {
+        let str = str.trim();
+        if str == "kvm" {
+            return Ok(Self::Kvm);
+        }
+        if str == "qemu" {
+            return Ok(Self::Qemu(None));
+        }
+        if let Some(version) = str.strip_prefix("qemu>=") {
+            let mut parts = version.trim().split('.').map(|part| part.parse::<u32>());
+            let major = parts.next().and_then(|part| part.ok());
+            let minor = parts.next().unwrap_or(Ok(0));
+            let patch = parts.next().unwrap_or(Ok(0));
+            return match (major, minor, patch) {
+                (Some(major), Ok(minor), Ok(patch)) => Ok(Self::Qemu(Some((major, minor, patch)))),
+                _ => Err(format!("invalid QEMU version in requirement {}", str)),
+            };
+        }
+        if str.starts_with('/') {
+            return Ok(Self::Path(PathBuf::from(str)));
+        }
+        if str.is_empty() || str.contains(char::is_whitespace) {
+            return Err(format!("invalid requirement \"{}\"", str));
+        }
+        Ok(Self::Program(str.to_string()))
+    }
+
+    /// Checks whether the requirement is met, using `qemu_binary` for QEMU capabilities, and
+    /// returns the reason if it isn't
+    pub fn check(&self, qemu_binary: &str) -> Result<(), String> {
+        match self {
+            Self::Kvm => {
+                let info = qemu::probe(qemu_binary)
+                    .ok_or_else(|| format!("{} could not be run", qemu_binary))?;
+                if !info.has_accelerator("kvm") {
+                    return Err(format!("{} does not support kvm", qemu_binary));
+                }
+                qemu::host_supports("kvm")
+            }
+            Self::Qemu(version) => {
+                let info = qemu::probe(qemu_binary)
+                    .ok_or_else(|| format!("{} could not be run", qemu_binary))?;
+                match version {
+                    Some(version) if info.version < *version => Err(format!(
+                        "QEMU {}.{}.{} is required, found {}.{}.{}",
+                        version.0,
+                        version.1,
+                        version.2,
+                        info.version.0,
+                        info.version.1,
+                        info.version.2
+                    )),
+                    _ => Ok(()),
+                }
+            }
+            Self::Program(program) => {
+                if find_in_path(program) {
+                    Ok(())
+                } else {
+                    Err(format!("{} was not found in the PATH", program))
+                }
+            }
+            Self::Network { url, .. } => check_network(url),
+            Self::Path(path) => {
+                if path.exists() {
+                    Ok(())
+                } else {
+                    Err(format!("{} does not exist", path.display()))
+                }
+            }
+        }
+    }
+}
+
+/// Checks all of `requirements`, and returns the reasons of those that aren't met, so that
+/// everything that is missing is reported at once
+pub fn validate(requirements: &[Requirement], qemu_binary: &str) -> Result<(), Vec<String>> {
+    let unmet: Vec<String> = requirements
+        .iter()
+        .filter_map(|requirement| requirement.check(qemu_binary).err())
+        .collect();
+    if unmet.is_empty() { Ok(()) } else { Err(unmet) }
+}
+
+/// What the firmware of `config` needs, the configured OVMF files, relative to `root_dir`, or
+/// network access to fetch those that aren't configured
+pub fn firmware_requirements(config: &ImageRunnerConfig, root_dir: &Path) -> Vec<Requirement> {
+    let mut requirements = Vec::new();
+    if config.boot_type != BootType::Uefi {
+        return requirements;
+    }
+    let ovmf = &config.ovmf;
+    for path in [&ovmf.code, &ovmf.vars].into_iter().flatten() {
+        requirements.push(Requirement::Path(root_dir.join(path)));
+    }
+    if ovmf.code.is_none() || ovmf.vars.is_none() {
+        requirements.push(Requirement::Network {
+            purpose: "fetching the OVMF firmware on the first run".to_string(),
+            url: OVMF_URL.to_string(),
+        });
+    }
+    requirements
+}
+
+/// Connects to the host of `url`, local paths and file URLs don't need the network
+fn check_network(url: &str) -> Result<(), String> {
+    let Some((host, port)) = url_host(url) else {
+        return Ok(());
+    };
+    let addrs = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|err| format!("{} could not be resolved: {}", host, err))?;
+    let mut result = Err(format!("{} has no addresses", host));
+    for addr in addrs {
+        // A short timeout, so an offline machine doesn't stall the check
+        result = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+            .map(|_| ())
+            .map_err(|err| format!("{} could not be reached: {}", host, err));
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// The host and port of a URL that git or an HTTP client would connect to, such as
+/// `https://github.com/limine-bootloader/limine.git` or `git@github.com:user/repo.git`
+fn url_host(url: &str) -> Option<(String, u16)> {
+    let (authority, default_port) = match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let port = match scheme {
+                "https" => 443,
+                "http" => 80,
+                "ssh" => 22,
+                "git" => 9418,
+                _ => return None,
+            };
+            (rest.split('/').next()?, port)
+        }
+        // The scp-like syntax of ssh, but not a local (e.g. Windows) path
+        None => {
+            let (authority, _) = url.split_once(':')?;
+            if authority.len() <= 1 || authority.contains(['/', '\\']) {
+                return None;
+            }
+            return Some((
+                authority
+                    .rsplit_once('@')
+                    .map_or(authority, |(_, host)| host)
+                    .to_string(),
+                22,
+            ));
+        }
+    };
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match authority
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+    {
+        Some((host, port)) => Some((host.to_string(), port)),
+        None => Some((authority.to_string(), default_port)),
+    }
+}
+
+fn find_in_path(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&path).any(|dir| {
+        let candidate = dir.join(program);
+        candidate.is_file() || Path::new(&format!("{}.exe", candidate.display())).is_file()
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_requirement() {
+    assert_eq!(Requirement::parse("kvm"), Ok(Requirement::Kvm));
+    assert_eq!(Requirement::parse("qemu"), Ok(Requirement::Qemu(None)));
+    assert_eq!(
+        Requirement::parse("qemu>=8.0"),
+        Ok(Requirement::Qemu(Some((8, 0, 0))))
+    );
+    assert_eq!(
+        Requirement::parse("swtpm"),
+        Ok(Requirement::Program("swtpm".to_string()))
+    );
+    assert_eq!(
+        Requirement::parse("/dev/net/tun"),
+        Ok(Requirement::Path(PathBuf::from("/dev/net/tun")))
+    );
+    assert!(Requirement::parse("qemu>=eight").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_url_host() {
+    assert_eq!(
+        url_host("https://github.com/limine-bootloader/limine.git"),
+        Some(("github.com".to_string(), 443))
+    );
+    assert_eq!(
+        url_host("ssh://git@example.com:2222/repo.git"),
+        Some(("example.com".to_string(), 2222))
+    );
+    assert_eq!(
+        url_host("git@github.com:user/repo.git"),
+        Some(("github.com".to_string(), 22))
+    );
+    assert_eq!(url_host("/srv/mirrors/limine"), None);
+    assert_eq!(url_host("file:///srv/mirrors/limine"), None);
+    assert_eq!(url_host("C:\\mirrors\\limine"), None);
+}
@@ -0,0 +1,180 @@
+use serde::Serialize;
+use std::path::PathBuf;
+
+use crate::manifest::sha256_file;
+
+/// A third-party component that is shipped in the image or that the image is run with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Component {
+    pub name: String,
+    /// The version or reference, if it is known
+    pub version: Option<String>,
+    /// The SPDX license expression, e.g. `BSD-2-Clause`
+    pub license: Option<String>,
+    /// The files of the component, which are hashed into the listing
+    pub files: Vec<PathBuf>,
+    /// Whether the component is in the image, or only used to run it, like firmware
+    pub shipped: bool,
+}
+
+impl Component {
+    pub fn new(name: impl Into<String>, license: &str) -> Self {
+        Self {
+            name: name.into(),
+            version: None,
+            license: Some(license.to_string()),
+            files: Vec::new(),
+            shipped: true,
+        }
+    }
+}
+
+/// Lists the third-party components of an image as a minimal CycloneDX document, for accounting
+/// for redistributed binaries
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sbom {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    version: u32,
+    metadata: Metadata,
+    components: Vec<SbomComponent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Metadata {
+    tools: Vec<SbomComponent>,
+    component: SbomComponent,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SbomComponent {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    licenses: Vec<License>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hashes: Vec<Hash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    components: Vec<SbomComponent>,
+}
+
+impl SbomComponent {
+    fn new(kind: &'static str, name: impl Into<String>) -> Self {
+        Self {
+            kind,
+            name: name.into(),
+            version: None,
+            scope: None,
+            licenses: Vec::new(),
+            hashes: Vec::new(),
+            components: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct License {
+    expression: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Hash {
+    alg: &'static str,
+    content: String,
+}
+
+impl Sbom {
+    /// Lists the `components` of the image of `executable`, the files of each are listed as its
+    /// subcomponents with their SHA-256
+    pub fn generate(executable: &str, components: &[Component]) -> Self {
+        let components = components
+            .iter()
+            .map(|component| {
+                let mut sbom_component = SbomComponent::new("application", &component.name);
+                sbom_component.version = component.version.clone();
+                // Components that the image is only run with aren't redistributed in it
+                sbom_component.scope = Some(if component.shipped {
+                    "required"
+                } else {
+                    "excluded"
+                });
+                sbom_component.licenses = component
+                    .license
+                    .iter()
+                    .map(|license| License {
+                        expression: license.clone(),
+                    })
+                    .collect();
+                sbom_component.components = component
+                    .files
+                    .iter()
+                    .map(|path| {
+                        let (_, sha256) = sha256_file(path).unwrap_or_else(|err| {
+                            panic!("failed to hash {}: {}", path.display(), err)
+                        });
+                        let name = path.file_name().unwrap_or(path.as_os_str());
+                        let mut file = SbomComponent::new("file", name.to_string_lossy());
+                        file.hashes.push(Hash {
+                            alg: "SHA-256",
+                            content: sha256,
+                        });
+                        file
+                    })
+                    .collect();
+                sbom_component
+            })
+            .collect();
+
+        let mut tool = SbomComponent::new("application", env!("CARGO_PKG_NAME"));
+        tool.version = Some(env!("CARGO_PKG_VERSION").to_string());
+        Self {
+            bom_format: "CycloneDX",
+            spec_version: "1.5",
+            version: 1,
+            metadata: Metadata {
+                tools: vec![tool],
+                component: SbomComponent::new("operating-system", executable),
+            },
+            components,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap() + "\n"
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_sbom() {
+    let dir = std::env::temp_dir().join(format!("image-runner-sbom-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("BOOTX64.EFI"), "abc").unwrap();
+
+    let mut limine = Component::new("limine", "BSD-2-Clause");
+    limine.version = Some("v8.x-binary".to_string());
+    limine.files.push(dir.join("BOOTX64.EFI"));
+    let mut ovmf = Component::new("edk2-ovmf", "BSD-2-Clause-Patent");
+    ovmf.shipped = false;
+    let sbom = Sbom::generate("kernel", &[limine, ovmf]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let json: serde_json::Value = serde_json::from_str(&sbom.to_json()).unwrap();
+    assert_eq!(json["bomFormat"], "CycloneDX");
+    assert_eq!(json["metadata"]["component"]["name"], "kernel");
+    let components = json["components"].as_array().unwrap();
+    assert_eq!(components[0]["version"], "v8.x-binary");
+    assert_eq!(components[0]["licenses"][0]["expression"], "BSD-2-Clause");
+    assert_eq!(
+        components[0]["components"][0]["hashes"][0]["content"],
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(components[1]["scope"], "excluded");
+    assert!(components[1].get("components").is_none());
+}
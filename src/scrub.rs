@@ -0,0 +1,119 @@
+use regex::Regex;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::config::ScrubConfig;
+
+/// Replaces parts of the output that change between runs, such as addresses, timestamps or random
+/// seeds, so the output can be compared
+pub trait Scrubber: Send + Sync {
+    fn scrub(&self, output: &str) -> String;
+}
+
+impl<F> Scrubber for F
+where
+    F: Fn(&str) -> String + Send + Sync,
+{
+    fn scrub(&self, output: &str) -> String {
+        self(output)
+    }
+}
+
+/// Replaces the matches of a regex, the replacement can refer to capture groups like `$1`
+#[derive(Debug, Clone)]
+pub struct RegexScrubber {
+    regex: Regex,
+    replacement: String,
+}
+
+impl RegexScrubber {
+    pub fn new(pattern: &str, replacement: impl Into<String>) -> Result<Self, String> {
+        let regex = Regex::new(pattern)
+            .map_err(|err| format!("invalid scrub pattern {}: {}", pattern, err))?;
+        Ok(Self {
+            regex,
+            replacement: replacement.into(),
+        })
+    }
+}
+
+impl Scrubber for RegexScrubber {
+    fn scrub(&self, output: &str) -> String {
+        self.regex
+            .replace_all(output, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// Scrubbers that are applied in order, from the `scrub` config and added with
+/// [`crate::context::ContextBuilder::scrubber`]
+#[derive(Clone, Default)]
+pub struct ScrubChain {
+    scrubbers: Vec<Arc<dyn Scrubber>>,
+}
+
+impl ScrubChain {
+    pub fn from_config(config: &[ScrubConfig]) -> Result<Self, String> {
+        let mut chain = Self::default();
+        for scrub in config {
+            chain.push(RegexScrubber::new(&scrub.pattern, &scrub.replacement)?);
+        }
+        Ok(chain)
+    }
+
+    /// Adds a scrubber to the end of the chain
+    pub fn push(&mut self, scrubber: impl Scrubber + 'static) {
+        self.scrubbers.push(Arc::new(scrubber));
+    }
+
+    /// Adds the scrubbers of `other` to the end of the chain
+    pub fn append(&mut self, other: &ScrubChain) {
+        self.scrubbers.extend(other.scrubbers.iter().cloned());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scrubbers.is_empty()
+    }
+
+    pub fn scrub(&self, output: &str) -> String {
+        let mut output = output.to_string();
+        for scrubber in &self.scrubbers {
+            output = scrubber.scrub(&output);
+        }
+        output
+    }
+}
+
+impl fmt::Debug for ScrubChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ScrubChain({} scrubbers)", self.scrubbers.len())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_scrub_chain() {
+    let config = [
+        ScrubConfig {
+            pattern: "0x[0-9a-f]+".to_string(),
+            replacement: "[addr]".to_string(),
+        },
+        ScrubConfig {
+            pattern: r"seed=(\w+)".to_string(),
+            replacement: "seed=[seed]".to_string(),
+        },
+    ];
+    let mut chain = ScrubChain::from_config(&config).unwrap();
+    chain.push(|output: &str| output.replace("[addr]", "[ADDR]"));
+    assert_eq!(
+        chain.scrub("heap at 0xffff8000, seed=4f2a\n"),
+        "heap at [ADDR], seed=[seed]\n"
+    );
+    assert!(
+        ScrubChain::from_config(&[ScrubConfig {
+            pattern: "(".to_string(),
+            replacement: String::new(),
+        }])
+        .is_err()
+    );
+}
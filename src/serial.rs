@@ -0,0 +1,889 @@
+use regex::bytes::Regex;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::outcome::ChunkTime;
+
+#[derive(Debug, Default)]
+struct Output {
+    bytes: Vec<u8>,
+    /// When each chunk was received, and the position it starts at
+    chunks: Vec<(Instant, usize)>,
+    closed: bool,
+    /// Removes escape sequences from the recorded output, if it is set
+    stripper: Option<AnsiStripper>,
+}
+
+/// Records the output of the guest while forwarding it, so input can wait for a prompt
+#[derive(Debug, Clone, Default)]
+pub struct OutputWatcher {
+    inner: Arc<(Mutex<Output>, Condvar)>,
+}
+
+impl OutputWatcher {
+    /// A watcher that records the output without ANSI escape sequences, so they don't get in the
+    /// way of patterns, they are still forwarded
+    pub fn stripping_ansi() -> Self {
+        let watcher = Self::default();
+        watcher.inner.0.lock().unwrap().stripper = Some(AnsiStripper::default());
+        watcher
+    }
+
+    /// Copies `from` to stdout until it is closed, recording everything that was read
+    pub fn forward(&self, from: impl Read) {
+        self.forward_to(from, std::io::stdout());
+    }
+
+    /// Like [`Self::forward`], but copies to `to`, e.g. stderr
+    pub fn forward_to(&self, mut from: impl Read, mut to: impl Write) {
+        let (output, changed) = &*self.inner;
+        let mut buf = [0u8; 1024];
+        loop {
+            let read = from.read(&mut buf).unwrap_or(0);
+            if read > 0 {
+                let _ = to.write_all(&buf[..read]);
+                let _ = to.flush();
+            }
+            let mut output = output.lock().unwrap();
+            if read == 0 {
+                output.closed = true;
+                changed.notify_all();
+                return;
+            }
+            let output = &mut *output;
+            output.chunks.push((Instant::now(), output.bytes.len()));
+            match &mut output.stripper {
+                Some(stripper) => stripper.strip(&buf[..read], &mut output.bytes),
+                None => output.bytes.extend_from_slice(&buf[..read]),
+            }
+            changed.notify_all();
+        }
+    }
+
+    /// The number of bytes that were output so far
+    pub fn position(&self) -> usize {
+        self.inner.0.lock().unwrap().bytes.len()
+    }
+
+    /// Everything that was output so far
+    pub fn output(&self) -> Vec<u8> {
+        self.inner.0.lock().unwrap().bytes.clone()
+    }
+
+    /// When each chunk of the output was received, relative to `start`
+    pub fn timestamps(&self, start: Instant) -> Vec<ChunkTime> {
+        self.inner
+            .0
+            .lock()
+            .unwrap()
+            .chunks
+            .iter()
+            .map(|&(received, offset)| ChunkTime {
+                offset,
+                received: received.saturating_duration_since(start),
+            })
+            .collect()
+    }
+
+    /// Waits until `pattern` is output after `start`, returning the position right after it, or
+    /// `None` if the output was closed first
+    pub fn wait_for(&self, pattern: &[u8], start: usize) -> Option<usize> {
+        self.wait_for_timeout(pattern, start, None)
+    }
+
+    /// Like [`Self::wait_for`], but also gives up if the guest outputs nothing for `timeout`
+    pub fn wait_for_timeout(
+        &self,
+        pattern: &[u8],
+        start: usize,
+        timeout: Option<Duration>,
+    ) -> Option<usize> {
+        let (output, changed) = &*self.inner;
+        let mut output = output.lock().unwrap();
+        loop {
+            let searched = output.bytes.get(start..).unwrap_or_default();
+            if let Some(index) = find(searched, pattern) {
+                return Some(start + index + pattern.len());
+            }
+            if output.closed {
+                return None;
+            }
+            output = match timeout {
+                Some(timeout) => {
+                    let len = output.bytes.len();
+                    let (output, result) = changed.wait_timeout(output, timeout).unwrap();
+                    // Only new output resets the timeout, not spurious wakeups
+                    if result.timed_out() && output.bytes.len() == len {
+                        return None;
+                    }
+                    output
+                }
+                None => changed.wait(output).unwrap(),
+            };
+        }
+    }
+
+    /// Waits until `find` returns something for the output after `start`, or returns `None` if
+    /// the output was closed first
+    pub fn wait_until<T>(
+        &self,
+        start: usize,
+        mut find: impl FnMut(&[u8]) -> Option<T>,
+    ) -> Option<T> {
+        let (output, changed) = &*self.inner;
+        let mut output = output.lock().unwrap();
+        loop {
+            if let Some(found) = find(output.bytes.get(start..).unwrap_or_default()) {
+                return Some(found);
+            }
+            if output.closed {
+                return None;
+            }
+            output = changed.wait(output).unwrap();
+        }
+    }
+
+    /// Waits until nothing is output for `timeout`, returning `false` if the output was closed
+    /// first
+    pub fn wait_for_idle(&self, timeout: Duration) -> bool {
+        let (output, changed) = &*self.inner;
+        let mut output = output.lock().unwrap();
+        let mut len = output.bytes.len();
+        let mut deadline = Instant::now() + timeout;
+        loop {
+            if output.closed {
+                return false;
+            }
+            if output.bytes.len() != len {
+                len = output.bytes.len();
+                deadline = Instant::now() + timeout;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            output = changed.wait_timeout(output, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Waits until a complete line is output after `start`, returning it with its line ending, so
+    /// patterns can be matched line by line as the output arrives, or `None` if the output was
+    /// closed first
+    pub fn wait_for_line(&self, start: usize) -> Option<Vec<u8>> {
+        self.wait_until(start, |output| {
+            let end = output.iter().position(|&byte| byte == b'\n')?;
+            Some(output[..=end].to_vec())
+        })
+    }
+
+    /// Like [`Self::wait_for_line`], but gives up at `deadline`, if there is one
+    pub fn wait_for_line_until(&self, start: usize, deadline: Option<Instant>) -> LineWait {
+        let (output, changed) = &*self.inner;
+        let mut output = output.lock().unwrap();
+        loop {
+            let searched = output.bytes.get(start..).unwrap_or_default();
+            if let Some(end) = searched.iter().position(|&byte| byte == b'\n') {
+                return LineWait::Line(searched[..=end].to_vec());
+            }
+            if output.closed {
+                return LineWait::Closed;
+            }
+            output = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return LineWait::TimedOut;
+                    }
+                    changed.wait_timeout(output, deadline - now).unwrap().0
+                }
+                None => changed.wait(output).unwrap(),
+            };
+        }
+    }
+
+    /// Waits until the guest resets more than `limit` times, which is detected by the first line
+    /// it output (its boot banner) being output again, returning `false` if the output was closed
+    /// first
+    pub fn wait_for_reset_loop(&self, limit: u32) -> bool {
+        let mut start = 0;
+        let (banner, mut position) = loop {
+            let Some(end) = self.wait_for(b"\n", start) else {
+                return false;
+            };
+            let line = self.inner.0.lock().unwrap().bytes[start..end]
+                .trim_ascii()
+                .to_vec();
+            if !line.is_empty() {
+                break (line, end);
+            }
+            start = end;
+        };
+        let mut resets = 0;
+        while let Some(end) = self.wait_for(&banner, position) {
+            resets += 1;
+            if resets > limit {
+                return true;
+            }
+            position = end;
+        }
+        false
+    }
+}
+
+/// What waiting for a line of the output ended with, see [`OutputWatcher::wait_for_line_until`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineWait {
+    /// The line, with its line ending
+    Line(Vec<u8>),
+    /// The deadline passed first
+    TimedOut,
+    /// The output was closed first
+    Closed,
+}
+
+/// The position of the first `needle` in `haystack`
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Where an [`AnsiStripper`] is in an escape sequence
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum AnsiState {
+    #[default]
+    Text,
+    Escape,
+    /// In a control sequence, which ends with a byte in `@` to `~`
+    Control,
+    /// In an operating system command, e.g. setting the title, which ends with BEL or `ESC \`
+    Command,
+    CommandEscape,
+}
+
+/// Removes ANSI escape sequences, such as colors and cursor movements, from output that is read
+/// in chunks, which can split a sequence
+#[derive(Debug, Clone, Default)]
+pub struct AnsiStripper {
+    state: AnsiState,
+}
+
+impl AnsiStripper {
+    /// Appends `input` to `output` without the escape sequences
+    pub fn strip(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (AnsiState::Text, 0x1b) => AnsiState::Escape,
+                (AnsiState::Text, _) => {
+                    output.push(byte);
+                    AnsiState::Text
+                }
+                (AnsiState::Escape, b'[') => AnsiState::Control,
+                (AnsiState::Escape, b']') => AnsiState::Command,
+                // Other escape sequences are two bytes long
+                (AnsiState::Escape, _) => AnsiState::Text,
+                (AnsiState::Control, 0x40..=0x7e) => AnsiState::Text,
+                (AnsiState::Control, _) => AnsiState::Control,
+                (AnsiState::Command, 0x07) => AnsiState::Text,
+                (AnsiState::Command, 0x1b) => AnsiState::CommandEscape,
+                (AnsiState::Command, _) => AnsiState::Command,
+                (AnsiState::CommandEscape, b'\\') => AnsiState::Text,
+                (AnsiState::CommandEscape, _) => AnsiState::Command,
+            };
+        }
+    }
+}
+
+/// Removes ANSI escape sequences, such as colors and cursor movements, from `output`
+pub fn strip_ansi(output: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(output.len());
+    AnsiStripper::default().strip(output, &mut stripped);
+    stripped
+}
+
+/// How to feed input to the guest
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Waits between lines
+    pub line_delay: Option<Duration>,
+    /// Waits for this to be output before sending each line
+    pub prompt: Option<String>,
+    /// Limits the input to this many bytes per second, so it doesn't outrun the guest's UART
+    pub rate: Option<u32>,
+    /// Waits for each line to be echoed back before sending the next one
+    pub wait_for_echo: bool,
+    /// Stops feeding input if the guest outputs nothing for this long while waiting for it
+    pub timeout: Option<Duration>,
+}
+
+impl InputOptions {
+    /// Whether the input has to be sent line by line, instead of all at once
+    fn line_by_line(&self) -> bool {
+        self.line_delay.is_some() || self.prompt.is_some() || self.wait_for_echo
+    }
+
+    /// Whether the output of the guest needs to be watched
+    pub fn needs_output(&self) -> bool {
+        self.prompt.is_some() || self.wait_for_echo
+    }
+}
+
+/// Writes `input` to the serial input of the guest, following `options`
+///
+/// Stops early if the guest closes its input, or closes its output or goes silent for longer
+/// than the timeout while waiting for a prompt, which is returned as an error.
+pub fn feed_input(
+    mut to: impl Write,
+    input: &[u8],
+    options: &InputOptions,
+    watcher: Option<&OutputWatcher>,
+) -> Result<(), String> {
+    if !options.line_by_line() {
+        let _ = send(&mut to, input, options.rate);
+        return Ok(());
+    }
+
+    let mut position = 0;
+    for (i, line) in input.split_inclusive(|&b| b == b'\n').enumerate() {
+        if i > 0
+            && let Some(delay) = options.line_delay
+        {
+            std::thread::sleep(delay);
+        }
+        if let (Some(prompt), Some(watcher)) = (&options.prompt, watcher) {
+            match watcher.wait_for_timeout(prompt.as_bytes(), position, options.timeout) {
+                Some(end) => position = end,
+                None => return timed_out(watcher, options),
+            }
+        }
+        if send(&mut to, line, options.rate).is_err() {
+            return Ok(());
+        }
+        if let (true, Some(watcher)) = (options.wait_for_echo, watcher) {
+            // The line ending is usually echoed differently, e.g. as `\r\n`
+            let echo = line.strip_suffix(b"\n").unwrap_or(line);
+            let echo = echo.strip_suffix(b"\r").unwrap_or(echo);
+            match watcher.wait_for_timeout(echo, position, options.timeout) {
+                Some(end) => position = end,
+                None => return timed_out(watcher, options),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails if the guest went silent, unless it just closed its output
+fn timed_out(watcher: &OutputWatcher, options: &InputOptions) -> Result<(), String> {
+    match options.timeout {
+        Some(timeout) if !watcher.inner.0.lock().unwrap().closed => Err(format!(
+            "the guest printed nothing for {:?}, no more input is sent",
+            timeout
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A writer shared between threads, such as the serial input of the guest, which is written by
+/// the input and the responders
+#[derive(Debug)]
+pub struct SharedWriter<W>(Arc<Mutex<W>>);
+
+impl<W> SharedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+}
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    /// Writes all of `buf` at once, so it isn't interleaved with the writes of other threads
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Answers the guest when its output matches a regex
+#[derive(Debug, Clone)]
+pub struct Responder {
+    pub regex: Regex,
+    /// The response, where `$1` or `${name}` are replaced by the capture groups of the match
+    pub response: String,
+    /// Only answers the first match, instead of every one
+    pub once: bool,
+}
+
+/// Writes the responses of `responders` to `to` for every match in the output, until the output
+/// is closed or only one-shot responders that already answered are left
+///
+/// The output is matched as it arrives, so patterns should end with a delimiter, such as a line
+/// ending, to not match a partial line.
+pub fn respond(mut to: impl Write, watcher: &OutputWatcher, mut responders: Vec<Responder>) {
+    let mut position = 0;
+    while !responders.is_empty() {
+        // The earliest match is answered first, in the order the guest printed them
+        let found = watcher.wait_until(position, |output| {
+            responders
+                .iter()
+                .enumerate()
+                .filter_map(|(index, responder)| {
+                    let captures = responder.regex.captures(output)?;
+                    let matched = captures.get(0).unwrap();
+                    let mut response = Vec::new();
+                    captures.expand(responder.response.as_bytes(), &mut response);
+                    Some((matched.start(), matched.end(), index, response))
+                })
+                .min_by_key(|(start, ..)| *start)
+        });
+        let Some((_, end, index, response)) = found else {
+            return;
+        };
+        // An empty match still moves on, so the same position isn't answered forever
+        position += end.max(1);
+        if to.write_all(&response).and_then(|_| to.flush()).is_err() {
+            return;
+        }
+        if responders[index].once {
+            responders.remove(index);
+        }
+    }
+}
+
+/// A step of an expect script, which waits for the guest to print something and answers it
+#[derive(Debug, Clone, Default)]
+pub struct ExpectStep {
+    /// Waits for this to be output, after the output the previous step waited for
+    pub wait: Option<String>,
+    /// Then sends this
+    pub send: Option<String>,
+    /// Fails the script if `wait` isn't output within this time
+    pub timeout: Option<Duration>,
+}
+
+/// Runs the `steps` of an expect script in order, writing to `to`
+///
+/// Returns why it failed, with the number of the step, if one timed out or the output was closed
+/// before the script finished.
+pub fn run_expect(
+    mut to: impl Write,
+    watcher: &OutputWatcher,
+    steps: &[ExpectStep],
+) -> Result<(), String> {
+    let mut position = 0;
+    for (index, step) in steps.iter().enumerate() {
+        if let Some(wait) = &step.wait {
+            let deadline = step.timeout.map(|timeout| Instant::now() + timeout);
+            let (output, changed) = &*watcher.inner;
+            let mut output = output.lock().unwrap();
+            loop {
+                let searched = output.bytes.get(position..).unwrap_or_default();
+                if let Some(found) = find(searched, wait.as_bytes()) {
+                    position += found + wait.len();
+                    break;
+                }
+                if output.closed {
+                    return Err(format!(
+                        "expect step {}: the guest stopped before printing {:?}",
+                        index + 1,
+                        wait
+                    ));
+                }
+                output = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(format!(
+                                "expect step {}: {:?} wasn't printed within {:?}",
+                                index + 1,
+                                wait,
+                                step.timeout.unwrap()
+                            ));
+                        }
+                        changed.wait_timeout(output, deadline - now).unwrap().0
+                    }
+                    None => changed.wait(output).unwrap(),
+                };
+            }
+        }
+        if let Some(send) = &step.send {
+            to.write_all(send.as_bytes())
+                .and_then(|_| to.flush())
+                .map_err(|err| format!("expect step {}: failed to send: {}", index + 1, err))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `bytes`, paced to `rate` bytes per second if set
+fn send(to: &mut impl Write, bytes: &[u8], rate: Option<u32>) -> std::io::Result<()> {
+    let Some(rate) = rate.filter(|&rate| rate > 0) else {
+        to.write_all(bytes)?;
+        return to.flush();
+    };
+    // Sending in small chunks keeps the pacing smooth without sleeping for every byte
+    let chunk_size = (rate as usize / 100).max(1);
+    let chunk_delay = Duration::from_secs_f64(chunk_size as f64 / rate as f64);
+    for chunk in bytes.chunks(chunk_size) {
+        to.write_all(chunk)?;
+        to.flush()?;
+        std::thread::sleep(chunk_delay);
+    }
+    Ok(())
+}
+
+/// Where QEMU listens for connections to the serial port of the guest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SerialSocket {
+    /// A unix socket at this path
+    Unix(PathBuf),
+    /// A TCP port of localhost
+    Tcp(u16),
+}
+
+impl SerialSocket {
+    /// Parses `unix:<path>` or `tcp:<port>`
+    pub fn parse(socket: &str) -> Result<Self, String> {
+        match socket.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            Some(("tcp", port)) => port
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|_| format!("invalid serial socket port: {}", port)),
+            _ => Err(format!(
+                "invalid serial socket {}, expected unix:<path> or tcp:<port>",
+                socket
+            )),
+        }
+    }
+
+    /// The arguments that make QEMU listen for connections to the serial port
+    pub fn qemu_args(&self) -> Vec<String> {
+        let backend = match self {
+            Self::Unix(path) => format!("unix:{}", path.display()),
+            Self::Tcp(port) => format!("tcp:127.0.0.1:{}", port),
+        };
+        vec![
+            "-serial".to_string(),
+            format!("{},server=on,wait=off", backend),
+        ]
+    }
+}
+
+/// Removes `-serial stdio` from `args`, which a [`SerialSocket`] replaces, other serial ports are
+/// an error as the socket wouldn't be the first one then
+pub fn strip_serial_stdio(args: &[String]) -> Result<Vec<String>, String> {
+    let mut stripped = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "-serial" {
+            stripped.push(arg.clone());
+            continue;
+        }
+        match args.next().map(String::as_str) {
+            Some("stdio" | "mon:stdio") => {}
+            Some(backend) => {
+                return Err(format!(
+                    "serial-socket replaces the serial port, remove `-serial {}` from the run-args",
+                    backend
+                ));
+            }
+            None => return Err("-serial is missing its value".to_string()),
+        }
+    }
+    Ok(stripped)
+}
+
+impl fmt::Display for SerialSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(port) => write!(f, "tcp:{}", port),
+        }
+    }
+}
+
+/// A connection to the serial port of a running guest, see [`SerialSocket`]
+#[derive(Debug)]
+pub enum SerialConnection {
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl SerialConnection {
+    /// Connects to the serial port at `socket`, retrying until `timeout` as QEMU may still be
+    /// starting
+    pub fn connect(socket: &SerialSocket, timeout: Duration) -> std::io::Result<Self> {
+        let start = Instant::now();
+        loop {
+            let result = match socket {
+                #[cfg(unix)]
+                SerialSocket::Unix(path) => UnixStream::connect(path).map(Self::Unix),
+                #[cfg(not(unix))]
+                SerialSocket::Unix(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "unix sockets are only supported on unix",
+                    ));
+                }
+                SerialSocket::Tcp(port) => TcpStream::connect(("127.0.0.1", *port)).map(Self::Tcp),
+            };
+            match result {
+                Err(_) if start.elapsed() < timeout => {
+                    std::thread::sleep(Duration::from_millis(50))
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Another handle to the same connection, e.g. to read and write from different threads
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+        }
+    }
+}
+
+impl Read for SerialConnection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for SerialConnection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_wait_for() {
+    let watcher = OutputWatcher::default();
+    watcher.forward(&b"booting...\n> ls\nfoo\n> "[..]);
+    assert_eq!(watcher.wait_for(b"> ", 0), Some(13));
+    assert_eq!(watcher.wait_for(b"> ", 13), Some(22));
+    assert_eq!(watcher.wait_for(b"> ", 22), None);
+    assert_eq!(watcher.wait_for_line(0), Some(b"booting...\n".to_vec()));
+    assert_eq!(watcher.wait_for_line(16), Some(b"foo\n".to_vec()));
+    // The prompt isn't a complete line
+    assert_eq!(watcher.wait_for_line(20), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_wait_for_across_reads() {
+    /// Returns a single byte per read, so every pattern straddles read boundaries
+    struct ByteReader<'a>(&'a [u8]);
+    impl Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    let watcher = OutputWatcher::default();
+    watcher.forward(ByteReader("héllo → wörld$ ".as_bytes()));
+    assert_eq!(
+        watcher.wait_for("→ wö".as_bytes(), 0),
+        Some("héllo → wö".len())
+    );
+    assert_eq!(watcher.wait_for(b"$ ", 0), Some("héllo → wörld$ ".len()));
+}
+
+#[cfg(test)]
+#[test]
+fn test_wait_for_reset_loop() {
+    let watcher = OutputWatcher::default();
+    watcher.forward(&b"\r\nBooting v1\r\npanic\r\nBooting v1\r\nBooting v1\r\n"[..]);
+    assert!(watcher.wait_for_reset_loop(1));
+    assert!(!watcher.wait_for_reset_loop(2));
+}
+
+#[cfg(test)]
+#[test]
+fn test_respond() {
+    let watcher = OutputWatcher::default();
+    watcher.forward(&b"token: abc123\nready\ntoken: def\nready\n"[..]);
+    let responders = vec![
+        Responder {
+            regex: Regex::new(r"token: (\w+)\n").unwrap(),
+            response: "echo $1\n".to_string(),
+            once: true,
+        },
+        Responder {
+            regex: Regex::new(r"ready\n").unwrap(),
+            response: "go\n".to_string(),
+            once: false,
+        },
+    ];
+    let mut input = Vec::new();
+    respond(&mut input, &watcher, responders);
+    assert_eq!(input, b"echo abc123\ngo\ngo\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_run_expect() {
+    let watcher = OutputWatcher::default();
+    watcher.forward(&b"login: root\nPassword: \n# "[..]);
+    let step = |wait: &str, send: &str| ExpectStep {
+        wait: Some(wait.to_string()),
+        send: Some(send.to_string()),
+        timeout: Some(Duration::from_millis(50)),
+    };
+    let mut input = Vec::new();
+    let steps = [step("login:", "root\n"), step("Password:", "toor\n")];
+    assert_eq!(run_expect(&mut input, &watcher, &steps), Ok(()));
+    assert_eq!(input, b"root\ntoor\n");
+
+    let steps = [step("# ", "ls\n"), step("# ", "exit\n")];
+    assert_eq!(
+        run_expect(Vec::new(), &watcher, &steps),
+        Err("expect step 2: the guest stopped before printing \"# \"".to_string())
+    );
+
+    let open = OutputWatcher::default();
+    assert_eq!(
+        run_expect(Vec::new(), &open, &[step("login:", "root\n")]),
+        Err("expect step 1: \"login:\" wasn't printed within 50ms".to_string())
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_wait_for_idle() {
+    let watcher = OutputWatcher::default();
+    assert!(watcher.wait_for_idle(Duration::from_millis(50)));
+    watcher.forward(&b"done\n"[..]);
+    assert!(!watcher.wait_for_idle(Duration::from_millis(50)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_wait_for_line_until() {
+    let watcher = OutputWatcher::default();
+    let deadline = Instant::now() + Duration::from_millis(50);
+    assert_eq!(
+        watcher.wait_for_line_until(0, Some(deadline)),
+        LineWait::TimedOut
+    );
+    watcher.forward(&b"[RUN] heap\n[PASS"[..]);
+    assert_eq!(
+        watcher.wait_for_line_until(0, None),
+        LineWait::Line(b"[RUN] heap\n".to_vec())
+    );
+    assert_eq!(watcher.wait_for_line_until(11, None), LineWait::Closed);
+}
+
+#[cfg(test)]
+#[test]
+fn test_wait_for_timeout() {
+    let watcher = OutputWatcher::default();
+    let writer = watcher.clone();
+    std::thread::spawn(move || {
+        let (output, changed) = &*writer.inner;
+        output.lock().unwrap().bytes.extend_from_slice(b"> ");
+        changed.notify_all();
+    });
+    let timeout = Some(Duration::from_millis(50));
+    assert_eq!(
+        watcher.wait_for_timeout(b"> ", 0, Some(Duration::from_secs(5))),
+        Some(2)
+    );
+    assert_eq!(watcher.wait_for_timeout(b"> ", 2, timeout), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_serial_socket() {
+    let socket = SerialSocket::parse("unix:target/serial.sock").unwrap();
+    assert_eq!(
+        socket.qemu_args(),
+        ["-serial", "unix:target/serial.sock,server=on,wait=off"]
+    );
+    assert!(SerialSocket::parse("tcp:serial").is_err());
+    let args = ["-serial", "stdio", "-m", "1G"].map(String::from);
+    assert_eq!(strip_serial_stdio(&args).unwrap(), ["-m", "1G"]);
+    assert!(strip_serial_stdio(&["-serial".to_string(), "file:com1.log".to_string()]).is_err());
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let socket = SerialSocket::Tcp(listener.local_addr().unwrap().port());
+    let mut connection = SerialConnection::connect(&socket, Duration::from_secs(1)).unwrap();
+    let (mut guest, _) = listener.accept().unwrap();
+    guest.write_all(b"login: ").unwrap();
+    let mut prompt = [0; 7];
+    connection.read_exact(&mut prompt).unwrap();
+    assert_eq!(&prompt, b"login: ");
+}
+
+#[cfg(test)]
+#[test]
+fn test_strip_ansi() {
+    assert_eq!(
+        strip_ansi(b"\x1b[1;32mok\x1b[0m \x1b]0;title\x07done\x1b]8;;\x1b\\\x1bc"),
+        b"ok done"
+    );
+
+    // Sequences can be split between the chunks that are read
+    let mut stripper = AnsiStripper::default();
+    let mut stripped = Vec::new();
+    for chunk in [&b"pan\x1b["[..], b"31", b"micked\x1b", b"[0m"] {
+        stripper.strip(chunk, &mut stripped);
+    }
+    assert_eq!(stripped, b"panicked");
+    let watcher = OutputWatcher::stripping_ansi();
+    watcher.forward_to(&b"\x1b[1mREADY\x1b[0m"[..], std::io::sink());
+    assert_eq!(watcher.output(), b"READY");
+
+    // Patterns see whole lines, even if the line and its escape sequences were split between
+    // the reads
+    let watcher = OutputWatcher::stripping_ansi();
+    let reads = (&b"[PA\x1b[3"[..]).chain(&b"2mSS] heap\n"[..]);
+    watcher.forward_to(reads, std::io::sink());
+    assert_eq!(watcher.wait_for_line(0), Some(b"[PASS] heap\n".to_vec()));
+}
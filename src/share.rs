@@ -0,0 +1,120 @@
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use crate::config::{ShareConfig, ShareDriver};
+
+/// The QEMU arguments that share the directory at `path` with the guest, `index` is the position
+/// of the share, and `socket` the one `virtiofsd` listens on with virtiofs
+pub fn share_args(path: &Path, share: &ShareConfig, index: usize, socket: &Path) -> Vec<String> {
+    let tag = share.tag(index);
+    match share.driver {
+        ShareDriver::NineP => {
+            // Commas are escaped by doubling them in QEMU options
+            let mut fsdev = format!(
+                "local,id=fsdev{},path={},security_model=none",
+                index,
+                path.to_string_lossy().replace(',', ",,")
+            );
+            if share.readonly {
+                fsdev.push_str(",readonly=on");
+            }
+            vec![
+                "-fsdev".to_string(),
+                fsdev,
+                "-device".to_string(),
+                format!("virtio-9p-pci,fsdev=fsdev{},mount_tag={}", index, tag),
+            ]
+        }
+        ShareDriver::Virtiofs => vec![
+            "-chardev".to_string(),
+            format!(
+                "socket,id=virtiofs{},path={}",
+                index,
+                socket.to_string_lossy().replace(',', ",,")
+            ),
+            "-device".to_string(),
+            format!("vhost-user-fs-pci,chardev=virtiofs{},tag={}", index, tag),
+        ],
+    }
+}
+
+/// The QEMU arguments that share the memory of the guest, which virtiofs needs
+pub fn shared_memory_args(memory: &str) -> Vec<String> {
+    vec![
+        "-m".to_string(),
+        memory.to_string(),
+        "-object".to_string(),
+        format!(
+            "memory-backend-memfd,id=virtiofs-mem,size={},share=on",
+            memory
+        ),
+        "-machine".to_string(),
+        "memory-backend=virtiofs-mem".to_string(),
+    ]
+}
+
+/// Starts `virtiofsd` serving the directory at `path` on `socket`, it exits when QEMU disconnects,
+/// so it is started again for every run of QEMU
+pub fn start_virtiofsd(path: &Path, share: &ShareConfig, socket: &Path) -> Result<Child, String> {
+    let _ = std::fs::remove_file(socket);
+    let mut command = Command::new("virtiofsd");
+    command
+        .arg(format!("--socket-path={}", socket.display()))
+        .arg(format!("--shared-dir={}", path.display()))
+        .arg("--sandbox=none")
+        .stdout(Stdio::null());
+    if share.readonly {
+        command.arg("--readonly");
+    }
+    let child = command
+        .spawn()
+        .map_err(|err| format!("failed to start virtiofsd: {}", err))?;
+    // QEMU fails if the socket doesn't exist yet when it connects
+    for _ in 0..50 {
+        if socket.exists() {
+            return Ok(child);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    stop_virtiofsd(vec![child]);
+    Err(format!(
+        "virtiofsd didn't create {} in time",
+        socket.display()
+    ))
+}
+
+/// Stops the `virtiofsd` instances that are still running, e.g. when QEMU failed to start
+pub fn stop_virtiofsd(children: Vec<Child>) {
+    for mut child in children {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_share_args() {
+    let mut share = ShareConfig {
+        path: "fixtures".to_string(),
+        tag: None,
+        driver: ShareDriver::NineP,
+        readonly: true,
+        memory: "512M".to_string(),
+    };
+    let socket = Path::new("/tmp/virtiofs1.sock");
+    assert_eq!(
+        share_args(Path::new("/work/fixtures"), &share, 1, socket),
+        [
+            "-fsdev",
+            "local,id=fsdev1,path=/work/fixtures,security_model=none,readonly=on",
+            "-device",
+            "virtio-9p-pci,fsdev=fsdev1,mount_tag=share1"
+        ]
+    );
+    share.driver = ShareDriver::Virtiofs;
+    share.tag = Some("fixtures".to_string());
+    assert_eq!(
+        share_args(Path::new("/work/fixtures"), &share, 1, socket)[3],
+        "vhost-user-fs-pci,chardev=virtiofs1,tag=fixtures"
+    );
+}
@@ -0,0 +1,105 @@
+use regex::Regex;
+use std::path::Path;
+
+use crate::config::SnapshotFilterConfig;
+
+/// The result of comparing the output against a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotOutcome {
+    Matched,
+    /// The snapshot didn't exist yet, or updates were requested, so it was written
+    Written,
+    /// The output differs from the snapshot, described by the first differing line
+    Mismatch(String),
+}
+
+/// Normalizes the line endings of `output` and applies the `filters` in order, so parts that
+/// change between runs, like addresses or timestamps, don't fail the comparison
+pub fn normalize(output: &[u8], filters: &[SnapshotFilterConfig]) -> Result<String, String> {
+    let mut output = String::from_utf8_lossy(output).replace("\r\n", "\n");
+    for filter in filters {
+        let regex = Regex::new(&filter.pattern)
+            .map_err(|err| format!("invalid snapshot filter {}: {}", filter.pattern, err))?;
+        output = regex
+            .replace_all(&output, filter.replacement.as_str())
+            .into_owned();
+    }
+    Ok(output)
+}
+
+/// Describes the first line where `actual` differs from `expected`
+fn first_difference(expected: &str, actual: &str) -> String {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    let mut line = 1;
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(expected), Some(actual)) if expected == actual => line += 1,
+            (expected, actual) => {
+                return format!(
+                    "first difference at line {}:\n- {}\n+ {}",
+                    line,
+                    expected.unwrap_or("<end of snapshot>"),
+                    actual.unwrap_or("<end of output>")
+                );
+            }
+        }
+    }
+}
+
+/// Compares `actual` against the snapshot at `path`, which is written if it doesn't exist or
+/// `update` is set
+pub fn check_snapshot(path: &Path, actual: &str, update: bool) -> Result<SnapshotOutcome, String> {
+    let expected = match std::fs::read_to_string(path) {
+        Ok(expected) if !update => expected.replace("\r\n", "\n"),
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(format!("failed to read {}: {}", path.display(), err));
+        }
+        _ => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+            }
+            std::fs::write(path, actual)
+                .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+            return Ok(SnapshotOutcome::Written);
+        }
+    };
+    if expected == actual {
+        Ok(SnapshotOutcome::Matched)
+    } else {
+        Ok(SnapshotOutcome::Mismatch(first_difference(
+            &expected, actual,
+        )))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot() {
+    let filters = [SnapshotFilterConfig {
+        pattern: "0x[0-9a-f]+".to_string(),
+        replacement: "[addr]".to_string(),
+    }];
+    let output = normalize(b"booting\r\nheap at 0xffff8000\r\n", &filters).unwrap();
+    assert_eq!(output, "booting\nheap at [addr]\n");
+
+    let path = std::env::temp_dir().join(format!(
+        "image-runner-snapshot-{}/boot.txt",
+        std::process::id()
+    ));
+    assert_eq!(
+        check_snapshot(&path, &output, false),
+        Ok(SnapshotOutcome::Written)
+    );
+    assert_eq!(
+        check_snapshot(&path, &output, false),
+        Ok(SnapshotOutcome::Matched)
+    );
+    assert_eq!(
+        check_snapshot(&path, "booting\npanic\n", false),
+        Ok(SnapshotOutcome::Mismatch(
+            "first difference at line 2:\n- heap at [addr]\n+ panic".to_string()
+        ))
+    );
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
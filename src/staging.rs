@@ -0,0 +1,105 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use crate::config::FastStaging;
+
+/// File systems that are backed by the network, where writing many small files is slow
+const NETWORK_FILE_SYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "virtiofs",
+    "fuse.sshfs",
+    "fuse.s3fs",
+    "ceph",
+    "glusterfs",
+];
+
+/// The type of the file system `path` is on, as listed in `mounts` (the format of
+/// `/proc/mounts`), which is the one of the longest mount point containing it
+pub fn file_system_type<'a>(mounts: &'a str, path: &Path) -> Option<&'a str> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let _device = fields.next()?;
+            // Spaces in mount points are escaped as \040
+            let mount_point = fields.next()?.replace("\\040", " ");
+            let fs_type = fields.next()?;
+            path.starts_with(&mount_point)
+                .then_some((mount_point.len(), fs_type))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, fs_type)| fs_type)
+}
+
+/// Whether `path` is on a network file system, which is only detected on Linux
+pub fn is_slow_storage(path: &Path) -> bool {
+    let Ok(mounts) = std::fs::read_to_string("/proc/mounts") else {
+        return false;
+    };
+    // The path may not exist yet, so the closest existing ancestor is resolved
+    let Some(path) = path.ancestors().find_map(|path| path.canonicalize().ok()) else {
+        return false;
+    };
+    file_system_type(&mounts, &path).is_some_and(|fs_type| NETWORK_FILE_SYSTEMS.contains(&fs_type))
+}
+
+/// A memory-backed directory to stage in, `/dev/shm` or the user's runtime directory
+fn memory_dir() -> Option<PathBuf> {
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() {
+        return Some(shm.to_path_buf());
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_dir())
+}
+
+/// The directory the image is staged in instead of `output_dir`, following `mode`, or `None` to
+/// stage in `output_dir` itself
+///
+/// The directory is unique to `output_dir`, so it persists across runs and unchanged files are
+/// not staged again.
+pub fn fast_staging_dir(mode: FastStaging, output_dir: &Path) -> Option<PathBuf> {
+    let enabled = match mode {
+        FastStaging::Never => false,
+        FastStaging::Always => true,
+        FastStaging::Auto => is_slow_storage(output_dir),
+    };
+    if !enabled {
+        return None;
+    }
+    let Some(memory_dir) = memory_dir() else {
+        if mode == FastStaging::Always {
+            eprintln!(
+                "warning: no memory-backed directory was found, staging in the output directory"
+            );
+        }
+        return None;
+    };
+    let mut hasher = DefaultHasher::new();
+    output_dir.hash(&mut hasher);
+    Some(memory_dir.join(format!("cargo-image-runner-{:016x}", hasher.finish())))
+}
+
+#[cfg(test)]
+#[test]
+fn test_file_system_type() {
+    let mounts = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+tmpfs /dev/shm tmpfs rw,nosuid,nodev 0 0
+server:/export /home/ci/build\\040dir nfs4 rw,relatime 0 0
+";
+    assert_eq!(
+        file_system_type(mounts, Path::new("/home/ci/build dir/target")),
+        Some("nfs4")
+    );
+    assert_eq!(
+        file_system_type(mounts, Path::new("/home/ci/target")),
+        Some("ext4")
+    );
+}
@@ -0,0 +1,205 @@
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::serial::SharedWriter;
+
+/// The number of chunks of output that are buffered before QEMU is paused until they are received
+const BUFFERED_EVENTS: usize = 16;
+
+/// An event of a run, see [`crate::context::Context::run_streaming`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunEvent {
+    /// Output on stdout, where the serial port of the guest is with `-serial stdio`
+    Output(Vec<u8>),
+    /// Output of QEMU on stderr
+    Stderr(Vec<u8>),
+    /// QEMU exited, with `None` if it was killed by a signal, this is always the last event
+    Exited(Option<i32>),
+}
+
+/// The events of a running QEMU, in the order they happened
+///
+/// Only a few events are buffered, QEMU blocks on its output until they are received, so a slow
+/// consumer such as a GUI isn't flooded. Dropping it kills QEMU.
+pub struct RunEvents {
+    receiver: Receiver<RunEvent>,
+    controller: RunController,
+}
+
+impl RunEvents {
+    /// Spawns `command` with its output piped, streaming it as events
+    pub fn spawn(command: &mut Command) -> std::io::Result<Self> {
+        Self::spawn_with(command, Vec::new())
+    }
+
+    /// Spawns `command` like [`RunEvents::spawn`], `helpers` are processes QEMU connects to, such
+    /// as `virtiofsd`, which are killed when it exits
+    pub(crate) fn spawn_with(command: &mut Command, helpers: Vec<Child>) -> std::io::Result<Self> {
+        let spawned = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                crate::share::stop_virtiofsd(helpers);
+                return Err(err);
+            }
+        };
+        let (sender, receiver) = sync_channel(BUFFERED_EVENTS);
+        let stdout = send_chunks(
+            child.stdout.take().unwrap(),
+            sender.clone(),
+            RunEvent::Output,
+        );
+        let stderr = send_chunks(
+            child.stderr.take().unwrap(),
+            sender.clone(),
+            RunEvent::Stderr,
+        );
+        let stdin = SharedWriter::new(child.stdin.take().unwrap());
+        let child = Arc::new(Mutex::new(child));
+
+        let waiter = child.clone();
+        std::thread::spawn(move || {
+            // The child is polled, so it can still be killed while it runs
+            let status = loop {
+                match waiter.lock().unwrap().try_wait() {
+                    Ok(Some(status)) => break Some(status),
+                    Ok(None) => {}
+                    Err(_) => break None,
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            };
+            crate::share::stop_virtiofsd(helpers);
+            let _ = stdout.join();
+            let _ = stderr.join();
+            let _ = sender.send(RunEvent::Exited(status.and_then(|status| status.code())));
+        });
+        let controller = RunController {
+            stdin,
+            child,
+            start: Instant::now(),
+        };
+        Ok(Self {
+            receiver,
+            controller,
+        })
+    }
+
+    /// A handle to send input to QEMU or stop it, which can be used from other threads while the
+    /// events are received
+    pub fn controller(&self) -> RunController {
+        self.controller.clone()
+    }
+
+    /// Kills QEMU, which still ends the events with [`RunEvent::Exited`]
+    pub fn kill(&self) {
+        self.controller.shutdown();
+    }
+}
+
+impl Iterator for RunEvents {
+    type Item = RunEvent;
+
+    fn next(&mut self) -> Option<RunEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Drop for RunEvents {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Controls a running QEMU from any thread, e.g. to send input on a timer, see
+/// [`RunEvents::controller`]
+#[derive(Debug, Clone)]
+pub struct RunController {
+    stdin: SharedWriter<ChildStdin>,
+    child: Arc<Mutex<Child>>,
+    start: Instant,
+}
+
+impl RunController {
+    /// Sends `bytes` to the standard input of QEMU, the serial port of the guest with
+    /// `-serial stdio`
+    pub fn send(&self, bytes: &[u8]) -> std::io::Result<()> {
+        let mut stdin = self.stdin.clone();
+        stdin.write_all(bytes)?;
+        stdin.flush()
+    }
+
+    /// Stops the guest by killing QEMU
+    pub fn shutdown(&self) {
+        let _ = self.child.lock().unwrap().kill();
+    }
+
+    /// How long QEMU has been running
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Sends what is read from `from` as events until it is closed or the events are dropped
+fn send_chunks(
+    mut from: impl Read + Send + 'static,
+    sender: SyncSender<RunEvent>,
+    event: fn(Vec<u8>) -> RunEvent,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            match from.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if sender.send(event(buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn test_run_events() {
+    let mut command = Command::new("sh");
+    command.args(["-c", "echo booting; echo warning >&2; exit 3"]);
+    let events: Vec<RunEvent> = RunEvents::spawn(&mut command).unwrap().collect();
+    assert!(events.contains(&RunEvent::Output(b"booting\n".to_vec())));
+    assert!(events.contains(&RunEvent::Stderr(b"warning\n".to_vec())));
+    assert_eq!(events.last(), Some(&RunEvent::Exited(Some(3))));
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn test_run_controller() {
+    let mut command = Command::new("sh");
+    command.args(["-c", "read line; echo got $line; exec sleep 10"]);
+    let events = RunEvents::spawn(&mut command).unwrap();
+    let controller = events.controller();
+    controller.send(b"hello\n").unwrap();
+    let mut output = Vec::new();
+    for event in events {
+        match event {
+            RunEvent::Output(bytes) => {
+                output.extend(bytes);
+                if output.ends_with(b"\n") {
+                    controller.shutdown();
+                }
+            }
+            RunEvent::Stderr(_) => {}
+            RunEvent::Exited(code) => assert_eq!(code, None),
+        }
+    }
+    assert_eq!(output, b"got hello\n");
+    assert!(controller.elapsed() < Duration::from_secs(10));
+}
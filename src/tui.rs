@@ -0,0 +1,241 @@
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::widgets::{Block, Paragraph};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, TryRecvError, sync_channel};
+use std::time::{Duration, Instant};
+
+use crate::config::BootStageConfig;
+use crate::context::Context;
+use crate::outcome::{self, CapturedOutput};
+use crate::qmp::QmpClient;
+use crate::stream::{RunController, RunEvent};
+
+/// How often the dashboard is redrawn while nothing happens
+const TICK: Duration = Duration::from_millis(100);
+
+/// The state shown by the dashboard
+struct Dashboard {
+    name: String,
+    /// The exit code of a successful test, or `None` for a run
+    success_code: Option<i32>,
+    idle_timeout: Option<Duration>,
+    stages: Vec<BootStageConfig>,
+    qmp_port: Option<u16>,
+    screenshot_dir: PathBuf,
+    serial: Vec<u8>,
+    stderr: Vec<u8>,
+    last_output: Instant,
+    /// The exit code of QEMU once it exited, `None` if it was killed
+    exited: Option<Option<i32>>,
+    /// The result of the last action, such as a screenshot
+    note: String,
+}
+
+impl Dashboard {
+    fn status(&self) -> String {
+        match (self.exited, self.success_code) {
+            (None, _) => "running".to_string(),
+            (Some(code), Some(success)) if code == Some(success) => "test passed".to_string(),
+            (Some(Some(code)), Some(_)) => format!("test failed, exit code {}", code),
+            (Some(Some(code)), None) => format!("exited with code {}", code),
+            (Some(None), _) => "killed".to_string(),
+        }
+    }
+
+    /// The exit code of the runner, following [`Context::run`]
+    fn exit_code(&self) -> i32 {
+        let success = self.success_code.unwrap_or(0);
+        match self.exited.flatten() {
+            Some(code) if code == success => 0,
+            Some(code) if code != 0 => code,
+            _ => 1,
+        }
+    }
+
+    fn screenshot(&self) -> Result<PathBuf, String> {
+        let port = self.qmp_port.ok_or("screenshots need qmp-port to be set")?;
+        std::fs::create_dir_all(&self.screenshot_dir).map_err(|err| err.to_string())?;
+        let path = self.screenshot_dir.join(format!(
+            "screenshot-{}.ppm",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        ));
+        let mut client = QmpClient::connect(("127.0.0.1", port))?;
+        client.screendump(&path.to_string_lossy())?;
+        Ok(path)
+    }
+
+    fn draw(&self, frame: &mut Frame, start: Instant) {
+        let [header, serial, stderr, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let mut header_text = format!(
+            "{} | {} | {:.1}s",
+            self.name,
+            self.status(),
+            start.elapsed().as_secs_f32()
+        );
+        if let Some(timeout) = self.idle_timeout {
+            header_text.push_str(&format!(
+                " | idle {:.1}s of {:.1}s",
+                self.last_output.elapsed().as_secs_f32(),
+                timeout.as_secs_f32()
+            ));
+        }
+        if !self.stages.is_empty() {
+            let mut output = CapturedOutput::default();
+            output.insert(CapturedOutput::SERIAL, self.serial.clone());
+            output.insert(CapturedOutput::QEMU_STDERR, self.stderr.clone());
+            let stage = outcome::reached_stage(&self.stages, &output)
+                .map_or("none", |index| self.stages[index].name.as_str());
+            header_text.push_str(&format!(" | stage: {}", stage));
+        }
+        frame.render_widget(Paragraph::new(header_text), header);
+
+        frame.render_widget(tail(&self.serial, serial, "serial"), serial);
+        frame.render_widget(tail(&self.stderr, stderr, "qemu"), stderr);
+
+        let mut footer_text = "ctrl+c: send ^C | s: screenshot | k: kill | q: quit".to_string();
+        if !self.note.is_empty() {
+            footer_text.push_str(" | ");
+            footer_text.push_str(&self.note);
+        }
+        frame.render_widget(Paragraph::new(footer_text), footer);
+    }
+}
+
+/// The last lines of `output` that fit in a bordered `area`
+fn tail<'a>(output: &[u8], area: Rect, title: &'a str) -> Paragraph<'a> {
+    let output = String::from_utf8_lossy(output).replace("\r\n", "\n");
+    let lines: Vec<&str> = output.lines().collect();
+    let height = area.height.saturating_sub(2) as usize;
+    let text = lines[lines.len().saturating_sub(height)..].join("\n");
+    Paragraph::new(text).block(Block::bordered().title(title))
+}
+
+/// Runs the image with an interactive dashboard of its output, returning the exit code of the
+/// runner
+///
+/// Keys are handled by the dashboard instead of being sent to the guest, Ctrl+C is sent to it as
+/// `^C` instead of stopping the runner.
+pub fn run(ctx: Context) -> i32 {
+    let config = ctx.config();
+    let mut dashboard = Dashboard {
+        name: ctx.executable_name().to_string_lossy().into_owned(),
+        success_code: ctx
+            .is_test()
+            .then_some(config.test_success_exit_code as i32),
+        idle_timeout: config
+            .test
+            .idle_timeout
+            .filter(|_| ctx.is_test())
+            .map(Duration::from_millis),
+        stages: config.boot_stages.clone(),
+        qmp_port: config.qmp_port,
+        screenshot_dir: ctx.scratch_dir(),
+        serial: Vec::new(),
+        stderr: Vec::new(),
+        last_output: Instant::now(),
+        exited: None,
+        note: String::new(),
+    };
+
+    let events = match ctx.run_streaming() {
+        Ok(events) => events,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return 1;
+        }
+    };
+    let controller = events.controller();
+    // The events are received on another thread, so keys are handled while the guest is quiet
+    let (sender, receiver) = sync_channel(16);
+    std::thread::spawn(move || {
+        for event in events {
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut dashboard, &controller, &receiver, start);
+    ratatui::restore();
+    controller.shutdown();
+    if let Err(err) = result {
+        eprintln!("error: the dashboard failed: {}", err);
+        return 1;
+    }
+    eprintln!("{}: {}", dashboard.name, dashboard.status());
+    dashboard.exit_code()
+}
+
+fn event_loop(
+    terminal: &mut ratatui::DefaultTerminal,
+    dashboard: &mut Dashboard,
+    controller: &RunController,
+    receiver: &Receiver<RunEvent>,
+    start: Instant,
+) -> std::io::Result<()> {
+    loop {
+        loop {
+            match receiver.try_recv() {
+                Ok(RunEvent::Output(bytes)) => {
+                    dashboard.serial.extend(bytes);
+                    dashboard.last_output = Instant::now();
+                }
+                Ok(RunEvent::Stderr(bytes)) => dashboard.stderr.extend(bytes),
+                Ok(RunEvent::Exited(code)) => dashboard.exited = Some(code),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        if let (None, Some(timeout)) = (dashboard.exited, dashboard.idle_timeout)
+            && dashboard.last_output.elapsed() > timeout
+        {
+            controller.shutdown();
+            dashboard.note = "the guest printed nothing for too long, killed it".to_string();
+        }
+
+        terminal.draw(|frame| dashboard.draw(frame, start))?;
+
+        if !event::poll(TICK)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                dashboard.note = match controller.send(&[0x03]) {
+                    Ok(()) => "sent ^C".to_string(),
+                    Err(err) => format!("failed to send ^C: {}", err),
+                };
+            }
+            KeyCode::Char('s') => {
+                dashboard.note = match dashboard.screenshot() {
+                    Ok(path) => format!("saved {}", path.display()),
+                    Err(err) => format!("screenshot failed: {}", err),
+                };
+            }
+            KeyCode::Char('k') => {
+                controller.shutdown();
+                dashboard.note = "killed QEMU".to_string();
+            }
+            KeyCode::Char('q') => return Ok(()),
+            _ => {}
+        }
+    }
+}